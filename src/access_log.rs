@@ -0,0 +1,175 @@
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+use futures::StreamExt;
+use hyper::body::HttpBody;
+use hyper::{Body, Request, Response};
+
+use crate::problem::Forwarded;
+
+// One line per request once its response body is sent (or the client went
+// away), an info event with the target `access` among the proxy's other
+// messages:
+//
+//     access: 192.0.2.7 "PROPFIND /docs/" 207 upstream in=0 out=1534 12ms
+//
+// or with each of those as a field of its own, for --log-format json to
+// print as a JSON object per line for log shippers. `upstream` marks answers
+// passed on from the upstream, `proxy` the ones the proxy made itself
+// (refusals, fallbacks, virtual files).
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Format {
+    Text,
+    Json,
+}
+
+impl Format {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match s.trim() {
+            "text" => Ok(Format::Text),
+            "json" => Ok(Format::Json),
+            _ => Err(format!("Unknown access log format (expected text or json): {}", s)),
+        }
+    }
+}
+
+pub struct Entry {
+    format: Format,
+    started: Instant,
+    client: SocketAddr,
+    method: String,
+    path: String,
+    received: Arc<AtomicU64>,
+    status: u16,
+    upstream: bool,
+    sent: u64,
+}
+
+// Start the entry of a request and count its body as it is read
+pub fn start(format: Format, client: SocketAddr, req: Request<Body>) -> (Request<Body>, Entry) {
+    let received = Arc::new(AtomicU64::new(0));
+    let counted = received.clone();
+    let entry = Entry {
+        format,
+        started: Instant::now(),
+        client,
+        method: req.method().to_string(),
+        path: req.uri().path().to_string(),
+        received,
+        status: 0,
+        upstream: false,
+        sent: 0,
+    };
+    // An empty body stays as it is, or it would go upstream chunked
+    let req = req.map(|body| match body.is_end_stream() {
+        true => body,
+        false => Body::wrap_stream(body.inspect(move |chunk| {
+            if let Ok(chunk) = chunk {
+                counted.fetch_add(chunk.len() as u64, Ordering::Relaxed);
+            }
+        })),
+    });
+    (req, entry)
+}
+
+impl Entry {
+    // Log when the body is done; one of known length right away, as
+    // wrapping it would lose the Content-Length
+    pub fn finish(mut self, response: Response<Body>) -> Response<Body> {
+        self.status = response.status().as_u16();
+        self.upstream = response.extensions().get::<Forwarded>().is_some();
+        if let Some(len) = response.body().size_hint().exact() {
+            self.sent = len;
+            return response;
+        }
+        response.map(|body| {
+            let mut entry = self;
+            Body::wrap_stream(body.inspect(move |chunk| {
+                if let Ok(chunk) = chunk {
+                    entry.count(chunk.len());
+                }
+            }))
+        })
+    }
+
+    fn count(&mut self, len: usize) {
+        self.sent += len as u64;
+    }
+}
+
+impl Drop for Entry {
+    fn drop(&mut self) {
+        let ms = self.started.elapsed().as_millis();
+        let received = self.received.load(Ordering::Relaxed);
+        let source = if self.upstream { "upstream" } else { "proxy" };
+        match self.format {
+            Format::Text => tracing::info!(
+                target: "access",
+                "access: {} \"{} {}\" {} {} in={} out={} {}ms",
+                self.client.ip(),
+                self.method,
+                self.path,
+                self.status,
+                source,
+                received,
+                self.sent,
+                ms
+            ),
+            Format::Json => tracing::info!(
+                target: "access",
+                client = %self.client.ip(),
+                method = %self.method,
+                path = %self.path,
+                status = self.status,
+                source,
+                bytes_in = received,
+                bytes_out = self.sent,
+                ms = ms as u64,
+                "access"
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    // What an upstream receives for `req`: its head and whatever body came
+    // before the answer
+    async fn sent_upstream(req: Request<Body>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let upstream = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut received = vec![0; 4096];
+            let n = socket.read(&mut received).await.unwrap();
+            socket.write_all(b"HTTP/1.1 201 Created\r\nContent-Length: 0\r\n\r\n").await.unwrap();
+            String::from_utf8_lossy(&received[..n]).to_lowercase()
+        });
+        let (mut parts, body) = req.into_parts();
+        parts.uri = format!("http://{}{}", address, parts.uri).parse().unwrap();
+        hyper::Client::new().request(Request::from_parts(parts, body)).await.unwrap();
+        upstream.await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn empty_bodies_stay_unchunked() {
+        let client = "127.0.0.1:1234".parse().unwrap();
+        let req = Request::builder().method("MKCOL").uri("/new/").body(Body::empty()).unwrap();
+        let (req, _entry) = start(Format::Text, client, req);
+        let sent = sent_upstream(req).await;
+        assert!(sent.starts_with("mkcol /new/ "));
+        assert!(!sent.contains("transfer-encoding"));
+
+        let req = Request::builder().method("PUT").uri("/a.txt").body(Body::from("hello")).unwrap();
+        let (req, entry) = start(Format::Text, client, req);
+        hyper::body::to_bytes(req.into_body()).await.unwrap();
+        assert_eq!(entry.received.load(Ordering::Relaxed), 5);
+    }
+}
@@ -0,0 +1,427 @@
+use hyper::header::{AUTHORIZATION, CONTENT_TYPE};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::digest::constant_time_eq;
+use crate::errors;
+use crate::features;
+use crate::fingerprint;
+use crate::info;
+use crate::json;
+use crate::local_dir;
+use crate::pause::{Mode, Paused, Scope};
+use crate::peers;
+use crate::routes::Disabled;
+use crate::state;
+use crate::switch;
+use crate::ProxyConfig;
+
+// Administrative API, served on its own listener so it never collides with
+// paths on the WebDAV share
+
+fn respond(status: StatusCode, content_type: &str, body: String) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, content_type)
+        .body(Body::from(body))
+        .expect("response builder")
+}
+
+fn json(body: String) -> Response<Body> {
+    respond(StatusCode::OK, "application/json", body)
+}
+
+fn not_found() -> Response<Body> {
+    respond(StatusCode::NOT_FOUND, "text/plain", "Not found\n".to_string())
+}
+
+fn bad_request(message: &str) -> Response<Body> {
+    respond(StatusCode::BAD_REQUEST, "text/plain", format!("{}\n", message))
+}
+
+// Look up a key in the request's query string
+fn query<'a>(req: &'a Request<Body>, key: &str) -> Option<&'a str> {
+    req.uri()
+        .query()?
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(k, _)| *k == key)
+        .map(|(_, v)| v)
+}
+
+// What each upstream last said it supports, null until it answered
+fn capabilities(config: &ProxyConfig) -> String {
+    let upstreams: Vec<String> = config
+        .settings
+        .current()
+        .upstreams
+        .iter()
+        .map(|(name, upstream)| {
+            json::object(&[
+                ("name", json::string(name)),
+                ("capabilities", upstream.capabilities.get().map_or("null".to_string(), |c| c.to_json())),
+            ])
+        })
+        .collect();
+    json::array(&upstreams)
+}
+
+fn pause_status(config: &ProxyConfig) -> Response<Body> {
+    let body = match config.pause.current() {
+        None => json::object(&[("paused", "false".to_string())]),
+        Some(p) => json::object(&[
+            ("paused", "true".to_string()),
+            ("scope", json::string(if p.scope == Scope::All { "all" } else { "writes" })),
+            ("mode", json::string(if p.mode == Mode::Queue { "queue" } else { "reject" })),
+            ("retry_after", p.retry_after.to_string()),
+            ("max_wait", p.max_wait.as_secs().to_string()),
+        ]),
+    };
+    json(body)
+}
+
+// POST /admin/pause?scope=writes|all&mode=queue|reject&retry_after=SECS&max_wait=SECS
+fn pause(req: &Request<Body>, config: &ProxyConfig) -> Response<Body> {
+    let scope = match query(req, "scope").unwrap_or("writes") {
+        "writes" => Scope::Writes,
+        "all" => Scope::All,
+        _ => return bad_request("scope must be writes or all"),
+    };
+    let mode = match query(req, "mode").unwrap_or("reject") {
+        "queue" => Mode::Queue,
+        "reject" => Mode::Reject,
+        _ => return bad_request("mode must be queue or reject"),
+    };
+    let number = |key: &str, default: u64| query(req, key).map_or(Ok(default), |v| v.parse::<u64>());
+    let (retry_after, max_wait) = match (number("retry_after", 30), number("max_wait", 60)) {
+        (Ok(r), Ok(w)) => (r, w),
+        _ => return bad_request("retry_after and max_wait must be numbers of seconds"),
+    };
+    config.pause.pause(Paused {
+        scope,
+        mode,
+        retry_after,
+        max_wait: Duration::from_secs(max_wait),
+    });
+    pause_status(config)
+}
+
+// POST /admin/routes/disable?prefix=/PATH&answer=fallback|404
+fn disable_route(req: &Request<Body>, config: &ProxyConfig) -> Response<Body> {
+    let Some(prefix) = query(req, "prefix").filter(|p| p.starts_with('/')) else {
+        return bad_request("prefix must be a path starting with /");
+    };
+    let answer = match Disabled::parse(query(req, "answer").unwrap_or("fallback")) {
+        Ok(answer) => answer,
+        Err(e) => return bad_request(&e),
+    };
+    match config.routes.disable(prefix, answer) {
+        Ok(()) => json(config.routes.to_json()),
+        Err(e) => save_failed(&e),
+    }
+}
+
+// POST /admin/routes/enable?prefix=/PATH
+fn enable_route(req: &Request<Body>, config: &ProxyConfig) -> Response<Body> {
+    let Some(prefix) = query(req, "prefix") else {
+        return bad_request("prefix is required");
+    };
+    match config.routes.enable(prefix) {
+        Ok(true) => json(config.routes.to_json()),
+        Ok(false) => not_found(),
+        Err(e) => save_failed(&e),
+    }
+}
+
+// POST /admin/rollout?percent=N
+fn set_rollout(req: &Request<Body>, config: &ProxyConfig) -> Response<Body> {
+    let Some(rollout) = &config.rollout else {
+        return not_found();
+    };
+    let Some(percent) = query(req, "percent").and_then(|p| p.parse().ok()) else {
+        return bad_request("percent must be a number from 0 to 100");
+    };
+    match rollout.set_percent(percent) {
+        Ok(()) => json(rollout.to_json()),
+        Err(e) => bad_request(&e),
+    }
+}
+
+// POST /admin/upstreams/switch?folder=NAME&to=REMOTE&check=1&drain=SECS
+async fn switch_upstream(req: &Request<Body>, config: &ProxyConfig) -> Response<Body> {
+    let Some(remote) = query(req, "to").and_then(local_dir::percent_decode).filter(|to| !to.is_empty()) else {
+        return bad_request("to must be the remote to switch to, as on the command line");
+    };
+    let folder = query(req, "folder").and_then(local_dir::percent_decode).unwrap_or_default();
+    let check = matches!(query(req, "check"), Some("1" | "true"));
+    let drain = match query(req, "drain").map(|secs| secs.parse::<u64>()) {
+        None => None,
+        Some(Ok(secs)) => Some(Duration::from_secs(secs)),
+        Some(Err(_)) => return bad_request("drain must be a number of seconds"),
+    };
+    match switch::run(config, folder.trim_matches('/'), &remote, check, drain).await {
+        Ok(report) => json(report),
+        Err(e) => respond(
+            StatusCode::CONFLICT,
+            "application/json",
+            json::object(&[("ok", "false".to_string()), ("error", json::string(&e))]),
+        ),
+    }
+}
+
+// POST /admin/guests?scope=/PATH/&hours=N[&name=NAME]. Without a token
+// anyone reaching the admin API could let themselves in.
+fn mint_guest(req: &Request<Body>, config: &ProxyConfig, token: &Option<String>) -> Response<Body> {
+    let Some(auth) = &config.auth else {
+        return bad_request("Guest accounts need the proxy to authenticate (--htpasswd, --ldap-url, --pam-service or --negotiate keytab)");
+    };
+    if token.is_none() {
+        return respond(StatusCode::FORBIDDEN, "text/plain", "Guest accounts need an --admin-token\n".to_string());
+    }
+    let Some(scope) = query(req, "scope").and_then(local_dir::percent_decode) else {
+        return bad_request("scope must be the folder the guest may use, e.g. /projects/acme/");
+    };
+    let hours = match query(req, "hours").map_or(Ok(24), |hours| hours.parse::<u64>()) {
+        Ok(hours) if hours > 0 => hours,
+        _ => return bad_request("hours must be a positive number"),
+    };
+    let name = query(req, "name").and_then(local_dir::percent_decode);
+    match config.guests.mint(name.as_deref(), &scope, Duration::from_secs(hours * 3600), |name| auth.has_user(name)) {
+        Ok((name, password, expires)) => {
+            tracing::info!("Guest {} may use {} for {} hour(s)", name, scope, hours);
+            json(json::object(&[
+                ("name", json::string(&name)),
+                ("password", json::string(&password)),
+                ("scope", json::string(&scope)),
+                ("expires", expires.to_string()),
+            ]))
+        }
+        Err(e) => bad_request(&e),
+    }
+}
+
+// POST /admin/guests/revoke?name=NAME
+fn revoke_guest(req: &Request<Body>, config: &ProxyConfig) -> Response<Body> {
+    let Some(name) = query(req, "name").and_then(local_dir::percent_decode) else {
+        return bad_request("name is required");
+    };
+    match config.guests.revoke(&name) {
+        true => json(config.guests.to_json()),
+        false => not_found(),
+    }
+}
+
+// POST /admin/requests/cancel?id=N
+fn cancel_request(req: &Request<Body>, config: &ProxyConfig) -> Response<Body> {
+    let Some(in_flight) = &config.in_flight else {
+        return not_found();
+    };
+    let Some(id) = query(req, "id").and_then(|id| id.parse().ok()) else {
+        return bad_request("id must be the number of an active request");
+    };
+    if in_flight.cancel(id) {
+        json(json::object(&[("cancelled", id.to_string())]))
+    } else {
+        not_found()
+    }
+}
+
+// POST /admin/transfers/cancel?id=N
+fn cancel_transfer(req: &Request<Body>, config: &ProxyConfig) -> Response<Body> {
+    let Some(transfers) = &config.transfers else {
+        return not_found();
+    };
+    let Some(id) = query(req, "id").and_then(|id| id.parse().ok()) else {
+        return bad_request("id must be the number of an active transfer");
+    };
+    if transfers.cancel(id) {
+        json(json::object(&[("cancelled", id.to_string())]))
+    } else {
+        not_found()
+    }
+}
+
+// POST /admin/state with the text GET /admin/state gave another instance.
+// Guest accounts come with it, so as with minting them a token is needed.
+async fn import_state(req: Request<Body>, config: &ProxyConfig, token: &Option<String>) -> Response<Body> {
+    if token.is_none() {
+        return respond(StatusCode::FORBIDDEN, "text/plain", "Importing state needs an --admin-token\n".to_string());
+    }
+    let body = match hyper::body::to_bytes(req.into_body()).await {
+        Ok(body) => body,
+        Err(e) => return bad_request(&format!("Failed to read the state: {}", e)),
+    };
+    let Ok(text) = std::str::from_utf8(&body) else {
+        return bad_request("The state must be text");
+    };
+    let (applied, problems) = state::import(config, text);
+    let problems: Vec<String> = problems.iter().map(|p| json::string(p)).collect();
+    json(json::object(&[("applied", applied.to_string()), ("problems", json::array(&problems))]))
+}
+
+// POST /admin/peer/usage from another instance (--peer)
+async fn peer_usage(req: Request<Body>, config: &ProxyConfig) -> Response<Body> {
+    let Some(limits) = &config.client_limits else {
+        return not_found();
+    };
+    let body = match hyper::body::to_bytes(req.into_body()).await {
+        Ok(body) => body,
+        Err(e) => return bad_request(&format!("Failed to read the report: {}", e)),
+    };
+    let Some(usage) = std::str::from_utf8(&body).ok().and_then(peers::parse) else {
+        return bad_request("The report must be IP<TAB>REQUESTS lines");
+    };
+    for (ip, requests) in &usage {
+        limits.charge(*ip, *requests);
+    }
+    json(json::object(&[("clients", usage.len().to_string())]))
+}
+
+// The change is in effect, but won't survive a restart
+fn save_failed(e: &std::io::Error) -> Response<Body> {
+    respond(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "application/json",
+        json::object(&[("ok", "false".to_string()), ("error", json::string(&format!("Failed to save route state: {}", e)))]),
+    )
+}
+
+fn authorized(req: &Request<Body>, token: &Option<String>) -> bool {
+    match token {
+        None => true,
+        Some(token) => req
+            .headers()
+            .get(AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .is_some_and(|v| constant_time_eq(v.as_bytes(), token.as_bytes())),
+    }
+}
+
+// The build, for telling apart binaries with different features
+fn version() -> String {
+    let features: Vec<String> = features::ENABLED.iter().map(|f| json::string(f)).collect();
+    json::object(&[
+        ("version", json::string(env!("CARGO_PKG_VERSION"))),
+        ("features", json::array(&features)),
+    ])
+}
+
+async fn handle(req: Request<Body>, config: Arc<ProxyConfig>, token: Arc<Option<String>>) -> Result<Response<Body>, Infallible> {
+    if !authorized(&req, &token) {
+        return Ok(respond(StatusCode::UNAUTHORIZED, "text/plain", "Unauthorized\n".to_string()));
+    }
+    let response = match (req.method(), req.uri().path()) {
+        (&Method::GET, "/admin/version") => json(version()),
+        (&Method::GET, "/admin/info") => json(info::to_json(&config)),
+        (&Method::GET, "/admin/errors") => json(config.errors.to_json()),
+        (&Method::GET, "/admin/memory") => json(config.memory.to_json()),
+        (&Method::GET, "/admin/capabilities") => json(capabilities(&config)),
+        (&Method::GET, "/admin/fingerprint") => json(fingerprint::report(&config).await),
+        (&Method::GET, "/admin/concurrency") => match &config.gate {
+            Some(gate) => json(gate.to_json()),
+            None => not_found(),
+        },
+        (&Method::GET, "/admin/stats") => json(config.stats.to_json()),
+        (&Method::GET, "/admin/pause") => pause_status(&config),
+        (&Method::POST, "/admin/pause") => pause(&req, &config),
+        (&Method::POST, "/admin/snapshot") => match &config.snapshot {
+            Some(hook) => match hook.run(&config).await {
+                Ok(()) => json(json::object(&[("ok", "true".to_string())])),
+                Err(e) => respond(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "application/json",
+                    json::object(&[("ok", "false".to_string()), ("error", json::string(&e))]),
+                ),
+            },
+            None => not_found(),
+        },
+        (&Method::GET, "/admin/requests") => match &config.in_flight {
+            Some(in_flight) => json(in_flight.to_json()),
+            None => not_found(),
+        },
+        (&Method::POST, "/admin/requests/cancel") => cancel_request(&req, &config),
+        (&Method::GET, "/admin/transfers") => match &config.transfers {
+            Some(transfers) => json(transfers.to_json()),
+            None => not_found(),
+        },
+        (&Method::POST, "/admin/transfers/cancel") => cancel_transfer(&req, &config),
+        (&Method::GET, "/admin/rollout") => match &config.rollout {
+            Some(rollout) => json(rollout.to_json()),
+            None => not_found(),
+        },
+        (&Method::POST, "/admin/rollout") => set_rollout(&req, &config),
+        (&Method::GET, "/admin/routes") => json(config.routes.to_json()),
+        (&Method::POST, "/admin/routes/disable") => disable_route(&req, &config),
+        (&Method::POST, "/admin/routes/enable") => enable_route(&req, &config),
+        (&Method::GET, "/admin/guests") => json(config.guests.to_json()),
+        (&Method::POST, "/admin/guests") => mint_guest(&req, &config, &token),
+        (&Method::POST, "/admin/guests/revoke") => revoke_guest(&req, &config),
+        (&Method::POST, "/admin/reload") => match crate::reload(&config) {
+            Ok(settings) => json(json::object(&[
+                ("ok", "true".to_string()),
+                ("upstreams", settings.upstreams.iter().count().to_string()),
+            ])),
+            Err(e) => respond(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "application/json",
+                json::object(&[("ok", "false".to_string()), ("error", json::string(&e))]),
+            ),
+        },
+        (&Method::POST, "/admin/upstreams/switch") => switch_upstream(&req, &config).await,
+        (&Method::GET, "/admin/state") => respond(StatusCode::OK, "text/plain; charset=utf-8", state::export(&config)),
+        (&Method::POST, "/admin/state") => import_state(req, &config, &token).await,
+        (&Method::POST, peers::PATH) => peer_usage(req, &config).await,
+        (&Method::POST, "/admin/resume") => {
+            config.pause.resume();
+            pause_status(&config)
+        }
+        _ => not_found(),
+    };
+    Ok(response)
+}
+
+pub async fn serve(addr: SocketAddr, config: Arc<ProxyConfig>, token: Option<String>) {
+    let token = Arc::new(token);
+    let make_svc = make_service_fn(move |_conn| {
+        let config = config.clone();
+        let token = token.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| handle(req, config.clone(), token.clone())))
+        }
+    });
+    if let Err(e) = Server::bind(&addr).serve(make_svc).await {
+        errors::report(format!("Admin server error: {}", e));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::WebdavProxy;
+
+    fn import(token: Option<&str>, bearer: Option<&str>) -> (StatusCode, String) {
+        let config = WebdavProxy::builder().upstream("http://127.0.0.1:1/").build().unwrap().config;
+        let mut req = Request::builder().method(Method::POST).uri("/admin/state");
+        if let Some(bearer) = bearer {
+            req = req.header(AUTHORIZATION, format!("Bearer {}", bearer));
+        }
+        let req = req.body(Body::from("guest\tguest-x\tSALT\tHASH\t99999999999\t/share/\n")).unwrap();
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        let response = runtime.block_on(handle(req, config.clone(), Arc::new(token.map(str::to_string)))).unwrap();
+        (response.status(), config.guests.to_json())
+    }
+
+    #[test]
+    fn imports_guests_only_with_a_token() {
+        assert_eq!(import(None, None), (StatusCode::FORBIDDEN, "[]".to_string()));
+        assert_eq!(import(Some("secret"), Some("wrong")).0, StatusCode::UNAUTHORIZED);
+        let (status, guests) = import(Some("secret"), Some("secret"));
+        assert_eq!(status, StatusCode::OK);
+        assert!(guests.contains("guest-x"));
+    }
+}
@@ -0,0 +1,50 @@
+use std::fs::{self, File};
+use std::io::{self, Write};
+
+// Replace the file at `path` with `contents` so that a crash leaves either
+// the old file or the new one, never a truncated one: written to PATH.tmp,
+// flushed to disk, then renamed over it. The temporary file is removed if
+// anything fails.
+pub fn write(path: &str, contents: impl AsRef<[u8]>) -> io::Result<()> {
+    let tmp = format!("{}.tmp", path);
+    let written = File::create(&tmp)
+        .and_then(|mut file| {
+            file.write_all(contents.as_ref())?;
+            file.sync_all()
+        })
+        .and_then(|_| fs::rename(&tmp, path));
+    if written.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    written?;
+    // The rename only lasts once the folder is on disk too
+    #[cfg(unix)]
+    if let Some(parent) = std::path::Path::new(path).parent() {
+        let parent = if parent.as_os_str().is_empty() { std::path::Path::new(".") } else { parent };
+        File::open(parent)?.sync_all()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    #[test]
+    fn replaces_whole_files() {
+        let dir = std::env::temp_dir().join(format!("atomic-file-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("state").to_string_lossy().into_owned();
+        write(&path, "old").unwrap();
+        write(&path, "new").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "new");
+        assert!(!Path::new(&format!("{}.tmp", path)).exists());
+        // A folder in the way fails the rename, which leaves no temporary file
+        fs::create_dir_all(dir.join("busy")).unwrap();
+        let busy = dir.join("busy").to_string_lossy().into_owned();
+        assert!(write(&busy, "new").is_err());
+        assert!(!Path::new(&format!("{}.tmp", busy)).exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
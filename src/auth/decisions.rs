@@ -0,0 +1,74 @@
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use hyper::StatusCode;
+
+// Recent outcomes of the authenticators that check Basic credentials, so a
+// client sending the same Authorization header request after request (as
+// WebDAV clients do) doesn't pay for a bcrypt hash, a PAM conversation or an
+// LDAP bind every time. Entries are keyed by the credentials and the scope
+// the authenticator decides on, and live for a short TTL or until a reload.
+
+#[derive(Clone)]
+pub enum Decision {
+    Allowed(String),
+    // 401 or 403
+    Denied(StatusCode),
+}
+
+struct Entry {
+    at: Instant,
+    decision: Decision,
+}
+
+pub struct DecisionCache {
+    ttl: Duration,
+    entries: Mutex<HashMap<u64, Entry>>,
+    hasher: RandomState,
+}
+
+impl DecisionCache {
+    pub fn new(ttl: Duration) -> Self {
+        DecisionCache {
+            ttl,
+            entries: Mutex::new(HashMap::new()),
+            hasher: RandomState::new(),
+        }
+    }
+
+    // Only a hash of the credentials is kept
+    pub fn key(&self, credentials: &[u8], scope: &str) -> u64 {
+        let mut h = self.hasher.build_hasher();
+        credentials.hash(&mut h);
+        scope.hash(&mut h);
+        h.finish()
+    }
+
+    pub fn get(&self, key: u64) -> Option<Decision> {
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, e| e.at.elapsed() < self.ttl);
+        entries.get(&key).map(|e| e.decision.clone())
+    }
+
+    pub fn insert(&self, key: u64, decision: Decision) {
+        self.entries.lock().unwrap().insert(
+            key,
+            Entry {
+                at: Instant::now(),
+                decision,
+            },
+        );
+    }
+
+    // After the users or their groups may have changed
+    pub fn clear(&self) {
+        let mut entries = self.entries.lock().unwrap();
+        if !entries.is_empty() {
+            tracing::info!("Dropped {} cached authorization decisions", entries.len());
+            entries.clear();
+        }
+    }
+}
@@ -0,0 +1,267 @@
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use hyper::header::{HeaderMap, AUTHORIZATION};
+use hyper::{Body, Response};
+
+use super::{basic_credentials, forbidden, AuthUser};
+use crate::digest;
+use crate::json;
+use crate::local_dir::percent_decode;
+use crate::methods;
+
+// Temporary accounts for contractors and the like, minted through the
+// admin API (POST /admin/guests) or the `guest` subcommand: a generated
+// password, an expiry and a folder outside of which the guest gets 403.
+// Guests log in with Basic auth ahead of the configured authentication,
+// which must exist, so the share isn't open anyway; a wrong guest password
+// is left to it. Guests can't take the name of a user, and where the users
+// can't be listed their names start with guest-. Passwords are kept
+// salted and hashed; the accounts are part of the runtime state (GET
+// /admin/state, --state-file), so they survive restarts and moves, and
+// are forgotten once expired.
+
+// Random bytes of a generated password and of a salt
+const PASSWORD_BYTES: usize = 12;
+
+struct Guest {
+    name: String,
+    salt: String,
+    hash: String,
+    // Seconds since the epoch
+    expires: u64,
+    // A path without the trailing slash, empty for the whole share
+    scope: String,
+}
+
+#[derive(Default)]
+pub struct Guests {
+    accounts: Mutex<Vec<Guest>>,
+}
+
+fn random_hex(length: usize) -> String {
+    let mut bytes = vec![0; length];
+    digest::random(&mut bytes);
+    digest::hex(&bytes)
+}
+
+fn digest(salt: &str, password: &str) -> String {
+    digest::hex(&digest::sha256(format!("{}{}", salt, password).as_bytes()))
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+fn valid_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | '@'))
+}
+
+// Whether `name` and `scope` make a guest: a name of no user (one starting
+// with guest- where `is_user` can't tell) and a folder below the root
+fn check(name: &str, scope: &str, is_user: impl Fn(&str) -> Option<bool>) -> Result<(), String> {
+    if !valid_name(name) {
+        return Err(format!("Invalid guest name (letters, digits and -_.@ only): {}", name));
+    }
+    if !scope.starts_with('/') || scope.split('/').any(|segment| segment == ".." || segment == ".") {
+        return Err(format!("The scope must be a folder path starting with /: {}", scope));
+    }
+    if scope.trim_matches('/').is_empty() {
+        return Err("The scope must be a folder, not the whole share".to_string());
+    }
+    match is_user(name) {
+        Some(true) => Err(format!("There already is a user named {}", name)),
+        None if !name.starts_with("guest-") => {
+            Err(format!("Guest names must start with guest- unless the users can be listed (htpasswd, PAM): {}", name))
+        }
+        _ => Ok(()),
+    }
+}
+
+// Whether a request path, percent-encoded, lies within `scope`
+fn within(scope: &str, path: &str) -> bool {
+    let Some(path) = percent_decode(path) else {
+        return false;
+    };
+    // No way up out of the folder
+    if path.split('/').any(|segment| segment == ".." || segment == ".") {
+        return false;
+    }
+    path == scope || path.strip_prefix(scope).is_some_and(|rest| rest.starts_with('/'))
+}
+
+impl Guest {
+    fn to_json(&self) -> String {
+        json::object(&[
+            ("name", json::string(&self.name)),
+            ("scope", json::string(&format!("{}/", self.scope))),
+            ("expires", self.expires.to_string()),
+            ("expires_in", self.expires.saturating_sub(now()).to_string()),
+        ])
+    }
+}
+
+impl Guests {
+    // A new account for `scope`, a folder, valid for `valid`, named `name`
+    // or `guest-` and random hex digits; its name, password and expiry.
+    // `is_user` tells whether a name is a user's, None if it can't tell.
+    pub fn mint(
+        &self,
+        name: Option<&str>,
+        scope: &str,
+        valid: Duration,
+        is_user: impl Fn(&str) -> Option<bool>,
+    ) -> Result<(String, String, u64), String> {
+        let name = name.map_or_else(|| format!("guest-{}", random_hex(4)), str::to_string);
+        check(&name, scope, is_user)?;
+        let mut accounts = self.accounts.lock().unwrap();
+        let now = now();
+        accounts.retain(|guest| guest.expires > now);
+        if accounts.iter().any(|guest| guest.name == name) {
+            return Err(format!("There already is a guest named {}", name));
+        }
+        let password = random_hex(PASSWORD_BYTES);
+        let salt = random_hex(PASSWORD_BYTES);
+        let expires = now + valid.as_secs();
+        accounts.push(Guest {
+            name: name.clone(),
+            hash: digest(&salt, &password),
+            salt,
+            expires,
+            scope: scope.trim_end_matches('/').to_string(),
+        });
+        Ok((name, password, expires))
+    }
+
+    // False if there was no such guest
+    pub fn revoke(&self, name: &str) -> bool {
+        let mut accounts = self.accounts.lock().unwrap();
+        let before = accounts.len();
+        accounts.retain(|guest| guest.name != name);
+        accounts.len() < before
+    }
+
+    // The outcome of a request with a guest's Basic credentials, None for
+    // anyone else's, a user's password included: a user who got the name
+    // of a guest later on isn't locked out. The credentials are consumed
+    // as by `authenticate`.
+    pub fn authenticate(&self, path: &str, headers: &mut HeaderMap) -> Option<Result<AuthUser, Response<Body>>> {
+        let (user, password) = basic_credentials(headers)?;
+        let mut accounts = self.accounts.lock().unwrap();
+        let now = now();
+        accounts.retain(|guest| guest.expires > now);
+        let guest = accounts
+            .iter()
+            .find(|guest| guest.name == user)
+            .filter(|guest| digest::constant_time_eq(digest(&guest.salt, &password).as_bytes(), guest.hash.as_bytes()))?;
+        let destination = methods::destination_path(headers);
+        if !within(&guest.scope, path) || destination.is_some_and(|d| !within(&guest.scope, &d)) {
+            return Some(Err(forbidden()));
+        }
+        headers.remove(AUTHORIZATION);
+        Some(Ok(AuthUser {
+            name: user,
+            response_challenge: None,
+        }))
+    }
+
+    pub fn to_json(&self) -> String {
+        let mut accounts = self.accounts.lock().unwrap();
+        let now = now();
+        accounts.retain(|guest| guest.expires > now);
+        json::array(&accounts.iter().map(Guest::to_json).collect::<Vec<_>>())
+    }
+
+    // The accounts as tab-separated fields for the runtime state: name,
+    // salt, hash, expiry and scope
+    pub fn export(&self) -> Vec<String> {
+        let now = now();
+        self.accounts
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|guest| guest.expires > now)
+            .map(|guest| format!("{}\t{}\t{}\t{}\t{}/", guest.name, guest.salt, guest.hash, guest.expires, guest.scope))
+            .collect()
+    }
+
+    // An account as `export` wrote it; false if the fields don't make one
+    // `mint` would have made. Accounts that expired meanwhile are left out.
+    pub fn restore(&self, fields: &[&str], is_user: impl Fn(&str) -> Option<bool>) -> bool {
+        let [name, salt, hash, expires, scope] = fields else {
+            return false;
+        };
+        let Ok(expires) = expires.parse::<u64>() else {
+            return false;
+        };
+        if hash.is_empty() || check(name, scope, is_user).is_err() {
+            return false;
+        }
+        let mut accounts = self.accounts.lock().unwrap();
+        accounts.retain(|guest| guest.name != *name);
+        if expires > now() {
+            accounts.push(Guest {
+                name: name.to_string(),
+                salt: salt.to_string(),
+                hash: hash.to_string(),
+                expires,
+                scope: scope.trim_end_matches('/').to_string(),
+            });
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::base64;
+    use hyper::header::HeaderValue;
+
+    fn basic(user: &str, password: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        let credentials = base64::encode(format!("{}:{}", user, password).as_bytes());
+        headers.insert(AUTHORIZATION, HeaderValue::from_str(&format!("Basic {}", credentials)).unwrap());
+        headers
+    }
+
+    #[test]
+    fn keeps_to_names_of_no_user() {
+        let guests = Guests::default();
+        let day = Duration::from_secs(86400);
+        let htpasswd = |name: &str| Some(name == "alice");
+        assert!(guests.mint(Some("alice"), "/share/", day, htpasswd).is_err());
+        assert!(guests.mint(Some("bob"), "/share/", day, htpasswd).is_ok());
+        let ldap = |_: &str| None;
+        assert!(guests.mint(Some("carol"), "/share/", day, ldap).is_err());
+        assert!(guests.mint(Some("guest-carol"), "/share/", day, ldap).is_ok());
+        assert!(guests.mint(None, "/share/", day, ldap).is_ok());
+    }
+
+    #[test]
+    fn restores_only_what_mint_would_make() {
+        let guests = Guests::default();
+        let expires = (now() + 3600).to_string();
+        let htpasswd = |name: &str| Some(name == "alice");
+        let line = |name, scope| [name, "SALT", "HASH", expires.as_str(), scope];
+        assert!(guests.restore(&line("bob", "/share/"), htpasswd));
+        assert!(!guests.restore(&line("alice", "/share/"), htpasswd));
+        assert!(!guests.restore(&line("carol", "/"), htpasswd));
+        assert!(!guests.restore(&line("carol", "/share/../"), htpasswd));
+        assert!(!guests.restore(&line("carol", "/share/"), |_| None));
+        assert!(guests.restore(&line("guest-carol", "/share/"), |_| None));
+        assert!(guests.mint(Some("dave"), "/", Duration::from_secs(60), htpasswd).is_err());
+        assert_eq!(guests.export().len(), 2);
+    }
+
+    #[test]
+    fn leaves_other_passwords_to_the_authentication() {
+        let guests = Guests::default();
+        let (name, password, _) = guests.mint(Some("dave"), "/share/", Duration::from_secs(60), |_| Some(false)).unwrap();
+        let outcome = guests.authenticate("/share/a.txt", &mut basic(&name, &password));
+        assert!(outcome.is_some_and(|outcome| outcome.is_ok_and(|user| user.name == "dave")));
+        assert!(guests.authenticate("/share/a.txt", &mut basic(&name, "not the guest's")).is_none());
+        let outside = guests.authenticate("/other/", &mut basic(&name, &password));
+        assert!(outside.is_some_and(|outcome| outcome.is_err()));
+    }
+}
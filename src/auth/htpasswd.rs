@@ -0,0 +1,213 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+
+use crate::base64;
+use crate::digest::{self, constant_time_eq};
+use crate::errors;
+use crate::signals::Signals;
+
+// Basic-auth backend reading an Apache-style htpasswd file. The file is
+// re-read when it changes (or on a reload signal), so users can be added or removed
+// without restarting and dropping active transfers.
+pub struct Htpasswd {
+    path: String,
+    users: RwLock<HashMap<String, String>>,
+    modified: Mutex<Option<SystemTime>>,
+}
+
+impl Htpasswd {
+    pub fn load(path: &str) -> Result<Self, String> {
+        let htpasswd = Htpasswd {
+            path: path.to_string(),
+            users: RwLock::new(HashMap::new()),
+            modified: Mutex::new(None),
+        };
+        htpasswd.reload()?;
+        Ok(htpasswd)
+    }
+
+    fn mtime(&self) -> Option<SystemTime> {
+        std::fs::metadata(&self.path).and_then(|m| m.modified()).ok()
+    }
+
+    // Swap in the file's current contents; on error the previous users stay
+    pub fn reload(&self) -> Result<usize, String> {
+        let modified = self.mtime();
+        let contents = std::fs::read_to_string(&self.path).map_err(|e| format!("{}: {}", self.path, e))?;
+        let users: HashMap<String, String> = contents
+            .lines()
+            .map(|l| l.trim())
+            .filter(|l| !l.is_empty() && !l.starts_with('#'))
+            .filter_map(|l| l.split_once(':'))
+            .map(|(user, hash)| (user.to_string(), hash.to_string()))
+            .collect();
+        let count = users.len();
+        *self.users.write().unwrap() = users;
+        *self.modified.lock().unwrap() = modified;
+        Ok(count)
+    }
+
+    fn reload_if_changed(&self) {
+        let modified = self.mtime();
+        if modified.is_some() && modified != *self.modified.lock().unwrap() {
+            match self.reload() {
+                Ok(count) => tracing::info!("Reloaded {} ({} users)", self.path, count),
+                Err(e) => errors::report(format!("Failed to reload {}: {}", self.path, e)),
+            }
+        }
+    }
+
+    // Poll the file for changes and reload on request (SIGHUP, SIGUSR1)
+    pub fn spawn_watcher(self: Arc<Self>, signals: &Signals) {
+        let watched = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(2));
+            loop {
+                interval.tick().await;
+                watched.reload_if_changed();
+            }
+        });
+        signals.on_reload(move || match self.reload() {
+            Ok(count) => tracing::info!("Reloaded {} ({} users)", self.path, count),
+            Err(e) => errors::report(format!("Failed to reload {}: {}", self.path, e)),
+        });
+    }
+
+    pub fn contains(&self, user: &str) -> bool {
+        self.users.read().unwrap().contains_key(user)
+    }
+
+    pub fn verify(&self, user: &str, password: &str) -> bool {
+        let stored = match self.users.read().unwrap().get(user) {
+            Some(stored) => stored.clone(),
+            None => return false,
+        };
+        verify_hash(&stored, password)
+    }
+}
+
+fn verify_hash(stored: &str, password: &str) -> bool {
+    if let Some(sha) = stored.strip_prefix("{SHA}") {
+        let digest = digest::sha1(password.as_bytes());
+        return constant_time_eq(base64::encode(&digest).as_bytes(), sha.as_bytes());
+    }
+    if let Some(rest) = stored.strip_prefix("$apr1$") {
+        let salt = rest.split('$').next().unwrap_or("");
+        return constant_time_eq(apr1(password, salt).as_bytes(), stored.as_bytes());
+    }
+    if stored.starts_with('$') {
+        // bcrypt, sha256-crypt, sha512-crypt, md5-crypt
+        return match crypt(password, stored) {
+            Some(computed) => constant_time_eq(computed.as_bytes(), stored.as_bytes()),
+            None => false,
+        };
+    }
+    // htpasswd -p stores the password in the clear
+    constant_time_eq(stored.as_bytes(), password.as_bytes())
+}
+
+const ITOA64: &[u8; 64] = b"./0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+
+fn md5(data: &[u8]) -> Vec<u8> {
+    digest::md5(data).to_vec()
+}
+
+// Apache's MD5 variant ($apr1$), as implemented by apr_md5_encode
+fn apr1(password: &str, salt: &str) -> String {
+    let pw = password.as_bytes();
+    let salt = &salt.as_bytes()[..salt.len().min(8)];
+
+    let alt = md5(&[pw, salt, pw].concat());
+    let mut ctx = [pw, b"$apr1$", salt].concat();
+    let mut remaining = pw.len();
+    while remaining > 0 {
+        ctx.extend_from_slice(&alt[..remaining.min(16)]);
+        remaining = remaining.saturating_sub(16);
+    }
+    let mut i = pw.len();
+    while i > 0 {
+        if i & 1 == 1 {
+            ctx.push(0);
+        } else {
+            ctx.push(*pw.first().unwrap_or(&0));
+        }
+        i >>= 1;
+    }
+    let mut digest = md5(&ctx);
+
+    for round in 0..1000 {
+        let mut ctx = Vec::new();
+        if round & 1 == 1 {
+            ctx.extend_from_slice(pw);
+        } else {
+            ctx.extend_from_slice(&digest);
+        }
+        if round % 3 != 0 {
+            ctx.extend_from_slice(salt);
+        }
+        if round % 7 != 0 {
+            ctx.extend_from_slice(pw);
+        }
+        if round & 1 == 1 {
+            ctx.extend_from_slice(&digest);
+        } else {
+            ctx.extend_from_slice(pw);
+        }
+        digest = md5(&ctx);
+    }
+
+    let mut out = format!("$apr1${}$", String::from_utf8_lossy(salt));
+    let mut to64 = |mut v: u32, n: usize| {
+        for _ in 0..n {
+            out.push(ITOA64[(v & 0x3f) as usize] as char);
+            v >>= 6;
+        }
+    };
+    for (a, b, c) in [(0, 6, 12), (1, 7, 13), (2, 8, 14), (3, 9, 15), (4, 10, 5)] {
+        to64((digest[a] as u32) << 16 | (digest[b] as u32) << 8 | digest[c] as u32, 4);
+    }
+    to64(digest[11] as u32, 2);
+    out
+}
+
+#[cfg(unix)]
+fn crypt(password: &str, setting: &str) -> Option<String> {
+    use std::ffi::{c_char, CStr, CString};
+
+    #[cfg_attr(target_os = "linux", link(name = "crypt"))]
+    extern "C" {
+        fn crypt(key: *const c_char, salt: *const c_char) -> *mut c_char;
+    }
+    // crypt(3) returns a static buffer
+    static LOCK: Mutex<()> = Mutex::new(());
+
+    let key = CString::new(password).ok()?;
+    let salt = CString::new(setting).ok()?;
+    let _guard = LOCK.lock().unwrap();
+    unsafe {
+        let out = crypt(key.as_ptr(), salt.as_ptr());
+        if out.is_null() {
+            return None;
+        }
+        CStr::from_ptr(out).to_str().ok().map(|s| s.to_string())
+    }
+}
+
+#[cfg(not(unix))]
+fn crypt(_password: &str, _setting: &str) -> Option<String> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verifies_sha1_apr1_and_plain_entries() {
+        for stored in ["{SHA}5en6G6MezRroT3XKqkdPOmY/BfQ=", "$apr1$r31....$gnsoqlxyxQQ0Ot5JCwiei.", "secret"] {
+            assert!(verify_hash(stored, "secret"), "{}", stored);
+            assert!(!verify_hash(stored, "Secret"), "{}", stored);
+        }
+    }
+}
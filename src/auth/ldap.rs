@@ -0,0 +1,495 @@
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+
+use crate::errors;
+
+// Basic-auth backend that verifies credentials with an LDAP simple bind and
+// maps LDAP group membership onto path prefixes
+
+// How long connecting, and each exchange after, may take; past it a stalled
+// server fails the login instead of holding every request that needs one
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+pub struct LdapAuth {
+    host: String,
+    port: u16,
+    tls: bool,
+    // DN template with a {user} placeholder, e.g. uid={user},ou=people,dc=example,dc=org
+    user_dn: String,
+    // Path prefix -> group DN whose members may access it
+    groups: Vec<(String, String)>,
+    group_attr: String,
+    cache_ttl: Duration,
+    cache: Mutex<HashMap<u64, CachedBind>>,
+    hasher: RandomState,
+    timeout: Duration,
+}
+
+struct CachedBind {
+    at: Instant,
+    // Membership for each entry of `groups`, in order
+    member_of: Vec<bool>,
+}
+
+pub enum LdapOutcome {
+    Allowed,
+    BadCredentials,
+    Forbidden,
+}
+
+impl LdapAuth {
+    pub fn new(
+        url: &str,
+        user_dn: String,
+        groups: Vec<(String, String)>,
+        group_attr: String,
+        cache_ttl: Duration,
+    ) -> Result<Self, String> {
+        let (tls, rest) = if let Some(rest) = url.strip_prefix("ldaps://") {
+            (true, rest)
+        } else if let Some(rest) = url.strip_prefix("ldap://") {
+            (false, rest)
+        } else {
+            return Err(format!("LDAP URL must start with ldap:// or ldaps://: {}", url));
+        };
+        if tls && !cfg!(feature = "tls") {
+            return Err(format!("Cannot use {}: built without the tls feature", url));
+        }
+        let rest = rest.trim_end_matches('/');
+        let (host, port) = match rest.rsplit_once(':') {
+            Some((host, port)) => (
+                host.to_string(),
+                port.parse().map_err(|_| format!("Invalid LDAP port in {}", url))?,
+            ),
+            None => (rest.to_string(), if tls { 636 } else { 389 }),
+        };
+        if !user_dn.contains("{user}") {
+            return Err("The LDAP user DN template needs a {user} placeholder".to_string());
+        }
+        Ok(LdapAuth {
+            host,
+            port,
+            tls,
+            user_dn,
+            groups,
+            group_attr,
+            cache_ttl,
+            cache: Mutex::new(HashMap::new()),
+            hasher: RandomState::new(),
+            timeout: TIMEOUT,
+        })
+    }
+
+    fn cache_key(&self, user: &str, password: &str) -> u64 {
+        let mut h = self.hasher.build_hasher();
+        user.hash(&mut h);
+        password.hash(&mut h);
+        h.finish()
+    }
+
+    pub async fn check(&self, user: &str, password: &str, path: &str) -> LdapOutcome {
+        // An empty password would be an anonymous bind, which always succeeds
+        if user.is_empty() || password.is_empty() {
+            return LdapOutcome::BadCredentials;
+        }
+        let key = self.cache_key(user, password);
+        let cached = {
+            let mut cache = self.cache.lock().unwrap();
+            cache.retain(|_, c| c.at.elapsed() < self.cache_ttl);
+            cache.get(&key).map(|c| c.member_of.clone())
+        };
+        let member_of = match cached {
+            Some(member_of) => member_of,
+            None => match self.bind_and_lookup(user, password).await {
+                Ok(Some(member_of)) => {
+                    self.cache.lock().unwrap().insert(
+                        key,
+                        CachedBind {
+                            at: Instant::now(),
+                            member_of: member_of.clone(),
+                        },
+                    );
+                    member_of
+                }
+                Ok(None) => return LdapOutcome::BadCredentials,
+                Err(e) => {
+                    errors::report(format!("LDAP error: {}", e));
+                    return LdapOutcome::BadCredentials;
+                }
+            },
+        };
+        self.authorize(path, &member_of)
+    }
+
+    // The longest group prefix matching `path`, which decides on it
+    pub fn scope<'a>(&'a self, path: &str) -> Option<&'a str> {
+        self.groups
+            .iter()
+            .map(|(prefix, _)| prefix.as_str())
+            .filter(|prefix| path.starts_with(prefix))
+            .max_by_key(|prefix| prefix.len())
+    }
+
+    // The longest matching prefix decides; paths without a mapping are open
+    // to every authenticated user
+    fn authorize(&self, path: &str, member_of: &[bool]) -> LdapOutcome {
+        let longest = match self.scope(path) {
+            Some(prefix) => prefix.len(),
+            None => return LdapOutcome::Allowed,
+        };
+        let allowed = self
+            .groups
+            .iter()
+            .zip(member_of)
+            .any(|((prefix, _), member)| prefix.len() == longest && path.starts_with(prefix.as_str()) && *member);
+        if allowed {
+            LdapOutcome::Allowed
+        } else {
+            LdapOutcome::Forbidden
+        }
+    }
+
+    async fn connect(&self) -> Result<Box<dyn Stream>, String> {
+        let tcp = timeout(self.timeout, TcpStream::connect((self.host.as_str(), self.port)))
+            .await
+            .map_err(|_| "connect timeout".to_string())?
+            .map_err(|e| e.to_string())?;
+        if !self.tls {
+            return Ok(Box::new(tcp));
+        }
+        #[cfg(not(feature = "tls"))]
+        return Err("ldaps:// needs the tls feature".to_string());
+        #[cfg(feature = "tls")]
+        {
+            let connector = native_tls::TlsConnector::new().map_err(|e| e.to_string())?;
+            let tls = timeout(self.timeout, tokio_native_tls::TlsConnector::from(connector).connect(&self.host, tcp))
+                .await
+                .map_err(|_| "TLS handshake timeout".to_string())?
+                .map_err(|e| e.to_string())?;
+            Ok(Box::new(tls))
+        }
+    }
+
+    async fn send(&self, stream: &mut Box<dyn Stream>, message: &[u8]) -> Result<(), String> {
+        timeout(self.timeout, stream.write_all(message))
+            .await
+            .map_err(|_| "write timeout".to_string())?
+            .map_err(|e| e.to_string())
+    }
+
+    async fn receive(&self, stream: &mut Box<dyn Stream>) -> Result<(u8, Vec<u8>), String> {
+        timeout(self.timeout, read_message(stream)).await.map_err(|_| "read timeout".to_string())?
+    }
+
+    // Returns None when the bind is rejected, or the group memberships otherwise
+    async fn bind_and_lookup(&self, user: &str, password: &str) -> Result<Option<Vec<bool>>, String> {
+        let dn = self.user_dn.replace("{user}", &escape_dn(user));
+        let mut stream = self.connect().await?;
+
+        self.send(&mut stream, &bind_request(1, &dn, password)).await?;
+        let (tag, body) = self.receive(&mut stream).await?;
+        if tag != 0x61 {
+            return Err(format!("unexpected LDAP response tag {:#x}", tag));
+        }
+        let code = result_code(&body)?;
+        if code != 0 {
+            // 49 is invalidCredentials; anything else is worth logging
+            if code != 49 {
+                tracing::warn!("LDAP bind for {} failed with result code {}", dn, code);
+            }
+            return Ok(None);
+        }
+
+        let mut member_of = Vec::new();
+        for (i, (_, group)) in self.groups.iter().enumerate() {
+            let id = i as u32 + 2;
+            self.send(&mut stream, &search_request(id, group, &self.group_attr, &dn)).await?;
+            let mut found = false;
+            loop {
+                let (tag, body) = self.receive(&mut stream).await?;
+                match tag {
+                    0x64 => found = true,
+                    0x65 => {
+                        let code = result_code(&body)?;
+                        // 32 is noSuchObject: the group doesn't exist
+                        if code != 0 && code != 32 {
+                            tracing::warn!("LDAP search in {} failed with result code {}", group, code);
+                        }
+                        break;
+                    }
+                    // Referrals and intermediate responses carry no membership
+                    _ => {}
+                }
+            }
+            member_of.push(found);
+        }
+
+        let _ = self.send(&mut stream, &unbind_request(self.groups.len() as u32 + 2)).await;
+        Ok(Some(member_of))
+    }
+}
+
+// RFC 4514 escaping for a value placed in a DN
+fn escape_dn(value: &str) -> String {
+    let mut out = String::new();
+    for (i, c) in value.chars().enumerate() {
+        match c {
+            ',' | '+' | '"' | '\\' | '<' | '>' | ';' | '=' => {
+                out.push('\\');
+                out.push(c);
+            }
+            '#' | ' ' if i == 0 => {
+                out.push('\\');
+                out.push(c);
+            }
+            '\0' => out.push_str("\\00"),
+            _ => out.push(c),
+        }
+    }
+    if out.ends_with(' ') {
+        out.pop();
+        out.push_str("\\ ");
+    }
+    out
+}
+
+// Minimal BER encoding for the handful of LDAP operations used here
+
+fn ber_len(len: usize, out: &mut Vec<u8>) {
+    if len < 0x80 {
+        out.push(len as u8);
+    } else {
+        let bytes = len.to_be_bytes();
+        let skip = bytes.iter().take_while(|&&b| b == 0).count();
+        out.push(0x80 | (bytes.len() - skip) as u8);
+        out.extend_from_slice(&bytes[skip..]);
+    }
+}
+
+fn tlv(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut out = vec![tag];
+    ber_len(content.len(), &mut out);
+    out.extend_from_slice(content);
+    out
+}
+
+fn integer(tag: u8, value: u32) -> Vec<u8> {
+    let mut bytes = value.to_be_bytes().to_vec();
+    while bytes.len() > 1 && bytes[0] == 0 && bytes[1] & 0x80 == 0 {
+        bytes.remove(0);
+    }
+    // Keep the value positive in two's complement
+    if bytes[0] & 0x80 != 0 {
+        bytes.insert(0, 0);
+    }
+    tlv(tag, &bytes)
+}
+
+fn message(id: u32, op: Vec<u8>) -> Vec<u8> {
+    tlv(0x30, &[integer(0x02, id), op].concat())
+}
+
+fn bind_request(id: u32, dn: &str, password: &str) -> Vec<u8> {
+    let op = tlv(
+        0x60,
+        &[integer(0x02, 3), tlv(0x04, dn.as_bytes()), tlv(0x80, password.as_bytes())].concat(),
+    );
+    message(id, op)
+}
+
+// Base-scope search on the group entry, matching only if the user DN is a member
+fn search_request(id: u32, base: &str, attr: &str, member: &str) -> Vec<u8> {
+    let filter = tlv(0xa3, &[tlv(0x04, attr.as_bytes()), tlv(0x04, member.as_bytes())].concat());
+    let op = tlv(
+        0x63,
+        &[
+            tlv(0x04, base.as_bytes()),
+            tlv(0x0a, &[0]),
+            tlv(0x0a, &[0]),
+            integer(0x02, 1),
+            integer(0x02, 5),
+            tlv(0x01, &[0]),
+            filter,
+            tlv(0x30, &tlv(0x04, b"1.1")),
+        ]
+        .concat(),
+    );
+    message(id, op)
+}
+
+fn unbind_request(id: u32) -> Vec<u8> {
+    message(id, vec![0x42, 0x00])
+}
+
+async fn read_tlv_header<S: AsyncRead + Unpin + ?Sized>(stream: &mut S) -> Result<(u8, usize), String> {
+    let mut hdr = [0u8; 2];
+    stream.read_exact(&mut hdr).await.map_err(|e| e.to_string())?;
+    let len = if hdr[1] & 0x80 == 0 {
+        hdr[1] as usize
+    } else {
+        let n = (hdr[1] & 0x7f) as usize;
+        if n == 0 || n > 4 {
+            return Err("unsupported BER length".to_string());
+        }
+        let mut buf = [0u8; 4];
+        stream.read_exact(&mut buf[4 - n..]).await.map_err(|e| e.to_string())?;
+        u32::from_be_bytes(buf) as usize
+    };
+    Ok((hdr[0], len))
+}
+
+// Read one LDAPMessage and return the protocolOp tag and its content
+async fn read_message<S: AsyncRead + Unpin + ?Sized>(stream: &mut S) -> Result<(u8, Vec<u8>), String> {
+    let (tag, len) = read_tlv_header(stream).await?;
+    if tag != 0x30 || len > 1 << 20 {
+        return Err("malformed LDAP message".to_string());
+    }
+    let mut body = vec![0u8; len];
+    stream.read_exact(&mut body).await.map_err(|e| e.to_string())?;
+    // Skip the message ID
+    let (_, rest) = split_tlv(&body)?;
+    let (op, _) = split_tlv(rest)?;
+    Ok((op.0, op.1.to_vec()))
+}
+
+type Tlv<'a> = (u8, &'a [u8]);
+
+fn split_tlv(data: &[u8]) -> Result<(Tlv<'_>, &[u8]), String> {
+    if data.len() < 2 {
+        return Err("truncated BER element".to_string());
+    }
+    let (len, start) = if data[1] & 0x80 == 0 {
+        (data[1] as usize, 2)
+    } else {
+        let n = (data[1] & 0x7f) as usize;
+        if n == 0 || n > 4 || data.len() < 2 + n {
+            return Err("unsupported BER length".to_string());
+        }
+        let len = data[2..2 + n].iter().fold(0usize, |acc, &b| acc << 8 | b as usize);
+        (len, 2 + n)
+    };
+    if data.len() < start + len {
+        return Err("truncated BER element".to_string());
+    }
+    Ok(((data[0], &data[start..start + len]), &data[start + len..]))
+}
+
+// The resultCode is the first element of every LDAPResult
+fn result_code(body: &[u8]) -> Result<u32, String> {
+    let ((tag, value), _) = split_tlv(body)?;
+    if tag != 0x0a {
+        return Err("LDAP result without a result code".to_string());
+    }
+    Ok(value.iter().fold(0u32, |acc, &b| acc << 8 | b as u32))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The elements of a constructed BER value, in order
+    fn elements(mut data: &[u8]) -> Vec<Tlv<'_>> {
+        let mut out = Vec::new();
+        while !data.is_empty() {
+            let (element, rest) = split_tlv(data).unwrap();
+            out.push(element);
+            data = rest;
+        }
+        out
+    }
+
+    // The message ID and the operation of an encoded LDAPMessage
+    fn operation(message: &[u8]) -> (u32, Tlv<'_>) {
+        let ((tag, body), rest) = split_tlv(message).unwrap();
+        assert_eq!((tag, rest.len()), (0x30, 0));
+        let parts = elements(body);
+        let id = parts[0].1.iter().fold(0u32, |acc, &b| acc << 8 | b as u32);
+        (id, parts[1])
+    }
+
+    #[test]
+    fn escapes_dn_values() {
+        assert_eq!(escape_dn("alice"), "alice");
+        assert_eq!(escape_dn(r#"a,b+c"d\e<f>g;h=i"#), r#"a\,b\+c\"d\\e\<f\>g\;h\=i"#);
+        assert_eq!(escape_dn("#admin"), "\\#admin");
+        assert_eq!(escape_dn("a#b"), "a#b");
+        assert_eq!(escape_dn(" x "), "\\ x\\ ");
+        // Filter syntax means nothing in a DN
+        assert_eq!(escape_dn("*)(uid=*"), "*)(uid\\=*");
+        assert_eq!(escape_dn("a\0b"), "a\\00b");
+    }
+
+    #[test]
+    fn encodes_binds() {
+        let dn = format!("uid={},ou=people,dc=example,dc=org", escape_dn("o'neil,admin"));
+        let message = bind_request(1, &dn, "pa*ss(word)");
+        let (id, (tag, op)) = operation(&message);
+        assert_eq!((id, tag), (1, 0x60));
+        let parts = elements(op);
+        assert_eq!(parts[0], (0x02, &[3][..]));
+        assert_eq!(parts[1], (0x04, dn.as_bytes()));
+        assert_eq!(parts[2], (0x80, &b"pa*ss(word)"[..]));
+    }
+
+    #[test]
+    fn encodes_searches() {
+        // Long enough for multi-byte lengths
+        let member = format!("uid=*)(cn=*,ou={},dc=example,dc=org", "x".repeat(300));
+        let message = search_request(300, "cn=staff,ou=groups,dc=example,dc=org", "member", &member);
+        let (id, (tag, op)) = operation(&message);
+        assert_eq!((id, tag), (300, 0x63));
+        let parts = elements(op);
+        assert_eq!(parts[0], (0x04, &b"cn=staff,ou=groups,dc=example,dc=org"[..]));
+        // Base scope, never dereference, one entry, five seconds, not types only
+        assert_eq!(parts[1..6], [(0x0a, &[0][..]), (0x0a, &[0][..]), (0x02, &[1][..]), (0x02, &[5][..]), (0x01, &[0][..])]);
+        // An equality match carries the DN as it is, so `*()` in it match nothing but themselves
+        assert_eq!(parts[6].0, 0xa3);
+        assert_eq!(elements(parts[6].1), [(0x04, &b"member"[..]), (0x04, member.as_bytes())]);
+        assert_eq!(parts[7], (0x30, &[0x04, 0x03, b'1', b'.', b'1'][..]));
+        assert_eq!(operation(&unbind_request(7)), (7, (0x42, &[][..])));
+    }
+
+    #[test]
+    fn encodes_integers() {
+        for (value, encoded) in [(0, &[0][..]), (127, &[127]), (128, &[0, 128]), (256, &[1, 0]), (u32::MAX, &[0, 255, 255, 255, 255])] {
+            assert_eq!(integer(0x02, value), [&[0x02, encoded.len() as u8][..], encoded].concat());
+        }
+    }
+
+    #[tokio::test]
+    async fn reads_results() {
+        let bind_response: &[u8] = &[0x30, 0x0c, 0x02, 0x01, 0x01, 0x61, 0x07, 0x0a, 0x01, 0x31, 0x04, 0x00, 0x04, 0x00];
+        let (tag, body) = read_message(&mut &bind_response[..]).await.unwrap();
+        assert_eq!((tag, result_code(&body)), (0x61, Ok(49)));
+        assert!(read_message(&mut &bind_response[..8]).await.is_err());
+        assert!(split_tlv(&[0x04, 0x85, 1, 2, 3, 4, 5]).is_err());
+    }
+
+    #[tokio::test]
+    async fn gives_up_on_stalled_servers() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ldap://{}", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            // Takes the bind and never answers
+            let (socket, _) = listener.accept().await.unwrap();
+            tokio::time::sleep(Duration::from_secs(10)).await;
+            drop(socket);
+        });
+        let mut ldap = LdapAuth::new(&url, "uid={user},dc=example,dc=org".to_string(), Vec::new(), "member".to_string(), Duration::ZERO).unwrap();
+        ldap.timeout = Duration::from_millis(200);
+        let started = Instant::now();
+        assert_eq!(ldap.bind_and_lookup("alice", "secret").await, Err("read timeout".to_string()));
+        assert!(started.elapsed() < Duration::from_secs(2));
+        server.abort();
+    }
+}
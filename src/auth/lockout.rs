@@ -0,0 +1,71 @@
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use hyper::{Body, Response, StatusCode};
+
+// Locks out addresses that keep failing to authenticate: after
+// `max_failures` failures within `window`, further requests from the
+// address are answered with 429 until `cooldown` has passed.
+pub struct Lockout {
+    max_failures: usize,
+    window: Duration,
+    cooldown: Duration,
+    clients: Mutex<HashMap<IpAddr, Client>>,
+}
+
+#[derive(Default)]
+struct Client {
+    failures: VecDeque<Instant>,
+    locked_until: Option<Instant>,
+}
+
+impl Lockout {
+    pub fn new(max_failures: usize, window: Duration, cooldown: Duration) -> Self {
+        Lockout {
+            max_failures,
+            window,
+            cooldown,
+            clients: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // The 429 to answer with while `ip` is locked out
+    pub fn check(&self, ip: IpAddr) -> Option<Response<Body>> {
+        let clients = self.clients.lock().unwrap();
+        let remaining = clients.get(&ip)?.locked_until?.checked_duration_since(Instant::now())?;
+        Some(
+            Response::builder()
+                .status(StatusCode::TOO_MANY_REQUESTS)
+                .header("Retry-After", remaining.as_secs() + 1)
+                .header("Content-Type", "text/plain")
+                .body(Body::from("Too many failed login attempts, try again later\n"))
+                .expect("response builder"),
+        )
+    }
+
+    // Count a failed attempt; returns whether it locked the address out
+    pub fn failed(&self, ip: IpAddr) -> bool {
+        let now = Instant::now();
+        let mut clients = self.clients.lock().unwrap();
+        clients.retain(|_, c| {
+            c.locked_until.is_some_and(|t| t > now) || c.failures.back().is_some_and(|t| now - *t < self.window)
+        });
+        let client = clients.entry(ip).or_default();
+        while client.failures.front().is_some_and(|t| now - *t >= self.window) {
+            client.failures.pop_front();
+        }
+        client.failures.push_back(now);
+        if client.failures.len() < self.max_failures {
+            return false;
+        }
+        client.failures.clear();
+        client.locked_until = Some(now + self.cooldown);
+        true
+    }
+
+    pub fn succeeded(&self, ip: IpAddr) {
+        self.clients.lock().unwrap().remove(&ip);
+    }
+}
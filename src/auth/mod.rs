@@ -0,0 +1,241 @@
+use hyper::header::{HeaderMap, HeaderValue, AUTHORIZATION, WWW_AUTHENTICATE};
+use hyper::{Body, Response, StatusCode};
+
+pub mod decisions;
+pub mod guests;
+pub mod htpasswd;
+#[cfg(feature = "ldap")]
+pub mod ldap;
+pub mod lockout;
+pub mod negotiate;
+#[cfg(feature = "pam")]
+pub mod pam;
+
+use crate::base64;
+use decisions::{Decision, DecisionCache};
+use htpasswd::Htpasswd;
+#[cfg(feature = "ldap")]
+use ldap::{LdapAuth, LdapOutcome};
+use std::sync::Arc;
+use negotiate::KeytabAcceptor;
+
+// Authentication performed by the proxy itself, before forwarding
+pub enum Authenticator {
+    // SPNEGO/Kerberos against a keytab
+    Negotiate(KeytabAcceptor),
+    // Basic credentials checked against an htpasswd file
+    Htpasswd(Arc<Htpasswd>),
+    // Basic credentials checked with an LDAP bind
+    #[cfg(feature = "ldap")]
+    Ldap(LdapAuth),
+    // Basic credentials checked against the host's PAM stack
+    #[cfg(feature = "pam")]
+    Pam(pam::PamAuth),
+}
+
+// A successfully authenticated downstream user
+pub struct AuthUser {
+    pub name: String,
+    // Extra WWW-Authenticate value for the final response (mutual auth)
+    pub response_challenge: Option<HeaderValue>,
+}
+
+// Split an Authorization header into its scheme and credentials
+fn credentials<'a>(headers: &'a HeaderMap, scheme: &str) -> Option<&'a str> {
+    let value = headers.get(AUTHORIZATION)?.to_str().ok()?;
+    let (s, rest) = value.split_once(' ')?;
+    if s.eq_ignore_ascii_case(scheme) {
+        Some(rest.trim())
+    } else {
+        None
+    }
+}
+
+// Decode Basic credentials into user and password
+fn basic_credentials(headers: &HeaderMap) -> Option<(String, String)> {
+    let decoded = base64::decode(credentials(headers, "Basic")?)?;
+    let decoded = String::from_utf8(decoded).ok()?;
+    let (user, password) = decoded.split_once(':')?;
+    Some((user.to_string(), password.to_string()))
+}
+
+// Whether the request tried to authenticate at all, as opposed to a
+// client that sends credentials only after being challenged
+pub fn attempted(headers: &HeaderMap) -> bool {
+    headers.contains_key(AUTHORIZATION)
+}
+
+// Log a rejected login on one line that fail2ban and the like can match:
+// auth failure: client=192.0.2.7 user=alice status=401 path=/docs/
+pub fn log_failure(client: std::net::IpAddr, headers: &HeaderMap, status: StatusCode, path: &str) {
+    let user = basic_credentials(headers).map(|(user, _)| user);
+    let clean = |s: &str| -> String {
+        s.chars()
+            .map(|c| if c.is_whitespace() || c.is_control() { '_' } else { c })
+            .collect()
+    };
+    tracing::warn!(
+        "auth failure: client={} user={} status={} path={}",
+        client,
+        user.as_deref().map(clean).unwrap_or("-".to_string()),
+        status.as_u16(),
+        clean(path)
+    );
+}
+
+const BASIC_CHALLENGE: &str = "Basic realm=\"WebDAV\", charset=\"UTF-8\"";
+
+fn unauthorized(challenge: &'static str) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::UNAUTHORIZED)
+        .header(WWW_AUTHENTICATE, challenge)
+        .body(Body::empty())
+        .expect("response builder")
+}
+
+fn forbidden() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::FORBIDDEN)
+        .body(Body::empty())
+        .expect("response builder")
+}
+
+impl Authenticator {
+    // What a decision on `path` depends on besides the credentials, None
+    // if it can't be cached: Negotiate tokens are single-use, and only
+    // LDAP group mappings look at the path
+    #[cfg_attr(not(feature = "ldap"), allow(unused_variables))]
+    fn scope(&self, path: &str) -> Option<String> {
+        match self {
+            Authenticator::Negotiate(_) => None,
+            #[cfg(feature = "ldap")]
+            Authenticator::Ldap(ldap) => Some(ldap.scope(path).unwrap_or("").to_string()),
+            _ => Some(String::new()),
+        }
+    }
+
+    // Whether `name` is one of the users, None where they can't be listed
+    // (LDAP needs a bind to search, Kerberos principals live in the KDC)
+    pub fn has_user(&self, name: &str) -> Option<bool> {
+        match self {
+            Authenticator::Htpasswd(htpasswd) => Some(htpasswd.contains(name)),
+            #[cfg(feature = "pam")]
+            Authenticator::Pam(pam) => Some(pam.has_user(name)),
+            _ => None,
+        }
+    }
+
+    // Check the request's credentials. On success the Authorization header
+    // is consumed so it doesn't leak to the upstream; on failure the
+    // challenge response to return to the client is given instead. With a
+    // cache, a recent decision on the same credentials and scope is reused.
+    pub async fn authenticate(
+        &self,
+        path: &str,
+        headers: &mut HeaderMap,
+        cache: Option<&DecisionCache>,
+    ) -> Result<AuthUser, Response<Body>> {
+        let key = match (cache, headers.get(AUTHORIZATION), self.scope(path)) {
+            (Some(cache), Some(credentials), Some(scope)) => Some((cache, cache.key(credentials.as_bytes(), &scope))),
+            _ => None,
+        };
+        let Some((cache, key)) = key else {
+            return self.check(path, headers).await;
+        };
+        match cache.get(key) {
+            Some(Decision::Allowed(name)) => {
+                headers.remove(AUTHORIZATION);
+                Ok(AuthUser {
+                    name,
+                    response_challenge: None,
+                })
+            }
+            Some(Decision::Denied(StatusCode::FORBIDDEN)) => Err(forbidden()),
+            Some(Decision::Denied(_)) => Err(unauthorized(BASIC_CHALLENGE)),
+            None => {
+                let outcome = self.check(path, headers).await;
+                let decision = match &outcome {
+                    Ok(user) => Decision::Allowed(user.name.clone()),
+                    Err(rejection) => Decision::Denied(rejection.status()),
+                };
+                cache.insert(key, decision);
+                outcome
+            }
+        }
+    }
+
+    #[cfg_attr(not(feature = "ldap"), allow(unused_variables))]
+    async fn check(&self, path: &str, headers: &mut HeaderMap) -> Result<AuthUser, Response<Body>> {
+        match self {
+            Authenticator::Htpasswd(htpasswd) => {
+                let (user, password) = match basic_credentials(headers) {
+                    Some(creds) => creds,
+                    None => return Err(unauthorized(BASIC_CHALLENGE)),
+                };
+                if !htpasswd.verify(&user, &password) {
+                    return Err(unauthorized(BASIC_CHALLENGE));
+                }
+                headers.remove(AUTHORIZATION);
+                Ok(AuthUser {
+                    name: user,
+                    response_challenge: None,
+                })
+            }
+            #[cfg(feature = "ldap")]
+            Authenticator::Ldap(ldap) => {
+                let (user, password) = match basic_credentials(headers) {
+                    Some(creds) => creds,
+                    None => return Err(unauthorized(BASIC_CHALLENGE)),
+                };
+                match ldap.check(&user, &password, path).await {
+                    LdapOutcome::Allowed => {
+                        headers.remove(AUTHORIZATION);
+                        Ok(AuthUser {
+                            name: user,
+                            response_challenge: None,
+                        })
+                    }
+                    LdapOutcome::BadCredentials => Err(unauthorized(BASIC_CHALLENGE)),
+                    LdapOutcome::Forbidden => Err(forbidden()),
+                }
+            }
+            #[cfg(feature = "pam")]
+            Authenticator::Pam(pam) => {
+                let (user, password) = match basic_credentials(headers) {
+                    Some(creds) => creds,
+                    None => return Err(unauthorized(BASIC_CHALLENGE)),
+                };
+                if !pam.check(&user, &password).await {
+                    return Err(unauthorized(BASIC_CHALLENGE));
+                }
+                headers.remove(AUTHORIZATION);
+                Ok(AuthUser {
+                    name: user,
+                    response_challenge: None,
+                })
+            }
+            Authenticator::Negotiate(acceptor) => {
+                let token = match credentials(headers, "Negotiate") {
+                    Some(token) => token.to_string(),
+                    None => return Err(unauthorized("Negotiate")),
+                };
+                match acceptor.accept(&token).await {
+                    Ok(accepted) => {
+                        headers.remove(AUTHORIZATION);
+                        let response_challenge = accepted
+                            .output_token
+                            .and_then(|t| HeaderValue::from_str(&format!("Negotiate {}", t)).ok());
+                        Ok(AuthUser {
+                            name: accepted.principal,
+                            response_challenge,
+                        })
+                    }
+                    Err(e) => {
+                        tracing::warn!("Negotiate authentication failed: {}", e);
+                        Err(unauthorized("Negotiate"))
+                    }
+                }
+            }
+        }
+    }
+}
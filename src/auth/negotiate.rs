@@ -0,0 +1,215 @@
+use std::ffi::{c_char, c_void, CString};
+use std::sync::Arc;
+
+use crate::base64;
+
+// SPNEGO/Kerberos acceptor backed by the system GSSAPI library, loaded at
+// runtime so the binary doesn't need Kerberos development files to build
+
+type OmUint32 = u32;
+
+#[repr(C)]
+struct GssBuffer {
+    length: usize,
+    value: *mut c_void,
+}
+
+type AcceptSecContext = unsafe extern "C" fn(
+    *mut OmUint32,
+    *mut *mut c_void,
+    *mut c_void,
+    *mut GssBuffer,
+    *mut c_void,
+    *mut *mut c_void,
+    *mut *mut c_void,
+    *mut GssBuffer,
+    *mut OmUint32,
+    *mut OmUint32,
+    *mut *mut c_void,
+) -> OmUint32;
+type DisplayName =
+    unsafe extern "C" fn(*mut OmUint32, *mut c_void, *mut GssBuffer, *mut *mut c_void) -> OmUint32;
+type ReleaseBuffer = unsafe extern "C" fn(*mut OmUint32, *mut GssBuffer) -> OmUint32;
+type ReleaseName = unsafe extern "C" fn(*mut OmUint32, *mut *mut c_void) -> OmUint32;
+type RegisterAcceptorIdentity = unsafe extern "C" fn(*const c_char) -> OmUint32;
+type DeleteSecContext =
+    unsafe extern "C" fn(*mut OmUint32, *mut *mut c_void, *mut GssBuffer) -> OmUint32;
+
+const GSS_S_COMPLETE: OmUint32 = 0;
+
+// MIT Kerberos, then Heimdal
+const LIBRARIES: [&str; 3] = ["libgssapi_krb5.so.2", "libgssapi_krb5.so", "libgssapi.so.3"];
+
+struct GssApi {
+    accept_sec_context: AcceptSecContext,
+    display_name: DisplayName,
+    release_buffer: ReleaseBuffer,
+    release_name: ReleaseName,
+    delete_sec_context: DeleteSecContext,
+}
+
+// The function pointers come from a library that stays loaded for the whole
+// process lifetime, and GSSAPI acceptor calls are thread-safe
+unsafe impl Send for GssApi {}
+unsafe impl Sync for GssApi {}
+
+#[derive(Clone)]
+pub struct KeytabAcceptor {
+    api: Arc<GssApi>,
+}
+
+// Outcome of accepting a single Negotiate token
+pub struct Accepted {
+    pub principal: String,
+    // Mutual authentication token to return in WWW-Authenticate, if any
+    pub output_token: Option<String>,
+}
+
+// The first of `names` that `library` has
+unsafe fn symbol<T>(handle: *mut c_void, library: &str, names: &[&str]) -> Result<T, String> {
+    for name in names {
+        let cname = CString::new(*name).expect("symbol name");
+        let ptr = libc::dlsym(handle, cname.as_ptr());
+        if !ptr.is_null() {
+            return Ok(std::mem::transmute_copy(&ptr));
+        }
+    }
+    Err(format!("The GSSAPI library {} lacks {}", library, names.join(" and ")))
+}
+
+impl KeytabAcceptor {
+    // The keytab is registered with the Kerberos library rather than set in
+    // KRB5_KTNAME, as changing the environment isn't safe once threads run
+    pub fn load(keytab: Option<&str>) -> Result<Self, String> {
+        let api = unsafe {
+            let mut handle = std::ptr::null_mut();
+            let mut library = "";
+            for lib in LIBRARIES {
+                let cname = CString::new(lib).expect("library name");
+                handle = libc::dlopen(cname.as_ptr(), libc::RTLD_NOW);
+                if !handle.is_null() {
+                    library = lib;
+                    break;
+                }
+            }
+            if handle.is_null() {
+                return Err(format!("Could not load a GSSAPI library (tried {})", LIBRARIES.join(", ")));
+            }
+            if let Some(keytab) = keytab {
+                // MIT's name, then Heimdal's
+                let register: RegisterAcceptorIdentity = symbol(
+                    handle,
+                    library,
+                    &["krb5_gss_register_acceptor_identity", "gsskrb5_register_acceptor_identity"],
+                )?;
+                let name = CString::new(format!("FILE:{}", keytab)).map_err(|_| format!("Invalid keytab path: {}", keytab))?;
+                if register(name.as_ptr()) != GSS_S_COMPLETE {
+                    return Err(format!("The GSSAPI library {} refused the keytab {}", library, keytab));
+                }
+            }
+            GssApi {
+                accept_sec_context: symbol(handle, library, &["gss_accept_sec_context"])?,
+                display_name: symbol(handle, library, &["gss_display_name"])?,
+                release_buffer: symbol(handle, library, &["gss_release_buffer"])?,
+                release_name: symbol(handle, library, &["gss_release_name"])?,
+                delete_sec_context: symbol(handle, library, &["gss_delete_sec_context"])?,
+            }
+        };
+        Ok(KeytabAcceptor { api: Arc::new(api) })
+    }
+
+    // Accept a base64 Negotiate token from an Authorization header. Only
+    // single-leg (Kerberos) exchanges are supported, which is what browsers
+    // and the Windows redirector use against a service with an SPN.
+    pub async fn accept(&self, token: &str) -> Result<Accepted, String> {
+        let input = base64::decode(token).ok_or("Malformed Negotiate token")?;
+        let api = self.api.clone();
+        tokio::task::spawn_blocking(move || unsafe { accept_blocking(&api, input) })
+            .await
+            .map_err(|e| e.to_string())?
+    }
+}
+
+unsafe fn accept_blocking(api: &GssApi, mut input: Vec<u8>) -> Result<Accepted, String> {
+    let mut minor: OmUint32 = 0;
+    let mut context: *mut c_void = std::ptr::null_mut();
+    let mut src_name: *mut c_void = std::ptr::null_mut();
+    let mut input_buf = GssBuffer {
+        length: input.len(),
+        value: input.as_mut_ptr() as *mut c_void,
+    };
+    let mut output_buf = GssBuffer {
+        length: 0,
+        value: std::ptr::null_mut(),
+    };
+    let major = (api.accept_sec_context)(
+        &mut minor,
+        &mut context,
+        std::ptr::null_mut(),
+        &mut input_buf,
+        std::ptr::null_mut(),
+        &mut src_name,
+        std::ptr::null_mut(),
+        &mut output_buf,
+        std::ptr::null_mut(),
+        std::ptr::null_mut(),
+        std::ptr::null_mut(),
+    );
+
+    let output_token = if output_buf.length > 0 {
+        let bytes = std::slice::from_raw_parts(output_buf.value as *const u8, output_buf.length);
+        Some(base64::encode(bytes))
+    } else {
+        None
+    };
+    (api.release_buffer)(&mut minor, &mut output_buf);
+
+    let result = if major == GSS_S_COMPLETE {
+        let mut name_buf = GssBuffer {
+            length: 0,
+            value: std::ptr::null_mut(),
+        };
+        let status = (api.display_name)(&mut minor, src_name, &mut name_buf, std::ptr::null_mut());
+        let principal = if status != GSS_S_COMPLETE || name_buf.value.is_null() {
+            String::new()
+        } else {
+            let bytes = std::slice::from_raw_parts(name_buf.value as *const u8, name_buf.length);
+            String::from_utf8_lossy(bytes).into_owned()
+        };
+        (api.release_buffer)(&mut minor, &mut name_buf);
+        // Without a name there's no one to forward as the user
+        if principal.is_empty() {
+            Err(format!("gss_display_name failed (major {:#x}, minor {})", status, minor))
+        } else {
+            Ok(Accepted {
+                principal,
+                output_token,
+            })
+        }
+    } else {
+        Err(format!("gss_accept_sec_context failed (major {:#x}, minor {})", major, minor))
+    };
+
+    if !src_name.is_null() {
+        (api.release_name)(&mut minor, &mut src_name);
+    }
+    if !context.is_null() {
+        (api.delete_sec_context)(&mut minor, &mut context, std::ptr::null_mut());
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn registers_keytabs() {
+        match KeytabAcceptor::load(Some("/etc/proxy.keytab")) {
+            Ok(_) => {}
+            // Hosts without Kerberos have nothing to test against
+            Err(e) if e.starts_with("Could not load") => {}
+            Err(e) => panic!("{}", e),
+        }
+    }
+}
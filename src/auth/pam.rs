@@ -0,0 +1,132 @@
+use std::ffi::{c_char, c_int, c_void, CString};
+
+// Basic-auth backend that checks credentials against the host's PAM stack
+
+#[repr(C)]
+struct PamMessage {
+    msg_style: c_int,
+    msg: *const c_char,
+}
+
+#[repr(C)]
+struct PamResponse {
+    resp: *mut c_char,
+    resp_retcode: c_int,
+}
+
+type ConvFn = extern "C" fn(c_int, *mut *const PamMessage, *mut *mut PamResponse, *mut c_void) -> c_int;
+
+#[repr(C)]
+struct PamConv {
+    conv: ConvFn,
+    appdata_ptr: *mut c_void,
+}
+
+const PAM_SUCCESS: c_int = 0;
+const PAM_BUF_ERR: c_int = 5;
+const PAM_CONV_ERR: c_int = 19;
+const PAM_PROMPT_ECHO_OFF: c_int = 1;
+const PAM_PROMPT_ECHO_ON: c_int = 2;
+
+#[link(name = "pam")]
+extern "C" {
+    fn pam_start(service: *const c_char, user: *const c_char, conv: *const PamConv, handle: *mut *mut c_void) -> c_int;
+    fn pam_authenticate(handle: *mut c_void, flags: c_int) -> c_int;
+    fn pam_acct_mgmt(handle: *mut c_void, flags: c_int) -> c_int;
+    fn pam_end(handle: *mut c_void, status: c_int) -> c_int;
+}
+
+struct Credentials {
+    user: CString,
+    password: CString,
+}
+
+// Answer password prompts with the password and any echoed prompt with the
+// user name; PAM frees the responses itself, so they must come from malloc
+extern "C" fn conversation(
+    num_msg: c_int,
+    msg: *mut *const PamMessage,
+    resp: *mut *mut PamResponse,
+    appdata: *mut c_void,
+) -> c_int {
+    if num_msg <= 0 || appdata.is_null() {
+        return PAM_CONV_ERR;
+    }
+    unsafe {
+        let creds = &*(appdata as *const Credentials);
+        let replies = libc::calloc(num_msg as usize, std::mem::size_of::<PamResponse>()) as *mut PamResponse;
+        if replies.is_null() {
+            return PAM_BUF_ERR;
+        }
+        for i in 0..num_msg as usize {
+            // Linux-PAM passes an array of pointers to messages
+            let message = &**msg.add(i);
+            let answer = match message.msg_style {
+                PAM_PROMPT_ECHO_OFF => libc::strdup(creds.password.as_ptr()),
+                PAM_PROMPT_ECHO_ON => libc::strdup(creds.user.as_ptr()),
+                _ => std::ptr::null_mut(),
+            };
+            (*replies.add(i)).resp = answer;
+        }
+        *resp = replies;
+    }
+    PAM_SUCCESS
+}
+
+pub struct PamAuth {
+    service: String,
+}
+
+impl PamAuth {
+    pub fn new(service: String) -> Self {
+        PamAuth { service }
+    }
+
+    pub async fn check(&self, user: &str, password: &str) -> bool {
+        let (service, creds) = match (
+            CString::new(self.service.as_str()),
+            CString::new(user),
+            CString::new(password),
+        ) {
+            (Ok(service), Ok(user), Ok(password)) => (service, Credentials { user, password }),
+            _ => return false,
+        };
+        // PAM modules block (and may sleep on failure), so keep them off the runtime
+        tokio::task::spawn_blocking(move || unsafe { authenticate_blocking(&service, &creds) })
+            .await
+            .unwrap_or(false)
+    }
+
+    // Whether the host knows an account named `user`; PAM stacks
+    // authenticate the accounts of the passwd database (files, LDAP via NSS)
+    pub fn has_user(&self, user: &str) -> bool {
+        let Ok(user) = CString::new(user) else {
+            return false;
+        };
+        let mut entry: libc::passwd = unsafe { std::mem::zeroed() };
+        let mut found = std::ptr::null_mut();
+        let mut buffer = vec![0 as c_char; 16384];
+        let status = unsafe { libc::getpwnam_r(user.as_ptr(), &mut entry, buffer.as_mut_ptr(), buffer.len(), &mut found) };
+        // Can't tell on an error, so the name counts as taken
+        status != 0 || !found.is_null()
+    }
+}
+
+unsafe fn authenticate_blocking(service: &CString, creds: &Credentials) -> bool {
+    let conv = PamConv {
+        conv: conversation,
+        appdata_ptr: creds as *const Credentials as *mut c_void,
+    };
+    let mut handle = std::ptr::null_mut();
+    let mut status = pam_start(service.as_ptr(), creds.user.as_ptr(), &conv, &mut handle);
+    if status != PAM_SUCCESS {
+        return false;
+    }
+    status = pam_authenticate(handle, 0);
+    if status == PAM_SUCCESS {
+        // Also honour account expiry and access restrictions
+        status = pam_acct_mgmt(handle, 0);
+    }
+    pam_end(handle, status);
+    status == PAM_SUCCESS
+}
@@ -0,0 +1,201 @@
+use hyper::header::{HeaderMap, HeaderValue, CONTENT_LENGTH, LOCATION};
+use hyper::http::uri::PathAndQuery;
+use hyper::{Body, Response, StatusCode, Uri};
+
+use crate::multistatus;
+use crate::rewrite_log::RewriteLog;
+use crate::unix;
+
+// The path the upstream share is mounted under (`/dav` for an upstream of
+// http://nas:8080/dav/). Clients see the share at the proxy's root, or at
+// its mount point when there are several upstreams: request paths get the
+// mount swapped for the base, and paths in responses the other way round.
+#[derive(Clone, Default)]
+pub struct BasePath {
+    // Without a trailing slash; empty when the share is at the upstream's root
+    path: String,
+    // Where clients see the share, in the same form
+    mount: String,
+}
+
+// Split the remote argument, `HOST:PORT`, `http[s]://HOST[:PORT][/BASE/PATH]`
+// or `unix:/PATH` to a socket, into the upstream scheme, authority and base
+// path
+pub fn split_remote(remote: &str) -> Result<(&'static str, String, BasePath), String> {
+    if let Some(path) = remote.strip_prefix("unix:") {
+        if !cfg!(unix) {
+            return Err(format!("Cannot use {}: Unix sockets need a Unix system", remote));
+        }
+        if !path.starts_with('/') {
+            return Err(format!("The upstream {} must name the socket by its absolute path", remote));
+        }
+        return Ok(("http", unix::authority(path), BasePath::default()));
+    }
+    let (scheme, rest) = match remote.split_once("://") {
+        Some((scheme, rest)) if scheme.eq_ignore_ascii_case("http") => ("http", rest),
+        Some((scheme, _)) if scheme.eq_ignore_ascii_case("https") && !cfg!(feature = "tls") => {
+            return Err(format!("Cannot use {}: built without the tls feature", remote))
+        }
+        Some((scheme, rest)) if scheme.eq_ignore_ascii_case("https") => ("https", rest),
+        Some((scheme, _)) => return Err(format!("Unsupported upstream scheme {} (expected http or https)", scheme)),
+        None => ("http", remote),
+    };
+    let (authority, path) = match rest.find('/') {
+        Some(slash) => rest.split_at(slash),
+        None => (rest, ""),
+    };
+    if authority.is_empty() {
+        return Err(format!("The upstream {} has no host", remote));
+    }
+    // A bare HOST gives no hint that port 80 is meant
+    if !remote.contains("://") && !authority.contains(':') {
+        return Err("A remote port is required (REMOTE_ADDR:PORT)".to_string());
+    }
+    if path.contains(['?', '#']) {
+        return Err(format!("The upstream {} may only have a path, not a query or fragment", remote));
+    }
+    Ok((scheme, authority.to_string(), BasePath::new(path)))
+}
+
+// Split an href or header value into what comes before its path (scheme and
+// authority of an absolute URL) and the path itself
+fn split_url(url: &str) -> (&str, &str) {
+    match url.find("://") {
+        Some(scheme_end) => {
+            let after = scheme_end + 3;
+            let path_start = url[after..].find('/').map_or(url.len(), |p| after + p);
+            url.split_at(path_start)
+        }
+        None => ("", url),
+    }
+}
+
+// What follows `prefix` in `path` if the path lies under it, `/` for the
+// prefix itself
+fn within<'a>(prefix: &str, path: &'a str) -> Option<&'a str> {
+    match path.strip_prefix(prefix)? {
+        "" => Some("/"),
+        rest if rest.starts_with(['/', '?']) => Some(rest),
+        _ => None,
+    }
+}
+
+impl BasePath {
+    pub fn new(path: &str) -> Self {
+        let path = path.trim_end_matches('/');
+        let path = if path.is_empty() || path.starts_with('/') {
+            path.to_string()
+        } else {
+            format!("/{}", path)
+        };
+        BasePath { path, mount: String::new() }
+    }
+
+    // Show the share under `/mount` instead of the proxy's root
+    pub fn mounted_at(mut self, mount: &str) -> Self {
+        self.mount = BasePath::new(mount).path;
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.path.is_empty() && self.mount.is_empty()
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.path
+    }
+
+    // The upstream target for a client's origin-form target, which has to
+    // lie under the mount
+    pub fn add(&self, target: &PathAndQuery) -> PathAndQuery {
+        if self.is_empty() {
+            return target.clone();
+        }
+        let rest = within(&self.mount, target.as_str()).unwrap_or(target.as_str());
+        format!("{}{}", self.path, rest).parse().expect("valid path")
+    }
+
+    // A path from the upstream as the client sees it, or None if it lies
+    // outside the base
+    fn strip(&self, path: &str) -> Option<String> {
+        Some(format!("{}{}", self.mount, within(&self.path, path)?))
+    }
+
+    fn strip_url(&self, url: &str) -> Option<String> {
+        let (origin, path) = split_url(url.trim());
+        Some(format!("{}{}", origin, self.strip(path)?))
+    }
+
+    // Point the Destination of a MOVE or COPY at the upstream: clients name
+    // the proxy's host and port, which the upstream would take for another
+    // server, and the path goes into the base. A destination outside the
+    // mount belongs to another upstream, which can't be reached from this
+    // one (RFC 4918 9.9.4).
+    pub fn apply_request(&self, upstream: &Uri, headers: &mut HeaderMap, log: Option<&RewriteLog>) -> Option<Response<Body>> {
+        let destination = headers.get("Destination").and_then(|v| v.to_str().ok())?.to_string();
+        let (_, path) = split_url(&destination);
+        let Some(rest) = within(&self.mount, path) else {
+            if let Some(log) = log {
+                log.kept("Destination", &destination, "outside the mount");
+            }
+            return Some(
+                Response::builder()
+                    .status(StatusCode::BAD_GATEWAY)
+                    .header("Content-Type", "text/plain")
+                    .body(Body::from("The destination is on another upstream\n"))
+                    .expect("response builder"),
+            );
+        };
+        let origin = format!(
+            "{}://{}",
+            upstream.scheme_str().unwrap_or("http"),
+            upstream.authority().map_or("", |a| a.as_str())
+        );
+        let rewritten = format!("{}{}{}", origin, self.path, rest);
+        if let Some(log) = log {
+            log.changed("Destination", &destination, &rewritten);
+        }
+        if let Ok(value) = HeaderValue::from_str(&rewritten) {
+            headers.insert("Destination", value);
+        }
+        None
+    }
+
+    // Map the Location header and the hrefs of a 207 body back to client paths
+    pub async fn apply_response(
+        &self,
+        response: Response<Body>,
+        log: Option<&RewriteLog>,
+    ) -> Result<Response<Body>, hyper::Error> {
+        if self.is_empty() {
+            return Ok(response);
+        }
+        // strip_url, logged
+        let strip = |what: &str, url: &str| {
+            let stripped = self.strip_url(url);
+            match (log, &stripped) {
+                (Some(log), Some(stripped)) => log.changed(what, url, stripped),
+                (Some(log), None) => log.kept(what, url, "outside the base path"),
+                (None, _) => {}
+            }
+            stripped
+        };
+        let (mut parts, body) = response.into_parts();
+        let location = parts.headers.get(LOCATION).and_then(|v| v.to_str().ok()).and_then(|v| strip("Location", v));
+        if let Some(value) = location.and_then(|l| HeaderValue::from_str(&l).ok()) {
+            parts.headers.insert(LOCATION, value);
+        }
+        if parts.status != StatusCode::MULTI_STATUS {
+            return Ok(Response::from_parts(parts, body));
+        }
+        let bytes = hyper::body::to_bytes(body).await?;
+        let body = match std::str::from_utf8(&bytes) {
+            Ok(xml) => {
+                parts.headers.remove(CONTENT_LENGTH);
+                Body::from(multistatus::map_hrefs(xml, |href| strip("href", href)))
+            }
+            Err(_) => Body::from(bytes),
+        };
+        Ok(Response::from_parts(parts, body))
+    }
+}
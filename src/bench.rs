@@ -0,0 +1,326 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use getopts::Options;
+use hyper::client::HttpConnector;
+use hyper::header::{HeaderValue, AUTHORIZATION};
+use hyper::{Body, Client, Method, Request, Uri};
+#[cfg(feature = "tls")]
+use hyper_tls::HttpsConnector;
+
+// `bench`: a WebDAV load generator, to point at the proxy and then at the
+// upstream directly and compare. Each worker keeps one request in flight,
+// picking PROPFIND, GET or PUT by the configured mix; all of them work in a
+// scratch collection that is cleaned up afterwards.
+
+#[cfg(feature = "tls")]
+pub type HttpClient = Client<HttpsConnector<HttpConnector>>;
+#[cfg(not(feature = "tls"))]
+pub type HttpClient = Client<HttpConnector>;
+
+// Certificates aren't checked: benchmarks and comparisons run against test
+// setups
+#[cfg(feature = "tls")]
+pub fn client() -> HttpClient {
+    let mut http = HttpConnector::new();
+    http.enforce_http(false);
+    let tls = native_tls::TlsConnector::builder()
+        .danger_accept_invalid_certs(true)
+        .build()
+        .expect("TLS connector");
+    Client::builder().build(HttpsConnector::from((http, tls.into())))
+}
+
+#[cfg(not(feature = "tls"))]
+pub fn client() -> HttpClient {
+    Client::new()
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Operation {
+    Propfind,
+    Get,
+    Put,
+}
+
+const OPERATIONS: [Operation; 3] = [Operation::Propfind, Operation::Get, Operation::Put];
+
+impl Operation {
+    fn name(self) -> &'static str {
+        match self {
+            Operation::Propfind => "PROPFIND",
+            Operation::Get => "GET",
+            Operation::Put => "PUT",
+        }
+    }
+}
+
+// Relative weights, e.g. `propfind=60,get=30,put=10`
+fn parse_mix(spec: &str) -> Result<[u32; 3], String> {
+    let mut weights = [0; 3];
+    for part in spec.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+        let (name, weight) = part
+            .split_once('=')
+            .ok_or(format!("Invalid mix entry (expected METHOD=WEIGHT): {}", part))?;
+        let index = OPERATIONS
+            .iter()
+            .position(|op| op.name().eq_ignore_ascii_case(name.trim()))
+            .ok_or(format!("Unknown method in mix (expected propfind, get or put): {}", name))?;
+        weights[index] = weight.trim().parse().map_err(|_| format!("Invalid weight in mix: {}", part))?;
+    }
+    if weights.iter().all(|w| *w == 0) {
+        return Err("The mix needs at least one method with a weight above 0".to_string());
+    }
+    Ok(weights)
+}
+
+// xorshift, so workers don't all send the same sequence
+struct Picker {
+    state: u64,
+    weights: [u32; 3],
+}
+
+impl Picker {
+    fn next(&mut self) -> Operation {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        let total: u32 = self.weights.iter().sum();
+        let mut roll = (self.state % total as u64) as u32;
+        for (op, weight) in OPERATIONS.iter().zip(self.weights) {
+            if roll < weight {
+                return *op;
+            }
+            roll -= weight;
+        }
+        unreachable!("the roll is below the total weight")
+    }
+}
+
+#[derive(Default)]
+struct Results {
+    // Latencies of successful requests, per operation
+    latencies: [Vec<Duration>; 3],
+    errors: [u64; 3],
+}
+
+struct Bench {
+    client: HttpClient,
+    // The scratch collection, with a trailing slash
+    base: String,
+    put_body: Vec<u8>,
+    authorization: Option<HeaderValue>,
+    results: Mutex<Results>,
+    bytes: AtomicU64,
+}
+
+impl Bench {
+    fn request(&self, method: Method, url: &str, depth: Option<&str>, body: Body) -> Request<Body> {
+        let mut builder = Request::builder().method(method).uri(url);
+        if let Some(depth) = depth {
+            builder = builder.header("Depth", depth);
+        }
+        if let Some(authorization) = &self.authorization {
+            builder = builder.header(AUTHORIZATION, authorization.clone());
+        }
+        builder.body(body).expect("request builder")
+    }
+
+    // Send a request and read the whole response; the status on success
+    async fn send(&self, request: Request<Body>) -> Result<u16, String> {
+        let response = self.client.request(request).await.map_err(|e| e.to_string())?;
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await.map_err(|e| e.to_string())?;
+        self.bytes.fetch_add(body.len() as u64, Ordering::Relaxed);
+        if status.is_client_error() || status.is_server_error() {
+            return Err(status.to_string());
+        }
+        Ok(status.as_u16())
+    }
+
+    fn file(&self, worker: usize, suffix: &str) -> String {
+        format!("{}worker-{}{}.bin", self.base, worker, suffix)
+    }
+
+    async fn run_one(&self, worker: usize, op: Operation) {
+        let request = match op {
+            Operation::Propfind => self.request(Method::from_bytes(b"PROPFIND").expect("valid method"), &self.base, Some("1"), Body::empty()),
+            Operation::Get => self.request(Method::GET, &self.file(worker, ""), None, Body::empty()),
+            Operation::Put => {
+                self.bytes.fetch_add(self.put_body.len() as u64, Ordering::Relaxed);
+                self.request(Method::PUT, &self.file(worker, "-put"), None, Body::from(self.put_body.clone()))
+            }
+        };
+        let index = OPERATIONS.iter().position(|o| *o == op).expect("known operation");
+        let started = Instant::now();
+        let result = self.send(request).await;
+        let mut results = self.results.lock().unwrap();
+        match result {
+            Ok(_) => results.latencies[index].push(started.elapsed()),
+            Err(_) => results.errors[index] += 1,
+        }
+    }
+}
+
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    let rank = ((p / 100.0) * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+fn millis(d: Duration) -> String {
+    format!("{:.1}", d.as_secs_f64() * 1000.0)
+}
+
+fn print_usage(program: &str, opts: &Options) {
+    let brief = format!("Usage: {} bench URL [options]\n\nURL is the collection to work in, on the proxy or the upstream", program);
+    print!("{}", opts.usage(&brief));
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("{}", message);
+    std::process::exit(-1);
+}
+
+pub async fn main(program: &str, args: &[String]) {
+    let mut opts = Options::new();
+    opts.optopt("c", "concurrency", "Requests in flight at once, defaulting to 4", "N");
+    opts.optopt("n", "requests", "Stop after this many requests, defaulting to 1000", "N");
+    opts.optopt("d", "duration", "Stop after this many seconds instead of a request count", "SECS");
+    opts.optopt("", "mix", "Relative weights of the methods, defaulting to propfind=40,get=40,put=20", "METHOD=WEIGHT,...");
+    opts.optopt("", "put-size", "Size of each uploaded and downloaded file, defaulting to 65536", "BYTES");
+    opts.optopt("", "user", "Log in with Basic auth as this user", "USER");
+    opts.optopt("", "pass-env", "Read the password from this environment variable", "VAR");
+    opts.optflag("h", "help", "Show this help");
+
+    let matches = opts.parse(args).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        print_usage(program, &opts);
+        std::process::exit(-1);
+    });
+    if matches.opt_present("help") || matches.free.len() != 1 {
+        print_usage(program, &opts);
+        std::process::exit(if matches.opt_present("help") { 0 } else { -1 });
+    }
+    let number = |name: &str, default: u64| -> u64 {
+        matches
+            .opt_str(name)
+            .map_or(Ok(default), |v| v.parse())
+            .unwrap_or_else(|_| fail(&format!("Failed to parse --{}", name)))
+    };
+    let concurrency = number("concurrency", 4).max(1) as usize;
+    let duration = matches.opt_str("duration").map(|_| Duration::from_secs(number("duration", 0)));
+    let requests = number("requests", 1000);
+    let mix = parse_mix(&matches.opt_str("mix").unwrap_or("propfind=40,get=40,put=20".to_string()))
+        .unwrap_or_else(|e| fail(&e));
+    let put_size = number("put-size", 65536) as usize;
+
+    let url = matches.free[0].clone();
+    if url.parse::<Uri>().map(|u| u.scheme().is_none()).unwrap_or(true) {
+        fail(&format!("Invalid URL {} (expected http://HOST[:PORT]/PATH/)", url));
+    }
+    let base = format!("{}/bench-{}/", url.trim_end_matches('/'), std::process::id());
+    let authorization = matches.opt_str("user").map(|user| {
+        let password = matches.opt_str("pass-env").map(|var| std::env::var(var).unwrap_or_default());
+        crate::upstream_auth::basic_authorization(&user, &password.unwrap_or_default())
+            .unwrap_or_else(|e| fail(&format!("Invalid credentials: {}", e)))
+    });
+
+    let bench = Arc::new(Bench {
+        client: client(),
+        base,
+        put_body: (0..put_size).map(|i| (i % 251) as u8).collect(),
+        authorization,
+        results: Mutex::default(),
+        bytes: AtomicU64::new(0),
+    });
+
+    // The scratch collection and a file per worker to download
+    let mkcol = bench.request(Method::from_bytes(b"MKCOL").expect("valid method"), &bench.base, None, Body::empty());
+    if let Err(e) = bench.send(mkcol).await {
+        fail(&format!("Failed to create {}: {}", bench.base, e));
+    }
+    for worker in 0..concurrency {
+        let put = bench.request(Method::PUT, &bench.file(worker, ""), None, Body::from(bench.put_body.clone()));
+        if let Err(e) = bench.send(put).await {
+            fail(&format!("Failed to upload {}: {}", bench.file(worker, ""), e));
+        }
+    }
+    bench.bytes.store(0, Ordering::Relaxed);
+
+    println!(
+        "Benchmarking {} with {} workers, {}",
+        bench.base,
+        concurrency,
+        match duration {
+            Some(d) => format!("for {} s", d.as_secs()),
+            None => format!("{} requests", requests),
+        }
+    );
+    let started = Instant::now();
+    let issued = Arc::new(AtomicU64::new(0));
+    let workers: Vec<_> = (0..concurrency)
+        .map(|worker| {
+            let bench = bench.clone();
+            let issued = issued.clone();
+            let mut picker = Picker {
+                state: 0x9E37_79B9_7F4A_7C15 ^ (worker as u64 + 1).wrapping_mul(0x2545_F491_4F6C_DD1D),
+                weights: mix,
+            };
+            tokio::spawn(async move {
+                loop {
+                    let more = match duration {
+                        Some(d) => started.elapsed() < d,
+                        None => issued.fetch_add(1, Ordering::Relaxed) < requests,
+                    };
+                    if !more {
+                        break;
+                    }
+                    bench.run_one(worker, picker.next()).await;
+                }
+            })
+        })
+        .collect();
+    for worker in workers {
+        let _ = worker.await;
+    }
+    let elapsed = started.elapsed();
+
+    let delete = bench.request(Method::DELETE, &bench.base, None, Body::empty());
+    if let Err(e) = bench.send(delete).await {
+        eprintln!("Failed to clean up {}: {}", bench.base, e);
+    }
+
+    let results = bench.results.lock().unwrap();
+    let total: usize = results.latencies.iter().map(Vec::len).sum();
+    let errors: u64 = results.errors.iter().sum();
+    println!(
+        "{} requests in {:.2} s: {:.1} requests/s, {:.2} MB/s, {} errors",
+        total as u64 + errors,
+        elapsed.as_secs_f64(),
+        (total as u64 + errors) as f64 / elapsed.as_secs_f64(),
+        bench.bytes.load(Ordering::Relaxed) as f64 / elapsed.as_secs_f64() / 1_000_000.0,
+        errors
+    );
+    println!("{:<9} {:>8} {:>7} {:>9} {:>9} {:>9} {:>9}", "method", "ok", "errors", "p50 ms", "p90 ms", "p99 ms", "max ms");
+    for (index, op) in OPERATIONS.iter().enumerate() {
+        let mut sorted = results.latencies[index].clone();
+        if sorted.is_empty() && results.errors[index] == 0 {
+            continue;
+        }
+        sorted.sort();
+        println!(
+            "{:<9} {:>8} {:>7} {:>9} {:>9} {:>9} {:>9}",
+            op.name(),
+            sorted.len(),
+            results.errors[index],
+            millis(percentile(&sorted, 50.0)),
+            millis(percentile(&sorted, 90.0)),
+            millis(percentile(&sorted, 99.0)),
+            millis(sorted.last().copied().unwrap_or_default())
+        );
+    }
+}
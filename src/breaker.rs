@@ -0,0 +1,175 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use hyper::header::CONTENT_TYPE;
+use hyper::{Body, Client, Method, Request, Uri};
+
+use crate::errors;
+use crate::json;
+use crate::phase::Tracker;
+use crate::resolve::Connector;
+use crate::upstream_error::UpstreamError;
+use crate::warm;
+
+// A circuit breaker for one upstream. After `threshold` requests in a row
+// failed to connect or timed out, the upstream is taken for down and
+// requests get the fallback at once instead of each waiting out the
+// timeout, which leaves a mapped drive frozen. A background probe sends
+// OPTIONS every warm::CHECK_INTERVAL and closes the breaker again as soon
+// as anything answers.
+//
+// Clients that got the fallback meanwhile may hold on to it. For a while
+// after the upstream is back (--recovery-grace) cached answers are only
+// reused while younger than RECOVERY_TTL and --cache-header max-ages are
+// cut to it, so they see the real listing again soon, and a webhook
+// (--recovery-webhook) is told, e.g. to nudge clients to refresh.
+pub struct Breaker {
+    threshold: u32,
+    // The share's root, which the probe asks
+    uri: Uri,
+    client: Client<Connector>,
+    timeout: Duration,
+    recovery: Recovery,
+    state: Mutex<State>,
+}
+
+// What the breaker does once the upstream is back
+#[derive(Clone, Default)]
+pub struct Recovery {
+    pub grace: Duration,
+    pub webhook: Option<String>,
+}
+
+// How old a cached answer may be during the grace period
+pub const RECOVERY_TTL: Duration = Duration::from_secs(5);
+
+#[derive(Default)]
+struct State {
+    failures: u32,
+    // Why the upstream is taken for down
+    open: Option<UpstreamError>,
+    // When it was last back
+    recovered: Option<Instant>,
+    // When the probe asks it next, while open
+    next_probe: Option<Instant>,
+}
+
+async fn call_webhook(url: &str, upstream: &Uri) -> Result<(), String> {
+    let body = json::object(&[
+        ("event", json::string("recovered")),
+        ("upstream", json::string(&upstream.to_string())),
+    ]);
+    let req = Request::builder()
+        .method(Method::POST)
+        .uri(url)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(body))
+        .map_err(|e| e.to_string())?;
+    let response = Client::new().request(req).await.map_err(|e| format!("{}: {}", url, e))?;
+    if response.status().is_success() {
+        Ok(())
+    } else {
+        Err(format!("{} answered {}", url, response.status()))
+    }
+}
+
+impl Breaker {
+    pub fn new(threshold: u32, uri: Uri, client: Client<Connector>, timeout: Duration, recovery: Recovery) -> Self {
+        Breaker {
+            threshold,
+            uri,
+            client,
+            timeout,
+            recovery,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    // Whether the upstream came back less than --recovery-grace ago
+    pub fn recovering(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        state.recovered.is_some_and(|at| at.elapsed() < self.recovery.grace)
+    }
+
+    // The error to answer from the fallback for without trying, if open
+    pub fn open_error(&self) -> Option<UpstreamError> {
+        self.state.lock().unwrap().open
+    }
+
+    // How long until the probe asks the upstream again, if open
+    pub fn next_probe(&self) -> Option<Duration> {
+        let state = self.state.lock().unwrap();
+        state.open?;
+        Some(state.next_probe.map_or(warm::CHECK_INTERVAL, |at| at.saturating_duration_since(Instant::now())))
+    }
+
+    pub fn succeeded(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.failures = 0;
+        if state.open.take().is_none() {
+            return;
+        }
+        tracing::info!("Upstream {} is back", self.uri);
+        state.recovered = Some(Instant::now());
+        if let Some(url) = self.recovery.webhook.clone() {
+            let upstream = self.uri.clone();
+            tokio::spawn(async move {
+                if let Err(e) = call_webhook(&url, &upstream).await {
+                    errors::report(format!("Recovery webhook failed: {}", e));
+                }
+            });
+        }
+    }
+
+    pub fn failed(self: &Arc<Self>, error: UpstreamError) {
+        let mut state = self.state.lock().unwrap();
+        state.failures += 1;
+        if state.failures < self.threshold || state.open.is_some() {
+            return;
+        }
+        state.open = Some(error);
+        tracing::warn!(
+            "Upstream {} failed {} requests in a row ({}), answering from the fallback until it is back",
+            self.uri, state.failures, error
+        );
+        tokio::spawn(self.clone().probe());
+    }
+
+    // Take the upstream for down without waiting for failures, as another
+    // instance of the proxy found it
+    pub fn trip(self: &Arc<Self>, error: UpstreamError) {
+        let mut state = self.state.lock().unwrap();
+        if state.open.replace(error).is_none() {
+            tracing::warn!("Upstream {} is down ({}), answering from the fallback until it is back", self.uri, error);
+            tokio::spawn(self.clone().probe());
+        }
+    }
+
+    // Runs while the breaker is open
+    async fn probe(self: Arc<Self>) {
+        loop {
+            self.state.lock().unwrap().next_probe = Some(Instant::now() + warm::CHECK_INTERVAL);
+            tokio::time::sleep(warm::CHECK_INTERVAL).await;
+            if self.open_error().is_none() {
+                return;
+            }
+            let request = Request::builder()
+                .method(Method::OPTIONS)
+                .uri(self.uri.clone())
+                .body(Body::empty())
+                .expect("request builder");
+            // Any answer will do, even a 401
+            let phase = Tracker::new();
+            let error = match tokio::time::timeout(self.timeout, phase.track(self.client.request(request))).await {
+                Ok(Ok(_)) => return self.succeeded(),
+                Ok(Err(_)) => UpstreamError::failed(phase.phase()),
+                Err(_) => UpstreamError::Timeout { phase: phase.phase() },
+            };
+            // Unless a request got through in the meantime
+            match self.state.lock().unwrap().open.as_mut() {
+                Some(open) => *open = error,
+                None => return,
+            }
+        }
+    }
+}
@@ -0,0 +1,72 @@
+use std::sync::Arc;
+
+use futures::StreamExt;
+use hyper::body::{Bytes, HttpBody};
+use hyper::header::{CONTENT_LENGTH, TRANSFER_ENCODING};
+use hyper::http::response::Parts;
+use hyper::Body;
+
+use crate::guard;
+use crate::memory::{MemoryBudget, Use};
+
+// Small responses (typically multistatus listings) are read whole before
+// being sent, so they go out with a Content-Length and can be rewritten or
+// cached as a unit; anything over the threshold streams as it arrives
+
+// The whole body if it ends within `limit` bytes; otherwise a body that
+// replays what was read and streams the rest
+pub async fn read_up_to(mut body: Body, limit: usize) -> Result<Result<Bytes, Body>, hyper::Error> {
+    let mut buffered: Vec<Bytes> = Vec::new();
+    let mut length = 0;
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+        length += chunk.len();
+        buffered.push(chunk);
+        if length > limit {
+            let head = futures::stream::iter(buffered.into_iter().map(Ok::<_, hyper::Error>));
+            return Ok(Err(Body::wrap_stream(head.chain(body))));
+        }
+    }
+    Ok(Ok(buffered.concat().into()))
+}
+
+// These never have a body to measure
+pub fn is_bodiless(parts: &Parts) -> bool {
+    parts.status.is_informational() || parts.status.as_u16() == 204 || parts.status.as_u16() == 304
+}
+
+// Buffer the body of an upstream response if it is at most `threshold`
+// bytes; `head` responses are left alone since their length describes a
+// body that isn't there
+pub async fn apply(
+    parts: &mut Parts,
+    body: Body,
+    threshold: usize,
+    head: bool,
+    memory: &Arc<MemoryBudget>,
+) -> Result<Body, hyper::Error> {
+    if head || is_bodiless(parts) {
+        return Ok(body);
+    }
+    let known = parts
+        .headers
+        .get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<usize>().ok());
+    if known.is_some_and(|length| length > threshold) {
+        return Ok(body);
+    }
+    // Streamed as is when the memory isn't there
+    let Some(reservation) = memory.reserve(Use::Bodies, known.unwrap_or(threshold)) else {
+        return Ok(body);
+    };
+    match read_up_to(body, threshold).await? {
+        Ok(bytes) => {
+            parts.headers.remove(TRANSFER_ENCODING);
+            parts.headers.insert(CONTENT_LENGTH, bytes.len().into());
+            Ok(guard::attach(reservation, Body::from(bytes)))
+        }
+        // Only the first chunks were held, and they go out first
+        Err(body) => Ok(body),
+    }
+}
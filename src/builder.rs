@@ -0,0 +1,324 @@
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use hyper::{Body, Client, Request, Response};
+use tokio::net::TcpListener;
+
+use crate::cache_headers::CacheHeaders;
+use crate::auth::guests::Guests;
+use crate::capabilities::Emulation;
+use crate::cookies::CookiePolicy;
+use crate::dates::DatePolicy;
+use crate::depth::DepthGuard;
+use crate::errors::ErrorLog;
+use crate::fallback::{EntryNames, Fallback, FallbackFiles, FallbackRoutes};
+use crate::fixtures::{self, Fixture, Mock, Outcome};
+use crate::header_rules::HeaderRules;
+use crate::hooks::Hooks;
+use crate::info::Addresses;
+use crate::legacy::LegacyPaths;
+use crate::limits::HeaderLimits;
+use crate::listener::{self, Connection};
+use crate::memory::MemoryBudget;
+use crate::network::AccessList;
+use crate::pause::PauseControl;
+use crate::problem::ApiRoutes;
+use crate::reload::{Reloadable, Settings};
+use crate::resolve::Resolver;
+use crate::rewrite::Rewrites;
+use crate::rewrite_log;
+use crate::routes::RouteSwitch;
+use crate::stats::Stats;
+use crate::strict::Strict;
+use crate::tenants::Tenants;
+use crate::upstreams::{Naming, Upstreams};
+use crate::vhost::VirtualHosts;
+use crate::ProxyConfig;
+
+// The proxy embedded in a tokio application instead of run as a binary:
+//
+//     let proxy = WebdavProxy::builder()
+//         .upstream("http://nas:8080/remote.php/webdav")
+//         .bind("127.0.0.1:8080".parse().unwrap())
+//         .timeout(Duration::from_secs(5))
+//         .fallback(Fallback::Empty)
+//         .build()?;
+//     proxy.serve(async { tokio::signal::ctrl_c().await.unwrap_or(()) }).await?;
+//
+// Whatever the builder doesn't set is what the command line defaults to.
+
+/// Settings for a [`WebdavProxy`], from [`WebdavProxy::builder`].
+pub struct Builder {
+    remotes: Vec<String>,
+    bind: SocketAddr,
+    timeout: Option<Duration>,
+    retries: Option<u32>,
+    retry_backoff: Option<Duration>,
+    idle_timeout: Option<Duration>,
+    transfer_timeout: Option<Duration>,
+    fallback: Option<Fallback>,
+    fallback_dir: Option<PathBuf>,
+    read_only: bool,
+    outbound_proxy: Option<String>,
+    hooks: Hooks,
+}
+
+/// A configured proxy, ready to serve. Its messages, the access log among
+/// them, are `tracing` events, shown once a subscriber is installed.
+pub struct WebdavProxy {
+    pub(crate) config: Arc<ProxyConfig>,
+    bind: SocketAddr,
+}
+
+impl Builder {
+    /// Add an upstream, written like REMOTE on the command line: `HOST:PORT`
+    /// or `http[s]://HOST[:PORT]/BASE/PATH`, optionally as `/MOUNT=REMOTE`.
+    /// Several upstreams are served as folders at the root.
+    pub fn upstream(mut self, remote: impl Into<String>) -> Self {
+        self.remotes.push(remote.into());
+        self
+    }
+
+    /// Where to listen, 127.0.0.1 on a free port by default.
+    pub fn bind(mut self, addr: SocketAddr) -> Self {
+        self.bind = addr;
+        self
+    }
+
+    /// How long to wait for the upstream to answer (connecting included)
+    /// before the fallback takes over.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// How often to retry a request that failed to reach the upstream.
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.retries = Some(retries);
+        self
+    }
+
+    /// How long to wait before the first retry, 250 ms by default; each
+    /// further one waits twice as long as the one before, up to 10 seconds.
+    pub fn retry_backoff(mut self, backoff: Duration) -> Self {
+        self.retry_backoff = Some(backoff);
+        self
+    }
+
+    /// Longest the upstream may pause while sending a body, 60 seconds by
+    /// default; None waits forever.
+    pub fn idle_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    /// Longest a whole exchange may take, uploads and downloads included.
+    pub fn transfer_timeout(mut self, timeout: Duration) -> Self {
+        self.transfer_timeout = Some(timeout);
+        self
+    }
+
+    /// What clients see while the upstream can't be reached.
+    pub fn fallback(mut self, fallback: Fallback) -> Self {
+        self.fallback = Some(fallback);
+        self
+    }
+
+    /// Serve this directory read-only while the upstream can't be reached.
+    pub fn fallback_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.fallback_dir = Some(dir.into());
+        self
+    }
+
+    /// Refuse everything that would change the share.
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Connect to the upstreams through a SOCKS5 or HTTP CONNECT proxy,
+    /// written like --outbound-proxy: `socks5://HOST:PORT` or
+    /// `http://[USER:PASSWORD@]HOST:PORT`.
+    pub fn outbound_proxy(mut self, url: impl Into<String>) -> Self {
+        self.outbound_proxy = Some(url.into());
+        self
+    }
+
+    /// Call `hook` with every request before the proxy handles it; an
+    /// answer it returns is sent instead of proxying the request.
+    pub fn on_request<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&mut Request<Body>) -> Option<Response<Body>> + Send + Sync + 'static,
+    {
+        self.hooks.on_request(Box::new(hook));
+        self
+    }
+
+    /// Call `hook` with every answer before it is sent. Answers to requests
+    /// the upstream failed carry an [`UpstreamError`](crate::UpstreamError)
+    /// extension.
+    pub fn on_response<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&mut Response<Body>) + Send + Sync + 'static,
+    {
+        self.hooks.on_response(Box::new(hook));
+        self
+    }
+
+    pub fn build(self) -> Result<WebdavProxy, String> {
+        if self.remotes.is_empty() {
+            return Err("A proxy needs at least one upstream".to_string());
+        }
+        let mut upstreams = Upstreams::parse(&self.remotes, Naming::Numbered)?;
+        for upstream in upstreams.iter_mut() {
+            if let Some(timeout) = self.timeout {
+                upstream.timeout = timeout;
+            }
+            if let Some(retries) = self.retries {
+                upstream.retries = retries;
+            }
+            if let Some(backoff) = self.retry_backoff {
+                upstream.backoff = backoff;
+            }
+            if let Some(fallback) = self.fallback {
+                upstream.fallback = fallback;
+            }
+        }
+        if let Some(dir) = self.fallback_dir.as_ref().filter(|dir| !dir.is_dir()) {
+            return Err(format!("The fallback directory {} is not a directory", dir.display()));
+        }
+        let resolver = match &self.outbound_proxy {
+            Some(url) => Resolver::default().with_outbound_proxy(url)?,
+            None => Resolver::default(),
+        };
+        let client = Client::builder().build(resolver.connector());
+        let settings = Settings {
+            upstreams,
+            upstream_auth: None,
+            rewrites: Rewrites::default(),
+            cache_headers: CacheHeaders::default(),
+            header_rules: HeaderRules::default(),
+            idle_timeout: self.idle_timeout,
+            transfer_timeout: self.transfer_timeout,
+        };
+        let config = ProxyConfig {
+            // Nothing to read again
+            settings: Reloadable::new(settings, None),
+            cookie_policy: CookiePolicy::Pass,
+            sharepoint: false,
+            emulate_locks: Emulation::Auto,
+            read_only: self.read_only,
+            depth_guard: DepthGuard::default(),
+            strict: Strict::default(),
+            pin_connections: false,
+            auth: None,
+            auth_cache: None,
+            lockout: None,
+            guests: Guests::default(),
+            client,
+            resolver,
+            stats: Arc::new(Stats::default()),
+            usage: None,
+            errors: Arc::new(ErrorLog::new(100, None)),
+            stats_file_name: None,
+            status: None,
+            virtual_files: Vec::new(),
+            snapshots: None,
+            forwarding: None,
+            upload_limiter: None,
+            download_limiter: None,
+            rate_caps: None,
+            gate: None,
+            client_limits: None,
+            tenants: Tenants::default(),
+            cors: None,
+            pause: PauseControl::default(),
+            routes: RouteSwitch::load(None),
+            snapshot: None,
+            fallback: FallbackRoutes::default(),
+            fallback_dir: self.fallback_dir,
+            fallback_names: EntryNames::default(),
+            fallback_files: FallbackFiles::default(),
+            dedup: None,
+            response_cache: None,
+            memory: MemoryBudget::new(0),
+            transfers: None,
+            in_flight: None,
+            buffer_responses: 0,
+            replay_buffer: 0,
+            header_limits: HeaderLimits::default(),
+            #[cfg(feature = "geoip")]
+            geo: None,
+            #[cfg(feature = "gzip")]
+            compress: false,
+            #[cfg(feature = "http3")]
+            alt_svc: None,
+            legacy_paths: LegacyPaths::Pass,
+            vhosts: VirtualHosts::default(),
+            api_routes: ApiRoutes::new(Vec::new()),
+            dates: DatePolicy {
+                normalize: false,
+                max_future: None,
+            },
+            rollout: None,
+            log_rewrites: rewrite_log::Level::Off,
+            log_rewrites_max: 20,
+            access_log: None,
+            hooks: self.hooks,
+            addresses: Addresses::default(),
+        };
+        Ok(WebdavProxy {
+            config: Arc::new(config),
+            bind: self.bind,
+        })
+    }
+}
+
+impl WebdavProxy {
+    pub fn builder() -> Builder {
+        Builder {
+            remotes: Vec::new(),
+            bind: SocketAddr::from(([127, 0, 0, 1], 0)),
+            timeout: None,
+            retries: None,
+            retry_backoff: None,
+            idle_timeout: Some(Duration::from_secs(60)),
+            transfer_timeout: None,
+            fallback: None,
+            fallback_dir: None,
+            read_only: false,
+            outbound_proxy: None,
+            hooks: Hooks::default(),
+        }
+    }
+
+    /// Run YAML fixtures (see [`Fixture::parse_all`]) against this proxy,
+    /// built with [`Mock::remote`] as its upstream, in order.
+    pub async fn verify(&self, mock: &Mock, fixtures: &[Fixture]) -> Vec<Outcome> {
+        fixtures::verify(&self.config, mock, fixtures).await
+    }
+
+    /// Listen on the configured address and serve until `shutdown`
+    /// completes, then finish the requests in flight.
+    pub async fn serve(self, shutdown: impl Future<Output = ()>) -> io::Result<()> {
+        let listener = listener::bind(self.bind).await?;
+        self.serve_on(listener, shutdown).await
+    }
+
+    /// Like [`serve`](Self::serve), on a listener the application bound,
+    /// e.g. to learn the port first.
+    pub async fn serve_on(self, listener: TcpListener, shutdown: impl Future<Output = ()>) -> io::Result<()> {
+        if let Ok(addr) = listener.local_addr() {
+            let _ = self.config.addresses.listen.set(format!("http://{}", addr));
+        }
+        crate::spawn_background(&self.config);
+        let wrap = |socket: Box<dyn Connection>| socket;
+        crate::server(self.config, listener.into(), AccessList::default(), wrap, shutdown)
+            .await
+            .map_err(io::Error::other)
+    }
+}
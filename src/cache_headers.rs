@@ -0,0 +1,94 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use hyper::header::{HeaderMap, HeaderValue, CACHE_CONTROL, EXPIRES};
+use hyper::{Method, StatusCode};
+
+use crate::config::wildcard;
+use crate::dates;
+
+// Cache-Control for GET answers by path, so browsers and CDNs in front of
+// the proxy cache what may be cached and nothing else:
+//
+//     --cache-header '/media/*=public, max-age=86400'
+//     --cache-header '/inbox/*=no-store'
+//
+// The first rule whose glob matches the client's path wins; `*` matches
+// anything (slashes included) and `?` one character. The rule replaces what
+// the upstream sent, and Expires follows its max-age for HTTP/1.0 caches.
+
+struct Rule {
+    glob: String,
+    value: HeaderValue,
+    max_age: Option<u64>,
+}
+
+#[derive(Default)]
+pub struct CacheHeaders {
+    rules: Vec<Rule>,
+}
+
+impl CacheHeaders {
+    // `GLOB=DIRECTIVES`
+    pub fn add(&mut self, spec: &str) -> Result<(), String> {
+        let (glob, directives) = spec
+            .split_once('=')
+            .ok_or(format!("Invalid --cache-header (expected GLOB=DIRECTIVES): {}", spec))?;
+        let (glob, directives) = (glob.trim(), directives.trim());
+        if !glob.starts_with(['/', '*']) || directives.is_empty() {
+            return Err(format!("Invalid --cache-header (expected GLOB=DIRECTIVES): {}", spec));
+        }
+        let value = HeaderValue::from_str(directives).map_err(|_| format!("Invalid Cache-Control: {}", directives))?;
+        let max_age = directives
+            .split(',')
+            .filter_map(|directive| directive.trim().strip_prefix("max-age="))
+            .next()
+            .map(|secs| secs.trim().parse().map_err(|_| format!("Invalid max-age in --cache-header: {}", spec)))
+            .transpose()?;
+        self.rules.push(Rule {
+            glob: glob.to_string(),
+            value,
+            max_age,
+        });
+        Ok(())
+    }
+
+    // The rule for a request, by its method and the path the client asked for
+    pub fn rule(&self, method: &Method, path: &str) -> Option<usize> {
+        if method != Method::GET && method != Method::HEAD {
+            return None;
+        }
+        self.rules.iter().position(|rule| wildcard(&rule.glob, path))
+    }
+
+    // Set the headers of rule `index` on a successful answer, with a
+    // max-age of at most `cap` seconds if given
+    pub fn apply(&self, index: usize, status: StatusCode, headers: &mut HeaderMap, cap: Option<u64>) {
+        if !status.is_success() && status != StatusCode::NOT_MODIFIED {
+            return;
+        }
+        let rule = &self.rules[index];
+        let capped = rule.max_age.zip(cap).filter(|(max_age, cap)| max_age > cap);
+        let value = match (capped, rule.value.to_str()) {
+            (Some((_, cap)), Ok(directives)) => {
+                let directives: Vec<String> = directives
+                    .split(',')
+                    .map(|directive| match directive.trim().starts_with("max-age=") {
+                        true => format!("max-age={}", cap),
+                        false => directive.trim().to_string(),
+                    })
+                    .collect();
+                HeaderValue::from_str(&directives.join(", ")).unwrap_or(rule.value.clone())
+            }
+            _ => rule.value.clone(),
+        };
+        headers.insert(CACHE_CONTROL, value);
+        headers.remove(EXPIRES);
+        if let Some(max_age) = rule.max_age.map(|max_age| cap.map_or(max_age, |cap| max_age.min(cap))) {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+            let expires = dates::format_http((now + max_age) as i64);
+            if let Ok(expires) = HeaderValue::from_str(&expires) {
+                headers.insert(EXPIRES, expires);
+            }
+        }
+    }
+}
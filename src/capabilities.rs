@@ -0,0 +1,172 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use hyper::header::{HeaderMap, ALLOW, AUTHORIZATION};
+use hyper::{Body, Client, Method, Request, StatusCode, Uri};
+
+use crate::json;
+use crate::resolve::Connector;
+use crate::upstream_auth::UpstreamAuth;
+use crate::warm;
+
+// What an upstream says it can do in its answer to OPTIONS: the DAV
+// compliance classes and the methods it allows. It is asked on startup and
+// every REFRESH after that, and the last answer decides which emulations
+// the proxy switches on for it.
+
+pub const REFRESH: Duration = Duration::from_secs(600);
+// Sooner while the upstream doesn't answer
+const RETRY: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Default, PartialEq)]
+pub struct Capabilities {
+    // DAV classes: "1", "2", "3" and extensions such as "access-control"
+    pub classes: Vec<String>,
+    // Empty if the upstream sent no Allow
+    pub methods: Vec<String>,
+}
+
+// The items of every value of a comma-separated header
+fn list(headers: &HeaderMap, name: &str) -> Vec<String> {
+    headers
+        .get_all(name)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|item| item.trim().to_string())
+        .filter(|item| !item.is_empty())
+        .collect()
+}
+
+impl Capabilities {
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        Capabilities {
+            classes: list(headers, "dav"),
+            methods: list(headers, ALLOW.as_str())
+                .into_iter()
+                .map(|m| m.to_ascii_uppercase())
+                .collect(),
+        }
+    }
+
+    // Class 2, or at least a LOCK method
+    pub fn locking(&self) -> bool {
+        self.classes.iter().any(|c| c == "2") || self.methods.iter().any(|m| m == "LOCK")
+    }
+
+    pub fn to_json(&self) -> String {
+        let strings = |items: &[String]| json::array(&items.iter().map(|i| json::string(i)).collect::<Vec<_>>());
+        json::object(&[
+            ("dav", strings(&self.classes)),
+            ("allow", strings(&self.methods)),
+            ("locking", self.locking().to_string()),
+        ])
+    }
+}
+
+// Whether an emulation runs for an upstream
+#[derive(Clone, Copy, PartialEq)]
+pub enum Emulation {
+    // When the upstream's OPTIONS says it lacks the feature
+    Auto,
+    On,
+    Off,
+}
+
+impl Emulation {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match s.trim() {
+            "auto" => Ok(Emulation::Auto),
+            "on" => Ok(Emulation::On),
+            "off" => Ok(Emulation::Off),
+            _ => Err(format!("Unknown emulation mode (expected auto, on or off): {}", s)),
+        }
+    }
+
+    // `supported` is None until the upstream has answered, and an unknown
+    // upstream is taken at its word
+    pub fn active(self, supported: Option<bool>) -> bool {
+        match self {
+            Emulation::Auto => supported == Some(false),
+            Emulation::On => true,
+            Emulation::Off => false,
+        }
+    }
+}
+
+// The last answer of one upstream
+pub struct Probe {
+    // The share's root on the upstream
+    uri: Uri,
+    known: Mutex<Option<Capabilities>>,
+}
+
+impl Probe {
+    pub fn new(uri: Uri) -> Self {
+        Probe {
+            uri,
+            known: Mutex::new(None),
+        }
+    }
+
+    pub fn get(&self) -> Option<Capabilities> {
+        self.known.lock().unwrap().clone()
+    }
+
+    // Ask again; false if the upstream didn't answer
+    pub async fn refresh(&self, client: &Client<Connector>, auth: Option<&UpstreamAuth>) -> bool {
+        let send = || {
+            let mut request = Request::builder().method(Method::OPTIONS).uri(self.uri.clone());
+            if let Some(auth) = auth {
+                let target = self.uri.path_and_query().map_or("/", |p| p.as_str());
+                request = request.header(AUTHORIZATION, auth.authorization(&Method::OPTIONS, target));
+            }
+            tokio::time::timeout(warm::CHECK_INTERVAL, client.request(request.body(Body::empty()).expect("request builder")))
+        };
+        let mut outcome = send().await;
+        // The first answer may be a Digest challenge
+        if let (Ok(Ok(response)), Some(auth)) = (&outcome, auth) {
+            if response.status() == StatusCode::UNAUTHORIZED && auth.challenged(response.headers()) {
+                outcome = send().await;
+            }
+        }
+        let response = match outcome {
+            Ok(Ok(response)) if response.status().is_success() => response,
+            Ok(Ok(response)) => {
+                tracing::warn!("Upstream {} answered OPTIONS with {}", self.uri, response.status());
+                return false;
+            }
+            Ok(Err(e)) => {
+                tracing::warn!("Failed to ask upstream {} for its capabilities: {}", self.uri, e);
+                return false;
+            }
+            Err(_) => {
+                tracing::warn!("Upstream {} didn't answer OPTIONS in time", self.uri);
+                return false;
+            }
+        };
+        let capabilities = Capabilities::from_headers(response.headers());
+        let mut known = self.known.lock().unwrap();
+        if known.as_ref() != Some(&capabilities) {
+            tracing::info!(
+                "Upstream {} supports DAV {} ({})",
+                self.uri,
+                if capabilities.classes.is_empty() { "-".to_string() } else { capabilities.classes.join(", ") },
+                if capabilities.locking() { "locking" } else { "no locking" }
+            );
+            *known = Some(capabilities);
+        }
+        true
+    }
+}
+
+// Ask now and every REFRESH, or every RETRY until the upstream answers,
+// for as long as a configuration has the upstream
+pub fn spawn(probe: Arc<Probe>, client: Client<Connector>, auth: Option<Arc<UpstreamAuth>>) {
+    tokio::spawn(async move {
+        while Arc::strong_count(&probe) > 1 {
+            let answered = probe.refresh(&client, auth.as_deref()).await;
+            tokio::time::sleep(if answered { REFRESH } else { RETRY }).await;
+        }
+    });
+}
@@ -0,0 +1,174 @@
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use hyper::{Body, Client, Method, Request};
+use openssl::asn1::Asn1Time;
+use openssl::hash::MessageDigest;
+use openssl::ocsp::{OcspCertId, OcspCertStatus, OcspRequest, OcspResponse, OcspResponseStatus};
+use openssl::x509::X509;
+
+use crate::errors;
+use crate::stats::Stats;
+
+// Background care for the listener certificate: fetching OCSP responses to
+// staple into handshakes, and warning before the certificate expires
+pub struct CertWatch {
+    path: String,
+    cert: X509,
+    // The next certificate of the chain file, needed to ask for OCSP status
+    issuer: Option<X509>,
+    warn_days: i32,
+    stapling: bool,
+    // DER of the current OCSP response
+    stapled: RwLock<Option<Vec<u8>>>,
+}
+
+// Slack for clock skew when checking OCSP response times
+const OCSP_SLACK: u32 = 300;
+
+impl CertWatch {
+    pub fn load(path: &str, warn_days: i32, stapling: bool) -> Result<Self, String> {
+        let pem = std::fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+        let mut chain = X509::stack_from_pem(&pem).map_err(|e| format!("{}: {}", path, e))?.into_iter();
+        let cert = chain.next().ok_or(format!("{}: no certificate", path))?;
+        Ok(CertWatch {
+            path: path.to_string(),
+            cert,
+            issuer: chain.next(),
+            warn_days,
+            stapling,
+            stapled: RwLock::new(None),
+        })
+    }
+
+    // The OCSP response to staple, if there is a current one
+    pub fn stapled(&self) -> Option<Vec<u8>> {
+        self.stapled.read().unwrap().clone()
+    }
+
+    // Whole days until expiry, negative once expired
+    fn days_left(&self) -> Result<i32, String> {
+        let now = Asn1Time::days_from_now(0).map_err(|e| e.to_string())?;
+        let diff = now.diff(self.cert.not_after()).map_err(|e| e.to_string())?;
+        Ok(if diff.days == 0 && diff.secs < 0 { -1 } else { diff.days })
+    }
+
+    fn check_expiry(&self, stats: &Stats) {
+        let days = match self.days_left() {
+            Ok(days) => days,
+            Err(e) => {
+                errors::report(format!("Failed to check the expiry of {}: {}", self.path, e));
+                return;
+            }
+        };
+        stats.set_certificate_days_left(days);
+        if days < 0 {
+            tracing::warn!("Warning: the TLS certificate {} has expired", self.path);
+        } else if days <= self.warn_days {
+            tracing::warn!("Warning: the TLS certificate {} expires in {} days", self.path, days);
+        }
+    }
+
+    async fn fetch_ocsp(&self, issuer: &X509, url: &str) -> Result<Vec<u8>, String> {
+        let id = || OcspCertId::from_cert(MessageDigest::sha1(), &self.cert, issuer).map_err(|e| e.to_string());
+        let mut request = OcspRequest::new().map_err(|e| e.to_string())?;
+        request.add_id(id()?).map_err(|e| e.to_string())?;
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri(url)
+            .header("Content-Type", "application/ocsp-request")
+            .body(Body::from(request.to_der().map_err(|e| e.to_string())?))
+            .map_err(|e| e.to_string())?;
+        let response = Client::new().request(req).await.map_err(|e| format!("{}: {}", url, e))?;
+        if !response.status().is_success() {
+            return Err(format!("{} answered {}", url, response.status()));
+        }
+        let der = hyper::body::to_bytes(response.into_body()).await.map_err(|e| format!("{}: {}", url, e))?;
+        let ocsp = OcspResponse::from_der(&der).map_err(|e| format!("{}: {}", url, e))?;
+        if ocsp.status() != OcspResponseStatus::SUCCESSFUL {
+            return Err(format!("{} refused the request ({})", url, ocsp.status().as_raw()));
+        }
+        let basic = ocsp.basic().map_err(|e| e.to_string())?;
+        let id = id()?;
+        let status = basic.find_status(&id).ok_or(format!("{} sent no status for the certificate", url))?;
+        if status.status != OcspCertStatus::GOOD {
+            let state = if status.status == OcspCertStatus::REVOKED { "revoked" } else { "unknown" };
+            return Err(format!("{} reports the certificate as {}", url, state));
+        }
+        status.check_validity(OCSP_SLACK, None).map_err(|_| format!("{} sent an outdated response", url))?;
+        Ok(der.to_vec())
+    }
+
+    // Drop the stapled response once it is no longer valid
+    fn expire_stapled(&self) {
+        let mut stapled = self.stapled.write().unwrap();
+        let valid = stapled.as_deref().is_some_and(|der| {
+            let check = || -> Option<()> {
+                let ocsp = OcspResponse::from_der(der).ok()?;
+                let basic = ocsp.basic().ok()?;
+                let issuer = self.issuer.as_ref()?;
+                let id = OcspCertId::from_cert(MessageDigest::sha1(), &self.cert, issuer).ok()?;
+                basic.find_status(&id)?.check_validity(OCSP_SLACK, None).ok()
+            };
+            check().is_some()
+        });
+        if !valid && stapled.take().is_some() {
+            tracing::warn!("The stapled OCSP response for {} expired, no longer stapling", self.path);
+        }
+    }
+
+    // Refresh the stapled response; returns when to try again
+    async fn refresh_ocsp(&self, issuer: &X509, url: &str) -> Duration {
+        match self.fetch_ocsp(issuer, url).await {
+            Ok(der) => {
+                *self.stapled.write().unwrap() = Some(der);
+                Duration::from_secs(6 * 3600)
+            }
+            Err(e) => {
+                tracing::warn!("Failed to fetch an OCSP response for {}: {}", self.path, e);
+                self.expire_stapled();
+                Duration::from_secs(300)
+            }
+        }
+    }
+
+    // Where to ask for OCSP status, if stapling is possible
+    fn responder(&self) -> Option<(&X509, String)> {
+        if !self.stapling {
+            return None;
+        }
+        let url = self.cert.ocsp_responders().ok()?.iter().next().map(|u| u.to_string());
+        match (&self.issuer, url) {
+            (Some(issuer), Some(url)) if url.starts_with("http://") => Some((issuer, url)),
+            (_, None) => None,
+            (None, Some(_)) => {
+                tracing::warn!("Not stapling OCSP: {} lacks the issuer certificate", self.path);
+                None
+            }
+            (_, Some(url)) => {
+                tracing::warn!("Not stapling OCSP: unsupported responder {}", url);
+                None
+            }
+        }
+    }
+}
+
+pub fn spawn(watch: Arc<CertWatch>, stats: Arc<Stats>) {
+    let expiry = watch.clone();
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(Duration::from_secs(12 * 3600));
+        loop {
+            ticker.tick().await;
+            expiry.check_expiry(&stats);
+        }
+    });
+    if let Some((issuer, url)) = watch.responder() {
+        let issuer = issuer.clone();
+        tokio::spawn(async move {
+            loop {
+                let next = watch.refresh_ocsp(&issuer, &url).await;
+                tokio::time::sleep(next).await;
+            }
+        });
+    }
+}
@@ -0,0 +1,2028 @@
+// The command line: options (and the config file) turned into a ProxyConfig
+// served until a signal says stop
+
+use std::env;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+
+use getopts::{Matches, Options};
+use hyper::Client;
+
+use crate::{
+    access_log, admin, atomic_file, clock, config, errors, features, fixtures, guest, listener, logging, rewrite_log, rollout, secrets, selftest, state,
+    throttle,
+};
+#[cfg(feature = "bench")]
+use crate::{bench, compare};
+#[cfg(feature = "tls")]
+use crate::{certwatch, tls};
+#[cfg(feature = "http3")]
+use crate::http3;
+use crate::auth::decisions::DecisionCache;
+use crate::auth::guests::Guests;
+use crate::auth::htpasswd::Htpasswd;
+#[cfg(feature = "ldap")]
+use crate::auth::ldap::LdapAuth;
+use crate::auth::lockout::Lockout;
+use crate::auth::negotiate::KeytabAcceptor;
+use crate::auth::Authenticator;
+use crate::breaker::Recovery;
+use crate::cache_headers::CacheHeaders;
+use crate::capabilities::Emulation;
+#[cfg(feature = "tls")]
+use crate::certwatch::CertWatch;
+use crate::client_limits::ClientLimits;
+use crate::cookies::CookiePolicy;
+use crate::cors::Cors;
+use crate::dates::DatePolicy;
+use crate::dedup::Dedup;
+use crate::depth::DepthGuard;
+use crate::errors::{ErrorLog, Sentry};
+use crate::fallback::{EntryNames, Fallback, FallbackFiles, FallbackRoutes};
+use crate::fixtures::{Fixture, Mock};
+use crate::forwarded::Forwarding;
+#[cfg(feature = "geoip")]
+use crate::geoip::{GeoIp, GeoPolicy};
+use crate::header_rules::HeaderRules;
+use crate::hooks::Hooks;
+use crate::inflight::InFlight;
+use crate::info::{self, Addresses};
+use crate::legacy::{LegacyPaths, RequestRewriter};
+use crate::limits::HeaderLimits;
+use crate::listener::Listener;
+use crate::memory::MemoryBudget;
+use crate::network::{AccessList, Network};
+use crate::pause::PauseControl;
+use crate::peers::Peers;
+use crate::priority::PriorityGate;
+use crate::problem::ApiRoutes;
+use crate::reload::{Reloadable, Settings, Source};
+use crate::resolve::{Connector, Resolver};
+use crate::response_cache::ResponseCache;
+use crate::rewrite::Rewrites;
+use crate::rollout::{Candidate, Rollout};
+use crate::routes::RouteSwitch;
+use crate::signals::Signals;
+use crate::snapshot::SnapshotHook;
+use crate::snapshots::SnapshotView;
+use crate::stats::Stats;
+use crate::status::StatusPage;
+use crate::strict::Strict;
+use crate::tenants::Tenants;
+#[cfg(feature = "test-upstream")]
+use crate::test_upstream::TestUpstream;
+use crate::throttle::{Limiter, RateCaps, Schedule};
+#[cfg(feature = "tls")]
+use crate::tls::{Preset, TlsOptions, TlsStream};
+use crate::transfers::Transfers;
+use crate::vhost::VirtualHosts;
+use crate::upstream_auth::UpstreamAuth;
+use crate::upstreams::{Naming, Upstreams};
+use crate::usage::Usage;
+use crate::virtual_files::VirtualFile;
+use crate::ProxyConfig;
+
+fn print_usage(program: &str, opts: Options) {
+    let program_path = std::path::PathBuf::from(program);
+    let program_name = program_path.file_stem().unwrap().to_string_lossy();
+    let brief = format!(
+        "Usage: {} REMOTE [REMOTE...] [-c CONFIG] [-b BIND_ADDR] [-l LOCAL_PORT]\n\n\
+         REMOTE is REMOTE_HOST:PORT, http[s]://REMOTE_HOST[:PORT]/BASE/PATH or unix:/PATH\n\
+         for a server on a Unix socket of this host; several upstreams are served as\n\
+         folders at the root, see --upstream-names. Prefix it with /MOUNT/PATH= to serve\n\
+         it there instead, e.g. /dav=http://server/remote.php/webdav or /files=nas:8080;\n\
+         a request goes to the upstream with the longest matching mount",
+        program_name
+    );
+    print!("{}", opts.usage(&brief));
+}
+
+// Report a problem with the options and quit
+fn fail(e: String) -> ! {
+    eprintln!("{}", e);
+    std::process::exit(-1);
+}
+
+// The value of `option` as a T, if given; one that doesn't parse fails
+// like any other bad option, so --check-config reports it
+fn parsed<T: std::str::FromStr>(matches: &Matches, option: &str) -> Option<T> {
+    matches.opt_str(option).map(|s| value(option, &s))
+}
+
+fn value<T: std::str::FromStr>(option: &str, s: &str) -> T {
+    s.trim().parse().unwrap_or_else(|_| fail(format!("Invalid --{}: {}", option, s)))
+}
+
+// The REMOTEs with --upstream-names `naming` and the per-upstream settings
+// of `matches`
+fn upstreams(remotes: &[String], naming: Option<String>, matches: &Matches) -> Result<Upstreams, String> {
+    let naming = naming.map_or(Ok(Naming::Numbered), |s| Naming::parse(&s))?;
+    let mut upstreams = Upstreams::parse(remotes, naming)?;
+    upstreams.configure("timeout", &matches.opt_strs("timeout"), |upstream, secs| {
+        let secs = secs.trim().parse::<u64>().map_err(|_| format!("Invalid --timeout: {}", secs))?;
+        upstream.timeout = Duration::from_secs(secs);
+        Ok(())
+    })?;
+    upstreams.configure("retries", &matches.opt_strs("retries"), |upstream, n| {
+        upstream.retries = n.trim().parse().map_err(|_| format!("Invalid --retries: {}", n))?;
+        Ok(())
+    })?;
+    upstreams.configure("retry-backoff", &matches.opt_strs("retry-backoff"), |upstream, ms| {
+        let ms = ms.trim().parse::<u64>().map_err(|_| format!("Invalid --retry-backoff: {}", ms))?;
+        upstream.backoff = Duration::from_millis(ms);
+        Ok(())
+    })?;
+    upstreams.configure("fallback-default", &matches.opt_strs("fallback-default"), |upstream, strategy| {
+        upstream.fallback = Fallback::parse(strategy)?;
+        Ok(())
+    })?;
+    Ok(upstreams)
+}
+
+fn rewrites(matches: &Matches) -> Result<Rewrites, String> {
+    let mut rewrites = Rewrites::default();
+    for rule in matches.opt_strs("rewrite") {
+        rewrites.add(&rule)?;
+    }
+    Ok(rewrites)
+}
+
+fn header_rules(matches: &Matches) -> Result<HeaderRules, String> {
+    let mut header_rules = HeaderRules::default();
+    for rule in matches.opt_strs("request-header") {
+        header_rules.add_request(&rule)?;
+    }
+    for rule in matches.opt_strs("response-header") {
+        header_rules.add_response(&rule)?;
+    }
+    Ok(header_rules)
+}
+
+// What the breakers do once an upstream is back
+fn recovery(matches: &Matches) -> Result<Recovery, String> {
+    let grace = match matches.opt_str("recovery-grace") {
+        Some(secs) => secs.parse().map_err(|_| format!("Invalid --recovery-grace: {}", secs))?,
+        None => 0,
+    };
+    let webhook = matches.opt_str("recovery-webhook");
+    let http = |url: &String| url.parse::<hyper::Uri>().is_ok_and(|uri| uri.scheme_str() == Some("http"));
+    if let Some(url) = webhook.as_ref().filter(|url| !http(url)) {
+        return Err(format!("Invalid --recovery-webhook (expected an http:// URL): {}", url));
+    }
+    if (grace > 0 || webhook.is_some()) && matches.opt_str("breaker-after").is_none_or(|n| n == "0") {
+        return Err("--recovery-grace and --recovery-webhook need --breaker-after".to_string());
+    }
+    Ok(Recovery {
+        grace: Duration::from_secs(grace),
+        webhook,
+    })
+}
+
+fn cache_headers(matches: &Matches) -> Result<CacheHeaders, String> {
+    let mut cache_headers = CacheHeaders::default();
+    for rule in matches.opt_strs("cache-header") {
+        cache_headers.add(&rule)?;
+    }
+    Ok(cache_headers)
+}
+
+// The credentials of --upstream-user, with the password from wherever the
+// options say
+fn upstream_auth(matches: &Matches) -> Result<Option<Arc<UpstreamAuth>>, String> {
+    let password = secrets::from_options(
+        matches.opt_str("upstream-pass-env"),
+        matches.opt_str("upstream-pass-file"),
+        matches.opt_str("upstream-pass-cmd"),
+    )
+    .and_then(|source| source.map(|s| s.resolve()).transpose())?;
+    match (matches.opt_str("upstream-user"), password) {
+        (None, _) if matches.opt_present("upstream-alt-credentials") => {
+            Err("--upstream-alt-credentials needs --upstream-user".to_string())
+        }
+        (None, None) => Ok(None),
+        (Some(user), password) => {
+            let alternates = match matches.opt_str("upstream-alt-credentials") {
+                Some(path) => std::fs::read_to_string(&path).map_err(|e| format!("{}: {}", path, e))?,
+                None => String::new(),
+            };
+            UpstreamAuth::new(&user, &password.unwrap_or_default())
+                .and_then(|auth| auth.with_alternates(&alternates))
+                .map(|auth| Some(Arc::new(auth)))
+                .map_err(|e| format!("Invalid upstream credentials: {}", e))
+        }
+        (None, Some(_)) => Err("An upstream password needs --upstream-user".to_string()),
+    }
+}
+
+// What a reload replaces, as `matches` set it: the upstreams of `remotes`,
+// with the `switched` folders served by other servers, and their
+// connections, credentials, rules and timeouts
+fn settings(
+    remotes: &[String],
+    switched: &[(String, String)],
+    matches: &Matches,
+    resolver: &Resolver,
+    client: &Client<Connector>,
+    pin_connections: bool,
+) -> Result<Settings, String> {
+    let mut upstreams = upstreams(remotes, matches.opt_str("upstream-names"), matches)?;
+    for (name, remote) in switched {
+        upstreams.switch(name, remote)?;
+    }
+    let mirrors = matches.opt_strs("fallback-upstream");
+    // A connection-bound login doesn't carry over to another server
+    if !mirrors.is_empty() && pin_connections {
+        return Err("--fallback-upstream can't be combined with pinned connections (--pin-connections, --sharepoint, --negotiate passthrough)".to_string());
+    }
+    upstreams.add_mirrors(&mirrors, client)?;
+    let number = |name: &str| {
+        matches
+            .opt_str(name)
+            .map(|n| n.parse::<u64>().map_err(|_| format!("Invalid --{}: {}", name, n)))
+            .transpose()
+    };
+    let breaker_after = number("breaker-after")?.unwrap_or(0);
+    let recovery = recovery(matches)?;
+    let warm = number("warm-connections")?.filter(|&n| n > 0);
+    let idle_timeout = Some(number("idle-timeout")?.unwrap_or(60))
+        .filter(|&secs| secs > 0)
+        .map(Duration::from_secs);
+    let transfer_timeout = number("transfer-timeout")?.filter(|&secs| secs > 0).map(Duration::from_secs);
+    let mut settings = Settings {
+        upstream_auth: upstream_auth(matches)?,
+        rewrites: rewrites(matches)?,
+        cache_headers: cache_headers(matches)?,
+        header_rules: header_rules(matches)?,
+        idle_timeout,
+        transfer_timeout,
+        upstreams,
+    };
+    // Last, as it starts connecting
+    if breaker_after > 0 {
+        settings.upstreams.add_breakers(breaker_after as u32, &recovery, client);
+    }
+    if let Some(size) = warm {
+        settings.upstreams.warm_up(size as usize, resolver);
+    }
+    Ok(settings)
+}
+
+// `matches` of `args` with the options of their --config file merged in,
+// the command line taking precedence
+fn with_config(opts: &Options, args: &[String], matches: Matches) -> Result<Matches, String> {
+    let Some(path) = matches.opt_str("config") else {
+        return Ok(matches);
+    };
+    let mut merged = config::load(&path, opts, &matches).map_err(|problems| problems.join("\n"))?;
+    merged.extend(args.iter().cloned());
+    opts.parse(&merged).map_err(|e| e.to_string())
+}
+
+// The --candidate-config file, which may only set rollout::KEYS and the
+// upstreams; the candidate upstreams get the per-upstream settings of
+// `matches`
+fn candidate(path: &str, opts: &Options, matches: &Matches) -> Result<Candidate, String> {
+    let none = opts.parse(Vec::<String>::new()).expect("no arguments");
+    let args = config::load(path, opts, &none).map_err(|problems| problems.join("\n"))?;
+    for arg in &args {
+        if let Some(key) = arg.strip_prefix("--").and_then(|arg| arg.split('=').next()) {
+            if !rollout::KEYS.contains(&key) {
+                return Err(format!(
+                    "{}: a candidate can only set upstream, {}, not {}",
+                    path,
+                    rollout::KEYS.join(", "),
+                    key
+                ));
+            }
+        }
+    }
+    let tried = opts.parse(&args).map_err(|e| format!("{}: {}", path, e))?;
+    let set = |keys: &[&str]| keys.iter().any(|key| tried.opt_present(key));
+    Ok(Candidate {
+        upstreams: match tried.free.is_empty() {
+            true => None,
+            false => Some(upstreams(&tried.free, tried.opt_str("upstream-names"), matches).map_err(|e| format!("{}: {}", path, e))?),
+        },
+        rewrites: set(&["rewrite"]).then(|| rewrites(&tried)).transpose()?,
+        header_rules: set(&["request-header", "response-header"]).then(|| header_rules(&tried)).transpose()?,
+        cache_headers: set(&["cache-header"]).then(|| cache_headers(&tried)).transpose()?,
+    })
+}
+
+//Commandline parsing from https://github.com/mqudsi/tcpproxy
+/// Run the proxy as configured by the process's arguments, as the binary does.
+pub async fn run() {
+    let args: Vec<String> = env::args().collect();
+    let program = args[0].clone();
+    if args.get(1).map(String::as_str) == Some("bench") {
+        #[cfg(feature = "bench")]
+        return bench::main(&program, &args[2..]).await;
+        #[cfg(not(feature = "bench"))]
+        features::missing("the bench subcommand", "bench");
+    }
+    if args.get(1).map(String::as_str) == Some("compare") {
+        #[cfg(feature = "bench")]
+        return compare::main(&program, &args[2..]).await;
+        #[cfg(not(feature = "bench"))]
+        features::missing("the compare subcommand", "bench");
+    }
+    if args.get(1).map(String::as_str) == Some("guest") {
+        return guest::main(&program, &args[2..]).await;
+    }
+
+    let mut opts = Options::new();
+    opts.optflag("V", "version", "Print the version and the optional features built in");
+    opts.optflag(
+        "",
+        "self-test",
+        "Start up, send OPTIONS and PROPFIND through the listener to every upstream, check the fallback against a closed port, report and exit",
+    );
+    opts.optopt(
+        "c",
+        "config",
+        "Read options from this file, as `long-option = value` lines in TOML syntax plus `upstream` for the remotes; the command line takes precedence. SIGHUP, SIGUSR1 or POST /admin/reload reads it again and switches new requests to its upstreams, upstream credentials, timeouts and rewrite, header and cache rules",
+        "FILE",
+    );
+    opts.optflag(
+        "",
+        "check-config",
+        "Parse the options and --config file and load the files they name (certificates, htpasswd, ...), report problems and exit without listening",
+    );
+    opts.optflag(
+        "",
+        "builtin-test-upstream",
+        "Instead of REMOTE, serve a scratch WebDAV share from a temporary directory as the upstream, to try the proxy without a server; type stop or start to take it down and bring it back (requires the test-upstream feature)",
+    );
+    opts.optopt(
+        "",
+        "verify",
+        "Check the YAML fixtures in FILE against the proxy as configured and exit, with 1 if any failed: each gives a request, what the upstream answers, and the response and forwarded request expected. A stand-in server takes the place of each REMOTE's, which only lends its mount and base path and may be left out",
+        "FILE",
+    );
+    opts.optopt(
+        "b",
+        "bind",
+        "The address on which to listen for incoming requests, defaulting to localhost, or unix:/PATH to listen on a Unix socket instead of a port (removed again on shutdown; clients on it count as 127.0.0.1)",
+        "BIND_ADDR",
+    );
+    opts.optopt(
+        "l",
+        "local-port",
+        "The local port to which tcpproxy should bind to, randomly chosen otherwise",
+        "LOCAL_PORT",
+    );
+    opts.optopt(
+        "",
+        "tls-cert",
+        "Serve HTTPS with this PEM certificate chain (needs --tls-key)",
+        "FILE",
+    );
+    opts.optopt("", "tls-key", "PEM private key of --tls-cert", "FILE");
+    opts.optopt(
+        "",
+        "tls-preset",
+        "Protocol and cipher policy after Mozilla's guidelines: modern (TLS 1.3 only), intermediate (default) or old",
+        "PRESET",
+    );
+    opts.optopt("", "tls-min-version", "Lowest TLS version to accept (1.0 to 1.3), overriding the preset", "VERSION");
+    opts.optopt("", "tls-max-version", "Highest TLS version to accept (1.0 to 1.3)", "VERSION");
+    opts.optopt(
+        "",
+        "tls-ciphers",
+        "OpenSSL cipher list for TLS 1.2 and older, overriding the preset",
+        "LIST",
+    );
+    opts.optopt(
+        "",
+        "tls-ciphersuites",
+        "TLS 1.3 cipher suites, overriding the preset",
+        "LIST",
+    );
+    opts.optopt(
+        "",
+        "tls-alpn",
+        "Protocols to offer in ALPN, in order of preference, defaulting to h2,http/1.1; empty to disable ALPN",
+        "PROTO,PROTO,...",
+    );
+    opts.optopt(
+        "",
+        "tls-expiry-warn",
+        "Warn when the TLS certificate expires within this many days, defaulting to 30",
+        "DAYS",
+    );
+    opts.optopt(
+        "",
+        "tls-ticket-rotation",
+        "Replace the key session tickets are encrypted with every SECS, defaulting to 43200 (12 hours); tickets of the previous key still resume for one more period, which spares clients that open many short connections, like Windows' WebDAV redirector, full handshakes; 0 turns tickets off, leaving resumption by session ID",
+        "SECS",
+    );
+    opts.optopt(
+        "",
+        "http3-port",
+        "Also serve HTTP/3 over QUIC on this UDP port, usually the HTTPS one, with the --tls-cert certificate; responses advertise it in Alt-Svc so clients switch over. Experimental, for mobile clients on lossy networks (requires the http3 feature)",
+        "PORT",
+    );
+    opts.optflag(
+        "",
+        "no-ocsp-stapling",
+        "Don't fetch OCSP responses from the certificate's responder to staple into handshakes",
+    );
+    opts.optopt(
+        "",
+        "cookies",
+        "What to do with cookies between clients and the upstream: pass (default), strip or rewrite",
+        "POLICY",
+    );
+    opts.optopt(
+        "",
+        "cookie-domain",
+        "With --cookies rewrite, the Domain attribute to set on upstream cookies (dropped otherwise)",
+        "DOMAIN",
+    );
+    opts.optopt(
+        "",
+        "cookie-path",
+        "With --cookies rewrite, the Path attribute to set on upstream cookies (kept otherwise)",
+        "PATH",
+    );
+    opts.optopt(
+        "",
+        "emulate-locks",
+        "Grant LOCK and UNLOCK in the proxy for upstreams that can't lock, so Windows and macOS mount them writable: auto (the default) when the upstream's OPTIONS lacks DAV class 2, which is asked on startup and every 10 minutes, on for every upstream or off",
+        "MODE",
+    );
+    opts.optmulti(
+        "",
+        "infinite-depth",
+        "What becomes of PROPFINDs with Depth: infinity (or no Depth) under PREFIX, all paths without one: reject with 403 and a propfind-finite-depth error, so clients list level by level, downgrade to Depth: 1, or allow; the longest prefix wins (repeatable)",
+        "[PREFIX=]ACTION",
+    );
+    opts.optopt(
+        "",
+        "strict",
+        "Check WebDAV requests against RFC 4918 before sending them on and answer 400 with what is wrong, for upstreams that choke on malformed ones; comma-separated rules: depth (0, 1 or infinity, as the method allows), headers (Destination, Overwrite, Lock-Token, Timeout and If) and xml (PROPFIND, PROPPATCH and LOCK bodies up to 1 MiB are well-formed UTF-8 XML with the right root element), or all; -RULE leaves one out, e.g. all,-xml",
+        "RULES",
+    );
+    opts.optflag(
+        "",
+        "read-only",
+        "Refuse PUT, DELETE, MKCOL, MOVE, COPY, PROPPATCH, LOCK, UNLOCK, POST and PATCH with 403 and leave them (and locking) out of OPTIONS answers, so clients mount the share read-only",
+    );
+    opts.optflag(
+        "",
+        "sharepoint",
+        "SharePoint compatibility: pin each client connection to its own upstream connection for NTLM/Negotiate and apply header fixups",
+    );
+    opts.optmulti(
+        "",
+        "allow",
+        "Only accept connections from this network, as ADDR or ADDR/BITS (repeatable); others are closed as soon as they are accepted",
+        "NETWORK",
+    );
+    opts.optmulti(
+        "",
+        "deny",
+        "Close connections from this network as soon as they are accepted, even if --allow covers it, as ADDR or ADDR/BITS (repeatable)",
+        "NETWORK",
+    );
+    opts.optflag(
+        "",
+        "no-forwarded-headers",
+        "Don't tell the upstream about the client: by default its address is appended to X-Forwarded-For, X-Forwarded-Proto and X-Forwarded-Host are set unless present, and the proxy adds itself to Via",
+    );
+    opts.optflag(
+        "",
+        "strip-forwarded",
+        "Drop the Forwarded, X-Forwarded-For/Proto/Host and X-Real-IP headers clients send, except those from a --trusted-proxy, so they can't pass themselves off as another client",
+    );
+    opts.optmulti(
+        "",
+        "trusted-proxy",
+        "With --strip-forwarded, a proxy in front of this one whose forwarding headers are kept, as ADDR or ADDR/BITS (repeatable)",
+        "NETWORK",
+    );
+    opts.optflag(
+        "",
+        "pin-connections",
+        "Disable upstream connection pooling and map each client connection to its own upstream connection",
+    );
+    opts.optopt(
+        "",
+        "negotiate",
+        "Kerberos/SPNEGO handling: passthrough (forward tokens over pinned connections) or keytab (authenticate at the proxy)",
+        "MODE",
+    );
+    opts.optopt(
+        "",
+        "keytab",
+        "With --negotiate keytab, the keytab holding the proxy's service key (KRB5_KTNAME otherwise)",
+        "FILE",
+    );
+    opts.optopt(
+        "",
+        "htpasswd",
+        "Authenticate Basic credentials against this htpasswd file, reloaded on change or SIGHUP/SIGUSR1 (Ctrl-Break on Windows)",
+        "FILE",
+    );
+    opts.optopt(
+        "",
+        "ldap-url",
+        "Authenticate Basic credentials with a bind against this LDAP server, ldap:// or ldaps:// (requires the ldap feature)",
+        "URL",
+    );
+    opts.optopt(
+        "",
+        "ldap-user-dn",
+        "DN template for binding, with {user} replaced by the login name",
+        "TEMPLATE",
+    );
+    opts.optmulti(
+        "",
+        "ldap-group",
+        "Only members of GROUP_DN may access paths under PREFIX (repeatable)",
+        "PREFIX=GROUP_DN",
+    );
+    opts.optopt(
+        "",
+        "ldap-group-attr",
+        "Group attribute listing member DNs, defaulting to member",
+        "ATTR",
+    );
+    opts.optopt(
+        "",
+        "ldap-cache-ttl",
+        "Seconds to cache successful binds, defaulting to 60",
+        "SECS",
+    );
+    opts.optopt(
+        "",
+        "auth-cache-ttl",
+        "Seconds to reuse the decision of --htpasswd, --ldap-url or --pam-service on the same credentials (and for LDAP the same group prefix), dropped on SIGHUP/SIGUSR1 (0, the default, checks every request)",
+        "SECS",
+    );
+    opts.optopt(
+        "",
+        "lockout-after",
+        "Lock out an address after this many failed logins (disabled by default)",
+        "N",
+    );
+    opts.optopt(
+        "",
+        "lockout-window",
+        "Minutes within which the failed logins have to happen, defaulting to 10",
+        "MINUTES",
+    );
+    opts.optopt(
+        "",
+        "lockout-time",
+        "Minutes a locked out address is refused with 429, defaulting to 15",
+        "MINUTES",
+    );
+    opts.optopt(
+        "",
+        "upstream-names",
+        "With several upstreams, name their root folders numbered (1, 2, ..., the default) or host (host_port)",
+        "NAMING",
+    );
+    opts.optmulti(
+        "",
+        "resolve",
+        "Connect to the upstream host at this address instead of looking it up, like a hosts file entry for the proxy only; repeat for more addresses",
+        "HOST=IP",
+    );
+    opts.optmulti(
+        "",
+        "upstream-ca",
+        "Trust the CA certificates in this PEM file for https:// upstreams, besides the system's (repeatable)",
+        "FILE",
+    );
+    opts.optflag(
+        "",
+        "insecure",
+        "Don't verify the certificates of https:// upstreams, for self-signed servers",
+    );
+    opts.optopt(
+        "",
+        "outbound-proxy",
+        "Connect to the upstreams through this proxy, e.g. socks5://127.0.0.1:1080 for an SSH dynamic forward (ssh -D) or http://proxy.corp:3128 for a proxy taking CONNECT, with USER:PASSWORD@ before the host if it wants credentials; it looks up the upstream's name",
+        "URL",
+    );
+    opts.optopt(
+        "",
+        "warm-connections",
+        "Keep this many upstream connections open and ready, so early requests don't wait for a connect",
+        "N",
+    );
+    opts.optopt(
+        "",
+        "pool-idle-timeout",
+        "Seconds a pooled upstream connection may sit unused before it is closed, defaulting to 90 (0 keeps it until the upstream closes it)",
+        "SECS",
+    );
+    opts.optopt(
+        "",
+        "pool-max-idle",
+        "Most unused connections to keep pooled per upstream host, unlimited by default (0 disables pooling)",
+        "N",
+    );
+    opts.optopt(
+        "",
+        "upstream-user",
+        "Log in to the upstream as this user, with Basic auth until the upstream asks for Digest (MD5 or SHA-256, qop=auth)",
+        "USER",
+    );
+    opts.optopt(
+        "",
+        "upstream-pass-env",
+        "Read the upstream password from this environment variable",
+        "VAR",
+    );
+    opts.optopt(
+        "",
+        "upstream-pass-file",
+        "Read the upstream password from this file",
+        "FILE",
+    );
+    opts.optopt(
+        "",
+        "upstream-pass-cmd",
+        "Read the upstream password from the first line printed by this command",
+        "COMMAND",
+    );
+    opts.optopt(
+        "",
+        "upstream-alt-credentials",
+        "File of USER:PASSWORD lines to try in turn when the upstream refuses the --upstream-user credentials, e.g. the old and new password during a rotation",
+        "FILE",
+    );
+    opts.optopt(
+        "",
+        "admin-bind",
+        "Serve the admin API (/admin/...) on this address, e.g. 127.0.0.1:9090",
+        "ADDR:PORT",
+    );
+    opts.optopt(
+        "",
+        "admin-token",
+        "Require `Authorization: Bearer TOKEN` on admin API requests; minting guests and importing state (POST /admin/guests, POST /admin/state) need one",
+        "TOKEN",
+    );
+    opts.optopt(
+        "",
+        "error-log-size",
+        "Keep this many panics and unexpected errors for /admin/errors, defaulting to 100",
+        "N",
+    );
+    opts.optopt(
+        "",
+        "sentry-dsn",
+        "Also report panics and unexpected errors to this Sentry-compatible collector",
+        "DSN",
+    );
+    opts.optopt(
+        "",
+        "routes-state",
+        "Keep the routes disabled through the admin API in this file, so they stay disabled after a restart",
+        "FILE",
+    );
+    opts.optopt(
+        "",
+        "state-file",
+        "Carry runtime state over restarts and moves to another host: a pause, the routes disabled through the admin API, which upstreams are down or served by their mirror, and the statistics are read from this file at startup if it exists and written to it on shutdown, in the form of GET /admin/state",
+        "FILE",
+    );
+    opts.optopt(
+        "",
+        "stats-file",
+        "Persist transfer statistics to this file every minute",
+        "FILE",
+    );
+    opts.optmulti(
+        "",
+        "usage-path",
+        "Count the requests and bytes up and down under this folder by calendar month (UTC) and show them on it in PROPFIND answers as the properties period, requests, bytes-uploaded and bytes-downloaded in the namespace urn:proxy-optional-webdav:usage, so users see their consumption in any DAV client; kept over restarts with --state-file (repeatable)",
+        "FOLDER",
+    );
+    opts.optopt(
+        "",
+        "status-path",
+        "Answer PREFIX/health (ok or unavailable) and PREFIX/status (uptime, request counters and each upstream's reachability and last error, as JSON if accepted) in the proxy, e.g. /_proxy, without authentication and never passing them on; both are 200 while every upstream accepts connections and 503 otherwise",
+        "PREFIX",
+    );
+    opts.optmulti(
+        "",
+        "virtual-file",
+        "Serve the local file PATH, read at startup, as NAME at the share root, listed among the upstream's entries, e.g. HOW_TO_CONNECT.txt=/etc/webdav/connect.txt (repeatable)",
+        "NAME=PATH",
+    );
+    opts.optmulti(
+        "",
+        "virtual-text",
+        "Serve TEXT as the file NAME at the share root like --virtual-file, e.g. NOTICE.txt=Files here are deleted after 30 days (repeatable)",
+        "NAME=TEXT",
+    );
+    opts.optmulti(
+        "",
+        "rewrite",
+        "Before routing, send requests whose whole (percent-encoded) path matches the regular expression PATTERN to REPLACEMENT instead, with $1..$9 for its groups, e.g. '/projects/(\\d{4})/(.*) /archive/$1/$2'; Destination headers are rewritten too, and hrefs and Location in the answer are mapped back; the first matching rule applies (repeatable)",
+        "'PATTERN REPLACEMENT'",
+    );
+    opts.optopt(
+        "",
+        "snapshot-view",
+        "Serve the upstream's snapshots of each share read-only at /@snapshots/NAME/ in it, which isn't listed anywhere; PATTERN is where the upstream keeps them relative to the share, e.g. .zfs/snapshot or .snapshots/{name}/snapshot",
+        "PATTERN",
+    );
+    opts.optopt(
+        "",
+        "virtual-stats",
+        "Serve the transfer statistics as a virtual file with this name at the share root, e.g. stats.txt",
+        "NAME",
+    );
+    opts.optopt(
+        "",
+        "bandwidth-schedule",
+        "Time-of-day bandwidth limits per direction, e.g. 08:00-18:00=512K,*=0 (0 is unlimited)",
+        "RULES",
+    );
+    opts.optopt(
+        "",
+        "max-upload-rate",
+        "Limit uploads to the upstream to RATE bytes per second, e.g. 512K or 2M, on top of --bandwidth-schedule",
+        "RATE",
+    );
+    opts.optopt(
+        "",
+        "max-download-rate",
+        "Limit downloads from the upstream to RATE bytes per second, e.g. 512K or 2M, on top of --bandwidth-schedule",
+        "RATE",
+    );
+    opts.optopt(
+        "",
+        "rate-limit-scope",
+        "Whether --max-upload-rate and --max-download-rate are shared by all transfers (global, the default) or apply to each client connection (connection)",
+        "SCOPE",
+    );
+    opts.optopt(
+        "",
+        "max-concurrent",
+        "Limit concurrent upstream requests; queued PROPFIND/OPTIONS/HEAD requests go first. /admin/concurrency shows the slots in use and the requests waiting",
+        "N",
+    );
+    opts.optopt(
+        "",
+        "client-rate",
+        "Allow each client address N requests per second on average (fractions allowed), answering 429 with Retry-After beyond that",
+        "N",
+    );
+    opts.optopt(
+        "",
+        "client-burst",
+        "With --client-rate, how many requests a client may send at once after a quiet spell, defaulting to 20",
+        "N",
+    );
+    opts.optopt(
+        "",
+        "client-max-concurrent",
+        "Answer 429 to a client address that already has N requests under way, counting a request until its response is sent",
+        "N",
+    );
+    opts.optmulti(
+        "",
+        "peer",
+        "Share --client-rate with another instance behind the same load balancer, given by the base URL of its admin API, e.g. http://10.0.0.2:9090: each second both report the requests they admitted per client, so a client gets its rate across them rather than from each. Needs --admin-bind here and there; --admin-token is sent along (repeatable)",
+        "URL",
+    );
+    opts.optmulti(
+        "",
+        "tenant",
+        "Give the site whose Host header is HOST, or * for every other, its own share of the proxy: at most N requests under way (429 beyond), RATE bytes per second each way and SIZE of the --cache-ttl response cache, e.g. files.example.com=max-concurrent:20,bandwidth:10M,cache:16M; its traffic is counted apart in the statistics (repeatable)",
+        "HOST=LIMITS",
+    );
+    opts.optmulti(
+        "",
+        "cors-origin",
+        "Let pages from ORIGIN, e.g. https://app.example.com, or * for any, use the share from the browser: CORS preflights are answered by the proxy and its answers carry Access-Control-Allow-Origin (repeatable)",
+        "ORIGIN",
+    );
+    opts.optflag(
+        "",
+        "cors-credentials",
+        "With --cors-origin, let the browser send cookies and HTTP authentication along (Access-Control-Allow-Credentials)",
+    );
+    opts.optopt(
+        "",
+        "cors-max-age",
+        "With --cors-origin, how long browsers may reuse a preflight answer, defaulting to 600 seconds",
+        "SECS",
+    );
+    opts.optopt(
+        "",
+        "max-queue-wait",
+        "With --max-concurrent, answer 503 with the number of busy slots and waiting requests to a request that got no slot within SECS (fractions allowed) rather than letting it wait for one however long it takes",
+        "SECS",
+    );
+    opts.optopt(
+        "",
+        "memory-cap",
+        "Once buffered bodies, caches and queued requests take more than SIZE (e.g. 64M), drop the caches and answer new requests with 503; /admin/memory shows the usage",
+        "SIZE",
+    );
+    opts.optopt(
+        "",
+        "metadata-reserve",
+        "With --max-concurrent, extra slots only metadata requests may use, defaulting to 2",
+        "N",
+    );
+    opts.optmulti(
+        "",
+        "timeout",
+        "Seconds to wait for the upstream to connect and start its response, defaulting to 5; time spent sending the request body doesn't count, see --transfer-timeout for that. UPSTREAM= sets it for one upstream folder (repeatable)",
+        "[UPSTREAM=]SECS",
+    );
+    opts.optmulti(
+        "",
+        "retries",
+        "Times to resend a request that doesn't modify the share after a timeout or failed connection, defaulting to 0; UPSTREAM= sets it for one upstream folder (repeatable)",
+        "[UPSTREAM=]N",
+    );
+    opts.optmulti(
+        "",
+        "retry-backoff",
+        "Milliseconds to wait before the first --retries attempt, doubled for each further one up to 10 seconds, defaulting to 250; switching to a --fallback-upstream doesn't wait. UPSTREAM= sets it for one upstream folder (repeatable)",
+        "[UPSTREAM=]MS",
+    );
+    opts.optopt(
+        "",
+        "replay-buffer",
+        "With --retries, also resend requests that modify the share when their body is at most SIZE (e.g. 64K), but only if the upstream connection failed before the request went out",
+        "SIZE",
+    );
+    opts.optmulti(
+        "",
+        "fallback",
+        "What paths under PREFIX answer while the upstream is unreachable: folder (or virtual-folder), empty, 503 or error, which passes the failure on as a 502 or 504 and is what writes get (PROPPATCH and LOCK get a 503 with a WebDAV error body and Retry-After, so sync clients try again later); none is error for a transparent proxy, e.g. --fallback none for every path; a single request can ask for error with an X-Proxy-No-Fallback: 1 header or a proxy-no-fallback=1 query parameter (repeatable)",
+        "[PREFIX=]STRATEGY",
+    );
+    opts.optopt(
+        "",
+        "fallback-dir",
+        "While the upstream is unreachable, serve this local directory read-only (PROPFIND, GET) instead of the fallback, e.g. a cached or placeholder tree laid out as clients see the share",
+        "PATH",
+    );
+    opts.optmulti(
+        "",
+        "api-route",
+        "Answer errors the proxy generates for paths under PREFIX, including the fallback while the upstream is unreachable, as JSON problem details (RFC 7807) with a request ID (repeatable)",
+        "PREFIX",
+    );
+    opts.optmulti(
+        "",
+        "fallback-default",
+        "Fallback for paths without a --fallback route, defaulting to folder; UPSTREAM= sets it for one upstream folder (repeatable)",
+        "[UPSTREAM=]STRATEGY",
+    );
+    opts.optmulti(
+        "",
+        "fallback-name",
+        "Name of the error folder shown for REASON (timeout, closed, dns for a host name that doesn't resolve, or disabled for a route disabled on the admin API), optionally only for clients accepting LANG; {HH:MM:SS}, {HH:MM}, {HHMMSS} and {YYYY-MM-DD} expand to the current time and {phase} to where a timeout happened: dns, connect, tls or first-byte (repeatable)",
+        "[LANG:]REASON=NAME",
+    );
+    opts.optmulti(
+        "",
+        "fallback-file",
+        "Show the local FILE as NAME in the error folder, e.g. a README explaining the outage, for every REASON (timeout, closed, dns or disabled) or just the one given; clients can open it while the upstream is down. {reason}, {phase} and the time placeholders of --fallback-name in it are expanded (repeatable)",
+        "[REASON:]NAME=FILE",
+    );
+    opts.optopt(
+        "",
+        "idle-timeout",
+        "Abort responses whose body the upstream stops sending for SECS, defaulting to 60 (0 waits forever)",
+        "SECS",
+    );
+    opts.optopt(
+        "",
+        "breaker-after",
+        "After N requests in a row failed to reach an upstream, answer its requests from the fallback right away instead of waiting out --timeout each time, until a background check every 5 seconds finds it back (0, the default, always tries)",
+        "N",
+    );
+    opts.optopt(
+        "",
+        "recovery-grace",
+        "For SECS after --breaker-after found an upstream back, reuse cached answers only while 5 seconds old and cut --cache-header max-ages to 5 seconds, so clients that got the fallback see the real listing again soon (0, the default, leaves caching alone)",
+        "SECS",
+    );
+    opts.optopt(
+        "",
+        "recovery-webhook",
+        "POST {\"event\": \"recovered\", \"upstream\": URL} to this http:// URL when --breaker-after finds an upstream back, e.g. to have clients refresh",
+        "URL",
+    );
+    opts.optmulti(
+        "",
+        "fallback-upstream",
+        "A mirror of the upstream with the same share: requests that can't reach one of the two (refused connection or --timeout) are sent to the other before the fallback answers, writes only with --replay-buffer, and whichever answered is used first from then on, checking every 5 seconds whether the upstream is back; UPSTREAM= sets it for one upstream folder (repeatable)",
+        "[UPSTREAM=]HOST:PORT",
+    );
+    opts.optopt(
+        "",
+        "drain-timeout",
+        "On SIGTERM/SIGINT (Ctrl-C), stop accepting connections and give the requests in flight up to SECS to finish before exiting; by default they all get to finish, and a second signal exits at once",
+        "SECS",
+    );
+    opts.optopt(
+        "",
+        "transfer-timeout",
+        "Abort requests whose upload and download together take longer than SECS, however steadily they progress (0, the default, allows any length)",
+        "SECS",
+    );
+    opts.optopt(
+        "",
+        "track-transfers",
+        "Log the progress of uploads and downloads larger than SIZE (e.g. 100M) every 10 seconds, and list them on /admin/transfers, where POST /admin/transfers/cancel?id=N aborts one",
+        "SIZE",
+    );
+    opts.optopt(
+        "",
+        "log-rewrites",
+        "Log the Destination, Location and href URLs the base path rewriting changed (changed), or also those it left alone and why (all), as original -> rewritten lines per request; off by default",
+        "LEVEL",
+    );
+    opts.optopt(
+        "",
+        "log-rewrites-max",
+        "Most URLs --log-rewrites logs per request, defaulting to 20",
+        "N",
+    );
+    opts.optopt(
+        "",
+        "access-log",
+        "Log every request with client, method, path, status, whether the upstream or the proxy answered, bytes in and out and duration, as a text line or json: fields of their own, which --log-format json prints as a JSON object",
+        "FORMAT",
+    );
+    opts.optopt(
+        "",
+        "log-level",
+        "Print messages of LEVEL and more severe: error, warn, info (the default, which includes the access log and the startup banner), debug (adds every exchange with the upstream) or trace (adds the HTTP library's messages); warnings and errors go to stderr. Without it RUST_LOG is used if set, e.g. RUST_LOG=warn,access=info",
+        "LEVEL",
+    );
+    opts.optopt(
+        "",
+        "log-format",
+        "Print messages as text lines, or as json objects with the time, level, target and each field, one per line for log shippers; defaults to json with --access-log json and text otherwise",
+        "FORMAT",
+    );
+    opts.optmulti(
+        "",
+        "cache-header",
+        "Send Cache-Control: DIRECTIVES (and a matching Expires) with successful GET answers for paths matching GLOB, replacing the upstream's, e.g. '/media/*=public, max-age=86400' or '/inbox/*=no-store'; * matches anything including slashes, the first matching rule applies (repeatable)",
+        "GLOB=DIRECTIVES",
+    );
+    opts.optmulti(
+        "",
+        "request-header",
+        "Edit the headers of requests to the upstream whose client path matches GLOB (and method, if METHODS is given): 'add NAME: VALUE', 'set NAME: VALUE', 'remove NAME' or 'replace NAME: FROM => TO', e.g. '* remove Cookie' or 'PUT,MKCOL /inbox/* set X-Source: proxy'; rules apply in order (repeatable)",
+        "'[METHODS] GLOB ACTION'",
+    );
+    opts.optmulti(
+        "",
+        "response-header",
+        "Edit the headers of the upstream's answers the same way before they go to the client, e.g. '* replace Location: http://nas:8080/ => /' or 'GET /media/* remove Expires' (repeatable)",
+        "'[METHODS] GLOB ACTION'",
+    );
+    opts.optopt(
+        "",
+        "candidate-config",
+        "A config file with settings to try on some requests before they replace the running ones: upstream, upstream-names, rewrite, request-header, response-header and cache-header; what it leaves out stays as configured, and its upstreams take --timeout, --retries, --retry-backoff and --fallback-default from the running configuration. /admin/rollout compares the requests and server errors of both",
+        "FILE",
+    );
+    opts.optopt(
+        "",
+        "candidate-percent",
+        "Percentage of requests that get the --candidate-config settings, defaulting to 0; POST /admin/rollout?percent=N changes it while running",
+        "N",
+    );
+    opts.optopt(
+        "",
+        "candidate-header",
+        "Requests carrying this header get the --candidate-config settings whatever --candidate-percent says, e.g. X-Proxy-Candidate; the header isn't passed on",
+        "NAME",
+    );
+    opts.optflag(
+        "",
+        "normalize-dates",
+        "Rewrite the upstream's Date and Last-Modified headers and getlastmodified properties as IMF-fixdate, and creationdate properties as RFC 3339 in UTC",
+    );
+    opts.optopt(
+        "",
+        "max-future-date",
+        "Set timestamps from the upstream that lie more than SECS ahead of the proxy's clock to the proxy's clock, for upstreams with a clock that runs fast",
+        "SECS",
+    );
+    opts.optopt(
+        "",
+        "buffer-responses",
+        "Read upstream responses of up to SIZE (e.g. 64K) whole before sending them, so they go out with a Content-Length; larger or unknown-length ones past SIZE stream as they arrive (0, the default, streams everything)",
+        "SIZE",
+    );
+    opts.optopt(
+        "",
+        "dedup-window",
+        "Answer a PUT identical to one that succeeded within SECS from its result instead of uploading it again, and report a DELETE or MKCOL repeated within SECS as successful",
+        "SECS",
+    );
+    opts.optopt(
+        "",
+        "cache-ttl",
+        "Answer a GET or PROPFIND identical to one the upstream answered successfully within SECS (same path, Depth, body and credentials) without asking it again; writes drop what is kept for their path, its members and its folder (0, the default, disables the cache)",
+        "SECS",
+    );
+    opts.optopt(
+        "",
+        "max-header-size",
+        "Answer 431 to requests whose headers add up to more than BYTES",
+        "BYTES",
+    );
+    opts.optopt(
+        "",
+        "max-headers",
+        "Answer 431 to requests with more than COUNT headers (hyper never accepts more than 100)",
+        "COUNT",
+    );
+    opts.optopt(
+        "",
+        "geoip-db",
+        "MaxMind database (e.g. GeoLite2-Country.mmdb) to look up client countries in; they are passed upstream as X-Client-Country and counted in the statistics (requires the geoip feature)",
+        "FILE",
+    );
+    opts.optopt(
+        "",
+        "geoip-allow",
+        "Only allow clients from these countries, with -- for addresses not in the database",
+        "CC,CC,...",
+    );
+    opts.optopt(
+        "",
+        "geoip-deny",
+        "Refuse clients from these countries",
+        "CC,CC,...",
+    );
+    opts.optflag(
+        "",
+        "compress",
+        "Gzip text, XML and JSON answers (PROPFIND listings above all) for clients that send Accept-Encoding: gzip, and unpack gzipped upstream answers for clients that don't; such answers go out chunked with a weak ETag (requires the gzip feature)",
+    );
+    opts.optopt(
+        "",
+        "legacy-paths",
+        "Handling of request paths that aren't UTF-8: pass (default), reject with 400, or transcode from latin1 or cp1252",
+        "MODE",
+    );
+    opts.optmulti(
+        "",
+        "vhost",
+        "Host name accepted in absolute-form request targets, defaulting to whatever the Host header says (repeatable)",
+        "HOST[:PORT]",
+    );
+    opts.optmulti(
+        "",
+        "snapshot-at",
+        "Daily local time to snapshot the upstream: hold writes, let in-flight ones finish, run the hook, resume (repeatable)",
+        "HH:MM",
+    );
+    opts.optopt(
+        "",
+        "snapshot-cmd",
+        "Command to run while writes are held back, e.g. to snapshot the NAS",
+        "COMMAND",
+    );
+    opts.optopt(
+        "",
+        "snapshot-webhook",
+        "URL to POST to while writes are held back",
+        "URL",
+    );
+    opts.optopt(
+        "",
+        "snapshot-max-wait",
+        "Seconds writes may be held back during a snapshot, defaulting to 300",
+        "SECS",
+    );
+    opts.optopt(
+        "",
+        "pam-service",
+        "Authenticate Basic credentials against this PAM service (requires the pam feature)",
+        "SERVICE",
+    );
+
+    let matches = match opts.parse(&args[1..]) {
+        Ok(opts) => opts,
+        Err(e) => {
+            eprintln!("{}", e);
+            print_usage(&program, opts);
+            std::process::exit(-1);
+        }
+    };
+    if matches.opt_present("version") {
+        println!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+        println!("features: {}", features::list());
+        return;
+    }
+    let matches = with_config(&opts, &args[1..], matches).unwrap_or_else(|e| fail(e));
+    let log_level = matches.opt_str("log-level").map(|s| logging::parse_level(&s).unwrap_or_else(|e| fail(e)));
+    // JSON access lines come with the other messages as JSON too
+    let log_format = match matches.opt_str("log-format") {
+        Some(format) => logging::Format::parse(&format).unwrap_or_else(|e| fail(e)),
+        None if matches.opt_str("access-log").is_some_and(|format| format.trim() == "json") => logging::Format::Json,
+        None => logging::Format::Text,
+    };
+    logging::init(log_level, log_format);
+    let sentry = matches.opt_str("sentry-dsn").map(|dsn| {
+        Sentry::parse(&dsn).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            std::process::exit(-1);
+        })
+    });
+    let error_log_size = parsed(&matches, "error-log-size").unwrap_or(100);
+    let error_log = ErrorLog::new(error_log_size, sentry).install();
+
+    let signals = Signals::install().unwrap_or_else(|e| {
+        eprintln!("Failed to install signal handlers: {}", e);
+        std::process::exit(-1);
+    });
+
+    #[cfg(feature = "test-upstream")]
+    let test_upstream = if matches.opt_present("builtin-test-upstream") {
+        if !matches.free.is_empty() {
+            eprintln!("--builtin-test-upstream takes the place of REMOTE");
+            std::process::exit(-1);
+        }
+        Some(TestUpstream::start_new().unwrap_or_else(|e| {
+            eprintln!("Failed to start the test upstream: {}", e);
+            std::process::exit(-1);
+        }))
+    } else {
+        None
+    };
+    #[cfg(feature = "test-upstream")]
+    let remotes = match &test_upstream {
+        Some(test) => vec![test.addr().to_string()],
+        None => matches.free.clone(),
+    };
+    #[cfg(not(feature = "test-upstream"))]
+    if matches.opt_present("builtin-test-upstream") {
+        features::missing("--builtin-test-upstream", "test-upstream");
+    }
+    #[cfg(not(feature = "test-upstream"))]
+    let remotes = matches.free.clone();
+    let verify = match matches.opt_str("verify") {
+        Some(path) => {
+            if matches.opt_present("builtin-test-upstream") {
+                fail("--verify and --builtin-test-upstream can't be used together".to_string());
+            }
+            if matches.opt_present("self-test") {
+                fail("--verify and --self-test can't be used together".to_string());
+            }
+            let text = std::fs::read_to_string(&path).unwrap_or_else(|e| fail(format!("Failed to read {}: {}", path, e)));
+            let fixtures = Fixture::parse_all(&text).unwrap_or_else(|e| fail(format!("{}: {}", path, e)));
+            let mock = Mock::start()
+                .await
+                .unwrap_or_else(|e| fail(format!("Failed to start the stand-in upstream: {}", e)));
+            Some((fixtures, mock))
+        }
+        None => None,
+    };
+    let remotes = match &verify {
+        Some((_, mock)) if remotes.is_empty() => vec![mock.remote()],
+        Some((_, mock)) => remotes.iter().map(|remote| mock.stand_in(remote).unwrap_or_else(|e| fail(e))).collect(),
+        None => remotes,
+    };
+    if remotes.is_empty() {
+        print_usage(&program, opts);
+        std::process::exit(-1);
+    }
+
+    let local_port: u16 = parsed(&matches, "local-port").unwrap_or(0);
+    let bind = matches.opt_str("b");
+    // `unix:/PATH` listens on a Unix socket instead of a port
+    let socket_path = bind.as_deref().and_then(|b| b.strip_prefix("unix:")).map(PathBuf::from);
+    let bind_addr = match bind.filter(|_| socket_path.is_none()) {
+        Some(addr) => value::<std::net::IpAddr>("bind", &addr),
+        None => std::net::IpAddr::from([127, 0, 0, 1]),
+    };
+    if socket_path.is_some() {
+        if !cfg!(unix) {
+            fail("--bind unix:PATH needs a Unix system".to_string());
+        }
+        if matches.opt_present("self-test") {
+            fail("--self-test needs a TCP port to send its requests to, not a Unix socket".to_string());
+        }
+    }
+
+    #[cfg(not(feature = "tls"))]
+    if matches.opt_present("tls-cert") || matches.opt_present("tls-key") {
+        features::missing("--tls-cert", "tls");
+    }
+    #[cfg(feature = "tls")]
+    let tls = match (matches.opt_str("tls-cert"), matches.opt_str("tls-key")) {
+        (Some(cert), Some(key)) => {
+            let fail = |e: String| -> ! {
+                eprintln!("{}", e);
+                std::process::exit(-1);
+            };
+            let warn_days: i32 = parsed(&matches, "tls-expiry-warn").unwrap_or(30);
+            let watch = Arc::new(CertWatch::load(&cert, warn_days, !matches.opt_present("no-ocsp-stapling")).unwrap_or_else(|e| fail(e)));
+            let version = |name: &str| matches.opt_str(name).map(|v| tls::parse_version(&v).unwrap_or_else(|e| fail(e)));
+            let options = TlsOptions {
+                cert,
+                key,
+                preset: Preset::parse(&matches.opt_str("tls-preset").unwrap_or("intermediate".to_string()))
+                    .unwrap_or_else(|e| fail(e)),
+                min_version: version("tls-min-version"),
+                max_version: version("tls-max-version"),
+                ciphers: matches.opt_str("tls-ciphers"),
+                ciphersuites: matches.opt_str("tls-ciphersuites"),
+                alpn: matches
+                    .opt_str("tls-alpn")
+                    .unwrap_or("h2,http/1.1".to_string())
+                    .split(',')
+                    .map(|p| p.trim().to_string())
+                    .filter(|p| !p.is_empty())
+                    .collect(),
+                watch: Some(watch.clone()),
+                ticket_rotation: Duration::from_secs(parsed(&matches, "tls-ticket-rotation").unwrap_or(12 * 3600)),
+            };
+            Some((Arc::new(tls::acceptor(&options).unwrap_or_else(|e| fail(e))), watch))
+        }
+        (None, None) => None,
+        _ => {
+            eprintln!("--tls-cert and --tls-key go together");
+            std::process::exit(-1);
+        }
+    };
+    #[cfg(not(feature = "http3"))]
+    if matches.opt_present("http3-port") {
+        features::missing("--http3-port", "http3");
+    }
+    #[cfg(feature = "http3")]
+    let quic = match (parsed::<u16>(&matches, "http3-port"), matches.opt_str("tls-cert"), matches.opt_str("tls-key")) {
+        (None, _, _) => None,
+        (Some(_), _, _) if socket_path.is_some() => fail("--http3-port needs --bind to an address, not a Unix socket".to_string()),
+        (Some(port), Some(cert), Some(key)) => Some((port, http3::server_config(&cert, &key).unwrap_or_else(|e| fail(e)))),
+        _ => fail("--http3-port needs --tls-cert and --tls-key".to_string()),
+    };
+    #[cfg(feature = "tls")]
+    let scheme = if tls.is_some() { "https" } else { "http" };
+    #[cfg(not(feature = "tls"))]
+    let scheme = "http";
+
+    let cookie_policy = match CookiePolicy::parse(
+        &matches.opt_str("cookies").unwrap_or("pass".to_string()),
+        matches.opt_str("cookie-domain"),
+        matches.opt_str("cookie-path"),
+    ) {
+        Ok(policy) => policy,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(-1);
+        }
+    };
+
+    let mut auth = None;
+    let mut negotiate_passthrough = false;
+    match matches.opt_str("negotiate").as_deref() {
+        None => {}
+        Some("passthrough") => negotiate_passthrough = true,
+        Some("keytab") => match KeytabAcceptor::load(matches.opt_str("keytab").as_deref()) {
+            Ok(acceptor) => auth = Some(Authenticator::Negotiate(acceptor)),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(-1);
+            }
+        },
+        Some(mode) => {
+            eprintln!("Unknown negotiate mode: {} (expected passthrough or keytab)", mode);
+            std::process::exit(-1);
+        }
+    }
+
+    if let Some(path) = matches.opt_str("htpasswd") {
+        if auth.is_some() {
+            eprintln!("--htpasswd cannot be combined with --negotiate keytab");
+            std::process::exit(-1);
+        }
+        match Htpasswd::load(&path) {
+            Ok(htpasswd) => {
+                let htpasswd = Arc::new(htpasswd);
+                htpasswd.clone().spawn_watcher(&signals);
+                auth = Some(Authenticator::Htpasswd(htpasswd));
+            }
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(-1);
+            }
+        }
+    }
+
+    #[cfg(not(feature = "ldap"))]
+    if matches.opt_present("ldap-url") {
+        features::missing("--ldap-url", "ldap");
+    }
+    #[cfg(feature = "ldap")]
+    if let Some(url) = matches.opt_str("ldap-url") {
+        if auth.is_some() {
+            eprintln!("--ldap-url cannot be combined with another authentication backend");
+            std::process::exit(-1);
+        }
+        let user_dn = matches.opt_str("ldap-user-dn").unwrap_or_else(|| {
+            eprintln!("--ldap-url requires --ldap-user-dn");
+            std::process::exit(-1);
+        });
+        let mut groups = Vec::new();
+        for mapping in matches.opt_strs("ldap-group") {
+            match mapping.split_once('=') {
+                Some((prefix, dn)) => groups.push((prefix.to_string(), dn.to_string())),
+                None => {
+                    eprintln!("Invalid --ldap-group (expected PREFIX=GROUP_DN): {}", mapping);
+                    std::process::exit(-1);
+                }
+            }
+        }
+        let ttl: u64 = parsed(&matches, "ldap-cache-ttl").unwrap_or(60);
+        match LdapAuth::new(
+            &url,
+            user_dn,
+            groups,
+            matches.opt_str("ldap-group-attr").unwrap_or("member".to_string()),
+            Duration::from_secs(ttl),
+        ) {
+            Ok(ldap) => auth = Some(Authenticator::Ldap(ldap)),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(-1);
+            }
+        }
+    }
+
+    if let Some(service) = matches.opt_str("pam-service") {
+        if auth.is_some() {
+            eprintln!("--pam-service cannot be combined with another authentication backend");
+            std::process::exit(-1);
+        }
+        #[cfg(feature = "pam")]
+        {
+            auth = Some(Authenticator::Pam(crate::auth::pam::PamAuth::new(service)));
+        }
+        #[cfg(not(feature = "pam"))]
+        features::missing(&format!("PAM service {}", service), "pam");
+    }
+
+    let minutes = |name: &str, default: u64| Duration::from_secs(60 * parsed(&matches, name).unwrap_or(default));
+    let auth_cache = parsed::<u64>(&matches, "auth-cache-ttl")
+        .filter(|&secs| secs > 0)
+        .map(|secs| Arc::new(DecisionCache::new(Duration::from_secs(secs))));
+    if let Some(cache) = &auth_cache {
+        if auth.is_none() {
+            eprintln!("--auth-cache-ttl needs an authentication backend");
+            std::process::exit(-1);
+        }
+        let cache = cache.clone();
+        signals.on_reload(move || cache.clear());
+    }
+    let lockout = matches.opt_str("lockout-after").map(|n| {
+        let n: usize = value("lockout-after", &n);
+        Lockout::new(n.max(1), minutes("lockout-window", 10), minutes("lockout-time", 15))
+    });
+    if lockout.is_some() && auth.is_none() {
+        eprintln!("--lockout-after needs an authentication backend");
+        std::process::exit(-1);
+    }
+
+    let stats = Arc::new(Stats::default());
+    let usage_paths = matches.opt_strs("usage-path");
+    let usage = (!usage_paths.is_empty()).then(|| Usage::new(&usage_paths).unwrap_or_else(|e| fail(e)));
+    if let Some(path) = matches.opt_str("stats-file") {
+        stats.load(&path);
+        stats.clone().spawn_persister(path, Duration::from_secs(60));
+    }
+
+    let status = matches.opt_str("status-path").map(|prefix| {
+        StatusPage::new(&prefix).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            std::process::exit(-1);
+        })
+    });
+
+    let (upload_limiter, download_limiter) = match matches.opt_str("bandwidth-schedule") {
+        Some(spec) => match Schedule::parse(&spec, 0) {
+            Ok(schedule) => {
+                let up = Arc::new(Limiter::new(0));
+                let down = Arc::new(Limiter::new(0));
+                schedule.spawn(vec![up.clone(), down.clone()]);
+                (Some(up), Some(down))
+            }
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(-1);
+            }
+        },
+        None => (None, None),
+    };
+    let max_rate = |option: &str| {
+        matches.opt_str(option).map_or(0, |s| {
+            throttle::parse_rate(&s).unwrap_or_else(|e| {
+                eprintln!("Invalid --{}: {}", option, e);
+                std::process::exit(-1);
+            })
+        })
+    };
+    let (max_upload_rate, max_download_rate) = (max_rate("max-upload-rate"), max_rate("max-download-rate"));
+    let per_connection = match matches.opt_str("rate-limit-scope").as_deref() {
+        None | Some("global") => false,
+        Some("connection") => true,
+        Some(other) => {
+            eprintln!("Unknown --rate-limit-scope (expected global or connection): {}", other);
+            std::process::exit(-1);
+        }
+    };
+    let rate_caps =
+        (max_upload_rate > 0 || max_download_rate > 0).then(|| RateCaps::new(max_upload_rate, max_download_rate, per_connection));
+
+    let gate = matches.opt_str("max-concurrent").map(|max| {
+        let max: usize = value("max-concurrent", &max);
+        let reserve: usize = parsed(&matches, "metadata-reserve").unwrap_or(2);
+        let max_wait = parsed(&matches, "max-queue-wait").map(Duration::from_secs_f64);
+        PriorityGate::new(max, reserve, max_wait)
+    });
+
+    let client_rate = parsed::<f64>(&matches, "client-rate")
+        .filter(|rate| *rate > 0.0);
+    let client_max_concurrent = parsed::<usize>(&matches, "client-max-concurrent")
+        .filter(|max| *max > 0);
+    let client_limits = (client_rate.is_some() || client_max_concurrent.is_some()).then(|| {
+        let burst: f64 = parsed(&matches, "client-burst").unwrap_or(20.0);
+        ClientLimits::new(client_rate, burst.max(1.0), client_max_concurrent, matches.opt_present("peer"))
+    });
+
+    let peers = match matches.opt_strs("peer") {
+        specs if specs.is_empty() => None,
+        _ if client_rate.is_none() || !matches.opt_present("admin-bind") => {
+            fail("--peer shares --client-rate through the admin API, so it needs --client-rate and --admin-bind".to_string())
+        }
+        specs => Some(Peers::new(&specs, matches.opt_str("admin-token")).unwrap_or_else(|e| fail(e))),
+    };
+
+    let mut tenants = Tenants::default();
+    for spec in matches.opt_strs("tenant") {
+        tenants.add(&spec).unwrap_or_else(|e| fail(e));
+    }
+
+    let cors_origins = matches.opt_strs("cors-origin");
+    let cors = (!cors_origins.is_empty()).then(|| {
+        let max_age: u64 = parsed(&matches, "cors-max-age").unwrap_or(600);
+        Cors::new(&cors_origins, matches.opt_present("cors-credentials"), max_age).unwrap_or_else(|e| fail(e))
+    });
+
+    let snapshot_times: Vec<u32> = matches
+        .opt_strs("snapshot-at")
+        .iter()
+        .map(|t| {
+            clock::parse_clock(t).unwrap_or_else(|e| {
+                eprintln!("{}", e);
+                std::process::exit(-1);
+            })
+        })
+        .collect();
+    let snapshot_command = matches.opt_str("snapshot-cmd");
+    let snapshot_webhook = matches.opt_str("snapshot-webhook");
+    let snapshot = if snapshot_command.is_some() || snapshot_webhook.is_some() {
+        let max_wait: u64 = parsed(&matches, "snapshot-max-wait").unwrap_or(300);
+        Some(Arc::new(SnapshotHook {
+            times: snapshot_times,
+            command: snapshot_command,
+            webhook: snapshot_webhook,
+            max_wait: Duration::from_secs(max_wait),
+        }))
+    } else {
+        if !snapshot_times.is_empty() {
+            eprintln!("--snapshot-at needs --snapshot-cmd or --snapshot-webhook");
+            std::process::exit(-1);
+        }
+        None
+    };
+
+    let emulate_locks = Emulation::parse(&matches.opt_str("emulate-locks").unwrap_or("auto".to_string()))
+        .unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            std::process::exit(-1);
+        });
+    let log_rewrites = rewrite_log::Level::parse(&matches.opt_str("log-rewrites").unwrap_or("off".to_string()))
+        .unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            std::process::exit(-1);
+        });
+    let log_rewrites_max: usize = parsed(&matches, "log-rewrites-max").unwrap_or(20);
+    let access_log = matches.opt_str("access-log").map(|format| {
+        access_log::Format::parse(&format).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            std::process::exit(-1);
+        })
+    });
+    let mut fallback = FallbackRoutes::default();
+    for mapping in matches.opt_strs("fallback") {
+        if let Err(e) = fallback.add(&mapping) {
+            eprintln!("{}", e);
+            std::process::exit(-1);
+        }
+    }
+    let mut depth_guard = DepthGuard::default();
+    for spec in matches.opt_strs("infinite-depth") {
+        depth_guard.add(&spec).unwrap_or_else(|e| fail(e));
+    }
+    let strict = matches
+        .opt_str("strict")
+        .map_or(Ok(Strict::default()), |spec| Strict::parse(&spec))
+        .unwrap_or_else(|e| fail(e));
+    let fallback_dir = matches.opt_str("fallback-dir").map(PathBuf::from);
+    if let Some(dir) = fallback_dir.as_ref().filter(|dir| !dir.is_dir()) {
+        eprintln!("--fallback-dir {} is not a directory", dir.display());
+        std::process::exit(-1);
+    }
+    let mut fallback_names = EntryNames::default();
+    for mapping in matches.opt_strs("fallback-name") {
+        if let Err(e) = fallback_names.add(&mapping) {
+            eprintln!("{}", e);
+            std::process::exit(-1);
+        }
+    }
+    let mut fallback_files = FallbackFiles::default();
+    for mapping in matches.opt_strs("fallback-file") {
+        fallback_files.add(&mapping).unwrap_or_else(|e| fail(e));
+    }
+    let buffer_responses = matches.opt_str("buffer-responses").map_or(0, |s| {
+        throttle::parse_rate(&s).unwrap_or_else(|_| {
+            eprintln!("Invalid --buffer-responses (expected a size like 64K): {}", s);
+            std::process::exit(-1);
+        })
+    }) as usize;
+    let dates = DatePolicy {
+        normalize: matches.opt_present("normalize-dates"),
+        max_future: parsed(&matches, "max-future-date"),
+    };
+    let replay_buffer = matches.opt_str("replay-buffer").map_or(0, |s| {
+        throttle::parse_rate(&s).unwrap_or_else(|_| {
+            eprintln!("Invalid --replay-buffer (expected a size like 64K): {}", s);
+            std::process::exit(-1);
+        })
+    }) as usize;
+
+    let dedup = parsed::<u64>(&matches, "dedup-window")
+        .filter(|secs| *secs > 0)
+        .map(|secs| Arc::new(Dedup::new(Duration::from_secs(secs))));
+    // Connection-bound auth schemes need their upstream connection pinned
+    let pin_connections =
+        matches.opt_present("pin-connections") || matches.opt_present("sharepoint") || negotiate_passthrough;
+    let response_cache = parsed::<u64>(&matches, "cache-ttl")
+        .filter(|secs| *secs > 0)
+        .map(|secs| Arc::new(ResponseCache::new(Duration::from_secs(secs))));
+    // Requests after a connection-bound login carry no credentials to key on
+    if response_cache.is_some() && pin_connections {
+        eprintln!("--cache-ttl can't be combined with pinned connections (--pin-connections, --sharepoint, --negotiate passthrough)");
+        std::process::exit(-1);
+    }
+
+    let snapshots = matches.opt_str("snapshot-view").map(|pattern| {
+        SnapshotView::parse(&pattern).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            std::process::exit(-1);
+        })
+    });
+    let networks = |option: &str| {
+        matches
+            .opt_strs(option)
+            .iter()
+            .map(|network| Network::parse(network))
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap_or_else(|e| {
+                eprintln!("{}", e);
+                std::process::exit(-1);
+            })
+    };
+    let access = AccessList {
+        allow: networks("allow"),
+        deny: networks("deny"),
+    };
+    let trusted = networks("trusted-proxy");
+    let forwarding = (!matches.opt_present("no-forwarded-headers"))
+        .then(|| Forwarding::new(scheme == "https", matches.opt_present("strip-forwarded"), trusted));
+
+    let virtual_files = matches
+        .opt_strs("virtual-file")
+        .iter()
+        .map(|spec| VirtualFile::from_file(spec))
+        .chain(matches.opt_strs("virtual-text").iter().map(|spec| VirtualFile::from_text(spec)))
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            std::process::exit(-1);
+        });
+    let mut names = matches.opt_strs("virtual-stats");
+    for file in &virtual_files {
+        if names.contains(&file.name) {
+            eprintln!("Two virtual files at the share root are named {}", file.name);
+            std::process::exit(-1);
+        }
+        names.push(file.name.clone());
+    }
+
+    let transfers = matches.opt_str("track-transfers").map(|s| {
+        Transfers::new(throttle::parse_rate(&s).unwrap_or_else(|_| {
+            eprintln!("Invalid --track-transfers (expected a size like 100M): {}", s);
+            std::process::exit(-1);
+        }))
+    });
+
+    let memory_cap = matches.opt_str("memory-cap").map_or(0, |s| {
+        throttle::parse_rate(&s).unwrap_or_else(|_| {
+            eprintln!("Invalid --memory-cap (expected a size like 64M): {}", s);
+            std::process::exit(-1);
+        })
+    });
+    let memory = MemoryBudget::new(memory_cap as usize);
+    if let Some(dedup) = &dedup {
+        memory.register(dedup.clone());
+    }
+    if let Some(cache) = &response_cache {
+        memory.register(cache.clone());
+    }
+
+    let header_limits = HeaderLimits {
+        max_size: parsed(&matches, "max-header-size"),
+        max_count: parsed(&matches, "max-headers"),
+    };
+
+    #[cfg(not(feature = "gzip"))]
+    if matches.opt_present("compress") {
+        features::missing("--compress", "gzip");
+    }
+    #[cfg(not(feature = "geoip"))]
+    if matches.opt_present("geoip-db") {
+        features::missing("--geoip-db", "geoip");
+    }
+    #[cfg(feature = "geoip")]
+    let geo = match matches.opt_str("geoip-db") {
+        Some(path) => {
+            let db = GeoIp::load(&path).unwrap_or_else(|e| {
+                eprintln!("{}", e);
+                std::process::exit(-1);
+            });
+            Some(GeoPolicy::new(
+                db,
+                matches.opt_str("geoip-allow").as_deref(),
+                matches.opt_str("geoip-deny").as_deref(),
+            ))
+        }
+        None if matches.opt_present("geoip-allow") || matches.opt_present("geoip-deny") => {
+            eprintln!("--geoip-allow and --geoip-deny need --geoip-db");
+            std::process::exit(-1);
+        }
+        None => None,
+    };
+
+    let legacy_paths = matches
+        .opt_str("legacy-paths")
+        .map(|s| LegacyPaths::parse(&s))
+        .unwrap_or(Ok(LegacyPaths::Pass))
+        .unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            std::process::exit(-1);
+        });
+
+    let mut vhosts = VirtualHosts::default();
+    for name in matches.opt_strs("vhost") {
+        vhosts.add(&name);
+    }
+
+    let resolver = Resolver::new(&matches.opt_strs("resolve")).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(-1);
+    });
+    #[cfg(feature = "tls")]
+    let resolver = resolver
+        .with_tls(&matches.opt_strs("upstream-ca"), matches.opt_present("insecure"))
+        .unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            std::process::exit(-1);
+        });
+    #[cfg(not(feature = "tls"))]
+    if matches.opt_present("upstream-ca") || matches.opt_present("insecure") {
+        features::missing("--upstream-ca and --insecure", "tls");
+    }
+    let resolver = match matches.opt_str("outbound-proxy") {
+        Some(spec) => resolver.with_outbound_proxy(&spec).unwrap_or_else(|e| fail(e)),
+        None => resolver,
+    };
+
+    // One pooled client for every request, so PROPFINDs reuse connections
+    let mut client = Client::builder();
+    if let Some(secs) = parsed::<u64>(&matches, "pool-idle-timeout") {
+        client.pool_idle_timeout((secs > 0).then(|| Duration::from_secs(secs)));
+    }
+    if let Some(n) = parsed(&matches, "pool-max-idle") {
+        client.pool_max_idle_per_host(n);
+    }
+    let client = client.build(resolver.connector());
+    let breaker_after: u32 = parsed(&matches, "breaker-after").unwrap_or(0);
+    let settings = settings(&remotes, &[], &matches, &resolver, &client, pin_connections).unwrap_or_else(|e| fail(e));
+
+    let rollout = matches.opt_str("candidate-config").map(|path| {
+        let mut candidate = candidate(&path, &opts, &matches).unwrap_or_else(|e| fail(e));
+        if let Some(upstreams) = &mut candidate.upstreams {
+            // Pinned connections are opened per upstream of the running configuration
+            if pin_connections {
+                fail("A --candidate-config with upstreams can't be combined with pinned connections (--pin-connections, --sharepoint, --negotiate passthrough)".to_string());
+            }
+            if breaker_after > 0 {
+                let recovery = recovery(&matches).unwrap_or_else(|e| fail(e));
+                upstreams.add_breakers(breaker_after, &recovery, &client);
+            }
+        }
+        let percent = parsed(&matches, "candidate-percent").unwrap_or(0);
+        Rollout::new(candidate, percent, matches.opt_str("candidate-header").as_deref()).unwrap_or_else(|e| fail(e))
+    });
+    if rollout.is_none() && (matches.opt_present("candidate-percent") || matches.opt_present("candidate-header")) {
+        fail("--candidate-percent and --candidate-header need --candidate-config".to_string());
+    }
+
+    // A reload, or a switch, reads the same command line and --config file again
+    let source: Source = {
+        let args = args[1..].to_vec();
+        let (resolver, client) = (resolver.clone(), client.clone());
+        let pinned_to = settings.upstreams.pinned_to();
+        // Remotes the proxy stands in for itself (--builtin-test-upstream) stay
+        let fixed = (remotes != matches.free).then(|| remotes.clone());
+        Box::new(move |switched| {
+            let matches = opts.parse(&args).map_err(|e| e.to_string())?;
+            let matches = with_config(&opts, &args, matches)?;
+            let remotes = fixed.clone().unwrap_or_else(|| matches.free.clone());
+            if remotes.is_empty() {
+                return Err("No upstream is left".to_string());
+            }
+            let settings = self::settings(&remotes, switched, &matches, &resolver, &client, pin_connections)?;
+            // Pinned connections are opened per upstream, in order, and stay
+            // with the server they were opened to
+            if pin_connections && settings.upstreams.pinned_to() != pinned_to {
+                return Err("The upstreams can't change with pinned connections (--pin-connections, --sharepoint, --negotiate passthrough)".to_string());
+            }
+            Ok(settings)
+        })
+    };
+    let config = Arc::new(ProxyConfig {
+        settings: Reloadable::new(settings, Some(source)),
+        cookie_policy,
+        sharepoint: matches.opt_present("sharepoint"),
+        emulate_locks,
+        read_only: matches.opt_present("read-only"),
+        depth_guard,
+        strict,
+        pin_connections,
+        auth,
+        auth_cache,
+        lockout,
+        guests: Guests::default(),
+        client,
+        resolver,
+        stats,
+        usage,
+        errors: error_log,
+        stats_file_name: matches.opt_str("virtual-stats"),
+        status,
+        virtual_files,
+        snapshots,
+        forwarding,
+        upload_limiter,
+        download_limiter,
+        rate_caps,
+        gate,
+        client_limits,
+        tenants,
+        cors,
+        pause: PauseControl::default(),
+        routes: RouteSwitch::load(matches.opt_str("routes-state")),
+        snapshot,
+        fallback,
+        fallback_dir,
+        fallback_names,
+        fallback_files,
+        dedup,
+        response_cache,
+        memory,
+        transfers,
+        in_flight: matches.opt_present("admin-bind").then(|| Arc::new(InFlight::default())),
+        buffer_responses,
+        replay_buffer,
+        header_limits,
+        #[cfg(feature = "geoip")]
+        geo,
+        #[cfg(feature = "gzip")]
+        compress: matches.opt_present("compress"),
+        #[cfg(feature = "http3")]
+        alt_svc: quic.as_ref().map(|(port, _)| {
+            hyper::header::HeaderValue::from_str(&format!("h3=\":{}\"; ma=86400", port)).expect("valid header")
+        }),
+        legacy_paths,
+        vhosts,
+        api_routes: ApiRoutes::new(matches.opt_strs("api-route")),
+        dates,
+        rollout,
+        log_rewrites,
+        log_rewrites_max,
+        access_log,
+        hooks: Hooks::default(),
+        addresses: Addresses {
+            listen: OnceLock::new(),
+            admin: matches.opt_str("admin-bind"),
+        },
+    });
+
+    let admin_addr: Option<SocketAddr> = parsed(&matches, "admin-bind");
+    let drain = parsed::<u64>(&matches, "drain-timeout")
+        .filter(|secs| *secs > 0)
+        .map(Duration::from_secs);
+    if matches.opt_present("check-config") {
+        let listen = match &socket_path {
+            Some(path) => format!("unix:{}", path.display()),
+            None => SocketAddr::new(bind_addr, local_port).to_string(),
+        };
+        println!("Configuration OK: {} upstream(s), would listen on {}", config.settings.current().upstreams.iter().count(), listen);
+        return;
+    }
+
+    if let Some(path) = matches.opt_str("state-file") {
+        // Nothing saved yet otherwise
+        if let Ok(text) = std::fs::read_to_string(&path) {
+            let (applied, problems) = state::import(&config, &text);
+            tracing::info!("Restored {} line(s) of runtime state from {}", applied, path);
+            for problem in problems {
+                tracing::warn!("{}: {}", path, problem);
+            }
+        }
+    }
+
+    if let Some((fixtures, mock)) = verify {
+        let mut failed = 0;
+        for outcome in fixtures::verify(&config, &mock, &fixtures).await {
+            if !outcome.problems.is_empty() {
+                failed += 1;
+            }
+            println!("{} {}", if outcome.problems.is_empty() { "ok  " } else { "FAIL" }, outcome.name);
+            for problem in outcome.problems {
+                println!("       {}", problem.replace('\n', "\n       "));
+            }
+        }
+        println!("{} of {} fixture(s) failed", failed, fixtures.len());
+        std::process::exit(if failed == 0 { 0 } else { 1 });
+    }
+
+    crate::spawn_background(&config);
+    let reloading = config.clone();
+    signals.on_reload(move || {
+        let _ = crate::reload(&reloading);
+    });
+
+    if let Some(admin_addr) = admin_addr {
+        tokio::spawn(admin::serve(admin_addr, config.clone(), matches.opt_str("admin-token")));
+    }
+    if let Some(peers) = peers {
+        tokio::spawn(peers.run(config.clone()));
+    }
+
+    // Define the address and port to listen on
+    let addr = SocketAddr::new(bind_addr, local_port);
+
+    // Create the server
+    let mut listening = addr;
+    let listener = match &socket_path {
+        #[cfg(unix)]
+        Some(path) => Listener::Unix(
+            listener::bind_unix(path).unwrap_or_else(|e| fail(format!("Failed to listen on {}: {}", path.display(), e))),
+        ),
+        _ => {
+            let listener = listener::bind(addr).await.unwrap_or_else(|e| {
+                eprintln!("Failed to listen on {}: {}", addr, e);
+                std::process::exit(-1);
+            });
+            listening = listener.local_addr().unwrap_or(addr);
+            Listener::Tcp(listener)
+        }
+    };
+    #[cfg(feature = "http3")]
+    let quic = quic.map(|(port, config)| {
+        let addr = SocketAddr::new(bind_addr, port);
+        let endpoint = http3::bind(addr, config).unwrap_or_else(|e| fail(e));
+        tracing::info!("Serving HTTP/3 on UDP {}", endpoint.local_addr().unwrap_or(addr));
+        endpoint
+    });
+    #[cfg(feature = "tls")]
+    let tls = tls.map(|(acceptor, watch)| {
+        certwatch::spawn(watch, config.stats.clone());
+        acceptor
+    });
+    let wrap = move |socket: Box<dyn listener::Connection>| -> Box<dyn listener::Connection> {
+        #[cfg(feature = "tls")]
+        if let Some(tls) = &tls {
+            return match legacy_paths {
+                // Raw 8-bit request lines have to be fixed before hyper parses them
+                LegacyPaths::Transcode(charset) => Box::new(RequestRewriter::new(TlsStream::new(tls, socket), charset)),
+                _ => Box::new(TlsStream::new(tls, socket)),
+            };
+        }
+        match legacy_paths {
+            LegacyPaths::Transcode(charset) => Box::new(RequestRewriter::new(socket, charset)),
+            _ => socket,
+        }
+    };
+    #[cfg(feature = "http3")]
+    let quic = quic.map(|endpoint| {
+        let stopping = signals.clone();
+        http3::serve(endpoint, config.clone(), access.clone(), async move { stopping.shutdown().await })
+    });
+    // A second request while draining gives up on what is left
+    let draining = signals.clone();
+    let server = crate::server(config.clone(), listener, access, wrap, async move {
+        draining.shutdown().await;
+        match drain {
+            Some(drain) => tracing::info!(
+                "Shutting down, waiting up to {}s for requests in flight (again to quit now)",
+                drain.as_secs()
+            ),
+            None => tracing::info!("Shutting down, waiting for requests in flight (again to quit now)"),
+        }
+        tokio::spawn(async move {
+            draining.shutdown().await;
+            std::process::exit(1);
+        });
+    });
+
+    // Both listeners drain together
+    #[cfg(feature = "http3")]
+    let server = async move {
+        match quic {
+            Some(quic) => futures::join!(server, quic).0,
+            None => server.await,
+        }
+    };
+
+    let listen = match &socket_path {
+        Some(path) => format!("unix:{}", path.display()),
+        None => format!("{}://{}", scheme, listening),
+    };
+    let _ = config.addresses.listen.set(listen);
+    tracing::info!("{}", info::banner(&config).trim_end());
+
+    if matches.opt_present("self-test") {
+        tokio::spawn(server);
+        let passed = selftest::run(&config, scheme, listening).await;
+        std::process::exit(if passed { 0 } else { 1 });
+    }
+
+    #[cfg(feature = "test-upstream")]
+    if let Some(test) = test_upstream {
+        tokio::spawn(test.control());
+    }
+
+    // Run the server, and after a shutdown request at most the drain period
+    let drained = async {
+        signals.shutdown().await;
+        match drain {
+            Some(drain) => tokio::time::sleep(drain).await,
+            None => std::future::pending().await,
+        }
+    };
+    tokio::select! {
+        result = server => {
+            if let Err(e) = result {
+                errors::report(format!("Server error: {}", e));
+            }
+        }
+        _ = drained => tracing::warn!(
+            "Gave up on the requests still in flight after {}s",
+            drain.expect("only ends with a drain period").as_secs()
+        ),
+    }
+    if let Some(path) = matches.opt_str("stats-file") {
+        if let Err(e) = config.stats.save(&path) {
+            tracing::error!("Failed to save statistics to {}: {}", path, e);
+        }
+    }
+    if let Some(path) = matches.opt_str("state-file") {
+        if let Err(e) = atomic_file::write(&path, state::export(&config)) {
+            tracing::error!("Failed to save runtime state to {}: {}", path, e);
+        }
+    }
+    if let Some(path) = &socket_path {
+        let _ = std::fs::remove_file(path);
+    }
+}
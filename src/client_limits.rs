@@ -0,0 +1,157 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use hyper::{Body, Response, StatusCode};
+
+// Limits per client address, so one misbehaving sync client can't flood the
+// upstream for everyone: a token bucket of `rate` requests per second with
+// room for a burst of `burst`, and at most `max_concurrent` requests at a
+// time, counting a request until its response body is sent. Over either,
+// requests are answered with 429 and a Retry-After. With --peer the rate is
+// shared with other instances, which report what they admitted.
+pub struct ClientLimits {
+    rate: Option<f64>,
+    burst: f64,
+    max_concurrent: Option<usize>,
+    clients: Mutex<HashMap<IpAddr, Client>>,
+    // Requests admitted since the peers were last told, when shared
+    admitted: Option<Mutex<HashMap<IpAddr, u32>>>,
+}
+
+struct Client {
+    tokens: f64,
+    last: Instant,
+    active: usize,
+    // Refused since its last admitted request, so that is logged once
+    limited: bool,
+}
+
+// A request counted against its client's concurrency until dropped
+pub struct Slot {
+    limits: Arc<ClientLimits>,
+    ip: IpAddr,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        if let Some(client) = self.limits.clients.lock().unwrap().get_mut(&self.ip) {
+            client.active -= 1;
+        }
+    }
+}
+
+// Why a request was refused
+pub enum Refusal {
+    Concurrency,
+    // Seconds until the bucket holds a request again
+    Rate(u64),
+}
+
+impl Refusal {
+    // The 429 to answer with
+    pub fn response(self) -> Response<Body> {
+        let (retry_after, message) = match self {
+            Refusal::Concurrency => (1, "Too many concurrent requests from your address"),
+            Refusal::Rate(wait) => (wait, "Too many requests from your address, slow down"),
+        };
+        Response::builder()
+            .status(StatusCode::TOO_MANY_REQUESTS)
+            .header("Retry-After", retry_after)
+            .header("Content-Type", "text/plain")
+            .body(Body::from(format!("{}\n", message)))
+            .expect("response builder")
+    }
+}
+
+impl ClientLimits {
+    pub fn new(rate: Option<f64>, burst: f64, max_concurrent: Option<usize>, shared: bool) -> Arc<Self> {
+        Arc::new(ClientLimits {
+            rate,
+            burst,
+            max_concurrent,
+            clients: Mutex::new(HashMap::new()),
+            admitted: shared.then(|| Mutex::new(HashMap::new())),
+        })
+    }
+
+    fn refill(&self, client: &mut Client, now: Instant) {
+        if let Some(rate) = self.rate {
+            client.tokens = (client.tokens + now.duration_since(client.last).as_secs_f64() * rate).min(self.burst);
+        }
+        client.last = now;
+    }
+
+    // A slot for a request from `ip`, or why it is refused
+    pub fn admit(self: &Arc<Self>, ip: IpAddr) -> Result<Slot, Refusal> {
+        let now = Instant::now();
+        let mut clients = self.clients.lock().unwrap();
+        // Forget clients that are idle and would have a full bucket again
+        clients.retain(|_, client| {
+            client.active > 0
+                || self
+                    .rate
+                    .is_some_and(|rate| client.tokens + now.duration_since(client.last).as_secs_f64() * rate < self.burst)
+        });
+        let client = clients.entry(ip).or_insert(Client {
+            tokens: self.burst,
+            last: now,
+            active: 0,
+            limited: false,
+        });
+        self.refill(client, now);
+        let refusal = if self.max_concurrent.is_some_and(|max| client.active >= max) {
+            Some(Refusal::Concurrency)
+        } else if let Some(rate) = self.rate.filter(|_| client.tokens < 1.0) {
+            let wait = ((1.0 - client.tokens) / rate).ceil() as u64;
+            Some(Refusal::Rate(wait.max(1)))
+        } else {
+            None
+        };
+        if let Some(refusal) = refusal {
+            if !client.limited {
+                client.limited = true;
+                tracing::info!("Client {} is over its request limits, answering 429", ip);
+            }
+            return Err(refusal);
+        }
+        client.limited = false;
+        if self.rate.is_some() {
+            client.tokens -= 1.0;
+            if let Some(admitted) = &self.admitted {
+                *admitted.lock().unwrap().entry(ip).or_default() += 1;
+            }
+        }
+        client.active += 1;
+        Ok(Slot {
+            limits: self.clone(),
+            ip,
+        })
+    }
+
+    // What was admitted since the last call, for the peers
+    pub fn take_admitted(&self) -> Vec<(IpAddr, u32)> {
+        self.admitted
+            .as_ref()
+            .map_or_else(Vec::new, |admitted| admitted.lock().unwrap().drain().collect())
+    }
+
+    // Take requests a peer admitted from `ip` out of its bucket here; an
+    // empty bucket refills as usual
+    pub fn charge(&self, ip: IpAddr, requests: u32) {
+        if self.rate.is_none() {
+            return;
+        }
+        let now = Instant::now();
+        let mut clients = self.clients.lock().unwrap();
+        let client = clients.entry(ip).or_insert(Client {
+            tokens: self.burst,
+            last: now,
+            active: 0,
+            limited: false,
+        });
+        self.refill(client, now);
+        client.tokens = (client.tokens - requests as f64).max(0.0);
+    }
+}
@@ -0,0 +1,90 @@
+// Wall-clock helpers for schedules configured in local time
+
+pub struct LocalTime {
+    pub year: i32,
+    pub month: u32,
+    pub day: u32,
+    pub hour: u32,
+    pub minute: u32,
+    pub second: u32,
+}
+
+#[cfg(unix)]
+pub fn local_time() -> LocalTime {
+    let tm = unsafe {
+        let now = libc::time(std::ptr::null_mut());
+        let mut tm: libc::tm = std::mem::zeroed();
+        libc::localtime_r(&now, &mut tm);
+        tm
+    };
+    LocalTime {
+        year: tm.tm_year + 1900,
+        month: tm.tm_mon as u32 + 1,
+        day: tm.tm_mday as u32,
+        hour: tm.tm_hour as u32,
+        minute: tm.tm_min as u32,
+        second: tm.tm_sec as u32,
+    }
+}
+
+// Without localtime_r, fall back to UTC
+#[cfg(not(unix))]
+pub fn local_time() -> LocalTime {
+    let secs = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+    let time = secs % 86400;
+    LocalTime {
+        year,
+        month,
+        day,
+        hour: (time / 3600) as u32,
+        minute: (time / 60 % 60) as u32,
+        second: (time % 60) as u32,
+    }
+}
+
+// Days since 1970-01-01 to (year, month, day), after Howard Hinnant's algorithm
+pub fn civil_from_days(days: i64) -> (i32, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = (if mp < 10 { mp + 3 } else { mp - 9 }) as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year as i32, month, day)
+}
+
+// Minutes since local midnight
+pub fn local_minute() -> u32 {
+    let now = local_time();
+    now.hour * 60 + now.minute
+}
+
+// Parse HH:MM into minutes since midnight (24:00 is allowed as an end time)
+pub fn parse_clock(s: &str) -> Result<u32, String> {
+    let (h, m) = s.trim().split_once(':').ok_or(format!("Invalid time: {}", s))?;
+    match (h.parse::<u32>(), m.parse::<u32>()) {
+        (Ok(h), Ok(m)) if h <= 24 && m < 60 && h * 60 + m <= 24 * 60 => Ok(h * 60 + m),
+        _ => Err(format!("Invalid time: {}", s)),
+    }
+}
+
+// Expand the {YYYY-MM-DD}, {HH:MM:SS}, {HH:MM} and {HHMMSS} placeholders
+// of a template with the current local time
+pub fn expand(template: &str) -> String {
+    if !template.contains('{') {
+        return template.to_string();
+    }
+    let now = local_time();
+    template
+        .replace("{YYYY-MM-DD}", &format!("{:04}-{:02}-{:02}", now.year, now.month, now.day))
+        .replace("{HH:MM:SS}", &format!("{:02}:{:02}:{:02}", now.hour, now.minute, now.second))
+        .replace("{HH:MM}", &format!("{:02}:{:02}", now.hour, now.minute))
+        .replace("{HHMMSS}", &format!("{:02}{:02}{:02}", now.hour, now.minute, now.second))
+}
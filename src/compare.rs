@@ -0,0 +1,303 @@
+use getopts::Options;
+use hyper::header::{HeaderMap, HeaderName, HeaderValue, AUTHORIZATION, CONTENT_TYPE};
+use hyper::{Method, Request, Uri};
+
+use crate::bench::{self, HttpClient};
+use crate::fixtures::{Exchange, Fixture};
+use crate::local_dir::percent_decode;
+use crate::methods;
+
+// `compare`: sends recorded requests to the upstream directly and then
+// through the proxy, and shows where the answers differ, to prove the
+// proxy stays transparent after turning on rewrites, caching and the
+// like. The requests come from a --verify fixture file, of which only
+// `request` is used. Before comparing, hrefs and Location headers lose
+// the root URL of each side, bodies of XML answers the whitespace between
+// tags, and headers that always differ (Date, Server, hop-by-hop) are left
+// out.
+
+const IGNORED: [&str; 6] = ["date", "server", "connection", "keep-alive", "transfer-encoding", "content-length"];
+
+// Headers holding URLs that point into the share
+const LOCATIONS: [&str; 2] = ["location", "content-location"];
+
+// One side of the comparison, as a URL without a trailing slash
+struct Side {
+    name: &'static str,
+    root: String,
+    // The root's path part, empty for /
+    path: String,
+}
+
+impl Side {
+    fn new(name: &'static str, url: &str) -> Result<Self, String> {
+        let uri: Uri = url.parse().map_err(|_| format!("Invalid {} URL {}", name, url))?;
+        if uri.scheme().is_none() || uri.query().is_some() {
+            return Err(format!("Invalid {} URL {} (expected http://HOST[:PORT]/PATH)", name, url));
+        }
+        Ok(Side {
+            name,
+            root: url.trim_end_matches('/').to_string(),
+            path: uri.path().trim_end_matches('/').to_string(),
+        })
+    }
+
+    // A URL or absolute path of this side as a path below its root
+    fn relative(&self, url: &str) -> String {
+        let rest = url
+            .strip_prefix(self.root.as_str())
+            .or_else(|| url.strip_prefix(self.path.as_str()))
+            .filter(|rest| rest.is_empty() || rest.starts_with('/'));
+        match rest {
+            Some("") => "/".to_string(),
+            Some(rest) => percent_decode(rest).unwrap_or(rest.to_string()),
+            None => url.to_string(),
+        }
+    }
+
+    fn request(&self, recorded: &Exchange, authorization: Option<&HeaderValue>) -> Result<Request<hyper::Body>, String> {
+        let mut builder = Request::builder()
+            .method(recorded.method.as_str())
+            .uri(format!("{}{}", self.root, recorded.path));
+        for (name, value) in &recorded.headers {
+            if name.eq_ignore_ascii_case("host") {
+                continue;
+            }
+            // A Destination is in the share of the side it goes to
+            let value = match name.eq_ignore_ascii_case("destination") {
+                true => match value.parse::<Uri>() {
+                    Ok(uri) => format!("{}{}", self.root, uri.path_and_query().map_or("/", |pq| pq.as_str())),
+                    Err(_) => value.clone(),
+                },
+                false => value.clone(),
+            };
+            builder = builder.header(name.as_str(), value);
+        }
+        if let Some(authorization) = authorization {
+            builder = builder.header(AUTHORIZATION, authorization.clone());
+        }
+        builder
+            .body(hyper::Body::from(recorded.body.clone()))
+            .map_err(|e| e.to_string())
+    }
+}
+
+// An answer made comparable
+struct Answer {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Body,
+}
+
+enum Body {
+    Text(Vec<String>),
+    Binary(Vec<u8>),
+}
+
+// Hrefs of a multistatus relative to the side's root
+fn relative_hrefs(side: &Side, xml: &str) -> String {
+    let mut out = String::with_capacity(xml.len());
+    let mut rest = xml;
+    while let Some(start) = rest.find("href>") {
+        let (before, after) = rest.split_at(start + "href>".len());
+        out.push_str(before);
+        rest = after;
+        // Only the contents of start tags, <href> or <PREFIX:href>
+        let tag = &before[..start];
+        let opening = tag.rfind('<').is_some_and(|lt| {
+            let name = &tag[lt + 1..];
+            !name.starts_with('/') && (name.is_empty() || name.ends_with(':'))
+        });
+        if opening {
+            let end = rest.find('<').unwrap_or(rest.len());
+            out.push_str(&side.relative(rest[..end].trim()));
+            rest = &rest[end..];
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+// XML without the whitespace between tags, a line per tag
+fn xml_lines(xml: &str) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut rest = xml;
+    while !rest.is_empty() {
+        let end = match rest.strip_prefix('<') {
+            Some(tag) => tag.find('>').map_or(rest.len(), |end| end + 2),
+            None => rest.find('<').unwrap_or(rest.len()),
+        };
+        let part = rest[..end].trim();
+        if !part.is_empty() {
+            lines.push(part.to_string());
+        }
+        rest = &rest[end..];
+    }
+    lines
+}
+
+async fn fetch(client: &HttpClient, side: &Side, request: Request<hyper::Body>) -> Result<Answer, String> {
+    let response = client.request(request).await.map_err(|e| e.to_string())?;
+    let status = response.status().as_u16();
+    let mut headers: Vec<(String, String)> = Vec::new();
+    for name in response.headers().keys() {
+        if IGNORED.contains(&name.as_str()) {
+            continue;
+        }
+        let values: Vec<String> = response
+            .headers()
+            .get_all(name)
+            .iter()
+            .map(|v| String::from_utf8_lossy(v.as_bytes()).into_owned())
+            .map(|v| if LOCATIONS.contains(&name.as_str()) { side.relative(&v) } else { v })
+            .collect();
+        headers.push((name.to_string(), values.join(", ")));
+    }
+    let xml = is_xml(response.headers());
+    let bytes = hyper::body::to_bytes(response.into_body()).await.map_err(|e| e.to_string())?;
+    let body = match String::from_utf8(bytes.to_vec()) {
+        Ok(text) if xml => Body::Text(xml_lines(&relative_hrefs(side, &text))),
+        Ok(text) => Body::Text(text.lines().map(str::to_string).collect()),
+        Err(_) => Body::Binary(bytes.to_vec()),
+    };
+    Ok(Answer { status, headers, body })
+}
+
+fn is_xml(headers: &HeaderMap) -> bool {
+    headers
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.contains("xml"))
+}
+
+// The differences between two answers, as lines to print
+fn differences(upstream: &Answer, proxy: &Answer, ignored: &[HeaderName]) -> Vec<String> {
+    let mut out = Vec::new();
+    if upstream.status != proxy.status {
+        out.push(format!("status: upstream {}, proxy {}", upstream.status, proxy.status));
+    }
+    let value = |answer: &Answer, name: &str| {
+        answer.headers.iter().find(|(n, _)| n == name).map(|(_, v)| v.clone())
+    };
+    let mut names: Vec<&String> = upstream.headers.iter().chain(&proxy.headers).map(|(name, _)| name).collect();
+    names.sort();
+    names.dedup();
+    for name in names.into_iter().filter(|name| !ignored.iter().any(|i| i.as_str() == name.as_str())) {
+        let (a, b) = (value(upstream, name), value(proxy, name));
+        if a != b {
+            let show = |v: Option<String>| v.unwrap_or("none".to_string());
+            out.push(format!("header {}: upstream {}, proxy {}", name, show(a), show(b)));
+        }
+    }
+    match (&upstream.body, &proxy.body) {
+        (Body::Text(a), Body::Text(b)) => {
+            if let Some(line) = (0..a.len().max(b.len())).find(|&i| a.get(i) != b.get(i)) {
+                let show = |l: Option<&String>| l.map_or("end of body".to_string(), |l| format!("`{}`", l));
+                out.push(format!("body line {}: upstream {}, proxy {}", line + 1, show(a.get(line)), show(b.get(line))));
+            }
+        }
+        (Body::Binary(a), Body::Binary(b)) if a == b => {}
+        (a, b) => {
+            let size = |body: &Body| match body {
+                Body::Text(lines) => format!("{} line(s) of text", lines.len()),
+                Body::Binary(bytes) => format!("{} bytes", bytes.len()),
+            };
+            out.push(format!("body: upstream {}, proxy {}", size(a), size(b)));
+        }
+    }
+    out
+}
+
+fn print_usage(program: &str, opts: &Options) {
+    let brief = format!(
+        "Usage: {} compare UPSTREAM PROXY FIXTURES [options]\n\n\
+         UPSTREAM is the upstream's URL and PROXY the URL it is served at on the proxy; FIXTURES\n\
+         is a --verify file, whose requests are sent to both, upstream first",
+        program
+    );
+    print!("{}", opts.usage(&brief));
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("{}", message);
+    std::process::exit(-1);
+}
+
+pub async fn main(program: &str, args: &[String]) {
+    let mut opts = Options::new();
+    opts.optflag(
+        "",
+        "writes",
+        "Also send requests that change the share (PUT, DELETE, MOVE, ...); each reaches the upstream twice, so the second answer may differ",
+    );
+    opts.optmulti("", "ignore-header", "Leave this response header out of the comparison (repeatable)", "NAME");
+    opts.optopt("", "user", "Log in with Basic auth as this user", "USER");
+    opts.optopt("", "pass-env", "Read the password from this environment variable", "VAR");
+    opts.optflag("h", "help", "Show this help");
+
+    let matches = opts.parse(args).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        print_usage(program, &opts);
+        std::process::exit(-1);
+    });
+    if matches.opt_present("help") || matches.free.len() != 3 {
+        print_usage(program, &opts);
+        std::process::exit(if matches.opt_present("help") { 0 } else { -1 });
+    }
+    let upstream = Side::new("upstream", &matches.free[0]).unwrap_or_else(|e| fail(&e));
+    let proxy = Side::new("proxy", &matches.free[1]).unwrap_or_else(|e| fail(&e));
+    let path = &matches.free[2];
+    let text = std::fs::read_to_string(path).unwrap_or_else(|e| fail(&format!("Failed to read {}: {}", path, e)));
+    let fixtures = Fixture::parse_all(&text).unwrap_or_else(|e| fail(&format!("{}: {}", path, e)));
+    let ignored: Vec<HeaderName> = matches
+        .opt_strs("ignore-header")
+        .iter()
+        .map(|name| {
+            HeaderName::from_bytes(name.trim().as_bytes())
+                .unwrap_or_else(|_| fail(&format!("Invalid --ignore-header {}", name)))
+        })
+        .collect();
+    let authorization = matches.opt_str("user").map(|user| {
+        let password = matches.opt_str("pass-env").map(|var| std::env::var(var).unwrap_or_default());
+        crate::upstream_auth::basic_authorization(&user, &password.unwrap_or_default())
+            .unwrap_or_else(|e| fail(&format!("Invalid credentials: {}", e)))
+    });
+    let writes = matches.opt_present("writes");
+
+    let client = bench::client();
+    let (mut compared, mut differing) = (0, 0);
+    for fixture in &fixtures {
+        let recorded = fixture.request();
+        let label = match fixture.name() {
+            "" => format!("{} {}", recorded.method, recorded.path),
+            name => format!("{} {} ({})", recorded.method, recorded.path, name),
+        };
+        let method = Method::from_bytes(recorded.method.as_bytes()).expect("checked when parsed");
+        if methods::is_write(&method) && !writes {
+            println!("skip {} (a write, see --writes)", label);
+            continue;
+        }
+        compared += 1;
+        let mut answers = Vec::new();
+        for side in [&upstream, &proxy] {
+            let answer = match side.request(recorded, authorization.as_ref()) {
+                Ok(request) => fetch(&client, side, request).await,
+                Err(e) => Err(e),
+            };
+            answers.push(answer.map_err(|e| format!("{}: {}", side.name, e)));
+        }
+        let problems = match (&answers[0], &answers[1]) {
+            (Ok(a), Ok(b)) => differences(a, b, &ignored),
+            _ => answers.into_iter().filter_map(Result::err).collect(),
+        };
+        println!("{} {}", if problems.is_empty() { "ok  " } else { "DIFF" }, label);
+        for problem in &problems {
+            println!("       {}", problem);
+        }
+        if !problems.is_empty() {
+            differing += 1;
+        }
+    }
+    println!("{} of {} request(s) answered differently", differing, compared);
+    std::process::exit(if differing == 0 { 0 } else { 1 });
+}
@@ -0,0 +1,483 @@
+use std::path::{Path, PathBuf};
+
+use getopts::{Fail, Matches, Options};
+
+// Options read from a file instead of the command line, one `key = value`
+// line per long option, in TOML syntax:
+//
+//     upstream = "http://nas:8080/dav"
+//     local-port = 8080
+//     sharepoint = true
+//     fallback = ["/photos=empty", "/backup=503"]
+//
+// `upstream` lists the remotes. Strings may refer to environment variables
+// as `${VAR}` or `${VAR:-default}` (`$$` for a plain `$`), so one file can
+// serve several environments and keep secrets out of it.
+//
+// `include = ["routes.d/*.toml"]` reads more files (relative to the one
+// including them, `*` and `?` allowed in the file name), in name order. A
+// file's own settings override those of the files it includes, and later
+// includes override earlier ones; repeatable options and `upstream` collect
+// the values of every file instead. The command line options are the schema:
+// unknown keys, values of the wrong type and conflicting options are all
+// reported with their line, instead of typos like `timout = 5` going
+// unnoticed. Options given on the command line take precedence.
+
+enum Value {
+    Bool(bool),
+    Number(String),
+    Text(String),
+    List(Vec<String>),
+}
+
+struct Entry {
+    // `file:line`, for messages
+    at: String,
+    key: String,
+    value: Value,
+}
+
+// A quoted string at the start of `s`, and what follows it
+fn parse_string(s: &str) -> Result<(String, &str), String> {
+    let mut out = String::new();
+    let mut chars = s.char_indices().skip(1);
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Ok((out, &s[i + 1..])),
+            '\\' => match chars.next().map(|(_, c)| c) {
+                Some('"') => out.push('"'),
+                Some('\\') => out.push('\\'),
+                Some('n') => out.push('\n'),
+                Some('t') => out.push('\t'),
+                Some(c) => return Err(format!("unknown escape \\{} in string", c)),
+                None => break,
+            },
+            c => out.push(c),
+        }
+    }
+    Err("unterminated string".to_string())
+}
+
+// Expand `${VAR}` and `${VAR:-default}` in a string
+fn interpolate(s: &str) -> Result<String, String> {
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(dollar) = rest.find('$') {
+        out.push_str(&rest[..dollar]);
+        rest = &rest[dollar + 1..];
+        if let Some(after) = rest.strip_prefix('$') {
+            out.push('$');
+            rest = after;
+            continue;
+        }
+        let Some(inner) = rest.strip_prefix('{') else {
+            out.push('$');
+            continue;
+        };
+        let end = inner.find('}').ok_or("unterminated ${ in string")?;
+        let (name, default) = match inner[..end].split_once(":-") {
+            Some((name, default)) => (name, Some(default)),
+            None => (&inner[..end], None),
+        };
+        match (std::env::var(name), default) {
+            (Ok(value), _) => out.push_str(&value),
+            (Err(_), Some(default)) => out.push_str(default),
+            (Err(_), None) => return Err(format!("environment variable {} is not set and has no default", name)),
+        }
+        rest = &inner[end + 1..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+// Only a comment may follow a value
+fn end_of_line(rest: &str) -> Result<(), String> {
+    let rest = rest.trim();
+    if rest.is_empty() || rest.starts_with('#') {
+        Ok(())
+    } else {
+        Err(format!("unexpected {} after the value", rest))
+    }
+}
+
+fn parse_scalar(s: &str) -> Result<(Value, &str), String> {
+    if s.starts_with('"') {
+        let (text, rest) = parse_string(s)?;
+        return Ok((Value::Text(interpolate(&text)?), rest));
+    }
+    let end = s.find([',', ']', '#', ' ', '\t']).unwrap_or(s.len());
+    let (word, rest) = s.split_at(end);
+    let value = match word {
+        "true" => Value::Bool(true),
+        "false" => Value::Bool(false),
+        word if !word.is_empty() && word.trim_start_matches('-').chars().all(|c| c.is_ascii_digit()) => {
+            Value::Number(word.to_string())
+        }
+        _ => {
+            return Err(format!(
+                "expected a quoted string, a number, true/false or a [list], found {}",
+                s.trim()
+            ))
+        }
+    };
+    Ok((value, rest))
+}
+
+fn parse_value(s: &str) -> Result<Value, String> {
+    let Some(mut rest) = s.strip_prefix('[') else {
+        let (value, rest) = parse_scalar(s)?;
+        end_of_line(rest)?;
+        return Ok(value);
+    };
+    let mut items = Vec::new();
+    loop {
+        rest = rest.trim_start();
+        if let Some(after) = rest.strip_prefix(']') {
+            end_of_line(after)?;
+            return Ok(Value::List(items));
+        }
+        let (item, after) = parse_scalar(rest)?;
+        items.push(match item {
+            Value::Text(text) | Value::Number(text) => text,
+            _ => return Err("lists may only hold strings and numbers".to_string()),
+        });
+        rest = after.trim_start();
+        rest = rest.strip_prefix(',').unwrap_or(rest);
+        if rest.is_empty() {
+            return Err("unterminated list (lists have to fit on one line)".to_string());
+        }
+    }
+}
+
+fn parse_file(file: &str, text: &str) -> (Vec<Entry>, Vec<String>) {
+    let mut entries: Vec<Entry> = Vec::new();
+    let mut errors = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let at = format!("{}:{}", file, i + 1);
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let Some((key, value)) = line.split_once('=') else {
+            errors.push(format!("{}: expected key = value", at));
+            continue;
+        };
+        // TOML style snake_case keys name the same options
+        let key = key.trim().replace('_', "-");
+        if let Some(first) = entries.iter().find(|e| e.key == key) {
+            errors.push(format!("{}: {} is already set at {}", at, key, first.at));
+            continue;
+        }
+        match parse_value(value.trim()) {
+            Ok(value) => entries.push(Entry { at, key, value }),
+            Err(e) => errors.push(format!("{}: {}: {}", at, key, e)),
+        }
+    }
+    (entries, errors)
+}
+
+// Whether `name` matches a pattern with `*` and `?` wildcards. On a
+// mismatch only the last `*` takes one more char, which is enough, so there
+// is no recursion or backtracking past it: the names are client paths.
+pub fn wildcard(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    // Just past the last `*`, and where in the name it stopped taking chars
+    let mut star = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                p += 1;
+                star = Some((p, n));
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((after, taken)) => {
+                    p = after;
+                    n = taken + 1;
+                    star = Some((after, taken + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+// The files an include pattern stands for, in name order
+fn expand(pattern: &Path) -> Result<Vec<PathBuf>, String> {
+    let file_pattern = pattern.file_name().and_then(|n| n.to_str()).unwrap_or("");
+    if !file_pattern.contains(['*', '?']) {
+        return Ok(vec![pattern.to_path_buf()]);
+    }
+    let dir = pattern.parent().filter(|d| !d.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    let listing = std::fs::read_dir(dir).map_err(|e| format!("Failed to list {}: {}", dir.display(), e))?;
+    let mut files: Vec<PathBuf> = listing
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_name().to_str().is_some_and(|name| wildcard(file_pattern, name)))
+        .map(|entry| entry.path())
+        .collect();
+    files.sort();
+    Ok(files)
+}
+
+// The entries of `path` and everything it includes, lowest precedence
+// first. `chain` holds the files including this one, to catch loops.
+fn read(path: &Path, chain: &mut Vec<PathBuf>, problems: &mut Vec<String>) -> Vec<Entry> {
+    let file = path.display().to_string();
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) => {
+            problems.push(format!("Failed to read {}: {}", file, e));
+            return Vec::new();
+        }
+    };
+    let (entries, errors) = parse_file(&file, &text);
+    problems.extend(errors);
+    let (includes, own): (Vec<Entry>, Vec<Entry>) = entries.into_iter().partition(|e| e.key == "include");
+    let mut merged = Vec::new();
+    chain.push(path.canonicalize().unwrap_or(path.to_path_buf()));
+    for include in includes {
+        let patterns = match include.value {
+            Value::Text(pattern) => vec![pattern],
+            Value::List(patterns) => patterns,
+            _ => {
+                problems.push(format!("{}: include takes a string or a list of strings", include.at));
+                continue;
+            }
+        };
+        let base = path.parent().unwrap_or(Path::new(""));
+        for pattern in patterns {
+            let files = match expand(&base.join(&pattern)) {
+                Ok(files) => files,
+                Err(e) => {
+                    problems.push(format!("{}: {}", include.at, e));
+                    continue;
+                }
+            };
+            for included in files {
+                if chain.contains(&included.canonicalize().unwrap_or(included.clone())) {
+                    problems.push(format!("{}: {} includes itself", include.at, included.display()));
+                    continue;
+                }
+                merged.extend(read(&included, chain, problems));
+            }
+        }
+    }
+    chain.pop();
+    merged.extend(own);
+    merged
+}
+
+struct Known {
+    name: String,
+    takes_value: bool,
+    repeatable: bool,
+    numeric: bool,
+}
+
+// Value hints of options that only take a whole number
+const NUMERIC_HINTS: &[&str] = &["N", "SECS", "MINUTES", "DAYS", "BYTES", "COUNT", "LOCAL_PORT"];
+
+// Options that can't be used together
+const CONFLICTS: &[&[&str]] = &[&["htpasswd", "ldap-url", "pam-service"]];
+
+// Options that need another one
+const REQUIRES: &[(&str, &str)] = &[
+    ("tls-cert", "tls-key"),
+    ("tls-key", "tls-cert"),
+    ("tls-ticket-rotation", "tls-cert"),
+    ("ldap-url", "ldap-user-dn"),
+    ("geoip-allow", "geoip-db"),
+    ("geoip-deny", "geoip-db"),
+    ("upstream-pass-env", "upstream-user"),
+    ("upstream-pass-file", "upstream-user"),
+    ("upstream-pass-cmd", "upstream-user"),
+    ("candidate-percent", "candidate-config"),
+    ("candidate-header", "candidate-config"),
+    ("peer", "admin-bind"),
+    ("peer", "client-rate"),
+    ("cors-credentials", "cors-origin"),
+    ("cors-max-age", "cors-origin"),
+];
+
+// What the command line accepts, worked out from `opts` itself: names and
+// hints from the usage text, the kind of option by trying it out
+fn schema(opts: &Options) -> Vec<Known> {
+    let usage = opts.usage("");
+    let mut known = Vec::new();
+    for line in usage.lines() {
+        let Some(start) = line.find("--").filter(|&s| line[..s].trim().is_empty() || line[..s].trim().ends_with(',')) else {
+            continue;
+        };
+        let mut words = line[start + 2..].split_whitespace();
+        let Some(name) = words.next() else {
+            continue;
+        };
+        // Wrapped descriptions may start with an option they mention
+        let takes_value = match opts.parse([format!("--{}", name)]) {
+            Err(Fail::UnrecognizedOption(_)) => continue,
+            Err(Fail::ArgumentMissing(_)) => true,
+            _ => false,
+        };
+        let repeatable = takes_value && opts.parse([format!("--{}=x", name), format!("--{}=x", name)]).is_ok();
+        let numeric = takes_value && words.next().is_some_and(|hint| NUMERIC_HINTS.contains(&hint));
+        known.push(Known {
+            name: name.to_string(),
+            takes_value,
+            repeatable,
+            numeric,
+        });
+    }
+    known
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != *cb);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+fn suggestion(key: &str, known: &[Known]) -> String {
+    known
+        .iter()
+        .map(|k| k.name.as_str())
+        .chain(["upstream"])
+        .map(|name| (edit_distance(key, name), name))
+        .filter(|(distance, name)| *distance <= 2.max(name.len() / 4))
+        .min_by_key(|(distance, _)| *distance)
+        .map_or(String::new(), |(_, name)| format!(" (did you mean {}?)", name))
+}
+
+// Check an entry against its option; the arguments it stands for
+fn arguments(entry: &Entry, option: &Known) -> Result<Vec<String>, String> {
+    let flag = format!("--{}", entry.key);
+    let check = |value: &str| -> Result<String, String> {
+        if option.numeric && value.parse::<u64>().is_err() {
+            return Err(format!("{} takes a whole number, not {}", entry.key, value));
+        }
+        Ok(format!("{}={}", flag, value))
+    };
+    match (&entry.value, option.takes_value) {
+        (Value::Bool(true), false) => Ok(vec![flag]),
+        (Value::Bool(false), false) => Ok(Vec::new()),
+        (_, false) => Err(format!("{} is a switch and takes true or false", entry.key)),
+        (Value::Bool(_), true) => Err(format!("{} takes a value, not true or false", entry.key)),
+        (Value::Text(value) | Value::Number(value), true) => Ok(vec![check(value)?]),
+        (Value::List(_), true) if !option.repeatable => {
+            Err(format!("{} takes a single value, not a list", entry.key))
+        }
+        (Value::List(values), true) => values.iter().map(|v| check(v)).collect(),
+    }
+}
+
+// Read `path` (and what it includes) into arguments to put before the
+// command line ones, or every problem found in it
+pub fn load(path: &str, opts: &Options, command_line: &Matches) -> Result<Vec<String>, Vec<String>> {
+    let mut problems = Vec::new();
+    let entries = read(Path::new(path), &mut Vec::new(), &mut problems);
+    let known = schema(opts);
+    // Per key, where it was last set and the arguments it stands for
+    let mut settings: Vec<(&str, &str, Vec<String>)> = Vec::new();
+    let mut remotes = Vec::new();
+    for entry in &entries {
+        if entry.key == "config" {
+            problems.push(format!("{}: config can only be given on the command line", entry.at));
+            continue;
+        }
+        if entry.key == "upstream" {
+            match &entry.value {
+                Value::Text(remote) => remotes.push(remote.clone()),
+                Value::List(list) => remotes.extend(list.iter().cloned()),
+                _ => problems.push(format!("{}: upstream takes a string or a list of strings", entry.at)),
+            }
+            continue;
+        }
+        let Some(option) = known.iter().find(|k| k.name == entry.key) else {
+            problems.push(format!("{}: unknown key {}{}", entry.at, entry.key, suggestion(&entry.key, &known)));
+            continue;
+        };
+        let entry_args = match arguments(entry, option) {
+            Ok(entry_args) => entry_args,
+            Err(e) => {
+                problems.push(format!("{}: {}", entry.at, e));
+                continue;
+            }
+        };
+        match settings.iter_mut().find(|(key, _, _)| *key == entry.key) {
+            Some(setting) if option.repeatable => {
+                setting.1 = &entry.at;
+                setting.2.extend(entry_args);
+            }
+            Some(setting) => *setting = (&entry.key, &entry.at, entry_args),
+            None => settings.push((&entry.key, &entry.at, entry_args)),
+        }
+    }
+
+    let at = |key: &str| settings.iter().find(|(k, _, _)| *k == key).map(|(_, at, _)| *at);
+    let present = |key: &str| at(key).is_some() || command_line.opt_present(key);
+    for group in CONFLICTS {
+        let set: Vec<(&str, &str)> = group.iter().filter_map(|key| at(key).map(|at| (*key, at))).collect();
+        if let [(first, first_at), rest @ ..] = set.as_slice() {
+            for (key, key_at) in rest {
+                problems.push(format!("{}: {} conflicts with {} at {}", key_at, key, first, first_at));
+            }
+        }
+    }
+    for (key, needed) in REQUIRES {
+        if let Some(key_at) = at(key).filter(|_| !present(needed)) {
+            problems.push(format!("{}: {} needs {}", key_at, key, needed));
+        }
+    }
+
+    if !problems.is_empty() {
+        return Err(problems);
+    }
+    // The command line wins
+    let mut args: Vec<String> = settings
+        .into_iter()
+        .filter(|(key, _, _)| !command_line.opt_present(key))
+        .flat_map(|(_, _, args)| args)
+        .collect();
+    if command_line.free.is_empty() {
+        args.extend(remotes);
+    }
+    Ok(args)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_wildcards() {
+        assert!(wildcard("*.pdf", "/docs/a.pdf"));
+        assert!(!wildcard("*.pdf", "/docs/a.pdf.txt"));
+        assert!(wildcard("/docs/*/?.txt", "/docs/x/y/a.txt"));
+        assert!(!wildcard("/docs/*/?.txt", "/docs/x/ab.txt"));
+        assert!(wildcard("*", ""));
+        assert!(wildcard("/é*", "/é/ü"));
+        assert!(!wildcard("/a", "/ab"));
+        assert!(!wildcard("/ab", "/a"));
+    }
+
+    #[test]
+    fn many_stars_stay_quick() {
+        let path = format!("/{}", "a".repeat(20_000));
+        let started = std::time::Instant::now();
+        assert!(!wildcard("*a*a*a*a*a*a*a*b", &path));
+        assert!(started.elapsed() < std::time::Duration::from_secs(5));
+    }
+}
@@ -0,0 +1,78 @@
+use hyper::header::{HeaderMap, HeaderValue, COOKIE, SET_COOKIE};
+
+// What to do with cookies flowing between the clients and the upstream
+#[derive(Clone, Debug, PartialEq)]
+pub enum CookiePolicy {
+    // Forward Cookie and Set-Cookie untouched
+    Pass,
+    // Never let cookies cross the proxy in either direction
+    Strip,
+    // Forward cookies, but rewrite the Domain/Path attributes of Set-Cookie
+    Rewrite {
+        domain: Option<String>,
+        path: Option<String>,
+    },
+}
+
+impl CookiePolicy {
+    pub fn parse(mode: &str, domain: Option<String>, path: Option<String>) -> Result<Self, String> {
+        match mode {
+            "pass" => Ok(CookiePolicy::Pass),
+            "strip" => Ok(CookiePolicy::Strip),
+            "rewrite" => Ok(CookiePolicy::Rewrite { domain, path }),
+            _ => Err(format!("Unknown cookie policy: {} (expected pass, strip or rewrite)", mode)),
+        }
+    }
+
+    // Applied to the headers of the request sent upstream
+    pub fn apply_request(&self, headers: &mut HeaderMap) {
+        if *self == CookiePolicy::Strip {
+            headers.remove(COOKIE);
+        }
+    }
+
+    // Applied to the headers of the response returned to the client
+    pub fn apply_response(&self, headers: &mut HeaderMap) {
+        match self {
+            CookiePolicy::Pass => {}
+            CookiePolicy::Strip => {
+                headers.remove(SET_COOKIE);
+            }
+            CookiePolicy::Rewrite { domain, path } => {
+                let rewritten: Vec<HeaderValue> = headers
+                    .get_all(SET_COOKIE)
+                    .iter()
+                    .filter_map(|v| v.to_str().ok())
+                    .map(|v| rewrite_set_cookie(v, domain.as_deref(), path.as_deref()))
+                    .filter_map(|v| HeaderValue::from_str(&v).ok())
+                    .collect();
+                headers.remove(SET_COOKIE);
+                for value in rewritten {
+                    headers.append(SET_COOKIE, value);
+                }
+            }
+        }
+    }
+}
+
+// Replace (or drop, when no domain is given) the Domain attribute and
+// optionally replace the Path attribute of a single Set-Cookie value
+fn rewrite_set_cookie(value: &str, domain: Option<&str>, path: Option<&str>) -> String {
+    let mut parts = value.split(';').map(|p| p.trim());
+    let mut out = vec![parts.next().unwrap_or("").to_string()];
+    for attr in parts {
+        let name = attr.split('=').next().unwrap_or("").trim().to_ascii_lowercase();
+        match name.as_str() {
+            "domain" => {}
+            "path" if path.is_some() => {}
+            _ => out.push(attr.to_string()),
+        }
+    }
+    if let Some(domain) = domain {
+        out.push(format!("Domain={}", domain));
+    }
+    if let Some(path) = path {
+        out.push(format!("Path={}", path));
+    }
+    out.join("; ")
+}
@@ -0,0 +1,98 @@
+use hyper::header::{HeaderMap, HeaderValue, ORIGIN, VARY};
+use hyper::{Body, Method, Request, Response, StatusCode};
+
+// Cross-origin access for WebDAV clients running in a browser
+// (--cors-origin). Preflights are answered by the proxy itself, ahead of
+// authentication, as browsers send them without credentials; the other
+// answers to an allowed origin get Access-Control-Allow-Origin, and expose
+// the headers WebDAV clients read.
+
+const METHODS: &str = "GET, HEAD, PUT, DELETE, OPTIONS, PROPFIND, PROPPATCH, MKCOL, COPY, MOVE, LOCK, UNLOCK";
+
+const HEADERS: &str = "Authorization, Content-Type, Depth, Destination, Overwrite, If, Lock-Token, Timeout, \
+                       Range, If-Match, If-None-Match, If-Modified-Since, Prefer, X-Requested-With";
+
+const EXPOSED: &str = "DAV, ETag, Last-Modified, Location, Lock-Token, Content-Range, Content-Length, \
+                       WWW-Authenticate, Preference-Applied";
+
+pub struct Cors {
+    // Allowed origins as sent by browsers, e.g. https://app.example.com,
+    // or `*` for any
+    origins: Vec<String>,
+    credentials: bool,
+    max_age: u64,
+}
+
+impl Cors {
+    pub fn new(origins: &[String], credentials: bool, max_age: u64) -> Result<Self, String> {
+        let mut allowed = Vec::new();
+        for origin in origins {
+            let origin = origin.trim().trim_end_matches('/');
+            if origin != "*" && !origin.contains("://") {
+                return Err(format!("Invalid --cors-origin (expected SCHEME://HOST[:PORT] or *): {}", origin));
+            }
+            allowed.push(origin.to_ascii_lowercase());
+        }
+        Ok(Cors {
+            origins: allowed,
+            credentials,
+            max_age,
+        })
+    }
+
+    // The Access-Control-Allow-Origin for a request from `origin`: the
+    // origin itself, or `*` for any origin when no credentials are allowed
+    fn allow(&self, origin: &HeaderValue) -> Option<HeaderValue> {
+        let text = origin.to_str().ok()?.to_ascii_lowercase();
+        let any = self.origins.iter().any(|o| o == "*");
+        if !any && !self.origins.contains(&text) {
+            return None;
+        }
+        Some(if any && !self.credentials { HeaderValue::from_static("*") } else { origin.clone() })
+    }
+
+    fn allow_headers(&self, origin: HeaderValue, headers: &mut HeaderMap) {
+        if origin != "*" {
+            headers.append(VARY, HeaderValue::from_static("Origin"));
+        }
+        headers.insert("Access-Control-Allow-Origin", origin);
+        if self.credentials {
+            headers.insert("Access-Control-Allow-Credentials", HeaderValue::from_static("true"));
+        }
+    }
+
+    // The answer to a preflight, which never reaches the upstream
+    pub fn preflight(&self, req: &Request<Body>) -> Option<Response<Body>> {
+        let headers = req.headers();
+        if req.method() != Method::OPTIONS || !headers.contains_key("Access-Control-Request-Method") {
+            return None;
+        }
+        let origin = headers.get(ORIGIN)?;
+        let Some(allowed) = self.allow(origin) else {
+            return Some(
+                Response::builder()
+                    .status(StatusCode::FORBIDDEN)
+                    .header("Content-Type", "text/plain")
+                    .body(Body::from("Origin not allowed\n"))
+                    .expect("response builder"),
+            );
+        };
+        let mut response = Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .header("Access-Control-Allow-Methods", METHODS)
+            .header("Access-Control-Allow-Headers", HEADERS)
+            .header("Access-Control-Max-Age", self.max_age)
+            .body(Body::empty())
+            .expect("response builder");
+        self.allow_headers(allowed, response.headers_mut());
+        Some(response)
+    }
+
+    // Let the page from `origin` read the response
+    pub fn apply(&self, origin: Option<&HeaderValue>, headers: &mut HeaderMap) {
+        if let Some(allowed) = origin.and_then(|origin| self.allow(origin)) {
+            self.allow_headers(allowed, headers);
+            headers.insert("Access-Control-Expose-Headers", HeaderValue::from_static(EXPOSED));
+        }
+    }
+}
@@ -0,0 +1,204 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use hyper::header::{HeaderValue, CONTENT_LENGTH, DATE, LAST_MODIFIED};
+use hyper::{Body, Response, StatusCode};
+
+use crate::clock;
+use crate::multistatus;
+
+// Timestamps passing through from the upstream, rewritten into the forms
+// clients parse reliably (IMF-fixdate for HTTP dates and getlastmodified,
+// RFC 3339 in UTC for creationdate), and optionally pulled back to the
+// proxy's clock when an upstream with a fast clock dates them in the
+// future, which sync clients take for a change that never settles
+
+const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+fn now() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs() as i64)
+}
+
+// Days since 1970-01-01 of a civil date, after Howard Hinnant's algorithm
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = (i64::from(month) + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + i64::from(day) - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+// Unix seconds to (year, month, day, hour, minute, second)
+fn civil(secs: i64) -> (i32, u32, u32, i64, i64, i64) {
+    let (days, rest) = (secs.div_euclid(86400), secs.rem_euclid(86400));
+    let (year, month, day) = clock::civil_from_days(days);
+    (year, month, day, rest / 3600, rest / 60 % 60, rest % 60)
+}
+
+pub fn format_http(secs: i64) -> String {
+    let (year, month, day, hour, minute, second) = civil(secs);
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        WEEKDAYS[secs.div_euclid(86400).rem_euclid(7) as usize],
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        hour,
+        minute,
+        second
+    )
+}
+
+pub fn format_rfc3339(secs: i64) -> String {
+    let (year, month, day, hour, minute, second) = civil(secs);
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z", year, month, day, hour, minute, second)
+}
+
+// RFC 7231 IMF-fixdate
+pub fn http_date(time: SystemTime) -> String {
+    format_http(time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs() as i64))
+}
+
+fn month_number(name: &str) -> Option<u32> {
+    MONTHS
+        .iter()
+        .position(|m| m.eq_ignore_ascii_case(name))
+        .map(|i| i as u32 + 1)
+}
+
+fn time_of_day(s: &str) -> Option<i64> {
+    let mut parts = s.splitn(3, ':').map(|p| p.parse::<i64>().ok());
+    let (hour, minute) = (parts.next()??, parts.next()??);
+    // Leap seconds become the last regular one
+    let second = parts.next().map_or(Some(0), |s| s)?.min(59);
+    (hour < 24 && minute < 60).then_some(hour * 3600 + minute * 60 + second)
+}
+
+fn from_parts(year: i64, month: u32, day: u32, time: i64) -> Option<i64> {
+    ((1..=31).contains(&day)).then(|| days_from_civil(year, month, day) * 86400 + time)
+}
+
+// The three HTTP date formats (IMF-fixdate, RFC 850, asctime), with or
+// without the weekday, as Unix seconds
+fn parse_http(s: &str) -> Option<i64> {
+    let s = s.split_once(',').map_or(s, |(_, rest)| rest).trim();
+    let fields: Vec<&str> = s.split_whitespace().collect();
+    match fields.as_slice() {
+        // 06 Nov 1994 08:49:37 GMT
+        [day, month, year, time, "GMT" | "UTC" | "+0000"] | [day, month, year, time] => {
+            from_parts(year.parse().ok()?, month_number(month)?, day.parse().ok()?, time_of_day(time)?)
+        }
+        // 06-Nov-94 08:49:37 GMT
+        [date, time, "GMT"] | [date, time] => {
+            let mut parts = date.split('-');
+            let (day, month, year) = (parts.next()?, parts.next()?, parts.next()?.parse::<i64>().ok()?);
+            // Two-digit years more than 50 years ahead are in the past
+            let year = if year < 100 { if year < 70 { 2000 + year } else { 1900 + year } } else { year };
+            from_parts(year, month_number(month)?, day.parse().ok()?, time_of_day(time)?)
+        }
+        // Sun Nov  6 08:49:37 1994
+        [_, month, day, time, year] => {
+            from_parts(year.parse().ok()?, month_number(month)?, day.parse().ok()?, time_of_day(time)?)
+        }
+        _ => None,
+    }
+}
+
+// 1994-11-06T08:49:37Z, with optional fractions and a numeric offset
+fn parse_rfc3339(s: &str) -> Option<i64> {
+    let (date, time) = s.split_once(['T', 't', ' '])?;
+    let mut parts = date.split('-');
+    let (year, month, day) = (parts.next()?.parse().ok()?, parts.next()?.parse().ok()?, parts.next()?.parse().ok()?);
+    let (time, offset) = match time.find(['Z', 'z', '+', '-']) {
+        Some(i) => time.split_at(i),
+        None => (time, "Z"),
+    };
+    let time = time.split_once('.').map_or(time, |(whole, _)| whole);
+    let offset = match offset {
+        "Z" | "z" => 0,
+        _ => {
+            let (hours, minutes) = offset[1..].split_once(':').unwrap_or((&offset[1..], "0"));
+            let seconds = hours.parse::<i64>().ok()? * 3600 + minutes.parse::<i64>().ok()? * 60;
+            if offset.starts_with('-') {
+                -seconds
+            } else {
+                seconds
+            }
+        }
+    };
+    Some(from_parts(year, month, day, time_of_day(time)?)? - offset)
+}
+
+// Either kind of date, whatever field it came in
+pub fn parse(s: &str) -> Option<i64> {
+    let s = s.trim();
+    parse_rfc3339(s).or_else(|| parse_http(s))
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Form {
+    Http,
+    Rfc3339,
+}
+
+#[derive(Clone)]
+pub struct DatePolicy {
+    // Rewrite every date into its canonical form
+    pub normalize: bool,
+    // Dates further ahead of the proxy's clock than this many seconds are
+    // set to the proxy's clock
+    pub max_future: Option<u64>,
+}
+
+impl DatePolicy {
+    pub fn is_active(&self) -> bool {
+        self.normalize || self.max_future.is_some()
+    }
+
+    // The replacement for a date value, if it needs one
+    fn fix(&self, value: &str, form: Form) -> Option<String> {
+        let mut secs = parse(value)?;
+        let mut changed = self.normalize;
+        if let Some(limit) = self.max_future {
+            let now = now();
+            if secs > now + limit as i64 {
+                secs = now;
+                changed = true;
+            }
+        }
+        changed.then(|| match form {
+            Form::Http => format_http(secs),
+            Form::Rfc3339 => format_rfc3339(secs),
+        })
+    }
+
+    pub async fn apply_response(&self, response: Response<Body>) -> Result<Response<Body>, hyper::Error> {
+        if !self.is_active() {
+            return Ok(response);
+        }
+        let (mut parts, body) = response.into_parts();
+        for name in [DATE, LAST_MODIFIED] {
+            let fixed = parts
+                .headers
+                .get(&name)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| self.fix(v, Form::Http));
+            if let Some(value) = fixed.and_then(|v| HeaderValue::from_str(&v).ok()) {
+                parts.headers.insert(name, value);
+            }
+        }
+        if parts.status != StatusCode::MULTI_STATUS {
+            return Ok(Response::from_parts(parts, body));
+        }
+        parts.headers.remove(CONTENT_LENGTH);
+        let policy = self.clone();
+        let body = multistatus::map_body(body, move |name, text| match name {
+            "getlastmodified" => policy.fix(text, Form::Http),
+            "creationdate" => policy.fix(text, Form::Rfc3339),
+            _ => None,
+        });
+        Ok(Response::from_parts(parts, body))
+    }
+}
@@ -0,0 +1,130 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use hyper::header::{HeaderValue, CONTENT_LENGTH, ETAG};
+use hyper::{Body, HeaderMap, Method, Response, StatusCode};
+
+use crate::digest;
+use crate::memory::Cache;
+use crate::methods;
+
+// Windows clients resend a PUT that timed out on their side even when it
+// went through. A successful write is remembered for a short window so an
+// identical retry is answered from the record instead of being uploaded
+// again. Only the latest write per path is kept, so a retry never undoes a
+// newer change.
+// Retried DELETEs and MKCOLs are still forwarded, but the 404 or 405 they
+// get right after the first one succeeded is turned back into that success.
+pub struct Dedup {
+    window: Duration,
+    // By path
+    recent: Mutex<HashMap<String, Recorded>>,
+}
+
+struct Recorded {
+    fingerprint: String,
+    at: Instant,
+    status: StatusCode,
+    etag: Option<HeaderValue>,
+}
+
+// PUT bodies larger than this are forwarded without checksumming
+pub const MAX_BODY: u64 = 16 * 1024 * 1024;
+
+// The length of a PUT body small enough to be buffered
+pub fn bufferable(headers: &HeaderMap) -> Option<usize> {
+    methods::content_length(headers).filter(|n| *n as u64 <= MAX_BODY)
+}
+
+// Identifies a PUT to a path by who sent what
+pub fn put_fingerprint(user: Option<&str>, body: &[u8]) -> String {
+    format!("PUT {} {} {}", user.unwrap_or("-"), body.len(), digest::hex(&digest::sha256(body)))
+}
+
+// Identifies a DELETE or MKCOL of a path by who sent it
+pub fn retry_fingerprint(method: &Method, user: Option<&str>) -> String {
+    format!("{} {}", method, user.unwrap_or("-"))
+}
+
+// Whether `status` is what repeating an already successful `method` yields
+pub fn is_repeat_failure(method: &Method, status: StatusCode) -> bool {
+    match method.as_str() {
+        "DELETE" => status == StatusCode::NOT_FOUND,
+        "MKCOL" => status == StatusCode::METHOD_NOT_ALLOWED,
+        _ => false,
+    }
+}
+
+impl Dedup {
+    pub fn new(window: Duration) -> Self {
+        Dedup {
+            window,
+            recent: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // The recorded outcome of an identical request within the window
+    pub fn replay(&self, path: &str, fingerprint: &str) -> Option<Response<Body>> {
+        let recent = self.recent.lock().unwrap();
+        let recorded = recent
+            .get(path)
+            .filter(|r| r.fingerprint == fingerprint && r.at.elapsed() < self.window)?;
+        let mut builder = Response::builder().status(recorded.status);
+        if recorded.status != StatusCode::NO_CONTENT {
+            builder = builder.header(CONTENT_LENGTH, 0);
+        }
+        if let Some(etag) = &recorded.etag {
+            builder = builder.header(ETAG, etag.clone());
+        }
+        Some(builder.body(Body::empty()).expect("response builder"))
+    }
+
+    // Remember the outcome of the latest write to `path`
+    pub fn record(&self, path: &str, fingerprint: String, response: &Response<Body>) {
+        let mut recent = self.recent.lock().unwrap();
+        recent.retain(|_, r| r.at.elapsed() < self.window);
+        if !response.status().is_success() {
+            recent.remove(path);
+            return;
+        }
+        recent.insert(
+            path.to_string(),
+            Recorded {
+                fingerprint,
+                at: Instant::now(),
+                status: response.status(),
+                etag: response.headers().get(ETAG).cloned(),
+            },
+        );
+    }
+
+    // Some other write touched `path` or, for collections, its members
+    pub fn forget(&self, path: &str) {
+        let collection = format!("{}/", path.trim_end_matches('/'));
+        self.recent
+            .lock()
+            .unwrap()
+            .retain(|p, _| p != path && !p.starts_with(&collection));
+    }
+}
+
+impl Cache for Dedup {
+    fn size(&self) -> usize {
+        self.recent
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(path, r)| {
+                std::mem::size_of::<(String, Recorded)>()
+                    + path.len()
+                    + r.fingerprint.len()
+                    + r.etag.as_ref().map_or(0, |e| e.len())
+            })
+            .sum()
+    }
+
+    fn evict(&self) {
+        self.recent.lock().unwrap().clear();
+    }
+}
@@ -0,0 +1,79 @@
+use hyper::header::{HeaderMap, HeaderValue, CONTENT_TYPE};
+use hyper::{Body, Method, Response, StatusCode};
+
+use crate::virtual_tree;
+
+// A PROPFIND with Depth: infinity, or without a Depth, which means the same,
+// walks the whole tree below its path, which on a big share keeps the
+// upstream busy for minutes (--infinite-depth). By path prefix, such
+// requests are refused with 403 and RFC 4918's propfind-finite-depth
+// precondition, which tells clients to list level by level, or sent on as
+// Depth: 1. The longest matching prefix decides; `allow` exempts a prefix
+// below a guarded one.
+
+#[derive(Clone, Copy, PartialEq)]
+enum Action {
+    Allow,
+    Reject,
+    Downgrade,
+}
+
+const FINITE_DEPTH: &str =
+    "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<D:error xmlns:D=\"DAV:\"><D:propfind-finite-depth/></D:error>\n";
+
+#[derive(Default)]
+pub struct DepthGuard {
+    rules: Vec<(String, Action)>,
+}
+
+impl DepthGuard {
+    // `[PREFIX=]ACTION`, every path without a prefix
+    pub fn add(&mut self, spec: &str) -> Result<(), String> {
+        let (prefix, action) = spec.rsplit_once('=').unwrap_or(("/", spec));
+        let action = match action.trim() {
+            "reject" => Action::Reject,
+            "downgrade" => Action::Downgrade,
+            "allow" => Action::Allow,
+            _ => {
+                return Err(format!(
+                    "Invalid --infinite-depth (expected [PREFIX=]reject, downgrade or allow): {}",
+                    spec
+                ))
+            }
+        };
+        let prefix = prefix.trim();
+        if !prefix.starts_with('/') {
+            return Err(format!("Invalid --infinite-depth prefix (expected a path): {}", prefix));
+        }
+        self.rules.push((prefix.to_string(), action));
+        Ok(())
+    }
+
+    // The refusal of a PROPFIND of `path` that asks for infinite depth, or
+    // None, with its Depth lowered to 1 if it should be
+    pub fn check(&self, method: &Method, path: &str, headers: &mut HeaderMap) -> Option<Response<Body>> {
+        if method.as_str() != "PROPFIND" || virtual_tree::depth(headers) != u32::MAX {
+            return None;
+        }
+        let action = self
+            .rules
+            .iter()
+            .filter(|(prefix, _)| path.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map_or(Action::Allow, |(_, action)| *action);
+        match action {
+            Action::Allow => None,
+            Action::Downgrade => {
+                headers.insert("Depth", HeaderValue::from_static("1"));
+                None
+            }
+            Action::Reject => Some(
+                Response::builder()
+                    .status(StatusCode::FORBIDDEN)
+                    .header(CONTENT_TYPE, "application/xml; charset=utf-8")
+                    .body(Body::from(FINITE_DEPTH))
+                    .expect("response builder"),
+            ),
+        }
+    }
+}
@@ -0,0 +1,448 @@
+
+use hyper::header::HeaderMap;
+use hyper::{Body, Method, Response, StatusCode, Uri};
+
+use crate::breaker::Breaker;
+use crate::clock;
+use crate::local_dir;
+use crate::methods;
+use crate::phase::Phase;
+use crate::problem::Unreachable;
+use crate::upstream_error::UpstreamError;
+use crate::virtual_files;
+use crate::virtual_tree::{PropRequest, VirtualTree};
+use crate::warm;
+use crate::xml;
+
+// Of the proxy's own elements in WebDAV error bodies
+const NAMESPACE: &str = "urn:proxy-optional-webdav";
+
+// The seconds 503s and 504s for an unreachable upstream ask clients to
+// wait: until the breaker's next probe of it, if it is taken for down, and
+// otherwise the interval at which its health is checked
+pub fn retry_after(breaker: Option<&Breaker>) -> u64 {
+    let wait = breaker.and_then(Breaker::next_probe).unwrap_or(warm::CHECK_INTERVAL);
+    wait.as_secs_f64().ceil().max(1.0) as u64
+}
+
+// What clients see when the upstream can't be reached
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Fallback {
+    // An empty folder holding a "TIMEOUT", "CLOSED" or "DNS" folder
+    Folder,
+    // A real-looking empty listing of the requested collection
+    Empty,
+    // A plain 503
+    Unavailable,
+    // The failure as it is: 502 for a refused connection or an unknown
+    // host, 504 for a timeout, for scripts that want real errors and
+    // proxies that are to be transparent (`none`)
+    Error,
+}
+
+// "disabled" is for routes disabled on the admin API
+const REASONS: &[&str] = &["timeout", "closed", "dns", "disabled"];
+
+// What the error folder looks like to one request
+pub struct Folder<'a> {
+    // Overrides the folder's name
+    pub name: Option<&'a str>,
+    pub files: &'a FallbackFiles,
+    pub method: &'a Method,
+    pub depth: u32,
+    // For Retry-After, from `retry_after`
+    pub retry_after: u64,
+}
+
+// Asks for Fallback::Error on a single request, as a header or a query
+// parameter
+const OPT_OUT_HEADER: &str = "x-proxy-no-fallback";
+const OPT_OUT_PARAM: &str = "proxy-no-fallback";
+
+fn truthy(value: &str) -> bool {
+    matches!(value.trim(), "" | "1" | "true" | "yes")
+}
+
+// Whether the client asked for real errors instead of the fallback; the
+// header and the parameter are removed so the upstream never sees them
+pub fn opted_out(headers: &mut HeaderMap, uri: &mut Uri) -> bool {
+    let mut asked = headers
+        .remove(OPT_OUT_HEADER)
+        .is_some_and(|v| v.to_str().is_ok_and(truthy));
+    let Some(query) = uri.query() else {
+        return asked;
+    };
+    let mut kept = Vec::new();
+    for pair in query.split('&') {
+        match pair.split_once('=').unwrap_or((pair, "")) {
+            (OPT_OUT_PARAM, value) => asked |= truthy(value),
+            _ => kept.push(pair),
+        }
+    }
+    if kept.len() < query.split('&').count() {
+        let target = if kept.is_empty() {
+            uri.path().to_string()
+        } else {
+            format!("{}?{}", uri.path(), kept.join("&"))
+        };
+        let mut parts = uri.clone().into_parts();
+        parts.path_and_query = target.parse().ok();
+        if let Ok(stripped) = Uri::from_parts(parts) {
+            *uri = stripped;
+        }
+    }
+    asked
+}
+
+impl Fallback {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match s.trim() {
+            "folder" | "virtual-folder" => Ok(Fallback::Folder),
+            "empty" => Ok(Fallback::Empty),
+            "503" | "unavailable" => Ok(Fallback::Unavailable),
+            // `none` for a proxy that is to be transparent
+            "error" | "none" => Ok(Fallback::Error),
+            _ => Err(format!("Unknown fallback (expected folder, empty, 503, error or none): {}", s)),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Fallback::Folder => "folder",
+            Fallback::Empty => "empty",
+            Fallback::Unavailable => "unavailable",
+            Fallback::Error => "error",
+        }
+    }
+
+    // Writes get an error whatever the strategy: a multistatus looks like
+    // success to some clients, which then drop the data they meant to save.
+    // Property changes and locks get a 503 to come back later instead.
+    pub fn for_method(self, method: &Method) -> Self {
+        match self {
+            Fallback::Folder | Fallback::Empty if deferrable(method) => Fallback::Unavailable,
+            Fallback::Folder | Fallback::Empty if methods::is_write(method) => Fallback::Error,
+            other => other,
+        }
+    }
+
+    // `phase` is where a timeout happened, if known
+    pub fn respond(self, reason: &str, phase: Option<Phase>, path: &str, folder: &Folder) -> Response<Body> {
+        self.answer(reason, describe(reason, phase), StatusCode::SERVICE_UNAVAILABLE, phase, path, folder)
+    }
+
+    // The answer to a request `error` kept from the upstream, which it
+    // carries for the response hooks
+    pub fn failed(self, error: UpstreamError, path: &str, folder: &Folder) -> Response<Body> {
+        let mut response = self.answer(error.reason(), error.to_string(), error.status(), error.phase(), path, folder);
+        response.extensions_mut().insert(error);
+        response
+    }
+
+    // `status` is what Fallback::Error answers with
+    fn answer(
+        self,
+        reason: &str,
+        described: String,
+        status: StatusCode,
+        phase: Option<Phase>,
+        path: &str,
+        folder: &Folder,
+    ) -> Response<Body> {
+        let mut response = match self {
+            Fallback::Folder => error_folder(reason, phase, path, folder),
+            Fallback::Empty => empty_listing(path),
+            Fallback::Unavailable if deferrable(folder.method) => deferred(&described, folder.retry_after),
+            Fallback::Unavailable => Response::builder()
+                .status(StatusCode::SERVICE_UNAVAILABLE)
+                .header("Retry-After", folder.retry_after)
+                .header("Content-Type", "text/plain")
+                .body(Body::from(format!("Upstream {}\n", described)))
+                .expect("response builder"),
+            // Not a stand-in, so problem details keep its status
+            // A refused connection (502) is no outage a client could wait out
+            Fallback::Error => {
+                let mut response = Response::builder().status(status);
+                if status == StatusCode::SERVICE_UNAVAILABLE || status == StatusCode::GATEWAY_TIMEOUT {
+                    response = response.header("Retry-After", folder.retry_after);
+                }
+                return response
+                    .header("Content-Type", "text/plain")
+                    .body(Body::from(format!("Upstream {}\n", described)))
+                    .expect("response builder");
+            }
+        };
+        response.extensions_mut().insert(Unreachable(described, folder.retry_after));
+        response
+    }
+}
+
+// PROPPATCH and locking, which sync clients take a plain error to for good
+// and stop syncing the file; a 503 with a WebDAV error body and a
+// Retry-After has them try again later
+pub fn deferrable(method: &Method) -> bool {
+    matches!(method.as_str(), "PROPPATCH" | "LOCK" | "UNLOCK")
+}
+
+// The answer to a deferrable request while the upstream is unreachable
+pub fn deferred(described: &str, retry_after: u64) -> Response<Body> {
+    let body = format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<D:error xmlns:D=\"DAV:\" xmlns:P=\"{}\"><P:upstream-unavailable/><D:responsedescription>Upstream {}; try again later</D:responsedescription></D:error>\n",
+        NAMESPACE,
+        xml::escape(described)
+    );
+    Response::builder()
+        .status(StatusCode::SERVICE_UNAVAILABLE)
+        .header("Retry-After", retry_after)
+        .header("Content-Type", "application/xml; charset=utf-8")
+        .body(Body::from(body))
+        .expect("response builder")
+}
+
+// The reason with the phase it happened in, e.g. `timeout (connect)`
+pub fn describe(reason: &str, phase: Option<Phase>) -> String {
+    match phase {
+        Some(phase) => format!("{} ({})", reason, phase.name()),
+        None => reason.to_string(),
+    }
+}
+
+// Logged for every write the upstream didn't get
+pub fn warn_lost_write(method: &Method, path: &str, reason: &str) {
+    tracing::warn!(
+        "WARNING: {} {} was not carried out (upstream {}); the client got an error, but may not show it",
+        method, path, reason
+    );
+}
+
+// Fallback strategies by path prefix
+#[derive(Default)]
+pub struct FallbackRoutes {
+    routes: Vec<(String, Fallback)>,
+}
+
+impl FallbackRoutes {
+    // Add a `PREFIX=STRATEGY` mapping, or a STRATEGY for every path
+    pub fn add(&mut self, mapping: &str) -> Result<(), String> {
+        let (prefix, strategy) = mapping.split_once('=').unwrap_or(("", mapping));
+        self.routes.push((prefix.to_string(), Fallback::parse(strategy)?));
+        Ok(())
+    }
+
+    // The longest matching prefix decides, then the upstream's default
+    pub fn for_path(&self, path: &str, default: Fallback) -> Fallback {
+        self.routes
+            .iter()
+            .filter(|(prefix, _)| path.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map_or(default, |(_, fallback)| *fallback)
+    }
+}
+
+// Names of the error folder by reason, optionally per language
+#[derive(Default)]
+pub struct EntryNames {
+    names: Vec<(Option<String>, String, String)>,
+}
+
+impl EntryNames {
+    // Add a `[LANG:]REASON=NAME` mapping, e.g. `de:timeout=ZEITÜBERSCHREITUNG`
+    pub fn add(&mut self, mapping: &str) -> Result<(), String> {
+        let (key, name) = mapping
+            .split_once('=')
+            .ok_or(format!("Invalid fallback name (expected [LANG:]REASON=NAME): {}", mapping))?;
+        let (language, reason) = match key.split_once(':') {
+            Some((language, reason)) => (Some(language.trim().to_ascii_lowercase()), reason),
+            None => (None, key),
+        };
+        let reason = reason.trim().to_ascii_lowercase();
+        if !REASONS.contains(&reason.as_str()) {
+            return Err(format!("Unknown fallback reason (expected timeout, closed, dns or disabled): {}", reason));
+        }
+        self.names.push((language, reason, name.to_string()));
+        Ok(())
+    }
+
+    // The name for the client's most preferred language that has one, then
+    // the one without a language
+    pub fn get(&self, reason: &str, accept_language: Option<&str>) -> Option<&str> {
+        let find = |language: Option<&str>| {
+            self.names
+                .iter()
+                .find(|(l, r, _)| r == reason && l.as_deref() == language)
+                .map(|(_, _, name)| name.as_str())
+        };
+        for tag in preferred_languages(accept_language.unwrap_or("")) {
+            // `de-AT` falls back to `de`
+            let primary = tag.split('-').next().unwrap_or("");
+            if let Some(name) = find(Some(&tag)).or_else(|| find(Some(primary))) {
+                return Some(name);
+            }
+        }
+        find(None)
+    }
+}
+
+// Files in the error folder, for all reasons or just one, such as a README
+// explaining the outage. Their content is read once at startup; {reason},
+// {phase} and the time placeholders of --fallback-name are expanded.
+#[derive(Default)]
+pub struct FallbackFiles {
+    files: Vec<(Option<String>, String, String)>,
+}
+
+impl FallbackFiles {
+    // Add a `[REASON:]NAME=FILE` mapping, e.g. `dns:README.txt=/etc/proxy/dns.txt`
+    pub fn add(&mut self, mapping: &str) -> Result<(), String> {
+        let (key, file) = mapping
+            .split_once('=')
+            .ok_or(format!("Invalid fallback file (expected [REASON:]NAME=FILE): {}", mapping))?;
+        let (reason, name) = match key.split_once(':') {
+            Some((reason, name)) if REASONS.contains(&reason.trim().to_ascii_lowercase().as_str()) => {
+                (Some(reason.trim().to_ascii_lowercase()), name.trim())
+            }
+            _ => (None, key.trim()),
+        };
+        if name.is_empty() || name.contains('/') || name == "." || name == ".." {
+            return Err(format!("--fallback-file needs a plain file name: {}", mapping));
+        }
+        let content = std::fs::read_to_string(file).map_err(|e| format!("Failed to read --fallback-file {}: {}", file, e))?;
+        self.files.push((reason, name.to_string(), content));
+        Ok(())
+    }
+
+    // The files shown for `reason`: those for just that reason win over
+    // those for all reasons of the same name
+    fn for_reason<'a>(&'a self, reason: &'a str) -> impl Iterator<Item = (&'a str, &'a str)> {
+        self.files
+            .iter()
+            .filter(move |(r, name, _)| match r {
+                Some(r) => r == reason,
+                None => !self.files.iter().any(|(r, n, _)| r.as_deref() == Some(reason) && n == name),
+            })
+            .map(|(_, name, content)| (name.as_str(), content.as_str()))
+    }
+}
+
+// Language tags of an Accept-Language header, most preferred first
+fn preferred_languages(header: &str) -> Vec<String> {
+    let mut tags: Vec<(f32, String)> = header
+        .split(',')
+        .filter_map(|item| {
+            let mut params = item.split(';');
+            let tag = params.next()?.trim().to_ascii_lowercase();
+            let q = params
+                .filter_map(|p| p.trim().strip_prefix("q="))
+                .next()
+                .and_then(|q| q.parse().ok())
+                .unwrap_or(1.0);
+            (!tag.is_empty() && tag != "*" && q > 0.0).then_some((q, tag))
+        })
+        .collect();
+    // Stable, so equally weighted tags keep their order
+    tags.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(std::cmp::Ordering::Equal));
+    tags.into_iter().map(|(_, tag)| tag).collect()
+}
+
+// Reasons and names end up in listings shown to users; keep them short
+const MAX_NAME: usize = 255;
+
+// Generate a response as if accessing an empty folder with a "TIMEOUT",
+// "CLOSED" or "DNS" folder, or one like "TIMEOUT-CONNECT" naming the phase;
+// requests for the folder itself or its --fallback-file files are answered
+// from it
+fn error_folder(reason: &str, phase: Option<Phase>, path: &str, folder: &Folder) -> Response<Body> {
+    let phase = phase.map_or("", Phase::name);
+    let described = match phase {
+        "" => xml::sanitize(reason, MAX_NAME),
+        phase => xml::sanitize(&format!("{}-{}", reason, phase), MAX_NAME),
+    };
+    let (segment, name) = match folder.name {
+        Some(name) => {
+            let name = xml::sanitize(&clock::expand(&name.replace("{phase}", phase)), MAX_NAME);
+            // A slash would nest the folder
+            (name.replace('/', "_"), name)
+        }
+        None => (described.clone(), described.to_uppercase()),
+    };
+    let tree = |base: &str| {
+        let mut tree = VirtualTree::at(base)
+            .collection(&segment)
+            .property(&segment, "displayname", &name);
+        for (file, content) in folder.files.for_reason(reason) {
+            let content = clock::expand(&content.replace("{reason}", reason).replace("{phase}", phase));
+            tree = tree.file(&format!("{}/{}", segment, file), content, virtual_files::content_type(file));
+        }
+        tree
+    };
+    // The path from the folder on, wherever the client found it
+    let decoded = local_dir::percent_decode(path).unwrap_or_default();
+    let segments: Vec<&str> = decoded.split('/').filter(|s| !s.is_empty()).collect();
+    if let Some(start) = segments.iter().rposition(|s| *s == segment) {
+        let base: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).take(start).collect();
+        let tree = tree(&format!("/{}", base.join("/")));
+        let within = segments[start..].join("/");
+        let answer = match folder.method.as_str() {
+            "PROPFIND" => tree.multistatus(&within, folder.depth, &PropRequest::ALL),
+            _ => tree.serve(folder.method, &within, &HeaderMap::new(), b""),
+        };
+        if let Some(answer) = answer {
+            return answer;
+        }
+    }
+    tree("/").multistatus(&segment, 0, &PropRequest::ALL).expect("just added")
+}
+
+// Only the requested collection itself, without any members
+fn empty_listing(path: &str) -> Response<Body> {
+    VirtualTree::at(path).multistatus("/", 0, &PropRequest::ALL).expect("the root always exists")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resolve::Resolver;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    fn retry_after_of(response: &Response<Body>) -> Option<&str> {
+        response.headers().get("Retry-After").map(|v| v.to_str().unwrap())
+    }
+
+    fn answer(fallback: Fallback, error: UpstreamError, retry_after: u64) -> Response<Body> {
+        let files = FallbackFiles::default();
+        let folder = Folder {
+            name: None,
+            files: &files,
+            method: &Method::GET,
+            depth: 0,
+            retry_after,
+        };
+        fallback.failed(error, "/a.txt", &folder)
+    }
+
+    #[test]
+    fn errors_say_when_to_retry() {
+        let timeout = UpstreamError::Timeout { phase: Phase::FirstByte };
+        let response = answer(Fallback::Error, timeout, 3);
+        assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+        assert_eq!(retry_after_of(&response), Some("3"));
+        let response = answer(Fallback::Unavailable, timeout, 4);
+        assert_eq!(retry_after_of(&response), Some("4"));
+        let response = answer(Fallback::Error, UpstreamError::ConnectRefused, 3);
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+        assert_eq!(retry_after_of(&response), None);
+    }
+
+    #[tokio::test]
+    async fn waits_for_the_next_probe() {
+        assert_eq!(retry_after(None), warm::CHECK_INTERVAL.as_secs());
+        let client = hyper::Client::builder().build(Resolver::new(&[]).unwrap().connector());
+        let uri = "http://127.0.0.1:1/".parse().unwrap();
+        let breaker = Arc::new(Breaker::new(1, uri, client, Duration::from_secs(1), Default::default()));
+        assert_eq!(retry_after(Some(&breaker)), warm::CHECK_INTERVAL.as_secs());
+        breaker.trip(UpstreamError::ConnectRefused);
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        assert_eq!(retry_after(Some(&breaker)), warm::CHECK_INTERVAL.as_secs() - 1);
+    }
+}
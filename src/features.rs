@@ -0,0 +1,36 @@
+// Optional subsystems the binary was built with, see [features] in
+// Cargo.toml. Their options are always accepted, so configurations stay
+// portable; using one that was left out is an error at startup.
+pub const ENABLED: &[&str] = &[
+    #[cfg(feature = "tls")]
+    "tls",
+    #[cfg(feature = "ldap")]
+    "ldap",
+    #[cfg(feature = "geoip")]
+    "geoip",
+    #[cfg(feature = "gzip")]
+    "gzip",
+    #[cfg(feature = "pam")]
+    "pam",
+    #[cfg(feature = "bench")]
+    "bench",
+    #[cfg(feature = "test-upstream")]
+    "test-upstream",
+    #[cfg(feature = "http3")]
+    "http3",
+];
+
+pub fn list() -> String {
+    if ENABLED.is_empty() {
+        "none".to_string()
+    } else {
+        ENABLED.join(", ")
+    }
+}
+
+// For options of a subsystem that was left out
+#[allow(dead_code)]
+pub fn missing(what: &str, feature: &str) -> ! {
+    eprintln!("Cannot use {}: built without the {} feature", what, feature);
+    std::process::exit(-1);
+}
@@ -0,0 +1,255 @@
+use std::cell::RefCell;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use hyper::header::{HeaderMap, AUTHORIZATION, DATE, SERVER, WWW_AUTHENTICATE};
+use hyper::{Body, Method, Request, StatusCode, Uri};
+
+use crate::capabilities::{Capabilities, Emulation};
+use crate::dates;
+use crate::json;
+use crate::multistatus;
+use crate::upstreams;
+use crate::ProxyConfig;
+
+// GET /admin/fingerprint: ask each upstream what it is and how it behaves,
+// and suggest the compatibility options that suit it. The probes only read:
+// OPTIONS and a Depth 0 PROPFIND of the share's root, whose headers and
+// dates are checked for the quirks the proxy can work around.
+
+const PROPFIND_BODY: &str = r#"<?xml version="1.0" encoding="utf-8"?><propfind xmlns="DAV:"><prop><resourcetype/><getlastmodified/><creationdate/></prop></propfind>"#;
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+// Clock differences below this are taken for network delay
+const SKEW: i64 = 5;
+
+// Servers by a substring of their Server header, first match wins
+const SERVERS: &[(&str, &str)] = &[
+    ("microsoft-iis", "IIS WebDAV"),
+    ("apache", "Apache mod_dav"),
+    ("nginx", "nginx dav module"),
+    ("lighttpd", "lighttpd mod_webdav"),
+    ("jetty", "Jetty"),
+    ("caddy", "Caddy webdav"),
+    ("rclone", "rclone serve webdav"),
+    ("synology", "Synology WebDAV Server"),
+];
+
+struct Answer {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: String,
+}
+
+// One request to the upstream, logging in as the proxy does
+async fn send(config: &ProxyConfig, method: Method, uri: &Uri, depth: bool) -> Result<Answer, String> {
+    let upstream_auth = config.settings.current().upstream_auth.clone();
+    let request = || {
+        let mut request = Request::builder().method(method.clone()).uri(uri.clone());
+        if let Some(auth) = &upstream_auth {
+            let target = uri.path_and_query().map_or("/", |p| p.as_str());
+            request = request.header(AUTHORIZATION, auth.authorization(&method, target));
+        }
+        let body = match depth {
+            true => {
+                request = request.header("Depth", "0").header("Content-Type", "application/xml; charset=utf-8");
+                Body::from(PROPFIND_BODY)
+            }
+            false => Body::empty(),
+        };
+        request.body(body).expect("request builder")
+    };
+    let ask = |request| async {
+        let response = match tokio::time::timeout(TIMEOUT, config.client.request(request)).await {
+            Ok(Ok(response)) => response,
+            Ok(Err(e)) => return Err(e.to_string()),
+            Err(_) => return Err(format!("no answer within {} seconds", TIMEOUT.as_secs())),
+        };
+        let (parts, body) = response.into_parts();
+        let body = match tokio::time::timeout(TIMEOUT, hyper::body::to_bytes(body)).await {
+            Ok(Ok(body)) => String::from_utf8_lossy(&body).into_owned(),
+            _ => String::new(),
+        };
+        Ok(Answer {
+            status: parts.status,
+            headers: parts.headers,
+            body,
+        })
+    };
+    let mut answer = ask(request()).await?;
+    // The first answer may be a Digest challenge
+    if let Some(auth) = &upstream_auth {
+        if answer.status == StatusCode::UNAUTHORIZED && auth.challenged(&answer.headers) {
+            answer = ask(request()).await?;
+        }
+    }
+    Ok(answer)
+}
+
+fn header<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers.get(name).and_then(|v| v.to_str().ok())
+}
+
+// What the headers and DAV classes give away about the server
+fn identify(headers: &HeaderMap, capabilities: &Capabilities) -> String {
+    let server = header(headers, SERVER.as_str()).unwrap_or("");
+    let classes = capabilities.classes.join(",").to_ascii_lowercase();
+    if headers.contains_key("microsoftsharepointteamservices") {
+        return "SharePoint".to_string();
+    }
+    if classes.contains("nextcloud") || classes.contains("nc-") {
+        return "Nextcloud".to_string();
+    }
+    if classes.contains("oc-") || headers.contains_key("oc-fileid") {
+        return "ownCloud".to_string();
+    }
+    if headers.contains_key("x-sabre-version") {
+        return "SabreDAV".to_string();
+    }
+    SERVERS
+        .iter()
+        .find(|(needle, _)| server.to_ascii_lowercase().contains(needle))
+        .map_or_else(|| if server.is_empty() { "unknown" } else { server }, |(_, name)| name)
+        .to_string()
+}
+
+// Authentication schemes the upstream offers
+fn schemes(headers: &HeaderMap) -> Vec<String> {
+    headers
+        .get_all(WWW_AUTHENTICATE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .filter_map(|v| v.split_whitespace().next())
+        .map(|scheme| scheme.trim_end_matches(',').to_string())
+        .collect()
+}
+
+// Dates in the PROPFIND answer that aren't in the form clients expect
+fn odd_dates(body: &str) -> Vec<String> {
+    let odd = RefCell::new(Vec::new());
+    multistatus::map_elements(body, |name, text| {
+        let text = text.trim();
+        let canonical = match name {
+            "getlastmodified" => dates::parse(text).map(dates::format_http),
+            "creationdate" => dates::parse(text).map(dates::format_rfc3339),
+            _ => return None,
+        };
+        if canonical.as_deref() != Some(text) {
+            odd.borrow_mut().push(format!("{}: {}", name, text));
+        }
+        None
+    });
+    odd.into_inner()
+}
+
+struct Report {
+    probes: Vec<String>,
+    suggestions: Vec<String>,
+}
+
+impl Report {
+    fn probe(&mut self, probe: &str, result: &str) {
+        self.probes.push(json::object(&[("probe", json::string(probe)), ("result", json::string(result))]));
+    }
+
+    fn suggest(&mut self, option: &str, reason: &str) {
+        self.suggestions.push(json::object(&[("option", json::string(option)), ("reason", json::string(reason))]));
+    }
+}
+
+async fn upstream(config: &ProxyConfig, name: &str, upstream: &upstreams::Upstream) -> String {
+    let uri = upstreams::root_uri(&upstream.uri, &upstream.base_path);
+    let mut report = Report {
+        probes: Vec::new(),
+        suggestions: Vec::new(),
+    };
+    let fields = |report: Report, reachable: bool, server: Option<&str>, implementation: &str, capabilities: Option<&Capabilities>| {
+        json::object(&[
+            ("name", json::string(name)),
+            ("uri", json::string(&uri.to_string())),
+            ("reachable", reachable.to_string()),
+            ("server", server.map_or("null".to_string(), json::string)),
+            ("implementation", json::string(implementation)),
+            ("capabilities", capabilities.map_or("null".to_string(), |c| c.to_json())),
+            ("probes", json::array(&report.probes)),
+            ("suggestions", json::array(&report.suggestions)),
+        ])
+    };
+
+    let options = match send(config, Method::OPTIONS, &uri, false).await {
+        Ok(options) => options,
+        Err(e) => {
+            report.probe("OPTIONS", &e);
+            return fields(report, false, None, "unknown", None);
+        }
+    };
+    report.probe("OPTIONS", &options.status.to_string());
+    let capabilities = Capabilities::from_headers(&options.headers);
+    let implementation = identify(&options.headers, &capabilities);
+    let server = header(&options.headers, SERVER.as_str()).map(str::to_string);
+
+    let schemes = schemes(&options.headers);
+    if !schemes.is_empty() {
+        report.probe("authentication", &schemes.join(", "));
+    }
+    let password = schemes.iter().any(|s| s.eq_ignore_ascii_case("basic") || s.eq_ignore_ascii_case("digest"));
+    if options.status == StatusCode::UNAUTHORIZED && config.settings.current().upstream_auth.is_none() && password {
+        report.suggest("--upstream-user", "the upstream wants a login; unless clients should each log in themselves, give it one");
+    }
+    let connection_auth = schemes.iter().any(|s| s.eq_ignore_ascii_case("ntlm") || s.eq_ignore_ascii_case("negotiate"));
+    if connection_auth && !config.pin_connections {
+        report.suggest("--pin-connections", "NTLM and Negotiate authenticate a connection, not a request");
+    }
+    if implementation == "SharePoint" && !config.sharepoint {
+        report.suggest("--sharepoint", "the upstream is SharePoint");
+    }
+    if options.status.is_success() {
+        if capabilities.classes.is_empty() {
+            report.probe("DAV classes", "none, the upstream may not speak WebDAV at this path");
+        }
+        if !capabilities.locking() {
+            report.probe("locking", "not supported");
+            if config.emulate_locks == Emulation::Off {
+                report.suggest("--emulate-locks auto", "Windows and macOS mount shares that can't lock read-only");
+            }
+        }
+    }
+
+    match send(config, Method::from_bytes(b"PROPFIND").expect("method"), &uri, true).await {
+        Ok(propfind) => {
+            report.probe("PROPFIND", &propfind.status.to_string());
+            let odd = odd_dates(&propfind.body);
+            if !odd.is_empty() {
+                report.probe("dates", &odd.join("; "));
+                if !config.dates.normalize {
+                    report.suggest("--normalize-dates", "the upstream dates properties in forms some clients misread");
+                }
+            }
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs() as i64);
+            if let Some(date) = header(&propfind.headers, DATE.as_str()).and_then(dates::parse) {
+                let skew = date - now;
+                if skew.abs() > SKEW {
+                    report.probe("clock", &format!("{} seconds {} the proxy's", skew.abs(), if skew > 0 { "ahead of" } else { "behind" }));
+                }
+                if skew > SKEW && config.dates.max_future.is_none() {
+                    report.suggest(
+                        "--max-future-date 60",
+                        "the upstream's clock runs fast, so its files look changed in the future to sync clients",
+                    );
+                }
+            }
+        }
+        Err(e) => report.probe("PROPFIND", &e),
+    }
+    fields(report, true, server.as_deref(), &implementation, Some(&capabilities))
+}
+
+// The report on every upstream
+pub async fn report(config: &ProxyConfig) -> String {
+    let mut upstreams = Vec::new();
+    let settings = config.settings.current();
+    for (name, upstream) in settings.upstreams.iter() {
+        upstreams.push(self::upstream(config, name, upstream).await);
+    }
+    json::array(&upstreams)
+}
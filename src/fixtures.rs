@@ -0,0 +1,410 @@
+use std::convert::Infallible;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+
+use hyper::header::{HeaderMap, HeaderName, HeaderValue, HOST};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+
+use crate::base_path;
+use crate::yaml::{self, Yaml};
+use crate::ProxyConfig;
+
+// Fixtures pinning down what the proxy answers itself: the virtual files
+// and listings, the rewriting of hrefs and headers, and the fallback. Each
+// one gives a request, what the upstream answers it with (a stand-in
+// server plays the upstream), and what the client should get back and the
+// upstream should have been sent:
+//
+//     - name: Depth 1 listing of the root shows the stats file
+//       request:
+//         method: PROPFIND
+//         path: /
+//         headers:
+//           Depth: "1"
+//       upstream:
+//         status: 207
+//         headers:
+//           Content-Type: application/xml
+//         body: |
+//           <?xml version="1.0"?><D:multistatus xmlns:D="DAV:">...
+//       expect:
+//         status: 207
+//         contains:
+//           - <D:href>/.proxy-stats</D:href>
+//
+// `upstream: down` drops the connection instead, for the fallback; without
+// `upstream` it answers 404. `{upstream}` in its answer and under
+// `forwarded` stands for its host and port, for absolute hrefs. Under `expect`, `headers` must match exactly,
+// `contains` and `excludes` look at the body, `body` is all of it, and
+// `forwarded` checks the method, path and headers the upstream received.
+// Requests go through the same handling as a client's, in process. The
+// files in fixtures/ run as part of `cargo test`, each with the command
+// line on its `# args:` line.
+
+/// A request, or an answer without its status.
+#[derive(Clone, Default)]
+pub struct Exchange {
+    pub method: String,
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+#[derive(Clone)]
+enum Upstream {
+    Answer { status: StatusCode, exchange: Exchange },
+    Down,
+}
+
+#[derive(Default)]
+struct Expect {
+    status: Option<StatusCode>,
+    headers: Vec<(String, String)>,
+    contains: Vec<String>,
+    excludes: Vec<String>,
+    body: Option<String>,
+    forwarded: Option<Exchange>,
+}
+
+/// A request and what the proxy should make of it, from a YAML fixture
+/// file (see `--verify`).
+pub struct Fixture {
+    name: String,
+    request: Exchange,
+    upstream: Option<Upstream>,
+    expect: Expect,
+}
+
+/// How a fixture went: passed unless there are problems.
+pub struct Outcome {
+    pub name: String,
+    pub problems: Vec<String>,
+}
+
+fn text(node: &Yaml, what: &str) -> Result<String, String> {
+    node.as_str().map(str::to_string).ok_or(format!("{} must be a single value", what))
+}
+
+fn list(node: &Yaml, what: &str) -> Result<Vec<String>, String> {
+    match node {
+        Yaml::List(items) => items.iter().map(|item| text(item, what)).collect(),
+        Yaml::Scalar(s) => Ok(vec![s.clone()]),
+        Yaml::Map(_) => Err(format!("{} must be a list", what)),
+    }
+}
+
+fn headers(node: &Yaml, what: &str) -> Result<Vec<(String, String)>, String> {
+    let Yaml::Map(entries) = node else {
+        return Err(format!("{} must map header names to values", what));
+    };
+    entries
+        .iter()
+        .map(|(name, value)| {
+            HeaderName::from_bytes(name.as_bytes()).map_err(|_| format!("{}: invalid header name {}", what, name))?;
+            Ok((name.clone(), text(value, &format!("{} {}", what, name))?))
+        })
+        .collect()
+}
+
+fn status(node: &Yaml, what: &str) -> Result<StatusCode, String> {
+    text(node, what)?
+        .parse::<u16>()
+        .ok()
+        .and_then(|code| StatusCode::from_u16(code).ok())
+        .ok_or(format!("{} must be an HTTP status code", what))
+}
+
+// The keys of a mapping, each checked against what it may hold
+fn fields<'a>(node: &'a Yaml, what: &str, known: &[&str]) -> Result<&'a [(String, Yaml)], String> {
+    let Yaml::Map(entries) = node else {
+        return Err(format!("{} must be a mapping", what));
+    };
+    match entries.iter().find(|(key, _)| !known.contains(&key.as_str())) {
+        Some((key, _)) => Err(format!("unknown key {} in {} (expected {})", key, what, known.join(", "))),
+        None => Ok(entries),
+    }
+}
+
+fn exchange(node: &Yaml, what: &str) -> Result<Exchange, String> {
+    let mut exchange = Exchange::default();
+    for (key, value) in fields(node, what, &["method", "path", "headers", "body"])? {
+        match key.as_str() {
+            "method" => exchange.method = text(value, "method")?,
+            "path" => exchange.path = text(value, "path")?,
+            "headers" => exchange.headers = headers(value, &format!("{} headers", what))?,
+            _ => exchange.body = text(value, "body")?,
+        }
+    }
+    Ok(exchange)
+}
+
+impl Fixture {
+    fn parse(node: &Yaml) -> Result<Self, String> {
+        let entries = fields(node, "a fixture", &["name", "request", "upstream", "expect"])?;
+        let name = node.get("name").map(|n| text(n, "name")).transpose()?.unwrap_or_default();
+        let named = |e: String| if name.is_empty() { e } else { format!("{}: {}", name, e) };
+        let mut fixture = Fixture {
+            name: name.clone(),
+            request: Exchange::default(),
+            upstream: None,
+            expect: Expect::default(),
+        };
+        for (key, value) in entries {
+            match key.as_str() {
+                "request" => fixture.request = exchange(value, "request").map_err(named)?,
+                "upstream" if value.as_str() == Some("down") => fixture.upstream = Some(Upstream::Down),
+                "upstream" => {
+                    let status = match value.get("status") {
+                        Some(node) => status(node, "upstream status").map_err(named)?,
+                        None => StatusCode::OK,
+                    };
+                    let known = ["status", "headers", "body"];
+                    fields(value, "upstream (or `upstream: down`)", &known).map_err(named)?;
+                    let mut answer = Exchange::default();
+                    if let Some(node) = value.get("headers") {
+                        answer.headers = headers(node, "upstream headers").map_err(named)?;
+                    }
+                    if let Some(node) = value.get("body") {
+                        answer.body = text(node, "upstream body").map_err(named)?;
+                    }
+                    fixture.upstream = Some(Upstream::Answer { status, exchange: answer });
+                }
+                "expect" => {
+                    let known = ["status", "headers", "contains", "excludes", "body", "forwarded"];
+                    for (key, value) in fields(value, "expect", &known).map_err(named)? {
+                        let expect = &mut fixture.expect;
+                        match key.as_str() {
+                            "status" => expect.status = Some(status(value, "expected status").map_err(named)?),
+                            "headers" => expect.headers = headers(value, "expected headers").map_err(named)?,
+                            "contains" => expect.contains = list(value, "contains").map_err(named)?,
+                            "excludes" => expect.excludes = list(value, "excludes").map_err(named)?,
+                            "body" => expect.body = Some(text(value, "expected body").map_err(named)?),
+                            _ => expect.forwarded = Some(exchange(value, "forwarded").map_err(named)?),
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        if fixture.request.path.is_empty() {
+            return Err(named("the request needs a path".to_string()));
+        }
+        if fixture.request.method.is_empty() {
+            fixture.request.method = "GET".to_string();
+        }
+        Method::from_bytes(fixture.request.method.as_bytes())
+            .map_err(|_| named(format!("invalid method {}", fixture.request.method)))?;
+        Ok(fixture)
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The request the fixture sends, its method set.
+    pub fn request(&self) -> &Exchange {
+        &self.request
+    }
+
+    /// The fixtures of a YAML file, a list of them.
+    pub fn parse_all(text: &str) -> Result<Vec<Fixture>, String> {
+        let Yaml::List(items) = yaml::parse(text)? else {
+            return Err("The fixtures must be a list, one `- name: ...` entry each".to_string());
+        };
+        items
+            .iter()
+            .enumerate()
+            .map(|(i, item)| Fixture::parse(item).map_err(|e| format!("fixture {}: {}", i + 1, e)))
+            .collect()
+    }
+}
+
+#[derive(Default)]
+struct Script {
+    // What the current fixture has the upstream answer
+    upstream: Option<Upstream>,
+    // What the upstream was last sent for it
+    received: Option<Exchange>,
+    // Whether the current fixture's own request is an OPTIONS
+    options: bool,
+}
+
+/// A stand-in upstream answering as the fixture being verified says.
+pub struct Mock {
+    addr: SocketAddr,
+    script: Arc<Mutex<Script>>,
+}
+
+async fn play(script: Arc<Mutex<Script>>, addr: SocketAddr, req: Request<Body>) -> Result<Response<Body>, io::Error> {
+    let (parts, body) = req.into_parts();
+    // Capability probes get a plain DAV server's answer
+    if parts.method == Method::OPTIONS && !script.lock().unwrap().options {
+        return Ok(Response::builder()
+            .header("DAV", "1, 2")
+            .header("Allow", "OPTIONS, GET, HEAD, PUT, DELETE, PROPFIND, PROPPATCH, MKCOL, COPY, MOVE, LOCK, UNLOCK")
+            .body(Body::empty())
+            .expect("response builder"));
+    }
+    let body = hyper::body::to_bytes(body).await.map_err(io::Error::other)?;
+    let mut script = script.lock().unwrap();
+    script.received = Some(Exchange {
+        method: parts.method.to_string(),
+        path: parts.uri.path_and_query().map_or("/", |pq| pq.as_str()).to_string(),
+        headers: parts
+            .headers
+            .iter()
+            .map(|(name, value)| (name.to_string(), String::from_utf8_lossy(value.as_bytes()).into_owned()))
+            .collect(),
+        body: String::from_utf8_lossy(&body).into_owned(),
+    });
+    match &script.upstream {
+        // An error from the service drops the connection
+        Some(Upstream::Down) => Err(io::Error::other("down")),
+        Some(Upstream::Answer { status, exchange }) => {
+            let own = |text: &str| text.replace("{upstream}", &addr.to_string());
+            let mut response = Response::builder().status(*status);
+            for (name, value) in &exchange.headers {
+                response = response.header(name.as_str(), own(value));
+            }
+            Ok(response.body(Body::from(own(&exchange.body))).map_err(io::Error::other)?)
+        }
+        None => Ok(Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::empty())
+            .expect("response builder")),
+    }
+}
+
+impl Mock {
+    /// Listen on a free local port; `remote()` is the upstream to give
+    /// the proxy.
+    pub async fn start() -> io::Result<Mock> {
+        let listener = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+        listener.set_nonblocking(true)?;
+        let addr = listener.local_addr()?;
+        let script = Arc::new(Mutex::new(Script::default()));
+        let shared = script.clone();
+        let service = make_service_fn(move |_| {
+            let script = shared.clone();
+            async move { Ok::<_, Infallible>(service_fn(move |req| play(script.clone(), addr, req))) }
+        });
+        let server = Server::from_tcp(listener).map_err(io::Error::other)?.serve(service);
+        tokio::spawn(server);
+        Ok(Mock { addr, script })
+    }
+
+    pub fn remote(&self) -> String {
+        self.addr.to_string()
+    }
+
+    /// `remote`, a REMOTE as on the command line, with the stand-in in
+    /// place of its server, keeping its mount and base path.
+    pub fn stand_in(&self, remote: &str) -> Result<String, String> {
+        // As in Upstreams::parse, a mount has no colon unless it starts with `/`
+        let (mount, remote) = match remote.split_once('=') {
+            Some((mount, remote)) if mount.starts_with('/') || !mount.contains(':') => (format!("{}=", mount), remote),
+            _ => (String::new(), remote),
+        };
+        let (_, _, base_path) = base_path::split_remote(remote)?;
+        Ok(format!("{}http://{}{}", mount, self.addr, base_path.as_str()))
+    }
+}
+
+fn check_headers(problems: &mut Vec<String>, what: &str, expected: &[(String, String)], got: &HeaderMap) {
+    for (name, value) in expected {
+        let got: Vec<&str> = got.get_all(name.as_str()).iter().filter_map(|v| v.to_str().ok()).collect();
+        if !got.contains(&value.as_str()) {
+            let got = if got.is_empty() { "none".to_string() } else { got.join(", ") };
+            problems.push(format!("{} {}: expected {}, got {}", what, name, value, got));
+        }
+    }
+}
+
+// Send one fixture's request through the proxy; what was wrong, if anything
+async fn verify_one(config: &Arc<ProxyConfig>, mock: &Mock, fixture: &Fixture) -> Result<Vec<String>, String> {
+    {
+        let mut script = mock.script.lock().unwrap();
+        script.upstream = fixture.upstream.clone();
+        script.received = None;
+        script.options = fixture.request.method == "OPTIONS";
+    }
+    let request = &fixture.request;
+    let mut builder = Request::builder().method(request.method.as_str()).uri(request.path.as_str());
+    for (name, value) in &request.headers {
+        builder = builder.header(name.as_str(), value.as_str());
+    }
+    let mut req = builder.body(Body::from(request.body.clone())).map_err(|e| e.to_string())?;
+    if !req.headers().contains_key(HOST) {
+        req.headers_mut().insert(HOST, HeaderValue::from_static("localhost"));
+    }
+    let remote = SocketAddr::from((Ipv4Addr::LOCALHOST, 0));
+    let response = crate::answer(req, config.clone(), None, remote).await.map_err(|e| e.to_string())?;
+    let (parts, body) = response.into_parts();
+    let body = hyper::body::to_bytes(body).await.map_err(|e| e.to_string())?;
+    let body = String::from_utf8_lossy(&body);
+
+    let expect = &fixture.expect;
+    let mut problems = Vec::new();
+    if let Some(status) = expect.status.filter(|status| *status != parts.status) {
+        problems.push(format!("status: expected {}, got {}", status, parts.status));
+    }
+    check_headers(&mut problems, "header", &expect.headers, &parts.headers);
+    for needle in &expect.contains {
+        if !body.contains(needle.as_str()) {
+            problems.push(format!("body lacks {}", needle));
+        }
+    }
+    for needle in &expect.excludes {
+        if body.contains(needle.as_str()) {
+            problems.push(format!("body has {}", needle));
+        }
+    }
+    if let Some(expected) = expect.body.as_ref().filter(|expected| **expected != body) {
+        problems.push(format!("body: expected\n{}\ngot\n{}", expected, body));
+    }
+    if let Some(forwarded) = &expect.forwarded {
+        let received = mock.script.lock().unwrap().received.take();
+        let own = |text: &str| text.replace("{upstream}", &mock.remote());
+        match received {
+            None => problems.push("the upstream received nothing".to_string()),
+            Some(received) => {
+                let forwarded = Exchange {
+                    method: forwarded.method.clone(),
+                    path: own(&forwarded.path),
+                    headers: forwarded.headers.iter().map(|(name, value)| (name.clone(), own(value))).collect(),
+                    body: own(&forwarded.body),
+                };
+                if !forwarded.method.is_empty() && forwarded.method != received.method {
+                    problems.push(format!("forwarded method: expected {}, got {}", forwarded.method, received.method));
+                }
+                if !forwarded.path.is_empty() && forwarded.path != received.path {
+                    problems.push(format!("forwarded path: expected {}, got {}", forwarded.path, received.path));
+                }
+                let mut headers = HeaderMap::new();
+                for (name, value) in &received.headers {
+                    if let (Ok(name), Ok(value)) = (HeaderName::from_bytes(name.as_bytes()), HeaderValue::from_str(value)) {
+                        headers.append(name, value);
+                    }
+                }
+                check_headers(&mut problems, "forwarded header", &forwarded.headers, &headers);
+                if !forwarded.body.is_empty() && forwarded.body != received.body {
+                    problems.push(format!("forwarded body: expected\n{}\ngot\n{}", forwarded.body, received.body));
+                }
+            }
+        }
+    }
+    Ok(problems)
+}
+
+// Run every fixture in turn
+pub async fn verify(config: &Arc<ProxyConfig>, mock: &Mock, fixtures: &[Fixture]) -> Vec<Outcome> {
+    let mut outcomes = Vec::new();
+    for (i, fixture) in fixtures.iter().enumerate() {
+        let name = if fixture.name.is_empty() { format!("fixture {}", i + 1) } else { fixture.name.clone() };
+        let problems = verify_one(config, mock, fixture).await.unwrap_or_else(|e| vec![e]);
+        outcomes.push(Outcome { name, problems });
+    }
+    outcomes
+}
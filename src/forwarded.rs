@@ -0,0 +1,81 @@
+use std::net::IpAddr;
+
+use hyper::header::{HeaderMap, HeaderValue, HOST, VIA};
+use hyper::Version;
+
+use crate::network::Network;
+
+// Tells the upstream about the client behind the proxy, which it otherwise
+// takes for the client itself in its logs and per-IP limits: the client
+// address is appended to X-Forwarded-For, X-Forwarded-Proto and
+// X-Forwarded-Host say how the client reached the proxy, and the proxy adds
+// itself to Via. With `strip`, forwarding headers sent by clients other
+// than trusted proxies are dropped first, so a client can't pass itself off
+// as another one.
+
+const STRIPPED: [&str; 5] = [
+    "forwarded",
+    "x-forwarded-for",
+    "x-forwarded-proto",
+    "x-forwarded-host",
+    "x-real-ip",
+];
+
+pub struct Forwarding {
+    // The scheme clients use
+    proto: &'static str,
+    strip: bool,
+    // Clients whose forwarding headers are kept despite `strip`
+    trusted: Vec<Network>,
+}
+
+fn protocol_version(version: Version) -> &'static str {
+    match version {
+        Version::HTTP_09 => "0.9",
+        Version::HTTP_10 => "1.0",
+        Version::HTTP_2 => "2",
+        Version::HTTP_3 => "3",
+        _ => "1.1",
+    }
+}
+
+// Add `value` to a comma-separated list header
+fn append(headers: &mut HeaderMap, name: &'static str, value: &str) {
+    let list = match headers.get(name).and_then(|v| v.to_str().ok()) {
+        Some(earlier) if !earlier.trim().is_empty() => format!("{}, {}", earlier, value),
+        _ => value.to_string(),
+    };
+    if let Ok(list) = HeaderValue::from_str(&list) {
+        headers.insert(name, list);
+    }
+}
+
+impl Forwarding {
+    pub fn new(https: bool, strip: bool, trusted: Vec<Network>) -> Self {
+        Forwarding {
+            proto: if https { "https" } else { "http" },
+            strip,
+            trusted,
+        }
+    }
+
+    pub fn apply(&self, client: IpAddr, version: Version, headers: &mut HeaderMap) {
+        if self.strip && !self.trusted.iter().any(|network| network.contains(client)) {
+            for name in STRIPPED {
+                headers.remove(name);
+            }
+        }
+        append(headers, "x-forwarded-for", &client.to_canonical().to_string());
+        if !headers.contains_key("x-forwarded-proto") {
+            headers.insert("x-forwarded-proto", HeaderValue::from_static(self.proto));
+        }
+        if let Some(host) = headers.get(HOST).cloned() {
+            headers.entry("x-forwarded-host").or_insert(host);
+        }
+        append(
+            headers,
+            VIA.as_str(),
+            &format!("{} {}", protocol_version(version), env!("CARGO_PKG_NAME")),
+        );
+    }
+}
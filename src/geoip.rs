@@ -0,0 +1,252 @@
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+
+// Country lookups in a MaxMind DB (GeoLite2-Country, GeoIP2-City, ...),
+// read straight from the file format:
+// https://maxmind.github.io/MaxMind-DB/
+pub struct GeoIp {
+    data: Vec<u8>,
+    node_count: usize,
+    record_size: usize,
+    ip_version: u64,
+    // Where the data section starts
+    data_start: usize,
+}
+
+// The subset of the MMDB data types lookups need
+enum Value {
+    Map(BTreeMap<String, Value>),
+    String(String),
+    Uint(u64),
+    Other,
+}
+
+const METADATA_MARKER: &[u8] = b"\xAB\xCD\xEFMaxMind.com";
+
+// Code used for addresses the database doesn't know (e.g. private ones)
+pub const UNKNOWN: &str = "--";
+
+struct Decoder<'a> {
+    data: &'a [u8],
+    // Offsets of pointers are relative to this
+    base: usize,
+}
+
+impl Decoder<'_> {
+    fn byte(&self, pos: usize) -> Result<usize, String> {
+        self.data.get(pos).map(|&b| b as usize).ok_or("Truncated MaxMind DB".to_string())
+    }
+
+    fn uint(&self, pos: usize, len: usize) -> Result<u64, String> {
+        let mut value = 0u64;
+        for i in 0..len {
+            value = value << 8 | self.byte(pos + i)? as u64;
+        }
+        Ok(value)
+    }
+
+    // Decode the value at `pos`, returning it and the position after it
+    fn decode(&self, pos: usize, depth: u32) -> Result<(Value, usize), String> {
+        if depth > 32 {
+            return Err("MaxMind DB data nests too deeply".to_string());
+        }
+        let control = self.byte(pos)?;
+        let mut pos = pos + 1;
+        let mut kind = control >> 5;
+        if kind == 1 {
+            // Pointer: the size bits hold part of the offset
+            let ss = (control >> 3) & 3;
+            let vvv = (control & 7) as u64;
+            let (offset, len) = match ss {
+                0 => (vvv << 8 | self.uint(pos, 1)?, 1),
+                1 => ((vvv << 16 | self.uint(pos, 2)?) + 2048, 2),
+                2 => ((vvv << 24 | self.uint(pos, 3)?) + 526336, 3),
+                _ => (self.uint(pos, 4)?, 4),
+            };
+            let (value, _) = self.decode(self.base + offset as usize, depth + 1)?;
+            return Ok((value, pos + len));
+        }
+        if kind == 0 {
+            kind = 7 + self.byte(pos)?;
+            pos += 1;
+        }
+        let mut size = control & 0x1f;
+        match size {
+            29 => {
+                size = 29 + self.byte(pos)?;
+                pos += 1;
+            }
+            30 => {
+                size = 285 + self.uint(pos, 2)? as usize;
+                pos += 2;
+            }
+            31 => {
+                size = 65821 + self.uint(pos, 3)? as usize;
+                pos += 3;
+            }
+            _ => {}
+        }
+        match kind {
+            // UTF-8 string
+            2 => {
+                let bytes = self.data.get(pos..pos + size).ok_or("Truncated MaxMind DB")?;
+                Ok((Value::String(String::from_utf8_lossy(bytes).into_owned()), pos + size))
+            }
+            // Map
+            7 => {
+                let mut map = BTreeMap::new();
+                for _ in 0..size {
+                    let (key, next) = self.decode(pos, depth + 1)?;
+                    let (value, next) = self.decode(next, depth + 1)?;
+                    pos = next;
+                    if let Value::String(key) = key {
+                        map.insert(key, value);
+                    }
+                }
+                Ok((Value::Map(map), pos))
+            }
+            // Array
+            11 => {
+                for _ in 0..size {
+                    pos = self.decode(pos, depth + 1)?.1;
+                }
+                Ok((Value::Other, pos))
+            }
+            // Double and float have fixed sizes
+            3 => Ok((Value::Other, pos + 8)),
+            15 => Ok((Value::Other, pos + 4)),
+            // Booleans keep their value in the size bits
+            14 => Ok((Value::Other, pos)),
+            // Unsigned integers, big-endian in `size` bytes
+            5 | 6 | 9 | 10 if size <= 8 => Ok((Value::Uint(self.uint(pos, size)?), pos + size)),
+            // Bytes, int32 and huge uint128s
+            4 | 5 | 6 | 8 | 9 | 10 => Ok((Value::Other, pos + size)),
+            _ => Err(format!("Unsupported MaxMind DB data type {}", kind)),
+        }
+    }
+}
+
+impl GeoIp {
+    pub fn load(path: &str) -> Result<Self, String> {
+        let data = std::fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+        let marker = data
+            .windows(METADATA_MARKER.len())
+            .rposition(|w| w == METADATA_MARKER)
+            .ok_or(format!("{}: not a MaxMind DB", path))?;
+        let metadata_start = marker + METADATA_MARKER.len();
+        let decoder = Decoder { data: &data, base: metadata_start };
+        let metadata = match decoder.decode(metadata_start, 0)?.0 {
+            Value::Map(map) => map,
+            _ => return Err(format!("{}: invalid metadata", path)),
+        };
+        let integer = |name: &str| match metadata.get(name) {
+            Some(Value::Uint(n)) => Ok(*n),
+            _ => Err(format!("{}: metadata lacks {}", path, name)),
+        };
+        let node_count = integer("node_count")? as usize;
+        let record_size = integer("record_size")? as usize;
+        let ip_version = integer("ip_version")?;
+        if ![24, 28, 32].contains(&record_size) {
+            return Err(format!("{}: unsupported record size {}", path, record_size));
+        }
+        let data_start = node_count * record_size * 2 / 8 + 16;
+        Ok(GeoIp {
+            data,
+            node_count,
+            record_size,
+            ip_version,
+            data_start,
+        })
+    }
+
+    fn record(&self, node: usize, bit: usize) -> usize {
+        let base = node * self.record_size * 2 / 8;
+        let d = &self.data;
+        let b = |i: usize| d.get(base + i).copied().unwrap_or(0) as usize;
+        match (self.record_size, bit) {
+            (24, 0) => b(0) << 16 | b(1) << 8 | b(2),
+            (24, _) => b(3) << 16 | b(4) << 8 | b(5),
+            (28, 0) => (b(3) >> 4) << 24 | b(0) << 16 | b(1) << 8 | b(2),
+            (28, _) => (b(3) & 0x0f) << 24 | b(4) << 16 | b(5) << 8 | b(6),
+            (_, 0) => b(0) << 24 | b(1) << 16 | b(2) << 8 | b(3),
+            _ => b(4) << 24 | b(5) << 16 | b(6) << 8 | b(7),
+        }
+    }
+
+    // ISO 3166 country code of an address, or UNKNOWN
+    pub fn country(&self, ip: IpAddr) -> String {
+        self.lookup(ip).unwrap_or_else(|| UNKNOWN.to_string())
+    }
+
+    fn lookup(&self, ip: IpAddr) -> Option<String> {
+        // Clients of a dual-stack listener show up as ::ffff:a.b.c.d
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+            ip => ip,
+        };
+        let bits: Vec<u8> = match ip {
+            IpAddr::V4(v4) if self.ip_version == 6 => {
+                // IPv4 lives at ::a.b.c.d in IPv6 databases
+                let mut bytes = vec![0u8; 12];
+                bytes.extend_from_slice(&v4.octets());
+                bytes
+            }
+            IpAddr::V4(v4) => v4.octets().to_vec(),
+            IpAddr::V6(_) if self.ip_version == 4 => return None,
+            IpAddr::V6(v6) => v6.octets().to_vec(),
+        };
+        let mut node = 0;
+        for i in 0..bits.len() * 8 {
+            if node >= self.node_count {
+                break;
+            }
+            let bit = (bits[i / 8] >> (7 - i % 8)) & 1;
+            node = self.record(node, bit as usize);
+        }
+        if node <= self.node_count {
+            return None;
+        }
+        let offset = self.data_start + (node - self.node_count - 16);
+        let decoder = Decoder {
+            data: &self.data,
+            base: self.data_start,
+        };
+        let record = match decoder.decode(offset, 0).ok()?.0 {
+            Value::Map(map) => map,
+            _ => return None,
+        };
+        ["country", "registered_country"].iter().find_map(|key| match record.get(*key) {
+            Some(Value::Map(country)) => match country.get("iso_code") {
+                Some(Value::String(code)) => Some(code.clone()),
+                _ => None,
+            },
+            _ => None,
+        })
+    }
+}
+
+// Which countries may use the proxy
+pub struct GeoPolicy {
+    pub db: GeoIp,
+    // Only these countries, if given
+    pub allow: Option<Vec<String>>,
+    pub deny: Vec<String>,
+}
+
+fn codes(list: &str) -> Vec<String> {
+    list.split(',').map(|c| c.trim().to_ascii_uppercase()).filter(|c| !c.is_empty()).collect()
+}
+
+impl GeoPolicy {
+    pub fn new(db: GeoIp, allow: Option<&str>, deny: Option<&str>) -> Self {
+        GeoPolicy {
+            db,
+            allow: allow.map(codes),
+            deny: deny.map(codes).unwrap_or_default(),
+        }
+    }
+
+    pub fn permits(&self, country: &str) -> bool {
+        !self.deny.iter().any(|c| c == country) && self.allow.as_ref().is_none_or(|allow| allow.iter().any(|c| c == country))
+    }
+}
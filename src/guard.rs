@@ -0,0 +1,10 @@
+use futures::TryStreamExt;
+use hyper::Body;
+
+// Keep `guard` alive until the body has been fully streamed (or dropped),
+// for limits that must cover the whole transfer and not just the headers
+pub fn attach<T: Send + Sync + 'static>(guard: T, body: Body) -> Body {
+    Body::wrap_stream(body.inspect_ok(move |_| {
+        let _ = &guard;
+    }))
+}
@@ -0,0 +1,84 @@
+use getopts::Options;
+use hyper::header::AUTHORIZATION;
+use hyper::{Body, Client, Method, Request, Uri};
+
+use crate::virtual_tree::encode_segment;
+
+// `guest`: mints, lists and revokes the guest accounts of a running proxy
+// through its admin API, for use from a shell or a ticketing script. The
+// answers are the API's JSON, the new password included.
+
+fn print_usage(program: &str, opts: &Options) {
+    let brief = format!(
+        "Usage: {0} guest ADMIN_URL add SCOPE [options]\n       {0} guest ADMIN_URL list\n       {0} guest ADMIN_URL revoke NAME\n\n\
+         ADMIN_URL is the proxy's admin API, e.g. http://127.0.0.1:9090; SCOPE is the folder the guest may use",
+        program
+    );
+    print!("{}", opts.usage(&brief));
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("{}", message);
+    std::process::exit(-1);
+}
+
+pub async fn main(program: &str, args: &[String]) {
+    let mut opts = Options::new();
+    opts.optopt("", "hours", "How long the account lasts, defaulting to 24", "N");
+    opts.optopt("", "name", "Name the account instead of guest-RANDOM", "NAME");
+    opts.optopt("", "token-env", "Read the --admin-token of the proxy from this environment variable", "VAR");
+    opts.optflag("h", "help", "Show this help");
+
+    let matches = opts.parse(args).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        print_usage(program, &opts);
+        std::process::exit(-1);
+    });
+    let free: Vec<&str> = matches.free.iter().map(String::as_str).collect();
+    let (admin, method, path) = match free.as_slice() {
+        _ if matches.opt_present("help") => {
+            print_usage(program, &opts);
+            std::process::exit(0);
+        }
+        [admin, "add", scope] => {
+            let mut query = format!("scope={}", encode_segment(scope));
+            if let Some(hours) = matches.opt_str("hours") {
+                query.push_str(&format!("&hours={}", encode_segment(&hours)));
+            }
+            if let Some(name) = matches.opt_str("name") {
+                query.push_str(&format!("&name={}", encode_segment(&name)));
+            }
+            (admin, Method::POST, format!("/admin/guests?{}", query))
+        }
+        [admin, "list"] => (admin, Method::GET, "/admin/guests".to_string()),
+        [admin, "revoke", name] => (admin, Method::POST, format!("/admin/guests/revoke?name={}", encode_segment(name))),
+        _ => {
+            print_usage(program, &opts);
+            std::process::exit(-1);
+        }
+    };
+    let uri = format!("{}{}", admin.trim_end_matches('/'), path)
+        .parse::<Uri>()
+        .ok()
+        .filter(|uri| uri.scheme_str() == Some("http") && uri.authority().is_some())
+        .unwrap_or_else(|| fail(&format!("Invalid admin API URL (expected http://HOST:PORT): {}", admin)));
+    let mut request = Request::builder().method(method).uri(uri);
+    if let Some(var) = matches.opt_str("token-env") {
+        let token = std::env::var(&var).unwrap_or_else(|_| fail(&format!("Environment variable {} is not set", var)));
+        request = request.header(AUTHORIZATION, format!("Bearer {}", token));
+    }
+    let request = request.body(Body::empty()).expect("request builder");
+    let response = Client::new()
+        .request(request)
+        .await
+        .unwrap_or_else(|e| fail(&format!("Failed to reach {}: {}", admin, e)));
+    let status = response.status();
+    let body = hyper::body::to_bytes(response.into_body())
+        .await
+        .unwrap_or_else(|e| fail(&format!("Failed to read the answer of {}: {}", admin, e)));
+    let body = String::from_utf8_lossy(&body);
+    if !status.is_success() {
+        fail(&format!("{} answered {}: {}", admin, status, body.trim_end()));
+    }
+    println!("{}", body.trim_end());
+}
@@ -0,0 +1,328 @@
+use std::ffi::{c_char, c_int, c_uint, c_ulong, c_void};
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use hyper::body::Bytes;
+use hyper::header::{
+    HeaderMap, HeaderValue, ACCEPT_ENCODING, ACCEPT_RANGES, CACHE_CONTROL, CONTENT_ENCODING, CONTENT_LENGTH,
+    CONTENT_RANGE, CONTENT_TYPE, ETAG, VARY,
+};
+use hyper::{Body, Response};
+
+use crate::methods;
+
+// Compression of the answers on their way to the client (--compress):
+// PROPFIND listings of big folders are hundreds of kilobytes of XML, which
+// gzip shrinks tenfold. Answers in a compressible type the upstream sent
+// as they are get gzipped for clients that accept it, and gzipped ones are
+// unpacked for clients that don't, as well as for the rewriting of
+// multistatus hrefs. The body is compressed as it streams, so the length
+// goes and the answer is sent chunked; a strong ETag becomes weak, as the
+// bytes aren't the upstream's any more. zlib is the system's.
+
+// Answers smaller than this aren't worth it
+const MIN_SIZE: usize = 1024;
+
+const OUTPUT: usize = 16 * 1024;
+
+#[repr(C)]
+struct ZStream {
+    next_in: *const u8,
+    avail_in: c_uint,
+    total_in: c_ulong,
+    next_out: *mut u8,
+    avail_out: c_uint,
+    total_out: c_ulong,
+    msg: *const c_char,
+    state: *mut c_void,
+    zalloc: *const c_void,
+    zfree: *const c_void,
+    opaque: *mut c_void,
+    data_type: c_int,
+    adler: c_ulong,
+    reserved: c_ulong,
+}
+
+#[link(name = "z")]
+extern "C" {
+    fn zlibVersion() -> *const c_char;
+    fn deflateInit2_(
+        strm: *mut ZStream,
+        level: c_int,
+        method: c_int,
+        window_bits: c_int,
+        mem_level: c_int,
+        strategy: c_int,
+        version: *const c_char,
+        stream_size: c_int,
+    ) -> c_int;
+    fn deflate(strm: *mut ZStream, flush: c_int) -> c_int;
+    fn deflateEnd(strm: *mut ZStream) -> c_int;
+    fn inflateInit2_(strm: *mut ZStream, window_bits: c_int, version: *const c_char, stream_size: c_int) -> c_int;
+    fn inflate(strm: *mut ZStream, flush: c_int) -> c_int;
+    fn inflateEnd(strm: *mut ZStream) -> c_int;
+}
+
+const Z_OK: c_int = 0;
+const Z_STREAM_END: c_int = 1;
+const Z_BUF_ERROR: c_int = -5;
+const Z_SYNC_FLUSH: c_int = 2;
+const Z_FINISH: c_int = 4;
+const Z_DEFLATED: c_int = 8;
+const Z_DEFAULT_COMPRESSION: c_int = -1;
+// 15 bits of window, +16 for a gzip wrapper, +32 to detect gzip or zlib
+const GZIP_WINDOW: c_int = 15 + 16;
+const DETECT_WINDOW: c_int = 15 + 32;
+
+// One direction of zlib, with its state boxed as zlib keeps a pointer back
+// to the stream
+struct Codec {
+    stream: Box<ZStream>,
+    compress: bool,
+    ended: bool,
+}
+
+// The stream is only ever used by the body that owns it
+unsafe impl Send for Codec {}
+
+impl Codec {
+    fn new(compress: bool) -> io::Result<Self> {
+        let mut stream = Box::new(ZStream {
+            next_in: std::ptr::null(),
+            avail_in: 0,
+            total_in: 0,
+            next_out: std::ptr::null_mut(),
+            avail_out: 0,
+            total_out: 0,
+            msg: std::ptr::null(),
+            state: std::ptr::null_mut(),
+            zalloc: std::ptr::null(),
+            zfree: std::ptr::null(),
+            opaque: std::ptr::null_mut(),
+            data_type: 0,
+            adler: 0,
+            reserved: 0,
+        });
+        let size = std::mem::size_of::<ZStream>() as c_int;
+        let result = unsafe {
+            if compress {
+                deflateInit2_(&mut *stream, Z_DEFAULT_COMPRESSION, Z_DEFLATED, GZIP_WINDOW, 8, 0, zlibVersion(), size)
+            } else {
+                inflateInit2_(&mut *stream, DETECT_WINDOW, zlibVersion(), size)
+            }
+        };
+        if result != Z_OK {
+            return Err(io::Error::other(format!("zlib failed to start ({})", result)));
+        }
+        Ok(Codec {
+            stream,
+            compress,
+            ended: false,
+        })
+    }
+
+    // Feed `input` through, returning what came out; `finish` at the end
+    fn run(&mut self, input: &[u8], finish: bool) -> io::Result<Vec<u8>> {
+        let mut out = Vec::new();
+        if self.ended {
+            return Ok(out);
+        }
+        let mut buffer = vec![0u8; OUTPUT];
+        self.stream.next_in = input.as_ptr();
+        self.stream.avail_in = input.len() as c_uint;
+        // Flushing each chunk keeps a slow upstream's bytes from being held back
+        let flush = if finish { Z_FINISH } else { Z_SYNC_FLUSH };
+        loop {
+            self.stream.next_out = buffer.as_mut_ptr();
+            self.stream.avail_out = OUTPUT as c_uint;
+            let result = unsafe {
+                if self.compress {
+                    deflate(&mut *self.stream, flush)
+                } else {
+                    inflate(&mut *self.stream, flush)
+                }
+            };
+            let produced = OUTPUT - self.stream.avail_out as usize;
+            out.extend_from_slice(&buffer[..produced]);
+            match result {
+                Z_STREAM_END => {
+                    self.ended = true;
+                    break;
+                }
+                Z_OK | Z_BUF_ERROR if self.stream.avail_out > 0 => break,
+                Z_OK => {}
+                _ => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("zlib failed ({})", result))),
+            }
+        }
+        self.stream.next_in = std::ptr::null();
+        self.stream.avail_in = 0;
+        if finish && !self.ended {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "the gzipped body ends early"));
+        }
+        Ok(out)
+    }
+}
+
+impl Drop for Codec {
+    fn drop(&mut self) {
+        unsafe {
+            if self.compress {
+                deflateEnd(&mut *self.stream);
+            } else {
+                inflateEnd(&mut *self.stream);
+            }
+        }
+    }
+}
+
+// A body run through a codec as it streams
+struct Coded {
+    body: Body,
+    codec: Codec,
+    done: bool,
+}
+
+impl futures::Stream for Coded {
+    type Item = Result<Bytes, Box<dyn std::error::Error + Send + Sync>>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            if self.done {
+                return Poll::Ready(None);
+            }
+            let output = match futures::ready!(Pin::new(&mut self.body).poll_next(cx)) {
+                Some(Ok(chunk)) => self.codec.run(&chunk, false),
+                Some(Err(e)) => return Poll::Ready(Some(Err(e.into()))),
+                None => {
+                    self.done = true;
+                    self.codec.run(&[], true)
+                }
+            };
+            match output {
+                Ok(bytes) if bytes.is_empty() => continue,
+                Ok(bytes) => return Poll::Ready(Some(Ok(bytes.into()))),
+                Err(e) => {
+                    self.done = true;
+                    return Poll::Ready(Some(Err(e.into())));
+                }
+            }
+        }
+    }
+}
+
+fn coded(body: Body, compress: bool) -> io::Result<Body> {
+    Ok(Body::wrap_stream(Coded {
+        body,
+        codec: Codec::new(compress)?,
+        done: false,
+    }))
+}
+
+// Whether the client takes gzip: named, or `*`, without q=0
+pub fn accepted(headers: &HeaderMap) -> bool {
+    let mut gzip = None;
+    let mut any = None;
+    for item in headers.get_all(ACCEPT_ENCODING).iter().filter_map(|v| v.to_str().ok()).flat_map(|v| v.split(',')) {
+        let mut params = item.split(';');
+        let coding = params.next().unwrap_or_default().trim().to_ascii_lowercase();
+        let q = params
+            .filter_map(|p| p.trim().strip_prefix("q="))
+            .next()
+            .and_then(|q| q.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+        match coding.as_str() {
+            "gzip" | "x-gzip" => gzip = Some(q > 0.0),
+            "*" => any = Some(q > 0.0),
+            _ => {}
+        }
+    }
+    gzip.or(any).unwrap_or(false)
+}
+
+fn gzipped(headers: &HeaderMap) -> bool {
+    headers
+        .get(CONTENT_ENCODING)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.trim().eq_ignore_ascii_case("gzip") || v.trim().eq_ignore_ascii_case("x-gzip"))
+}
+
+fn compressible(headers: &HeaderMap, multistatus: bool) -> bool {
+    let Some(content_type) = headers.get(CONTENT_TYPE).and_then(|v| v.to_str().ok()) else {
+        return multistatus;
+    };
+    let essence = content_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase();
+    essence.starts_with("text/")
+        || essence.ends_with("+xml")
+        || essence.ends_with("+json")
+        || ["application/xml", "application/json", "application/javascript", "application/x-javascript"]
+            .contains(&essence.as_str())
+}
+
+// The answer no longer has the upstream's exact bytes
+fn recoded(headers: &mut HeaderMap) {
+    headers.remove(CONTENT_LENGTH);
+    headers.remove(ACCEPT_RANGES);
+    let strong = headers.get(ETAG).and_then(|v| v.to_str().ok()).filter(|v| v.starts_with('"')).map(str::to_string);
+    if let Some(value) = strong.and_then(|etag| HeaderValue::from_str(&format!("W/{}", etag)).ok()) {
+        headers.insert(ETAG, value);
+    }
+}
+
+// Unpack a gzipped answer the client can't take, or whose hrefs need
+// rewriting
+pub fn decode(response: Response<Body>, client_gzip: bool) -> Response<Body> {
+    let multistatus = response.status().as_u16() == 207;
+    if !gzipped(response.headers()) || (client_gzip && !multistatus) {
+        return response;
+    }
+    let (mut parts, body) = response.into_parts();
+    match coded(body, false) {
+        Ok(body) => {
+            parts.headers.remove(CONTENT_ENCODING);
+            recoded(&mut parts.headers);
+            Response::from_parts(parts, body)
+        }
+        Err(e) => {
+            tracing::warn!("Failed to unpack a gzipped answer: {}", e);
+            Response::from_parts(parts, Body::empty())
+        }
+    }
+}
+
+// Gzip an answer worth it for a client that takes it, and tell caches the
+// answer depends on that
+pub fn encode(response: Response<Body>, client_gzip: bool) -> Response<Body> {
+    let status = response.status().as_u16();
+    let headers = response.headers();
+    let no_transform = headers
+        .get_all(CACHE_CONTROL)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .any(|v| v.to_ascii_lowercase().contains("no-transform"));
+    let eligible = matches!(status, 200 | 203 | 207)
+        && !headers.contains_key(CONTENT_ENCODING)
+        && !headers.contains_key(CONTENT_RANGE)
+        && !no_transform
+        && compressible(headers, status == 207);
+    if !eligible {
+        return response;
+    }
+    let (mut parts, body) = response.into_parts();
+    parts.headers.append(VARY, HeaderValue::from_static("Accept-Encoding"));
+    let small = methods::content_length(&parts.headers).is_some_and(|length| length < MIN_SIZE);
+    if !client_gzip || small {
+        return Response::from_parts(parts, body);
+    }
+    match coded(body, true) {
+        Ok(body) => {
+            parts.headers.insert(CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+            recoded(&mut parts.headers);
+            Response::from_parts(parts, body)
+        }
+        Err(e) => {
+            tracing::warn!("Failed to start compressing an answer: {}", e);
+            Response::from_parts(parts, Body::empty())
+        }
+    }
+}
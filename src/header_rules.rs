@@ -0,0 +1,145 @@
+use hyper::header::{HeaderMap, HeaderName, HeaderValue};
+use hyper::Method;
+
+use crate::config::wildcard;
+
+// Rules that add, set, remove or edit headers of the requests sent to the
+// upstream and of its answers, for clients and upstreams that disagree
+// about them. A rule is `[METHODS] GLOB ACTION`:
+//
+//     * remove Cache-Control
+//     GET,HEAD /media/* set Cache-Control: max-age=3600
+//     * replace Location: http://nas:8080/ => /
+//     PROPFIND * add X-Client: webdav-proxy
+//
+// METHODS is a comma-separated list (all methods without it) and GLOB is
+// matched against the client's path, `*` matching anything. `set` replaces
+// every value of the header, `add` appends another one, and `replace`
+// changes text within the values. Rules apply in order.
+
+enum Action {
+    Add(HeaderValue),
+    Set(HeaderValue),
+    Remove,
+    Replace(String, String),
+}
+
+struct Rule {
+    methods: Vec<Method>,
+    glob: String,
+    name: HeaderName,
+    action: Action,
+}
+
+#[derive(Default)]
+pub struct HeaderRules {
+    request: Vec<Rule>,
+    response: Vec<Rule>,
+}
+
+fn parse(spec: &str) -> Result<Rule, String> {
+    let invalid = || format!("Invalid header rule (expected [METHODS] GLOB ACTION NAME[: VALUE]): {}", spec);
+    let mut rest = spec.trim();
+    let mut word = || {
+        let (first, after) = rest.split_once(char::is_whitespace).ok_or_else(invalid)?;
+        rest = after.trim_start();
+        Ok::<_, String>(first)
+    };
+    let mut first = word()?;
+    let mut methods = Vec::new();
+    if !first.starts_with(['/', '*']) {
+        for method in first.split(',') {
+            methods.push(Method::from_bytes(method.trim().to_ascii_uppercase().as_bytes()).map_err(|_| invalid())?);
+        }
+        first = word()?;
+    }
+    let glob = first.to_string();
+    let action = word()?;
+    let (name, value) = match rest.split_once(':') {
+        Some((name, value)) => (name.trim(), Some(value.trim())),
+        None => (rest.trim(), None),
+    };
+    let name = HeaderName::from_bytes(name.as_bytes()).map_err(|_| format!("Invalid header name in rule: {}", spec))?;
+    let header_value =
+        |value: &str| HeaderValue::from_str(value).map_err(|_| format!("Invalid header value in rule: {}", spec));
+    let action = match (action.to_ascii_lowercase().as_str(), value) {
+        ("add", Some(value)) => Action::Add(header_value(value)?),
+        ("set", Some(value)) => Action::Set(header_value(value)?),
+        ("remove", None) => Action::Remove,
+        ("replace", Some(value)) => {
+            let (from, to) = value.split_once("=>").ok_or_else(invalid)?;
+            let (from, to) = (from.trim(), to.trim());
+            if from.is_empty() {
+                return Err(invalid());
+            }
+            header_value(to)?;
+            Action::Replace(from.to_string(), to.to_string())
+        }
+        _ => return Err(invalid()),
+    };
+    Ok(Rule {
+        methods,
+        glob,
+        name,
+        action,
+    })
+}
+
+fn apply(rules: &[Rule], method: &Method, path: &str, headers: &mut HeaderMap) {
+    for rule in rules {
+        if !rule.methods.is_empty() && !rule.methods.contains(method) || !wildcard(&rule.glob, path) {
+            continue;
+        }
+        match &rule.action {
+            Action::Add(value) => {
+                headers.append(rule.name.clone(), value.clone());
+            }
+            Action::Set(value) => {
+                headers.insert(rule.name.clone(), value.clone());
+            }
+            Action::Remove => {
+                headers.remove(&rule.name);
+            }
+            Action::Replace(from, to) => {
+                let values: Vec<HeaderValue> = headers
+                    .get_all(&rule.name)
+                    .iter()
+                    .map(|value| match value.to_str() {
+                        Ok(text) if text.contains(from.as_str()) => {
+                            HeaderValue::from_str(&text.replace(from.as_str(), to)).unwrap_or_else(|_| value.clone())
+                        }
+                        _ => value.clone(),
+                    })
+                    .collect();
+                headers.remove(&rule.name);
+                for value in values {
+                    headers.append(rule.name.clone(), value);
+                }
+            }
+        }
+    }
+}
+
+impl HeaderRules {
+    pub fn add_request(&mut self, spec: &str) -> Result<(), String> {
+        self.request.push(parse(spec)?);
+        Ok(())
+    }
+
+    pub fn add_response(&mut self, spec: &str) -> Result<(), String> {
+        self.response.push(parse(spec)?);
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.request.is_empty() && self.response.is_empty()
+    }
+
+    pub fn apply_request(&self, method: &Method, path: &str, headers: &mut HeaderMap) {
+        apply(&self.request, method, path, headers);
+    }
+
+    pub fn apply_response(&self, method: &Method, path: &str, headers: &mut HeaderMap) {
+        apply(&self.response, method, path, headers);
+    }
+}
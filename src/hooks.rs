@@ -0,0 +1,35 @@
+use hyper::{Body, Request, Response};
+
+// Callbacks of an application embedding the proxy: request hooks see each
+// request before the proxy does and may answer it themselves, response
+// hooks see each answer, the proxy's own ones included, before it goes out
+
+pub type RequestHook = Box<dyn Fn(&mut Request<Body>) -> Option<Response<Body>> + Send + Sync>;
+pub type ResponseHook = Box<dyn Fn(&mut Response<Body>) + Send + Sync>;
+
+#[derive(Default)]
+pub struct Hooks {
+    requests: Vec<RequestHook>,
+    responses: Vec<ResponseHook>,
+}
+
+impl Hooks {
+    pub fn on_request(&mut self, hook: RequestHook) {
+        self.requests.push(hook);
+    }
+
+    pub fn on_response(&mut self, hook: ResponseHook) {
+        self.responses.push(hook);
+    }
+
+    // The answer of the first request hook that gives one
+    pub fn request(&self, req: &mut Request<Body>) -> Option<Response<Body>> {
+        self.requests.iter().find_map(|hook| hook(req))
+    }
+
+    pub fn response(&self, response: &mut Response<Body>) {
+        for hook in &self.responses {
+            hook(response);
+        }
+    }
+}
@@ -0,0 +1,24 @@
+use hyper::header::{CONTENT_LENGTH, TRANSFER_ENCODING};
+use hyper::{Body, Response};
+
+use crate::buffering;
+
+// HTTP/1.0 clients know neither chunked encoding nor persistent connections
+// without a length. Bodies up to this size are buffered to send a
+// Content-Length; larger ones are streamed and end with the connection.
+const BUFFER_LIMIT: usize = 8 * 1024 * 1024;
+
+pub async fn with_length(response: Response<Body>) -> Result<Response<Body>, hyper::Error> {
+    let (mut parts, body) = response.into_parts();
+    parts.headers.remove(TRANSFER_ENCODING);
+    if buffering::is_bodiless(&parts) || parts.headers.contains_key(CONTENT_LENGTH) {
+        return Ok(Response::from_parts(parts, body));
+    }
+    match buffering::read_up_to(body, BUFFER_LIMIT).await? {
+        Ok(bytes) => {
+            parts.headers.insert(CONTENT_LENGTH, bytes.len().into());
+            Ok(Response::from_parts(parts, Body::from(bytes)))
+        }
+        Err(body) => Ok(Response::from_parts(parts, body)),
+    }
+}
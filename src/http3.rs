@@ -0,0 +1,327 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use h3::error::{Code, StreamError};
+use h3::server::{RequestResolver, RequestStream};
+use hyper::body::{Buf, Bytes, HttpBody};
+use hyper::{Body, HeaderMap, Request, Response};
+use quinn::crypto::rustls::QuicServerConfig;
+use quinn::{Endpoint, Incoming, ServerConfig};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+
+use crate::network::AccessList;
+use crate::{methods, Downstream, ProxyConfig};
+
+// Experimental HTTP/3 listener (--http3-port): QUIC on a UDP port, with the
+// certificate of the HTTPS listener, handing requests to the same service.
+// Over a lossy mobile link a lost packet then holds up only the request it
+// belongs to, rather than every PROPFIND queued behind it on one TCP
+// connection. The certificate is read once at startup, and responses carry
+// no OCSP staple.
+
+// Headers that only mean something on a TCP connection; HTTP/3 forbids them
+const CONNECTION_HEADERS: [&str; 5] = ["connection", "keep-alive", "proxy-connection", "transfer-encoding", "upgrade"];
+
+// QUIC's TLS 1.3 setup for `cert` and `key`, PEM files as for --tls-cert
+pub fn server_config(cert: &str, key: &str) -> Result<ServerConfig, String> {
+    let chain = CertificateDer::pem_file_iter(cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| format!("Failed to load {}: {}", cert, e))?;
+    let private = PrivateKeyDer::from_pem_file(key).map_err(|e| format!("Failed to load {}: {}", key, e))?;
+    let mut tls = rustls::ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_protocol_versions(&[&rustls::version::TLS13])
+        .map_err(|e| format!("Invalid TLS policy: {}", e))?
+        .with_no_client_auth()
+        .with_single_cert(chain, private)
+        .map_err(|e| format!("{} does not match {}: {}", key, cert, e))?;
+    tls.alpn_protocols = vec![b"h3".to_vec()];
+    let crypto = QuicServerConfig::try_from(tls).map_err(|e| format!("Invalid TLS policy for QUIC: {}", e))?;
+    Ok(ServerConfig::with_crypto(Arc::new(crypto)))
+}
+
+pub fn bind(addr: SocketAddr, config: ServerConfig) -> Result<Endpoint, String> {
+    Endpoint::server(config, addr).map_err(|e| format!("Failed to listen on UDP {}: {}", addr, e))
+}
+
+// Answers connections on `endpoint` until `shutdown` completes, then sends
+// each client a GOAWAY and ends once the requests in flight are answered
+pub async fn serve(endpoint: Endpoint, config: Arc<ProxyConfig>, access: AccessList, shutdown: impl std::future::Future<Output = ()>) {
+    let (stop, stopping) = watch::channel(false);
+    let mut connections = JoinSet::new();
+    tokio::pin!(shutdown);
+    loop {
+        tokio::select! {
+            incoming = endpoint.accept() => {
+                let Some(incoming) = incoming else { break };
+                let remote = incoming.remote_address();
+                if !access.permits(remote.ip()) {
+                    tracing::info!("Refused a connection from {}", remote.ip());
+                    incoming.refuse();
+                    continue;
+                }
+                let downstream = Downstream::new(config.clone(), remote);
+                let stopping = stopping.clone();
+                connections.spawn(async move {
+                    if let Err(e) = connection(incoming, downstream, stopping).await {
+                        tracing::debug!("HTTP/3 connection from {} failed: {}", remote, e);
+                    }
+                });
+            }
+            Some(_) = connections.join_next(), if !connections.is_empty() => {}
+            _ = &mut shutdown => break,
+        }
+    }
+    let _ = stop.send(true);
+    while connections.join_next().await.is_some() {}
+}
+
+async fn connection(incoming: Incoming, downstream: Downstream, mut stopping: watch::Receiver<bool>) -> Result<(), String> {
+    let conn = incoming.await.map_err(|e| e.to_string())?;
+    let mut conn = h3::server::Connection::<_, Bytes>::new(h3_quinn::Connection::new(conn))
+        .await
+        .map_err(|e| e.to_string())?;
+    let mut requests = JoinSet::new();
+    let mut closing = false;
+    loop {
+        tokio::select! {
+            accepted = conn.accept() => match accepted.map_err(|e| e.to_string())? {
+                Some(resolver) => {
+                    requests.spawn(request(resolver, downstream.clone()));
+                }
+                // The client is done with the connection
+                None => break,
+            },
+            Some(_) = requests.join_next(), if !requests.is_empty() => {}
+            _ = stopping.changed(), if !closing => {
+                closing = true;
+                conn.shutdown(0).await.map_err(|e| e.to_string())?;
+            }
+        }
+        if closing && requests.is_empty() {
+            break;
+        }
+    }
+    while requests.join_next().await.is_some() {}
+    Ok(())
+}
+
+async fn request(resolver: RequestResolver<h3_quinn::Connection, Bytes>, downstream: Downstream) {
+    let (head, stream) = match resolver.resolve_request().await {
+        Ok(request) => request,
+        Err(e) => return tracing::debug!("Failed to read an HTTP/3 request from {}: {}", downstream.remote, e),
+    };
+    let (mut send, recv) = stream.split();
+    let req = match incoming(head) {
+        Some(req) => req,
+        None => {
+            let refusal = http::Response::builder().status(400).body(()).expect("static response");
+            let _ = send.send_response(refusal).await;
+            let _ = send.finish().await;
+            return;
+        }
+    };
+    let req = match body(req, recv).await {
+        Ok(req) => req,
+        Err(e) => return tracing::debug!("HTTP/3 request from {} failed: {}", downstream.remote, e),
+    };
+    let response = match downstream.serve(req).await {
+        Ok(response) => response,
+        Err(e) => {
+            tracing::debug!("HTTP/3 request from {} failed: {}", downstream.remote, e);
+            return send.stop_stream(Code::H3_INTERNAL_ERROR);
+        }
+    };
+    if let Err(e) = respond(&mut send, response).await {
+        tracing::debug!("Failed to send an HTTP/3 response to {}: {}", downstream.remote, e);
+        send.stop_stream(Code::H3_INTERNAL_ERROR);
+    }
+}
+
+// The request head in hyper's types; h3 uses those of http 1.x. The target
+// stays in absolute form, as for HTTP/2, and the proxy takes Host from it.
+fn incoming(head: http::Request<()>) -> Option<Request<()>> {
+    let mut req = Request::builder()
+        .method(head.method().as_str())
+        .uri(head.uri().to_string())
+        .version(hyper::Version::HTTP_3);
+    for (name, value) in head.headers() {
+        req = req.header(name.as_str(), value.as_bytes());
+    }
+    req.body(()).ok()
+}
+
+// The request with its body as it arrives. One that ends with its head gets
+// an empty body, which goes upstream unchunked.
+async fn body<S>(req: Request<()>, mut recv: RequestStream<S, Bytes>) -> Result<Request<Body>, StreamError>
+where
+    S: h3::quic::RecvStream + Send + 'static,
+{
+    let first = match methods::content_length(req.headers()) {
+        Some(0) => return Ok(req.map(|()| Body::empty())),
+        // A client that announced a length may be waiting for 100 Continue
+        Some(_) => None,
+        None => match recv.recv_data().await? {
+            Some(mut data) => Some(data.copy_to_bytes(data.remaining())),
+            None => return Ok(req.map(|()| Body::empty())),
+        },
+    };
+    let rest = futures::stream::unfold(Some(recv), |recv| async move {
+        let mut recv = recv?;
+        match recv.recv_data().await {
+            Ok(Some(mut data)) => Some((Ok(data.copy_to_bytes(data.remaining())), Some(recv))),
+            Ok(None) => None,
+            Err(e) => Some((Err(e), None)),
+        }
+    });
+    Ok(req.map(|()| Body::wrap_stream(futures::StreamExt::chain(futures::stream::iter(first.map(Ok)), rest))))
+}
+
+async fn respond<S>(send: &mut RequestStream<S, Bytes>, response: Response<Body>) -> Result<(), String>
+where
+    S: h3::quic::SendStream<Bytes>,
+{
+    let (parts, mut body) = response.into_parts();
+    let mut head = http::Response::new(());
+    *head.status_mut() = http::StatusCode::from_u16(parts.status.as_u16()).map_err(|e| e.to_string())?;
+    *head.headers_mut() = outgoing(&parts.headers);
+    send.send_response(head).await.map_err(|e| e.to_string())?;
+    while let Some(data) = body.data().await {
+        send.send_data(data.map_err(|e| e.to_string())?).await.map_err(|e| e.to_string())?;
+    }
+    if let Some(trailers) = body.trailers().await.map_err(|e| e.to_string())? {
+        send.send_trailers(outgoing(&trailers)).await.map_err(|e| e.to_string())?;
+    }
+    send.finish().await.map_err(|e| e.to_string())
+}
+
+// Response headers or trailers in http 1.x's types
+fn outgoing(headers: &HeaderMap) -> http::HeaderMap {
+    headers
+        .iter()
+        .filter(|(name, _)| !CONNECTION_HEADERS.contains(&name.as_str()))
+        .filter_map(|(name, value)| {
+            Some((
+                http::HeaderName::from_bytes(name.as_str().as_bytes()).ok()?,
+                http::HeaderValue::from_bytes(value.as_bytes()).ok()?,
+            ))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::service::{make_service_fn, service_fn};
+    use std::convert::Infallible;
+    use std::time::Duration;
+
+    // A self-signed certificate for localhost as PEM files in `dir`, and its DER
+    fn certificate(dir: &std::path::Path) -> (String, String, Vec<u8>) {
+        use openssl::asn1::Asn1Time;
+        use openssl::bn::BigNum;
+        use openssl::ec::{EcGroup, EcKey};
+        use openssl::hash::MessageDigest;
+        use openssl::nid::Nid;
+        use openssl::pkey::PKey;
+        use openssl::x509::extension::SubjectAlternativeName;
+        use openssl::x509::{X509NameBuilder, X509};
+
+        let key = PKey::from_ec_key(EcKey::generate(&EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap()).unwrap()).unwrap();
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", "localhost").unwrap();
+        let name = name.build();
+        let mut cert = X509::builder().unwrap();
+        cert.set_version(2).unwrap();
+        cert.set_serial_number(&BigNum::from_u32(1).unwrap().to_asn1_integer().unwrap()).unwrap();
+        cert.set_subject_name(&name).unwrap();
+        cert.set_issuer_name(&name).unwrap();
+        cert.set_pubkey(&key).unwrap();
+        cert.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+        cert.set_not_after(&Asn1Time::days_from_now(1).unwrap()).unwrap();
+        let san = SubjectAlternativeName::new().dns("localhost").build(&cert.x509v3_context(None, None)).unwrap();
+        cert.append_extension(san).unwrap();
+        cert.sign(&key, MessageDigest::sha256()).unwrap();
+        let cert = cert.build();
+        let (cert_path, key_path) = (dir.join("cert.pem"), dir.join("key.pem"));
+        std::fs::write(&cert_path, cert.to_pem().unwrap()).unwrap();
+        std::fs::write(&key_path, key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+        let path = |p: std::path::PathBuf| p.to_string_lossy().into_owned();
+        (path(cert_path), path(key_path), cert.to_der().unwrap())
+    }
+
+    // An upstream answering with the request line, how the body came and the body
+    async fn echo(req: Request<Body>) -> Result<Response<Body>, Infallible> {
+        let framing = match req.headers().get(hyper::header::TRANSFER_ENCODING) {
+            Some(encoding) => encoding.to_str().unwrap().to_string(),
+            None => "-".to_string(),
+        };
+        let line = format!("{} {} {} ", req.method(), req.uri().path(), framing);
+        let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+        Ok(Response::new(Body::from([line.as_bytes(), &body].concat())))
+    }
+
+    // The status and body of `method` on `path` over `send`, with `upload`
+    // sent as the body in a frame of its own
+    async fn exchange(
+        send: &mut h3::client::SendRequest<h3_quinn::OpenStreams, Bytes>,
+        method: &str,
+        path: &str,
+        upload: Option<&'static str>,
+    ) -> (u16, String) {
+        let req = http::Request::builder().method(method).uri(format!("https://localhost{}", path)).body(()).unwrap();
+        let mut stream = send.send_request(req).await.unwrap();
+        if let Some(upload) = upload {
+            stream.send_data(Bytes::from_static(upload.as_bytes())).await.unwrap();
+        }
+        stream.finish().await.unwrap();
+        let response = stream.recv_response().await.unwrap();
+        let mut body = Vec::new();
+        while let Some(mut data) = stream.recv_data().await.unwrap() {
+            body.extend_from_slice(&data.copy_to_bytes(data.remaining()));
+        }
+        (response.status().as_u16(), String::from_utf8(body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn serves_the_proxy_over_quic() {
+        let dir = std::env::temp_dir().join(format!("http3-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (cert, key, der) = certificate(&dir);
+
+        let upstream = hyper::Server::bind(&"127.0.0.1:0".parse().unwrap())
+            .serve(make_service_fn(|_| async { Ok::<_, Infallible>(service_fn(echo)) }));
+        let upstream_url = format!("http://{}/", upstream.local_addr());
+        tokio::spawn(upstream);
+        let config = crate::WebdavProxy::builder().upstream(&upstream_url).build().unwrap().config;
+        let endpoint = bind("127.0.0.1:0".parse().unwrap(), server_config(&cert, &key).unwrap()).unwrap();
+        let addr = endpoint.local_addr().unwrap();
+        tokio::spawn(serve(endpoint, config, AccessList::default(), std::future::pending()));
+
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(CertificateDer::from(der)).unwrap();
+        let mut tls = rustls::ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_protocol_versions(&[&rustls::version::TLS13])
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        tls.alpn_protocols = vec![b"h3".to_vec()];
+        let mut client = Endpoint::client("127.0.0.1:0".parse().unwrap()).unwrap();
+        let crypto = quinn::crypto::rustls::QuicClientConfig::try_from(tls).unwrap();
+        client.set_default_client_config(quinn::ClientConfig::new(Arc::new(crypto)));
+
+        let exchanges = async {
+            let conn = client.connect(addr, "localhost").unwrap().await.unwrap();
+            let (mut driver, mut send) = h3::client::new(h3_quinn::Connection::new(conn)).await.unwrap();
+            tokio::spawn(async move { driver.wait_idle().await });
+            // A request without a body stays one without, rather than going upstream chunked
+            assert_eq!(exchange(&mut send, "MKCOL", "/a/", None).await, (200, "MKCOL /a/ - ".to_string()));
+            assert_eq!(exchange(&mut send, "GET", "/a.txt", None).await, (200, "GET /a.txt - ".to_string()));
+            assert_eq!(exchange(&mut send, "PUT", "/b.txt", Some("data")).await, (200, "PUT /b.txt chunked data".to_string()));
+        };
+        tokio::time::timeout(Duration::from_secs(10), exchanges).await.unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,94 @@
+use std::future::Future;
+use std::io;
+use std::time::Duration;
+
+use futures::{StreamExt, TryStreamExt};
+use hyper::body::Bytes;
+use hyper::Body;
+use tokio::sync::watch;
+use tokio::time::{error::Elapsed, Instant};
+
+use crate::pinned::BoxError;
+
+// Abort a body whose source sends nothing for `idle`. Once the headers are
+// out, cutting the connection is the only way left to tell the client the
+// transfer failed, rather than leaving it waiting forever.
+pub fn limit(body: Body, idle: Duration, what: String) -> Body {
+    let stream = futures::stream::unfold(Some(body), move |body| {
+        let what = what.clone();
+        async move {
+            let mut body = body?;
+            match tokio::time::timeout(idle, body.next()).await {
+                Ok(Some(chunk)) => Some((chunk.map_err(BoxError::from), Some(body))),
+                Ok(None) => None,
+                Err(_) => {
+                    tracing::warn!("Upstream stalled for {}s while sending {}, aborting", idle.as_secs(), what);
+                    let error = io::Error::new(io::ErrorKind::TimedOut, "upstream body stalled");
+                    Some((Err::<Bytes, _>(BoxError::from(error)), None))
+                }
+            }
+        }
+    });
+    Body::wrap_stream(stream)
+}
+
+// Abort a body still being sent at `deadline`, the end of the time allowed
+// for the whole transfer
+pub fn deadline(body: Body, deadline: Instant, what: String) -> Body {
+    let stream = futures::stream::unfold(Some(body), move |body| {
+        let what = what.clone();
+        async move {
+            let mut body = body?;
+            match tokio::time::timeout_at(deadline, body.next()).await {
+                Ok(Some(chunk)) => Some((chunk.map_err(BoxError::from), Some(body))),
+                Ok(None) => None,
+                Err(_) => {
+                    tracing::warn!("Transfer time over while sending {}, aborting", what);
+                    let error = io::Error::new(io::ErrorKind::TimedOut, "transfer timeout");
+                    Some((Err::<Bytes, _>(BoxError::from(error)), None))
+                }
+            }
+        }
+    });
+    Body::wrap_stream(stream)
+}
+
+// Note when each chunk of a request body is taken for sending; without a
+// body there is nothing to note, and an empty one is handed on as it is
+pub fn watch_upload(body: Option<Body>) -> (Body, watch::Receiver<Instant>) {
+    let (sender, receiver) = watch::channel(Instant::now());
+    let body = match body {
+        Some(body) => Body::wrap_stream(body.inspect_ok(move |_| {
+            let _ = sender.send(Instant::now());
+        })),
+        None => Body::empty(),
+    };
+    (body, receiver)
+}
+
+// Wait for `response`, the upstream's response head, giving up once
+// nothing happened for `limit`: no chunk of the request body went out and
+// no response came. Uploads of any length thus only count once they are
+// done, and a connect or a first byte that takes too long still fails
+// fast. `until` caps the wait regardless.
+pub async fn first_byte<F: Future>(
+    response: F,
+    limit: Duration,
+    upload: watch::Receiver<Instant>,
+    until: Option<Instant>,
+) -> Result<F::Output, Elapsed> {
+    tokio::pin!(response);
+    loop {
+        let mut at = *upload.borrow() + limit;
+        if let Some(until) = until {
+            at = at.min(until);
+        }
+        match tokio::time::timeout_at(at, &mut response).await {
+            Ok(output) => return Ok(output),
+            Err(elapsed) if upload.borrow().elapsed() >= limit || until.is_some_and(|u| u <= Instant::now()) => {
+                return Err(elapsed)
+            }
+            Err(_) => {}
+        }
+    }
+}
@@ -0,0 +1,201 @@
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use futures::StreamExt;
+use hyper::body::HttpBody;
+use hyper::{Body, Request, Response, StatusCode};
+use tokio::sync::Notify;
+
+use crate::json;
+use crate::pinned::BoxError;
+
+// The requests the proxy is handling, for /admin/requests. One can be
+// cancelled there, e.g. a PROPFIND Depth: infinity or a stuck upload that
+// ties up the upstream: while it waits for the upstream, the client gets a
+// 503 and the upstream request is dropped; once the response is under
+// way, its body fails.
+
+struct Entry {
+    id: u64,
+    method: String,
+    path: String,
+    client: IpAddr,
+    started: Instant,
+    received: AtomicU64,
+    sent: AtomicU64,
+    cancelled: AtomicBool,
+    cancel: Notify,
+}
+
+impl Entry {
+    fn to_json(&self) -> String {
+        json::object(&[
+            ("id", self.id.to_string()),
+            ("method", json::string(&self.method)),
+            ("path", json::string(&self.path)),
+            ("client", json::string(&self.client.to_string())),
+            ("age_ms", self.started.elapsed().as_millis().to_string()),
+            ("bytes_in", self.received.load(Ordering::Relaxed).to_string()),
+            ("bytes_out", self.sent.load(Ordering::Relaxed).to_string()),
+        ])
+    }
+}
+
+#[derive(Default)]
+pub struct InFlight {
+    next_id: AtomicU64,
+    active: Mutex<BTreeMap<u64, Arc<Entry>>>,
+}
+
+// A request's place in the list, given up when dropped
+pub struct Handle {
+    inflight: Arc<InFlight>,
+    entry: Arc<Entry>,
+}
+
+impl Drop for Handle {
+    fn drop(&mut self) {
+        self.inflight.active.lock().unwrap().remove(&self.entry.id);
+    }
+}
+
+impl InFlight {
+    // List a request, counting its body as it is read
+    pub fn start(self: &Arc<Self>, client: IpAddr, req: Request<Body>) -> (Request<Body>, Handle) {
+        let entry = Arc::new(Entry {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed) + 1,
+            method: req.method().to_string(),
+            path: req.uri().path().to_string(),
+            client,
+            started: Instant::now(),
+            received: AtomicU64::new(0),
+            sent: AtomicU64::new(0),
+            cancelled: AtomicBool::new(false),
+            cancel: Notify::new(),
+        });
+        self.active.lock().unwrap().insert(entry.id, entry.clone());
+        let counted = entry.clone();
+        // An empty body stays as it is, or it would go upstream chunked
+        let req = req.map(|body| match body.is_end_stream() {
+            true => body,
+            false => Body::wrap_stream(body.inspect(move |chunk| {
+                if let Ok(chunk) = chunk {
+                    counted.received.fetch_add(chunk.len() as u64, Ordering::Relaxed);
+                }
+            })),
+        });
+        let handle = Handle {
+            inflight: self.clone(),
+            entry,
+        };
+        (req, handle)
+    }
+
+    // False if there is no such request
+    pub fn cancel(&self, id: u64) -> bool {
+        let Some(entry) = self.active.lock().unwrap().get(&id).cloned() else {
+            return false;
+        };
+        entry.cancelled.store(true, Ordering::Relaxed);
+        entry.cancel.notify_one();
+        tracing::info!(
+            "Cancelling request {}: {} {} from {}",
+            id, entry.method, entry.path, entry.client
+        );
+        true
+    }
+
+    pub fn to_json(&self) -> String {
+        let active = self.active.lock().unwrap();
+        json::array(&active.values().map(|e| e.to_json()).collect::<Vec<_>>())
+    }
+}
+
+// The answer to a request cancelled before its response
+pub fn refusal() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::SERVICE_UNAVAILABLE)
+        .header("Content-Type", "text/plain")
+        .body(Body::from("The request was cancelled by the administrator\n"))
+        .expect("response builder")
+}
+
+impl Handle {
+    // Resolves when the request is cancelled
+    pub async fn cancelled(&self) {
+        self.entry.cancel.notified().await
+    }
+
+    // Keep the request listed until its response body is sent, counting it
+    pub fn finish(self, response: Response<Body>) -> Response<Body> {
+        response.map(|body| {
+            let entry = self.entry.clone();
+            let cancel = self.entry.clone();
+            let stream = body
+                .map(move |chunk| {
+                    if let Ok(chunk) = &chunk {
+                        entry.sent.fetch_add(chunk.len() as u64, Ordering::Relaxed);
+                    }
+                    chunk.map_err(BoxError::from)
+                })
+                .take_until(async move { cancel.cancel.notified().await })
+                .chain(
+                    futures::stream::once(async move {
+                        // Also drops the handle, which unlists the request
+                        let handle = self;
+                        handle
+                            .entry
+                            .cancelled
+                            .load(Ordering::Relaxed)
+                            .then(|| Err(BoxError::from("request cancelled")))
+                    })
+                    .filter_map(|cancelled| async move { cancelled }),
+                );
+            Body::wrap_stream(stream)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    // What an upstream receives for `req`: its head and whatever body came
+    // before the answer
+    async fn sent_upstream(req: Request<Body>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let upstream = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut received = vec![0; 4096];
+            let n = socket.read(&mut received).await.unwrap();
+            socket.write_all(b"HTTP/1.1 201 Created\r\nContent-Length: 0\r\n\r\n").await.unwrap();
+            String::from_utf8_lossy(&received[..n]).to_lowercase()
+        });
+        let (mut parts, body) = req.into_parts();
+        parts.uri = format!("http://{}{}", address, parts.uri).parse().unwrap();
+        hyper::Client::new().request(Request::from_parts(parts, body)).await.unwrap();
+        upstream.await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn empty_bodies_stay_unchunked() {
+        let inflight = Arc::new(InFlight::default());
+        let client = "127.0.0.1".parse().unwrap();
+        let req = Request::builder().method("MKCOL").uri("/new/").body(Body::empty()).unwrap();
+        let (req, _handle) = inflight.start(client, req);
+        let sent = sent_upstream(req).await;
+        assert!(sent.starts_with("mkcol /new/ "));
+        assert!(!sent.contains("transfer-encoding"));
+
+        let req = Request::builder().method("PUT").uri("/a.txt").body(Body::from("hello")).unwrap();
+        let (req, handle) = inflight.start(client, req);
+        hyper::body::to_bytes(req.into_body()).await.unwrap();
+        assert_eq!(handle.entry.received.load(Ordering::Relaxed), 5);
+    }
+}
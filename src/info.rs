@@ -0,0 +1,133 @@
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use crate::upstreams::{self, Upstream, Upstreams};
+use crate::{features, json, unix, ProxyConfig};
+
+// What an instance runs with: printed when it starts and served as JSON on
+// /admin/info, so deployment tooling can check it is configured as
+// intended. The routes are those in use, after reloads and switches.
+
+// Where the instance can be reached
+#[derive(Default)]
+pub struct Addresses {
+    // As URL, set once the listener is bound and the port known
+    pub listen: OnceLock<String>,
+    pub admin: Option<String>,
+}
+
+fn secs(duration: Option<Duration>) -> Option<u64> {
+    duration.map(|d| d.as_secs())
+}
+
+// Limits by name, None where there is none; sizes and rates in bytes
+fn limits(config: &ProxyConfig) -> Vec<(&'static str, Option<u64>)> {
+    let settings = config.settings.current();
+    let (upload, download) = config.rate_caps.as_ref().map_or((0, 0), |caps| caps.rates());
+    let positive = |n: u64| (n > 0).then_some(n);
+    vec![
+        ("max_concurrent", config.gate.as_ref().map(|gate| gate.max() as u64)),
+        ("max_upload_rate", positive(upload)),
+        ("max_download_rate", positive(download)),
+        ("memory_cap", positive(config.memory.cap() as u64)),
+        ("max_header_size", config.header_limits.max_size.map(|n| n as u64)),
+        ("max_headers", config.header_limits.max_count.map(|n| n as u64)),
+        ("idle_timeout_secs", secs(settings.idle_timeout)),
+        ("transfer_timeout_secs", secs(settings.transfer_timeout)),
+        ("buffer_responses", positive(config.buffer_responses as u64)),
+        ("replay_buffer", positive(config.replay_buffer as u64)),
+    ]
+}
+
+// An upstream as given, a socket by its path
+fn remote(upstream: &Upstream) -> String {
+    match unix::socket_of(&upstream.uri) {
+        Some(path) => format!("unix:{}", path.display()),
+        None => upstreams::root_uri(&upstream.uri, &upstream.base_path).to_string(),
+    }
+}
+
+fn path(folder: &str) -> String {
+    match folder {
+        "" => "/".to_string(),
+        folder => format!("/{}/", folder),
+    }
+}
+
+// What the folder itself answers with while its upstream is unreachable,
+// --fallback routes included
+fn fallback(config: &ProxyConfig, folder: &str, upstream: &Upstream) -> &'static str {
+    config.fallback.for_path(&path(folder), upstream.fallback).name()
+}
+
+fn routes(config: &ProxyConfig, upstreams: &Upstreams) -> Vec<String> {
+    upstreams
+        .iter()
+        .map(|(folder, upstream)| {
+            json::object(&[
+                ("path", json::string(&path(folder))),
+                ("upstream", json::string(&remote(upstream))),
+                ("timeout_ms", upstream.timeout.as_millis().to_string()),
+                ("retries", upstream.retries.to_string()),
+                ("fallback", json::string(fallback(config, folder, upstream))),
+                ("mirror", upstream.mirror.as_ref().map_or("null".to_string(), |m| json::string(&m.uri.to_string()))),
+            ])
+        })
+        .collect()
+}
+
+fn optional(value: Option<&String>) -> String {
+    value.map_or("null".to_string(), |v| json::string(v))
+}
+
+pub fn to_json(config: &ProxyConfig) -> String {
+    let limits: Vec<(&str, String)> = limits(config)
+        .into_iter()
+        .map(|(name, limit)| (name, limit.map_or("null".to_string(), |n| n.to_string())))
+        .collect();
+    json::object(&[
+        ("name", json::string(env!("CARGO_PKG_NAME"))),
+        ("version", json::string(env!("CARGO_PKG_VERSION"))),
+        ("features", json::array(&features::ENABLED.iter().map(|f| json::string(f)).collect::<Vec<_>>())),
+        ("listen", optional(config.addresses.listen.get())),
+        ("admin", optional(config.addresses.admin.as_ref())),
+        ("read_only", config.read_only.to_string()),
+        ("routes", json::array(&routes(config, &config.settings.current().upstreams))),
+        ("limits", json::object(&limits)),
+    ])
+}
+
+// The startup report, a line per fact
+pub fn banner(config: &ProxyConfig) -> String {
+    let mut out = format!(
+        "{} {} (features: {})\n",
+        env!("CARGO_PKG_NAME"),
+        env!("CARGO_PKG_VERSION"),
+        features::list()
+    );
+    if let Some(listen) = config.addresses.listen.get() {
+        out.push_str(&format!("Listening on {}{}\n", listen, if config.read_only { ", read-only" } else { "" }));
+    }
+    if let Some(admin) = &config.addresses.admin {
+        out.push_str(&format!("Admin API on http://{}/admin/\n", admin));
+    }
+    for (folder, upstream) in config.settings.current().upstreams.iter() {
+        out.push_str(&format!(
+            "{} -> {} (timeout {}s, {} retries, fallback {}{})\n",
+            path(folder),
+            remote(upstream),
+            upstream.timeout.as_secs_f64(),
+            upstream.retries,
+            fallback(config, folder, upstream),
+            upstream.mirror.as_ref().map_or(String::new(), |m| format!(", mirror {}", m.uri)),
+        ));
+    }
+    let limits: Vec<String> = limits(config)
+        .into_iter()
+        .filter_map(|(name, limit)| limit.map(|n| format!("{} {}", name, n)))
+        .collect();
+    if !limits.is_empty() {
+        out.push_str(&format!("Limits: {}\n", limits.join(", ")));
+    }
+    out
+}
@@ -0,0 +1,30 @@
+// Just enough JSON output for the admin API, without pulling in serde
+
+pub fn string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+// Render `"key": value` pairs, with values already encoded
+pub fn object(fields: &[(&str, String)]) -> String {
+    let body: Vec<String> = fields.iter().map(|(k, v)| format!("{}: {}", string(k), v)).collect();
+    format!("{{{}}}", body.join(", "))
+}
+
+// Render a list of already encoded values
+pub fn array(items: &[String]) -> String {
+    format!("[{}]", items.join(", "))
+}
@@ -0,0 +1,466 @@
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use hyper::http::uri::PathAndQuery;
+use hyper::Uri;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+// Paths from legacy clients that are not UTF-8: either raw 8-bit bytes in
+// the request line, which hyper can't parse, or percent-encoded bytes of
+// some legacy charset
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Charset {
+    Latin1,
+    Windows1252,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum LegacyPaths {
+    // Forward percent-encoded bytes as they are (raw bytes get hyper's 400)
+    Pass,
+    // Answer 400 to paths that don't decode to UTF-8
+    Reject,
+    // Reinterpret them in a legacy charset and forward them as UTF-8
+    Transcode(Charset),
+}
+
+// Characters 0x80-0x9F of windows-1252, where it differs from Latin-1
+const WINDOWS_1252: [char; 32] = [
+    '\u{20AC}', '\u{81}', '\u{201A}', '\u{192}', '\u{201E}', '\u{2026}', '\u{2020}', '\u{2021}',
+    '\u{2C6}', '\u{2030}', '\u{160}', '\u{2039}', '\u{152}', '\u{8D}', '\u{17D}', '\u{8F}',
+    '\u{90}', '\u{2018}', '\u{2019}', '\u{201C}', '\u{201D}', '\u{2022}', '\u{2013}', '\u{2014}',
+    '\u{2DC}', '\u{2122}', '\u{161}', '\u{203A}', '\u{153}', '\u{9D}', '\u{17E}', '\u{178}',
+];
+
+impl Charset {
+    fn decode(self, byte: u8) -> char {
+        match (self, byte) {
+            (Charset::Windows1252, 0x80..=0x9F) => WINDOWS_1252[(byte - 0x80) as usize],
+            _ => byte as char,
+        }
+    }
+}
+
+impl LegacyPaths {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "pass" => Ok(LegacyPaths::Pass),
+            "reject" => Ok(LegacyPaths::Reject),
+            "latin1" | "iso-8859-1" => Ok(LegacyPaths::Transcode(Charset::Latin1)),
+            "cp1252" | "windows-1252" => Ok(LegacyPaths::Transcode(Charset::Windows1252)),
+            _ => Err(format!("Unknown legacy path handling (expected pass, reject, latin1 or cp1252): {}", s)),
+        }
+    }
+
+    // Check the path of a request target: Ok(None) leaves it alone,
+    // Ok(Some(_)) is the transcoded replacement, Err asks for a 400
+    pub fn check(self, uri: &Uri) -> Result<Option<PathAndQuery>, ()> {
+        let decoded = percent_decode(uri.path().as_bytes());
+        if self == LegacyPaths::Pass || std::str::from_utf8(&decoded).is_ok() {
+            return Ok(None);
+        }
+        let charset = match self {
+            LegacyPaths::Transcode(charset) => charset,
+            _ => return Err(()),
+        };
+        let mut target = transcode(uri.path().as_bytes(), charset);
+        if let Some(query) = uri.query() {
+            target.push('?');
+            target.push_str(query);
+        }
+        target.parse().map(Some).map_err(|_| ())
+    }
+}
+
+fn hex_value(b: u8) -> Option<u8> {
+    (b as char).to_digit(16).map(|d| d as u8)
+}
+
+// Decode the 8-bit escapes only; ASCII ones like %2F keep their meaning
+fn percent_decode(input: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(input.len());
+    let mut i = 0;
+    while i < input.len() {
+        if input[i] == b'%' && i + 2 < input.len() {
+            if let (Some(h), Some(l)) = (hex_value(input[i + 1]), hex_value(input[i + 2])) {
+                if h >= 8 {
+                    out.push(h << 4 | l);
+                    i += 3;
+                    continue;
+                }
+            }
+        }
+        out.push(input[i]);
+        i += 1;
+    }
+    out
+}
+
+// Re-encode a path whose raw and percent-encoded 8-bit bytes are in
+// `charset` as percent-encoded UTF-8. Bytes that decode to valid UTF-8
+// sequences are taken to be UTF-8 already.
+fn transcode(path: &[u8], charset: Charset) -> String {
+    let decoded = percent_decode(path);
+    let mut out = String::with_capacity(path.len() * 2);
+    let mut rest = decoded.as_slice();
+    while !rest.is_empty() {
+        let (valid, invalid) = match std::str::from_utf8(rest) {
+            Ok(s) => (s, &rest[rest.len()..]),
+            Err(e) => (
+                std::str::from_utf8(&rest[..e.valid_up_to()]).expect("checked"),
+                &rest[e.valid_up_to()..],
+            ),
+        };
+        encode_into(&mut out, valid);
+        if let Some((&byte, tail)) = invalid.split_first() {
+            encode_into(&mut out, charset.decode(byte).encode_utf8(&mut [0; 4]));
+            rest = tail;
+        } else {
+            rest = invalid;
+        }
+    }
+    out
+}
+
+// Percent-encode everything but unreserved characters, path delimiters and
+// the escapes that were left in place
+fn encode_into(out: &mut String, s: &str) {
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' | b'!' | b'$' | b'&'
+            | b'\'' | b'(' | b')' | b'*' | b'+' | b',' | b';' | b'=' | b':' | b'@' | b'%' => out.push(b as char),
+            _ => out.push_str(&format!("%{:02X}", b)),
+        }
+    }
+}
+
+// Rewrite raw 8-bit bytes in the request target and Destination header of
+// a request head (everything up to the blank line)
+fn rewrite_head(head: &[u8], charset: Charset) -> Vec<u8> {
+    let mut out = Vec::with_capacity(head.len() + 64);
+    for (i, line) in head.split_inclusive(|&b| b == b'\n').enumerate() {
+        let is_destination = line.len() > 12 && line[..12].eq_ignore_ascii_case(b"destination:");
+        if !line.iter().any(|&b| b >= 0x80) || !(i == 0 || is_destination) {
+            out.extend_from_slice(line);
+            continue;
+        }
+        // The target is the second space-separated field of the request
+        // line, and the whole value of Destination
+        let (start, end) = if i == 0 {
+            let start = line.iter().position(|&b| b == b' ').map_or(0, |p| p + 1);
+            let end = line.iter().rposition(|&b| b == b' ').filter(|&p| p >= start).unwrap_or(line.len());
+            (start, end)
+        } else {
+            let start = 12 + line[12..].iter().take_while(|&&b| b == b' ' || b == b'\t').count();
+            let end = line.len() - line.iter().rev().take_while(|&&b| b == b'\r' || b == b'\n').count();
+            (start, end)
+        };
+        let target = &line[start..end];
+        // Keep scheme and authority of absolute targets untouched; they are ASCII
+        let (prefix, path) = match target.iter().position(|&b| b == b'/') {
+            Some(p) if target[..p].ends_with(b":") && target.len() >= p + 2 => {
+                let path_start = target[p + 2..].iter().position(|&b| b == b'/').map_or(target.len(), |q| p + 2 + q);
+                target.split_at(path_start)
+            }
+            _ => target.split_at(0),
+        };
+        let (path, query) = match path.iter().position(|&b| b == b'?') {
+            Some(q) => path.split_at(q),
+            None => (path, &path[path.len()..]),
+        };
+        out.extend_from_slice(&line[..start]);
+        out.extend_from_slice(prefix);
+        out.extend_from_slice(transcode(path, charset).as_bytes());
+        if let Some((mark, query)) = query.split_first() {
+            out.push(*mark);
+            out.extend_from_slice(transcode(query, charset).as_bytes());
+        }
+        out.extend_from_slice(&line[end..]);
+    }
+    out
+}
+
+// Longest request head that is buffered for rewriting
+const MAX_HEAD: usize = 64 * 1024;
+
+enum State {
+    Head,
+    Fixed(u64),
+    ChunkLine,
+    ChunkData(u64),
+    Trailers,
+    // Unknown framing (upgrades, oversized heads): stop rewriting
+    Passthrough,
+}
+
+// Connection wrapper that follows HTTP/1.1 framing on the incoming side and
+// transcodes the raw 8-bit bytes of each request head before hyper parses it
+pub struct RequestRewriter<S> {
+    inner: S,
+    charset: Charset,
+    state: State,
+    line: Vec<u8>,
+    out: Vec<u8>,
+    pos: usize,
+}
+
+impl<S> RequestRewriter<S> {
+    pub fn new(inner: S, charset: Charset) -> Self {
+        RequestRewriter {
+            inner,
+            charset,
+            state: State::Head,
+            line: Vec::new(),
+            out: Vec::new(),
+            pos: 0,
+        }
+    }
+
+    // Framing of the body following a request head
+    fn body_state(head: &[u8]) -> State {
+        let mut length = 0;
+        for line in head.split(|&b| b == b'\n').skip(1) {
+            let line = String::from_utf8_lossy(line);
+            let (name, value) = match line.split_once(':') {
+                Some((name, value)) => (name.trim().to_ascii_lowercase(), value.trim().to_ascii_lowercase()),
+                None => continue,
+            };
+            match name.as_str() {
+                "transfer-encoding" if value.contains("chunked") => return State::ChunkLine,
+                "content-length" => length = value.parse().unwrap_or(0),
+                "upgrade" => return State::Passthrough,
+                _ => {}
+            }
+        }
+        if head.starts_with(b"CONNECT ") {
+            return State::Passthrough;
+        }
+        if length == 0 {
+            State::Head
+        } else {
+            State::Fixed(length)
+        }
+    }
+
+    fn feed(&mut self, mut input: &[u8]) {
+        while !input.is_empty() {
+            match self.state {
+                State::Head => {
+                    let end = input.iter().position(|&b| b == b'\n').map_or(input.len(), |p| p + 1);
+                    self.line.extend_from_slice(&input[..end]);
+                    input = &input[end..];
+                    if self.line.ends_with(b"\r\n\r\n") || self.line.ends_with(b"\n\n") {
+                        let head = std::mem::take(&mut self.line);
+                        // Blank lines between requests are allowed and skipped
+                        if head.iter().all(|&b| b == b'\r' || b == b'\n') {
+                            self.out.extend_from_slice(&head);
+                            continue;
+                        }
+                        self.out.extend_from_slice(&rewrite_head(&head, self.charset));
+                        self.state = Self::body_state(&head);
+                    } else if self.line == b"\r\n" || self.line == b"\n" {
+                        let blank = std::mem::take(&mut self.line);
+                        self.out.extend_from_slice(&blank);
+                    } else if self.line.len() > MAX_HEAD {
+                        let head = std::mem::take(&mut self.line);
+                        self.out.extend_from_slice(&head);
+                        self.state = State::Passthrough;
+                    }
+                }
+                State::Fixed(n) | State::ChunkData(n) => {
+                    let take = (n.min(input.len() as u64)) as usize;
+                    self.out.extend_from_slice(&input[..take]);
+                    input = &input[take..];
+                    let left = n - take as u64;
+                    self.state = match (&self.state, left) {
+                        (State::Fixed(_), 0) => State::Head,
+                        (State::Fixed(_), left) => State::Fixed(left),
+                        (_, 0) => State::ChunkLine,
+                        (_, left) => State::ChunkData(left),
+                    };
+                }
+                State::ChunkLine | State::Trailers => {
+                    let end = input.iter().position(|&b| b == b'\n').map_or(input.len(), |p| p + 1);
+                    self.line.extend_from_slice(&input[..end]);
+                    input = &input[end..];
+                    if !self.line.ends_with(b"\n") {
+                        if self.line.len() > MAX_HEAD {
+                            self.state = State::Passthrough;
+                            let line = std::mem::take(&mut self.line);
+                            self.out.extend_from_slice(&line);
+                        }
+                        continue;
+                    }
+                    let line = std::mem::take(&mut self.line);
+                    self.out.extend_from_slice(&line);
+                    self.state = match self.state {
+                        State::ChunkLine => {
+                            let size = String::from_utf8_lossy(&line);
+                            let size = size.split(';').next().unwrap_or("").trim();
+                            match u64::from_str_radix(size, 16) {
+                                Ok(0) => State::Trailers,
+                                // The chunk data is followed by CRLF
+                                Ok(n) => State::ChunkData(n + 2),
+                                Err(_) => State::Passthrough,
+                            }
+                        }
+                        _ if line == b"\r\n" || line == b"\n" => State::Head,
+                        _ => State::Trailers,
+                    };
+                }
+                State::Passthrough => {
+                    self.out.extend_from_slice(input);
+                    input = &[];
+                }
+            }
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for RequestRewriter<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        loop {
+            if this.pos < this.out.len() {
+                let n = (this.out.len() - this.pos).min(buf.remaining());
+                buf.put_slice(&this.out[this.pos..this.pos + n]);
+                this.pos += n;
+                if this.pos == this.out.len() {
+                    this.out.clear();
+                    this.pos = 0;
+                }
+                return Poll::Ready(Ok(()));
+            }
+            let mut chunk = [0u8; 8192];
+            let mut read = ReadBuf::new(&mut chunk);
+            match Pin::new(&mut this.inner).poll_read(cx, &mut read) {
+                Poll::Ready(Ok(())) => {
+                    let filled = read.filled();
+                    if filled.is_empty() {
+                        // EOF; an incomplete head is handed over as it is
+                        let rest = std::mem::take(&mut this.line);
+                        if rest.is_empty() {
+                            return Poll::Ready(Ok(()));
+                        }
+                        this.out.extend_from_slice(&rest);
+                        continue;
+                    }
+                    let filled = filled.to_vec();
+                    this.feed(&filled);
+                }
+                other => return other,
+            }
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for RequestRewriter<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(mode: &str, target: &str) -> Result<Option<String>, ()> {
+        let uri: Uri = target.parse().unwrap();
+        LegacyPaths::parse(mode).unwrap().check(&uri).map(|p| p.map(|p| p.to_string()))
+    }
+
+    #[test]
+    fn decodes_only_8_bit_escapes() {
+        assert_eq!(percent_decode(b"/a%2Fb%20c%e9%C3%A9"), b"/a%2Fb%20c\xe9\xc3\xa9");
+        // Incomplete or invalid escapes stay
+        assert_eq!(percent_decode(b"/%E%ZZ%8"), b"/%E%ZZ%8");
+    }
+
+    #[test]
+    fn encodes_what_is_not_allowed_in_a_path() {
+        let mut out = String::new();
+        encode_into(&mut out, "/a b/é?#%2F;x=1");
+        assert_eq!(out, "/a%20b/%C3%A9%3F%23%2F;x=1");
+    }
+
+    #[test]
+    fn transcodes_escaped_and_raw_bytes() {
+        assert_eq!(transcode(b"/caf%E9/x", Charset::Latin1), "/caf%C3%A9/x");
+        assert_eq!(transcode(b"/caf\xe9/x", Charset::Latin1), "/caf%C3%A9/x");
+        assert_eq!(transcode(b"/%80%9F", Charset::Latin1), "/%C2%80%C2%9F");
+        assert_eq!(transcode(b"/%80%9F", Charset::Windows1252), "/%E2%82%AC%C5%B8");
+        // UTF-8 already is left as it is, next to legacy bytes
+        assert_eq!(transcode(b"/%C3%A9%E9", Charset::Latin1), "/%C3%A9%C3%A9");
+    }
+
+    #[test]
+    fn transcoding_keeps_ascii_escapes() {
+        assert_eq!(transcode(b"/a%2Fb%E9/%25%3F", Charset::Latin1), "/a%2Fb%C3%A9/%25%3F");
+    }
+
+    #[test]
+    fn round_trips_every_legacy_byte() {
+        for charset in [Charset::Latin1, Charset::Windows1252] {
+            for byte in 0x80..=0xFF_u8 {
+                let expected = format!("/{}.txt", charset.decode(byte));
+                let encoded = transcode(format!("/%{:02X}.txt", byte).as_bytes(), charset);
+                assert_eq!(std::str::from_utf8(&percent_decode(encoded.as_bytes())), Ok(expected.as_str()));
+                // Transcoding is idempotent
+                assert_eq!(transcode(encoded.as_bytes(), charset), encoded);
+            }
+        }
+    }
+
+    #[test]
+    fn pass_leaves_paths_alone() {
+        assert_eq!(check("pass", "/caf%E9"), Ok(None));
+    }
+
+    #[test]
+    fn reject_refuses_only_paths_that_are_not_utf8() {
+        assert_eq!(check("reject", "/caf%E9"), Err(()));
+        assert_eq!(check("reject", "/caf%E9/x?q=1"), Err(()));
+        assert_eq!(check("reject", "/caf%C3%A9"), Ok(None));
+        assert_eq!(check("reject", "/a%2Fb%20c"), Ok(None));
+        // Only the path is looked at
+        assert_eq!(check("reject", "/a?q=%E9"), Ok(None));
+    }
+
+    #[test]
+    fn transcode_replaces_the_path_and_keeps_the_query() {
+        assert_eq!(check("latin1", "/caf%E9/a%2Fb?q=%E9"), Ok(Some("/caf%C3%A9/a%2Fb?q=%E9".to_string())));
+        assert_eq!(check("cp1252", "/%80"), Ok(Some("/%E2%82%AC".to_string())));
+        assert_eq!(check("cp1252", "/%E2%82%AC"), Ok(None));
+    }
+
+    #[test]
+    fn parses_modes() {
+        assert!(LegacyPaths::parse(" Windows-1252 ") == Ok(LegacyPaths::Transcode(Charset::Windows1252)));
+        assert!(LegacyPaths::parse("ISO-8859-1") == Ok(LegacyPaths::Transcode(Charset::Latin1)));
+        assert!(LegacyPaths::parse("utf-16").is_err());
+    }
+
+    #[test]
+    fn rewrites_raw_bytes_in_request_heads_only() {
+        let mut rewriter = RequestRewriter::new((), Charset::Latin1);
+        let input: &[u8] = b"MOVE /caf\xe9?x=\xe9 HTTP/1.1\r\nHost: h\r\nDestination: http://h/th\xe9\r\nContent-Length: 2\r\n\r\n\xe9\xe9\
+            GET /%2F\xff HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n1\r\n\xe9\r\n0\r\n\r\n";
+        // Fed a byte at a time, as a slow client might send it
+        for byte in input {
+            rewriter.feed(std::slice::from_ref(byte));
+        }
+        let expected: &[u8] = b"MOVE /caf%C3%A9?x=%C3%A9 HTTP/1.1\r\nHost: h\r\nDestination: http://h/th%C3%A9\r\nContent-Length: 2\r\n\r\n\xe9\xe9\
+            GET /%2F%C3%BF HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n1\r\n\xe9\r\n0\r\n\r\n";
+        assert_eq!(rewriter.out, expected);
+    }
+}
@@ -0,0 +1,1315 @@
+//! A WebDAV reverse proxy that keeps clients working while the upstream is
+//! unreachable. The binary is a thin wrapper around [`cli::run`];
+//! applications embed the proxy with [`WebdavProxy::builder`].
+
+use hyper::body::HttpBody;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Client, Request, Response, Server, Uri};
+use std::convert::Infallible;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+mod access_log;
+mod admin;
+mod atomic_file;
+mod auth;
+mod base64;
+mod base_path;
+mod breaker;
+mod buffering;
+mod builder;
+mod cache_headers;
+mod capabilities;
+#[cfg(feature = "bench")]
+mod bench;
+#[cfg(feature = "tls")]
+mod certwatch;
+pub mod cli;
+mod client_limits;
+mod clock;
+#[cfg(feature = "bench")]
+mod compare;
+mod config;
+mod cookies;
+mod cors;
+mod dates;
+mod dedup;
+mod digest;
+mod depth;
+mod errors;
+mod fallback;
+mod features;
+mod fingerprint;
+mod fixtures;
+mod forwarded;
+#[cfg(feature = "geoip")]
+mod geoip;
+mod guard;
+mod guest;
+#[cfg(feature = "gzip")]
+mod gzip;
+mod header_rules;
+mod hooks;
+mod info;
+mod http10;
+#[cfg(feature = "http3")]
+mod http3;
+mod idle;
+mod inflight;
+mod json;
+mod legacy;
+mod limits;
+mod listener;
+mod local_dir;
+mod locks;
+mod logging;
+mod mapping;
+mod memory;
+mod methods;
+mod mirror;
+mod multistatus;
+mod network;
+mod outbound;
+mod pattern;
+mod pause;
+mod peers;
+mod phase;
+mod pinned;
+mod priority;
+mod problem;
+mod read_only;
+mod reload;
+mod replay;
+mod resolve;
+mod response_cache;
+mod rewrite;
+mod rewrite_log;
+mod rollout;
+mod routes;
+mod secrets;
+mod selftest;
+mod sharepoint;
+mod signals;
+mod snapshot;
+mod snapshots;
+mod state;
+mod stats;
+mod status;
+mod strict;
+mod switch;
+mod tenants;
+#[cfg(feature = "test-upstream")]
+mod test_upstream;
+mod throttle;
+#[cfg(feature = "tls")]
+mod tls;
+mod transfers;
+mod unix;
+mod unread;
+mod upstream_auth;
+mod upstream_error;
+mod upstreams;
+mod usage;
+mod vhost;
+mod warm;
+mod virtual_files;
+mod virtual_tree;
+mod xml;
+mod yaml;
+
+use auth::decisions::DecisionCache;
+use auth::guests::Guests;
+use auth::lockout::Lockout;
+use auth::Authenticator;
+use capabilities::Emulation;
+use client_limits::ClientLimits;
+use cors::Cors;
+use cookies::CookiePolicy;
+use dates::DatePolicy;
+use dedup::Dedup;
+use depth::DepthGuard;
+use errors::ErrorLog;
+use fallback::{EntryNames, FallbackFiles, FallbackRoutes};
+use forwarded::Forwarding;
+#[cfg(feature = "geoip")]
+use geoip::GeoPolicy;
+use hooks::Hooks;
+use inflight::InFlight;
+use info::Addresses;
+use legacy::LegacyPaths;
+use limits::HeaderLimits;
+use listener::Listener;
+use memory::{MemoryBudget, Use};
+use network::AccessList;
+use pinned::{BoxError, PinnedConnection};
+use pause::PauseControl;
+use phase::Tracker;
+use priority::{Class, PriorityGate};
+use problem::{ApiRoutes, Forwarded};
+use reload::{Reloadable, Settings};
+use resolve::{Connector, Resolver};
+use response_cache::ResponseCache;
+use rewrite_log::RewriteLog;
+use rollout::{Generation, Rollout};
+use routes::{Disabled, RouteSwitch};
+use snapshot::SnapshotHook;
+use snapshots::SnapshotView;
+use stats::Stats;
+use status::StatusPage;
+use strict::Strict;
+use tenants::{Tenant, Tenants};
+use throttle::{Limiter, Limiters, RateCaps};
+use transfers::{Direction, Info, Transfers};
+use vhost::VirtualHosts;
+use upstreams::Upstream;
+use usage::Usage;
+use virtual_files::VirtualFile;
+use virtual_tree::{PropRequest, VirtualTree};
+
+pub use builder::{Builder, WebdavProxy};
+pub use fallback::Fallback;
+pub use fixtures::{Fixture, Mock, Outcome};
+pub use phase::Phase;
+pub use upstream_error::UpstreamError;
+
+// Settings shared by every connection of the proxy
+struct ProxyConfig {
+    // The upstream shares, each in its own root folder if there are several,
+    // their credentials, rewrites, header rules and timeouts, as last (re)loaded
+    settings: Reloadable,
+    cookie_policy: CookiePolicy,
+    sharepoint: bool,
+    // Fake locking for upstreams without it
+    emulate_locks: Emulation,
+    // Refuse everything that would change the share
+    read_only: bool,
+    // What becomes of PROPFINDs with Depth: infinity, by path
+    depth_guard: DepthGuard,
+    // RFC 4918 rules a request has to meet to be sent on
+    strict: Strict,
+    // Map each client connection to its own upstream connection instead of pooling
+    pin_connections: bool,
+    // Authentication done by the proxy; None forwards credentials as-is
+    auth: Option<Authenticator>,
+    // Recent decisions of `auth`, reused for the same credentials
+    auth_cache: Option<Arc<DecisionCache>>,
+    // Addresses that failed to log in too often are kept out for a while
+    lockout: Option<Lockout>,
+    // Temporary accounts minted through the admin API
+    guests: Guests,
+    // Resolves the upstream host, with --resolve entries taking precedence
+    resolver: Resolver,
+    // Pooled upstream connections
+    client: Client<Connector>,
+    stats: Arc<Stats>,
+    // Monthly traffic of the --usage-path folders
+    usage: Option<Usage>,
+    // Recent panics and unexpected errors, for /admin/errors
+    errors: Arc<ErrorLog>,
+    // Name of the virtual statistics file served at the share root
+    stats_file_name: Option<String>,
+    // The health and status paths for load balancers and monitors
+    status: Option<StatusPage>,
+    // What the upstream is told about the client
+    forwarding: Option<Forwarding>,
+    virtual_files: Vec<VirtualFile>,
+    snapshots: Option<SnapshotView>,
+    // Bandwidth limits shared by all transfers in each direction
+    upload_limiter: Option<Arc<Limiter>>,
+    download_limiter: Option<Arc<Limiter>>,
+    // Fixed limits on top, per connection or for all of them
+    rate_caps: Option<RateCaps>,
+    // Caps concurrent upstream requests, letting metadata requests go first
+    gate: Option<Arc<PriorityGate>>,
+    client_limits: Option<Arc<ClientLimits>>,
+    // Shares of the proxy for the sites it serves
+    tenants: Tenants,
+    // Cross-origin access for browser clients
+    cors: Option<Cors>,
+    // Pause/resume switch driven by the admin API
+    pause: PauseControl,
+    // Routes switched off through the admin API
+    routes: RouteSwitch,
+    // Scheduled (or admin-triggered) snapshot orchestration
+    snapshot: Option<Arc<SnapshotHook>>,
+    // What each route answers while the upstream is unreachable
+    fallback: FallbackRoutes,
+    // Served read-only instead, if given
+    fallback_dir: Option<PathBuf>,
+    fallback_names: EntryNames,
+    fallback_files: FallbackFiles,
+    // Recently completed writes, to answer client retries of them
+    dedup: Option<Arc<Dedup>>,
+    // Recent GET and PROPFIND answers
+    response_cache: Option<Arc<ResponseCache>>,
+    // Accounting of buffered bodies, caches and queues, with an optional cap
+    memory: Arc<MemoryBudget>,
+    // Uploads and downloads past a size, for progress and /admin/transfers
+    transfers: Option<Arc<Transfers>>,
+    // Requests being handled, with the admin API
+    in_flight: Option<Arc<InFlight>>,
+    // Responses up to this size are read whole and sent with a length; 0 streams all
+    buffer_responses: usize,
+    // Largest body of a write that may be resent after a failed connect; 0 never resends writes
+    replay_buffer: usize,
+    header_limits: HeaderLimits,
+    // Country lookups and the countries allowed in
+    #[cfg(feature = "geoip")]
+    geo: Option<GeoPolicy>,
+    // Gzip answers for clients that take it, and unpack them for those that don't
+    #[cfg(feature = "gzip")]
+    compress: bool,
+    // Alt-Svc pointing clients at the HTTP/3 listener
+    #[cfg(feature = "http3")]
+    alt_svc: Option<hyper::header::HeaderValue>,
+    // What to do with paths that aren't UTF-8
+    legacy_paths: LegacyPaths,
+    // Accepted authorities of absolute-form request targets
+    vhosts: VirtualHosts,
+    // Paths whose proxy-generated errors are JSON problem details
+    api_routes: ApiRoutes,
+    // Rewriting of the upstream's timestamps
+    dates: DatePolicy,
+    // A candidate configuration tried on some of the requests
+    rollout: Option<Rollout>,
+    // Logging of the URLs the base path rewrites, and the most lines per request
+    log_rewrites: rewrite_log::Level,
+    log_rewrites_max: usize,
+    // One line per request, if enabled
+    access_log: Option<access_log::Format>,
+    // Callbacks of an application embedding the proxy
+    hooks: Hooks,
+    // Where it listens, for the startup report and /admin/info
+    addresses: Addresses,
+}
+
+// The largest body of a request that doesn't modify the share (PROPFIND,
+// REPORT and the like) kept for sending it again; larger ones stream
+const RETRY_BODY_LIMIT: usize = 1024 * 1024;
+
+fn throttled(limiter: &Option<Arc<Limiter>>, body: Body) -> Body {
+    match limiter {
+        Some(limiter) => limiter.wrap(body),
+        None => body,
+    }
+}
+
+// The names of the files the proxy serves at the share root itself: the
+// virtual statistics file and the --virtual-file ones
+fn root_file_names(config: &ProxyConfig) -> impl Iterator<Item = &str> {
+    config
+        .stats_file_name
+        .as_deref()
+        .into_iter()
+        .chain(config.virtual_files.iter().map(|file| file.name.as_str()))
+}
+
+// Those files, added to the root of `tree`
+fn with_root_files(tree: VirtualTree, config: &ProxyConfig) -> VirtualTree {
+    let tree = match &config.stats_file_name {
+        Some(name) => tree.file(name, config.stats.to_text(), "text/plain; charset=utf-8"),
+        None => tree,
+    };
+    config
+        .virtual_files
+        .iter()
+        .fold(tree, |tree, file| tree.file(&file.name, file.content.clone(), file.content_type))
+}
+
+// Add the proxy's own files to a PROPFIND listing of the share root
+async fn inject_root_files(response: Response<Body>, config: &ProxyConfig) -> Result<Response<Body>, hyper::Error> {
+    let (mut parts, body) = response.into_parts();
+    let bytes = hyper::body::to_bytes(body).await?;
+    let tree = with_root_files(VirtualTree::default(), config);
+    let injected = std::str::from_utf8(&bytes).ok().and_then(|xml| {
+        multistatus::inject(xml, |prefix| {
+            root_file_names(config)
+                .filter_map(|name| tree.responses(name, 0, prefix, &PropRequest::ALL))
+                .collect()
+        })
+    });
+    let body = match injected {
+        Some(xml) => {
+            parts.headers.remove("Content-Length");
+            Body::from(xml)
+        }
+        None => Body::from(bytes),
+    };
+    Ok(Response::from_parts(parts, body))
+}
+
+// Proxy-generated errors on API routes become problem details
+async fn serve(
+    req: Request<Body>,
+    config: Arc<ProxyConfig>,
+    pinned: Option<Arc<Vec<PinnedConnection>>>,
+    remote: SocketAddr,
+) -> Result<Response<Body>, hyper::Error> {
+    let (req, entry) = match config.access_log {
+        Some(format) => {
+            let (req, entry) = access_log::start(format, remote, req);
+            (req, Some(entry))
+        }
+        None => (req, None),
+    };
+    let (req, handle) = match &config.in_flight {
+        Some(in_flight) => {
+            let (req, handle) = in_flight.start(remote.ip(), req);
+            (req, Some(handle))
+        }
+        None => (req, None),
+    };
+    let response = match handle {
+        // Cancelling drops the request to the upstream along with the future
+        Some(handle) => tokio::select! {
+            response = answer(req, config.clone(), pinned, remote) => handle.finish(response?),
+            _ = handle.cancelled() => inflight::refusal(),
+        },
+        None => answer(req, config.clone(), pinned, remote).await?,
+    };
+    #[cfg(feature = "http3")]
+    let response = match &config.alt_svc {
+        // In place of any the upstream sent, which would name its own ports
+        Some(alt_svc) => {
+            let mut response = response;
+            response.headers_mut().insert(hyper::header::ALT_SVC, alt_svc.clone());
+            response
+        }
+        None => response,
+    };
+    Ok(match entry {
+        Some(entry) => entry.finish(response),
+        None => response,
+    })
+}
+
+async fn answer(
+    req: Request<Body>,
+    config: Arc<ProxyConfig>,
+    pinned: Option<Arc<Vec<PinnedConnection>>>,
+    remote: SocketAddr,
+) -> Result<Response<Body>, hyper::Error> {
+    if let Some(refusal) = unread::unmet_expectation(&req) {
+        return Ok(refusal);
+    }
+    if let Some(status) = &config.status {
+        if let Some(response) = status.answer(&req, &config).await {
+            return Ok(response);
+        }
+    }
+    if let Some(response) = config.cors.as_ref().and_then(|cors| cors.preflight(&req)) {
+        return Ok(response);
+    }
+    let origin = config.cors.as_ref().and_then(|_| req.headers().get(hyper::header::ORIGIN).cloned());
+    let cors = |response: &mut Response<Body>| {
+        if let Some(cors) = &config.cors {
+            cors.apply(origin.as_ref(), response.headers_mut());
+        }
+    };
+    // Counted against the client until the response body has been sent
+    let slot = match config.client_limits.as_ref().map(|limits| limits.admit(remote.ip())) {
+        Some(Err(refusal)) => {
+            let mut response = refusal.response();
+            cors(&mut response);
+            return Ok(response);
+        }
+        Some(Ok(slot)) => Some(slot),
+        None => None,
+    };
+    let mut req = req;
+    // Likewise counted against its site, whose limits proxy_request applies
+    let tenant_slot = match config.tenants.of(req.headers()) {
+        Some(tenant) => match tenant.admit() {
+            Some(slot) => {
+                req.extensions_mut().insert(tenant);
+                Some(slot)
+            }
+            None => {
+                let mut response = tenant.busy();
+                cors(&mut response);
+                return Ok(response);
+            }
+        },
+        None => None,
+    };
+    if let Some(mut response) = config.hooks.request(&mut req) {
+        config.hooks.response(&mut response);
+        cors(&mut response);
+        return Ok(response);
+    }
+    let generation = config.rollout.as_ref().map(|rollout| rollout.pick(&mut req));
+    let (req, unread) = unread::track(req);
+    let api = config
+        .api_routes
+        .matches(req.uri().path())
+        .then(|| (problem::request_id(req.headers()), req.uri().path().to_string()));
+    let mut response = proxy_request(req, config.clone(), pinned, remote).await?;
+    // Refusals and fallbacks leave the body unread
+    if response.extensions().get::<Forwarded>().is_none() {
+        unread.settle(&mut response).await;
+    }
+    let mut response = match api {
+        Some((request_id, instance)) => problem::convert(response, &request_id, &instance).await,
+        None => response,
+    };
+    config.hooks.response(&mut response);
+    cors(&mut response);
+    if let (Some(rollout), Some(generation)) = (&config.rollout, generation) {
+        rollout.record(generation, response.status());
+    }
+    Ok(match (slot, tenant_slot) {
+        (None, None) => response,
+        slots => response.map(|body| guard::attach(slots, body)),
+    })
+}
+
+async fn proxy_request(
+    req: Request<Body>,
+    config: Arc<ProxyConfig>,
+    pinned: Option<Arc<Vec<PinnedConnection>>>,
+    remote: SocketAddr,
+) -> Result<Response<Body>, hyper::Error> {
+    if let Some(rejection) = config.header_limits.check(req.headers()) {
+        return Ok(rejection);
+    }
+    if let Some(rejection) = limits::check_path(req.uri(), req.headers()) {
+        return Ok(rejection);
+    }
+    if let Some(refusal) = config.memory.admit() {
+        return Ok(refusal);
+    }
+    #[cfg(feature = "geoip")]
+    let country = config.geo.as_ref().map(|geo| geo.db.country(remote.ip()));
+    #[cfg(not(feature = "geoip"))]
+    let country: Option<String> = None;
+    #[cfg(feature = "geoip")]
+    if let (Some(geo), Some(country)) = (&config.geo, &country) {
+        if !geo.permits(country) {
+            tracing::warn!("Refused {} from {} ({})", req.uri().path(), remote.ip(), country);
+            return Ok(Response::builder()
+                .status(403)
+                .header("Content-Type", "text/plain")
+                .body(Body::from("Access from your country is not allowed\n"))
+                .expect("response builder"));
+        }
+    }
+    let mut req = req;
+    match config.legacy_paths.check(req.uri()) {
+        Ok(None) => {}
+        Ok(Some(target)) => {
+            let mut parts = req.uri().clone().into_parts();
+            parts.path_and_query = Some(target);
+            *req.uri_mut() = Uri::from_parts(parts).expect("valid URI");
+        }
+        Err(()) => {
+            return Ok(Response::builder()
+                .status(400)
+                .header("Content-Type", "text/plain")
+                .body(Body::from("The request path is not valid UTF-8\n"))
+                .expect("response builder"));
+        }
+    }
+    // The candidate's settings where it has its own
+    let candidate = match (&config.rollout, req.extensions().get::<Generation>()) {
+        (Some(rollout), Some(Generation::Candidate)) => Some(&rollout.candidate),
+        _ => None,
+    };
+    // Kept to the end even if the configuration is reloaded meanwhile
+    let settings = config.settings.current();
+    let upstreams = candidate.and_then(|c| c.upstreams.as_ref()).unwrap_or(&settings.upstreams);
+    let rewrites = candidate.and_then(|c| c.rewrites.as_ref()).unwrap_or(&settings.rewrites);
+    let header_rules = candidate.and_then(|c| c.header_rules.as_ref()).unwrap_or(&settings.header_rules);
+    let cache_headers = candidate.and_then(|c| c.cache_headers.as_ref()).unwrap_or(&settings.cache_headers);
+    // By the path the client asked for, before any rewriting
+    let cache_rule = cache_headers.rule(req.method(), req.uri().path());
+    let client_path = (!header_rules.is_empty()).then(|| req.uri().path().to_string());
+    // Moved out instead of copied; from here on only this map is current
+    let mut req_header_temp = std::mem::take(req.headers_mut());
+    #[cfg(feature = "gzip")]
+    let client_gzip = config.compress && gzip::accepted(&req_header_temp);
+    // Rewrite rules come before routing
+    let rewrite = match rewrites.is_empty() {
+        true => None,
+        false => rewrites.apply_request(req.uri_mut(), &mut req_header_temp),
+    };
+    // Only the proxy may tell the upstream where the client is
+    req_header_temp.remove("x-client-country");
+    if let Some(country) = &country {
+        if let Ok(value) = hyper::header::HeaderValue::from_str(country) {
+            req_header_temp.insert("x-client-country", value);
+        }
+    }
+    if let Some(rejection) = config.vhosts.check(req.uri(), &mut req_header_temp) {
+        return Ok(rejection);
+    }
+    if let Some(forwarding) = &config.forwarding {
+        forwarding.apply(remote.ip(), req.version(), &mut req_header_temp);
+    }
+    config.cookie_policy.apply_request(&mut req_header_temp);
+
+    let mut response_challenge = None;
+    let mut user_name = None;
+    if let Some(auth) = &config.auth {
+        // Only the proxy may tell the upstream who the user is
+        req_header_temp.remove("x-forwarded-user");
+        if let Some(rejection) = config.lockout.as_ref().and_then(|l| l.check(remote.ip())) {
+            return Ok(rejection);
+        }
+        let attempted = auth::attempted(&req_header_temp);
+        let outcome = match config.guests.authenticate(req.uri().path(), &mut req_header_temp) {
+            Some(outcome) => outcome,
+            None => {
+                auth.authenticate(req.uri().path(), &mut req_header_temp, config.auth_cache.as_deref())
+                    .await
+            }
+        };
+        match outcome {
+            Ok(user) => {
+                if let Some(lockout) = &config.lockout {
+                    lockout.succeeded(remote.ip());
+                }
+                if let Ok(name) = hyper::header::HeaderValue::from_str(&user.name) {
+                    req_header_temp.insert("x-forwarded-user", name);
+                }
+                response_challenge = user.response_challenge;
+                user_name = Some(user.name);
+            }
+            Err(rejection) => {
+                if attempted {
+                    auth::log_failure(remote.ip(), &req_header_temp, rejection.status(), req.uri().path());
+                    let locked = rejection.status() == hyper::StatusCode::UNAUTHORIZED
+                        && config.lockout.as_ref().is_some_and(|l| l.failed(remote.ip()));
+                    if locked {
+                        tracing::warn!("auth lockout: client={}", remote.ip());
+                    }
+                }
+                return Ok(rejection);
+            }
+        }
+    }
+
+    // Names with spaces and the like come percent-encoded
+    let root_file = req
+        .uri()
+        .path()
+        .strip_prefix('/')
+        .and_then(|encoded| root_file_names(&config).find(|name| virtual_tree::encode_segment(name) == encoded))
+        .map(str::to_string);
+    if let Some(name) = root_file {
+        let tree = with_root_files(VirtualTree::default(), &config);
+        let (parts, body) = req.into_parts();
+        let body = hyper::body::to_bytes(body).await?;
+        return Ok(tree.serve(&parts.method, &name, &req_header_temp, &body).expect("the file is in the tree"));
+    }
+    // With several upstreams the root only holds their folders
+    let Some(index) = upstreams.route(req.uri().path()) else {
+        let tree = with_root_files(upstreams.root(), &config);
+        let (parts, body) = req.into_parts();
+        let body = hyper::body::to_bytes(body).await?;
+        return Ok(tree.serve(&parts.method, parts.uri.path(), &req_header_temp, &body).unwrap_or_else(|| {
+            Response::builder()
+                .status(404)
+                .header("Content-Type", "text/plain")
+                .body(Body::from("Not found\n"))
+                .expect("response builder")
+        }));
+    };
+    let upstream = upstreams.get(index);
+    if let Some(problem) = config.strict.check(req.method(), &req_header_temp) {
+        return Ok(strict::rejection(&problem));
+    }
+    if config.strict.wants_body(req.method()) {
+        // A body too large to hold goes on unchecked
+        match buffering::read_up_to(std::mem::take(req.body_mut()), strict::BODY_LIMIT).await? {
+            Ok(bytes) => {
+                if let Some(problem) = config.strict.check_body(req.method(), &req_header_temp, &bytes) {
+                    return Ok(strict::rejection(&problem));
+                }
+                *req.body_mut() = Body::from(bytes);
+            }
+            Err(streamed) => *req.body_mut() = streamed,
+        }
+    }
+    if let Some(refusal) = config.depth_guard.check(req.method(), req.uri().path(), &mut req_header_temp) {
+        return Ok(refusal);
+    }
+    // The snapshot view reads the upstream's snapshot folders instead
+    let view = config.snapshots.as_ref();
+    let snapshot = match view.and_then(|view| view.map(upstreams.mount(index), req.uri().path())) {
+        Some((mapping, target)) => {
+            if let Some(refusal) = read_only::check(req.method()) {
+                return Ok(refusal);
+            }
+            let target = match req.uri().query() {
+                Some(query) => format!("{}?{}", target, query),
+                None => target,
+            };
+            let mut parts = req.uri().clone().into_parts();
+            parts.path_and_query = Some(target.parse().expect("made of a valid path"));
+            *req.uri_mut() = Uri::from_parts(parts).expect("valid URI");
+            Some(mapping)
+        }
+        None => None,
+    };
+    // Dedup and the cache work with client paths, which the base path is
+    // about to change
+    let client_destination = (config.dedup.is_some() || config.response_cache.is_some())
+        .then(|| methods::destination_path(&req_header_temp))
+        .flatten();
+    let rewrite_log = RewriteLog::new(
+        config.log_rewrites,
+        config.log_rewrites_max,
+        format!("{} {}", req.method(), req.uri().path()),
+    );
+    if let Some(rejection) = upstream
+        .base_path
+        .apply_request(&upstream.uri, &mut req_header_temp, rewrite_log.as_ref())
+    {
+        return Ok(rejection);
+    }
+    if config.sharepoint {
+        sharepoint::apply_request_fixups(&mut req_header_temp, &upstream.uri);
+    }
+    if config.read_only {
+        if let Some(refusal) = read_only::check(req.method()) {
+            return Ok(refusal);
+        }
+    }
+    let emulate_locks = !config.read_only
+        && config
+            .emulate_locks
+            .active(upstream.capabilities.get().map(|c| c.locking()));
+    if emulate_locks {
+        if locks::is_lock_method(req.method()) {
+            let (parts, body) = req.into_parts();
+            let body = hyper::body::to_bytes(body).await?;
+            return Ok(locks::respond(&parts.method, parts.uri.path(), &req_header_temp, &body));
+        }
+        locks::strip_tokens(&mut req_header_temp);
+    }
+    // A leg of an NTLM or Negotiate handshake gets the upstream's failure as
+    // it is, not a listing the client would take for being logged in
+    let handshake = pinned.as_ref().is_some_and(|conns| conns[index].in_handshake());
+    let fallback = if fallback::opted_out(&mut req_header_temp, req.uri_mut()) || handshake {
+        Fallback::Error
+    } else {
+        config.fallback.for_path(req.uri().path(), upstream.fallback).for_method(req.method())
+    };
+    // Real errors are wanted instead of the local copy too
+    let fallback_dir = config.fallback_dir.as_deref().filter(|_| fallback != Fallback::Error);
+    match config.routes.check(req.uri().path()) {
+        None => {}
+        Some(Disabled::Fallback) => {
+            if let Some(dir) = fallback_dir {
+                let depth = virtual_tree::depth(&req_header_temp);
+                let html = local_dir::wants_html(&req_header_temp);
+                let retry_after = fallback::retry_after(None);
+                return Ok(local_dir::serve(dir, req.method(), req.uri().path(), depth, html, "disabled", retry_after).await);
+            }
+            let accept_language = req_header_temp.get(hyper::header::ACCEPT_LANGUAGE).and_then(|v| v.to_str().ok());
+            let folder = fallback::Folder {
+                name: config.fallback_names.get("disabled", accept_language),
+                files: &config.fallback_files,
+                method: req.method(),
+                depth: virtual_tree::depth(&req_header_temp),
+                retry_after: fallback::retry_after(None),
+            };
+            return Ok(fallback.respond("disabled", None, req.uri().path(), &folder));
+        }
+        Some(Disabled::NotFound) => {
+            return Ok(Response::builder()
+                .status(404)
+                .header("Content-Type", "text/plain")
+                .body(Body::from("Not found\n"))
+                .expect("response builder"))
+        }
+    }
+    // Only a Depth: 1 listing of the root shows the proxy's own files
+    let list_root = root_file_names(&config).next().is_some()
+        && req.method().as_str() == "PROPFIND"
+        && req.uri().path() == "/"
+        && req_header_temp.get("Depth").is_some_and(|d| d != "0");
+    let tenant = req.extensions().get::<Arc<Tenant>>().cloned();
+    let tally = config
+        .stats
+        .start(req.method().as_str(), "/", user_name.as_deref(), country.as_deref(), tenant.as_ref().map(|t| t.name()));
+    let tally = match &config.usage {
+        Some(usage) => tally.include(usage.counters(req.uri().path())),
+        None => tally,
+    };
+    // Which usage properties a PROPFIND that may list their folders asks for
+    let usage_props = match &config.usage {
+        Some(usage) if req.method().as_str() == "PROPFIND" && usage.listed(req.uri().path()) => {
+            match buffering::read_up_to(std::mem::take(req.body_mut()), usage::MAX_REQUEST).await? {
+                Ok(bytes) => {
+                    let requested = multistatus::requested_props(&bytes);
+                    *req.body_mut() = Body::from(bytes);
+                    Some(requested)
+                }
+                Err(streamed) => {
+                    *req.body_mut() = streamed;
+                    None
+                }
+            }
+        }
+        _ => None,
+    };
+    let transfer_deadline = settings.transfer_timeout.map(|limit| tokio::time::Instant::now() + limit);
+    if let Err(rejection) = config.pause.check(req.method()).await {
+        return Ok(rejection);
+    }
+    let write_guard = config.pause.track(req.method());
+    let path = req.uri().path().to_string();
+    let method = req.method().clone();
+    // HEAD responses don't carry the body their length describes
+    let http10 = req.version() == hyper::Version::HTTP_10 && req.method() != hyper::Method::HEAD;
+    let accept_language = req_header_temp
+        .get(hyper::header::ACCEPT_LANGUAGE)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string);
+    // The headers are gone by the time the upstream turns out unreachable
+    let depth = virtual_tree::depth(&req_header_temp);
+    // The breaker may have opened by the time the fallback answers
+    let retry_after = || fallback::retry_after(upstream.breaker.as_deref());
+    let folder = |reason| fallback::Folder {
+        name: config.fallback_names.get(reason, accept_language.as_deref()),
+        files: &config.fallback_files,
+        method: &method,
+        depth,
+        retry_after: retry_after(),
+    };
+    let html = local_dir::wants_html(&req_header_temp);
+    // An upstream taken for down isn't tried at all
+    if let Some(error) = upstream.breaker.as_ref().and_then(|b| b.open_error()) {
+        if methods::is_write(&method) {
+            fallback::warn_lost_write(&method, &path, &error.to_string());
+        }
+        return Ok(match fallback_dir {
+            Some(dir) => local_dir::serve(dir, &method, &path, depth, html, &error.to_string(), retry_after()).await,
+            None => fallback.failed(error, &path, &folder(error.reason())),
+        });
+    }
+    // Clients may still hold the fallback's answers
+    let recovering = upstream.breaker.as_ref().is_some_and(|b| b.recovering());
+    let mut fingerprint = None;
+    let mut earlier_success = None;
+    // Held until the buffered body has been sent
+    let mut buffered = None;
+    if let Some(dedup) = &config.dedup {
+        let reservation = match dedup::bufferable(&req_header_temp) {
+            Some(length) if method == hyper::Method::PUT => config.memory.reserve(Use::Bodies, length),
+            _ => None,
+        };
+        if let Some(reservation) = reservation {
+            buffered = Some(reservation);
+            let bytes = hyper::body::to_bytes(std::mem::take(req.body_mut())).await?;
+            let print = dedup::put_fingerprint(user_name.as_deref(), &bytes);
+            if let Some(replay) = dedup.replay(&path, &print) {
+                tracing::info!("Answered a repeated PUT of {} from the previous upload", path);
+                return Ok(replay);
+            }
+            *req.body_mut() = Body::from(bytes);
+            fingerprint = Some(print);
+        } else if method == hyper::Method::DELETE || method.as_str() == "MKCOL" {
+            let print = dedup::retry_fingerprint(&method, user_name.as_deref());
+            earlier_success = dedup.replay(&path, &print);
+            dedup.forget(&path);
+            fingerprint = Some(print);
+        } else if methods::is_write(&method) {
+            dedup.forget(&path);
+            if let Some(destination) = &client_destination {
+                dedup.forget(destination);
+            }
+        }
+    }
+    let mut cache_key = None;
+    let mut cache_hit = None;
+    if let Some(cache) = &config.response_cache {
+        if methods::is_write(&method) {
+            cache.forget(&path);
+            if let Some(destination) = &client_destination {
+                cache.forget(destination);
+            }
+        } else if response_cache::cacheable(&method, &req_header_temp) {
+            // A PROPFIND's body says which properties it wants
+            match buffering::read_up_to(std::mem::take(req.body_mut()), response_cache::MAX_REQUEST).await? {
+                Ok(bytes) => {
+                    let target = req.uri().path_and_query().map_or("/", |p| p.as_str());
+                    let key = cache.key(&method, target, &req_header_temp, user_name.as_deref(), &bytes);
+                    cache_hit = cache.get(&key, recovering.then_some(breaker::RECOVERY_TTL));
+                    cache_key = Some(key);
+                    *req.body_mut() = Body::from(bytes);
+                }
+                Err(streamed) => *req.body_mut() = streamed,
+            }
+        }
+    }
+    let permit = match &config.gate {
+        Some(gate) => {
+            let head = req.uri().path_and_query().map_or(0, |p| p.as_str().len())
+                + req_header_temp.iter().map(|(name, value)| name.as_str().len() + value.len()).sum::<usize>();
+            let _queued = config.memory.track(Use::Queues, head);
+            match gate.acquire(Class::of(req.method())).await {
+                Ok(permit) => Some(permit),
+                Err(refusal) => return Ok(refusal),
+            }
+        }
+        None => None,
+    };
+
+    // Create the new URI by merging the upstream base URI with the incoming request's path and query
+    let mut parts = upstream.uri.clone().into_parts();
+    parts.path_and_query = Some(upstream.base_path.add(&vhost::origin_form(req.uri())));
+    let new_uri = Uri::from_parts(parts).expect("valid URI");
+
+    // Requests that don't modify the share may be sent again, so keep their
+    // (usually tiny) bodies around, memory permitting and up to
+    // RETRY_BODY_LIMIT; writes only with --replay-buffer and bodies up to
+    // that size
+    let write = methods::is_write(&method);
+    let replay_limit = if write { config.replay_buffer } else { RETRY_BODY_LIMIT };
+    // Counted until answered, so a switch away from the upstream can wait for it
+    let _writing = write.then(|| upstream.writing());
+    // Nor is it sent again, over a connection that doesn't know the handshake
+    // Failing over to the mirror takes one more attempt
+    let failover = upstream.mirror.is_some() as u32;
+    let mut retries = if handshake || (write && config.replay_buffer == 0) { 0 } else { upstream.retries + failover };
+    if methods::content_length(&req_header_temp).is_some_and(|length| length > replay_limit) {
+        retries = 0;
+    }
+    if retries > 0 && buffered.is_none() {
+        match methods::content_length(&req_header_temp).map(|length| config.memory.reserve(Use::Bodies, length)) {
+            Some(Some(reservation)) => buffered = Some(reservation),
+            Some(None) => retries = 0,
+            None => {}
+        }
+    }
+    let caps = req.extensions_mut().remove::<Limiters>().unwrap_or_default();
+    let site = tenant.as_ref().map_or_else(Limiters::default, |t| t.limits().clone());
+    let mut body = Some(req.into_body());
+    let retry_body = match retries {
+        0 => None,
+        // Chunked bodies are only known once read, and sent as they come
+        // once they outgrow the limit
+        _ => match buffering::read_up_to(body.take().expect("not taken yet"), replay_limit).await? {
+            Ok(bytes) => {
+                if buffered.is_none() {
+                    buffered = Some(config.memory.track(Use::Bodies, bytes.len()));
+                }
+                Some(bytes)
+            }
+            Err(streamed) => {
+                body = Some(streamed);
+                retries = 0;
+                None
+            }
+        },
+    };
+    // A Digest challenge, and a 401 while alternate credentials remain, are
+    // answered by sending the request once more, if its body is at hand
+    let mut auth_retries = match &settings.upstream_auth {
+        Some(auth) if retry_body.is_some() || methods::has_no_body(&req_header_temp) => auth.count() + 1,
+        _ => 0,
+    };
+    let target = new_uri.path_and_query().map_or("/", |p| p.as_str()).to_string();
+    let mut attempt = 0;
+    let cached = cache_hit.is_some();
+    // Uploads are followed if there is a body, of known length or not
+    let upload_total = (!methods::has_no_body(&req_header_temp)).then(|| methods::content_length(&req_header_temp).map(|n| n as u64));
+    let transfer_info = || {
+        Arc::new(Info {
+            method: method.to_string(),
+            path: path.clone(),
+            user: user_name.clone(),
+            client: remote.ip(),
+        })
+    };
+    if let Some(client_path) = &client_path {
+        header_rules.apply_request(&method, client_path, &mut req_header_temp);
+    }
+    // Whichever of the upstream and its mirror answered last is asked first
+    let mut on_mirror = upstream.mirror.as_ref().is_some_and(|mirror| mirror.active());
+    let mut failed_over = None;
+    // How far the last attempt got
+    let mut phase = Tracker::new();
+    let result = loop {
+        if let Some(hit) = cache_hit.take() {
+            break Ok(Ok(hit));
+        }
+        let body = match &retry_body {
+            Some(bytes) => Body::from(bytes.clone()),
+            // Only requests without a body get here twice
+            None => body.take().unwrap_or_default(),
+        };
+
+        // Create a new request for the upstream WebDAV server. An empty body
+        // stays unwrapped: hyper's client sends a wrapped one chunked, which
+        // servers refuse on MKCOL and the like.
+        let (body, upload) = if body.is_end_stream() {
+            idle::watch_upload(None)
+        } else {
+            let body = match (&config.transfers, &upload_total) {
+                (Some(transfers), Some(total)) => transfers.watch(Direction::Upload, transfer_info(), *total, body),
+                _ => body,
+            };
+            let body = throttled(&config.upload_limiter, tally.count_upload(body));
+            let body = throttled(&caps.upload, throttled(&site.upload, body));
+            idle::watch_upload(Some(body))
+        };
+        let mut new_req = Request::builder()
+            .method(&method)
+            .uri(new_uri.clone())
+            .body(body)
+            .expect("request builder");
+
+        // The headers prepared above; copied only while another attempt may follow
+        *new_req.headers_mut() = if attempt < retries || auth_retries > 0 {
+            req_header_temp.clone()
+        } else {
+            std::mem::take(&mut req_header_temp)
+        };
+        if let Some(mirror) = upstream.mirror.as_ref().filter(|_| on_mirror) {
+            let uri = new_req.uri().clone();
+            *new_req.uri_mut() = mirror.redirect(&uri, new_req.headers_mut());
+        }
+        let credentials = settings.upstream_auth.as_ref().map(|auth| auth.in_use());
+        if let Some(auth) = &settings.upstream_auth {
+            new_req
+                .headers_mut()
+                .insert(hyper::header::AUTHORIZATION, auth.authorization(&method, &target));
+        }
+
+        // Pinned connections bypass the client so every request of a downstream
+        // connection goes over the same upstream socket
+        let forwarded = async {
+            match (&pinned, upstream.warm.as_ref().filter(|_| !on_mirror).and_then(|pool| pool.take())) {
+                (Some(conns), _) => conns[index].request(new_req).await,
+                (None, Some(warm)) => warm.request(new_req).await,
+                (None, None) => config.client.request(new_req).await.map_err(BoxError::from),
+            }
+        };
+
+        // Try to forward the request within the upstream's timeout
+        phase = Tracker::new();
+        let outcome = idle::first_byte(phase.track(forwarded), upstream.timeout, upload, transfer_deadline).await;
+        if let (Ok(Ok(response)), Some(auth), Some(used)) = (&outcome, &settings.upstream_auth, credentials) {
+            if response.status() == hyper::StatusCode::UNAUTHORIZED {
+                // Taken up even when it can't be answered now, for the requests to come
+                if auth.refused(used, response.headers()) && auth_retries > 0 {
+                    auth_retries -= 1;
+                    continue;
+                }
+            } else {
+                auth.accepted(used);
+            }
+        }
+        let retry = attempt < retries
+            && match &outcome {
+                Ok(Ok(_)) => false,
+                Ok(Err(e)) => !write || replay::unsent(e),
+                // The upstream may be carrying out a write it got
+                Err(_) => !write,
+            };
+        if !retry {
+            break outcome;
+        }
+        attempt += 1;
+        // The first failure sends the rest to the other server
+        if let Some(mirror) = upstream.mirror.as_ref().filter(|_| failed_over.is_none()) {
+            failed_over = Some(match outcome {
+                Err(_) => UpstreamError::Timeout { phase: phase.phase() },
+                _ => UpstreamError::failed(phase.phase()),
+            });
+            on_mirror = !on_mirror;
+            let server = if on_mirror { &mirror.uri } else { &upstream.uri };
+            tracing::info!("Retrying {} {} on {} ({} of {})", method, path, server, attempt, retries);
+            continue;
+        }
+        let backoff = upstream.backoff(attempt);
+        tracing::info!(
+            "Retrying {} {} in {} ms ({} of {})",
+            method,
+            path,
+            backoff.as_millis(),
+            attempt,
+            retries
+        );
+        tokio::time::sleep(backoff).await;
+    };
+    if let (Some(mirror), Ok(Ok(_)), false) = (&upstream.mirror, &result, cached) {
+        mirror.answered(on_mirror, &failed_over.unwrap_or(UpstreamError::ConnectRefused).to_string());
+    }
+    // The buffered body has been sent
+    drop(buffered);
+    tracing::debug!(
+        cached,
+        attempts = attempt + 1,
+        "{} {} on {}: {}",
+        method,
+        path,
+        upstream.uri,
+        match &result {
+            Ok(Ok(response)) => response.status().to_string(),
+            Ok(Err(e)) => e.to_string(),
+            Err(_) => "timed out".to_string(),
+        }
+    );
+    let error = match &result {
+        Ok(Ok(response)) => UpstreamError::answered(response.status()),
+        Ok(Err(_)) => Some(UpstreamError::failed(phase.phase())),
+        Err(_) => Some(UpstreamError::Timeout { phase: phase.phase() }),
+    };
+    // A cached answer says nothing about the upstream
+    if !cached {
+        if let Some(error) = &error {
+            config.stats.count_error(error, method.as_str(), &path);
+        }
+    }
+    if let Some(breaker) = upstream.breaker.as_ref().filter(|_| !cached) {
+        match (&result, error) {
+            // A gateway's error page still means the upstream is there
+            (Ok(Ok(_)), _) => breaker.succeeded(),
+            (_, Some(error)) => breaker.failed(error),
+            (_, None) => {}
+        }
+    }
+    if let Some(status) = config.status.as_ref().filter(|_| !cached) {
+        match (&result, error) {
+            (Ok(Err(e)), Some(error)) => status.failed(index, format!("{} {}: {} ({})", method, path, error, e)),
+            (Err(_), Some(error)) => status.failed(index, format!("{} {}: {}", method, path, error)),
+            _ => status.succeeded(index),
+        }
+    }
+    let result = match (result, &config.response_cache, cache_key) {
+        (Ok(Ok(response)), Some(cache), Some(key)) if !cached => {
+            Ok(Ok(cache.store(key, &path, tenant.as_ref(), response).await?))
+        }
+        (result, _, _) => result,
+    };
+    // Reads that were under way while the write went on may have kept the old state
+    if let Some(cache) = config.response_cache.as_ref().filter(|_| write) {
+        cache.forget(&path);
+        if let Some(destination) = &client_destination {
+            cache.forget(destination);
+        }
+    }
+
+    if let (Ok(Err(_)) | Err(_), true, Some(error)) = (&result, write, error) {
+        fallback::warn_lost_write(&method, &path, &error.to_string());
+    }
+
+    match result {
+        Ok(Ok(mut response)) => {
+            if let (Some(dedup), Some(fingerprint)) = (&config.dedup, fingerprint) {
+                if let Some(success) = earlier_success.filter(|_| dedup::is_repeat_failure(&method, response.status())) {
+                    tracing::info!("Answered a repeated {} of {} with the earlier success", method, path);
+                    dedup.record(&path, fingerprint, &success);
+                    return Ok(success);
+                }
+                dedup.record(&path, fingerprint, &response);
+            }
+            config.cookie_policy.apply_response(response.headers_mut());
+            // Before the hrefs are rewritten, which needs them readable
+            #[cfg(feature = "gzip")]
+            if config.compress {
+                response = gzip::decode(response, client_gzip);
+            }
+            response = upstream.base_path.apply_response(response, rewrite_log.as_ref()).await?;
+            if let Some(mapping) = &snapshot {
+                response = mapping.apply_response(response).await?;
+            }
+            if let Some(mapping) = &rewrite {
+                response = mapping.apply_response(response).await?;
+            }
+            if let Some(log) = &rewrite_log {
+                log.finish();
+            }
+            response = config.dates.apply_response(response).await?;
+            if let Some(rule) = cache_rule {
+                let status = response.status();
+                let cap = recovering.then_some(breaker::RECOVERY_TTL.as_secs());
+                cache_headers.apply(rule, status, response.headers_mut(), cap);
+            }
+            if config.pin_connections {
+                let status = response.status();
+                pinned::keep_open(status, response.headers_mut());
+            }
+            if emulate_locks && method == hyper::Method::OPTIONS {
+                locks::advertise(response.headers_mut());
+            }
+            if (config.read_only || snapshot.is_some()) && method == hyper::Method::OPTIONS {
+                read_only::filter_options(response.headers_mut());
+            }
+            if let Some(challenge) = response_challenge {
+                response.headers_mut().insert(hyper::header::WWW_AUTHENTICATE, challenge);
+            }
+            if list_root && response.status().as_u16() == 207 {
+                response = inject_root_files(response, &config).await?;
+            }
+            if let (Some(usage), Some(requested)) = (&config.usage, &usage_props) {
+                response = usage.apply_response(response, requested.as_deref()).await?;
+            }
+            if let Some(client_path) = &client_path {
+                header_rules.apply_response(&method, client_path, response.headers_mut());
+            }
+            #[cfg(feature = "gzip")]
+            if config.compress && method != hyper::Method::HEAD {
+                response = gzip::encode(response, client_gzip);
+            }
+            let (mut parts, mut body) = response.into_parts();
+            if let Some(idle) = settings.idle_timeout {
+                body = idle::limit(body, idle, format!("{} {}", method, path));
+            }
+            if let Some(deadline) = transfer_deadline {
+                body = idle::deadline(body, deadline, format!("{} {}", method, path));
+            }
+            if config.buffer_responses > 0 {
+                let head = method == hyper::Method::HEAD;
+                body = buffering::apply(&mut parts, body, config.buffer_responses, head, &config.memory).await?;
+            }
+            let total = methods::content_length(&parts.headers).map(|n| n as u64);
+            if let Some(transfers) = &config.transfers {
+                body = transfers.watch(Direction::Download, transfer_info(), total, body);
+            }
+            body = throttled(&config.download_limiter, tally.count_download(body, total));
+            body = throttled(&caps.download, throttled(&site.download, body));
+            if let Some(permit) = permit {
+                body = guard::attach(permit, body);
+            }
+            if let Some(write_guard) = write_guard {
+                body = guard::attach(write_guard, body);
+            }
+            let mut response = Response::from_parts(parts, body);
+            response.extensions_mut().insert(Forwarded);
+            if let Some(error) = error {
+                response.extensions_mut().insert(error);
+            }
+            if http10 {
+                return http10::with_length(response).await;
+            }
+            Ok(response)
+        }
+        _ => {
+            // A refused or dropped connection, a host name that doesn't
+            // resolve, or a timeout naming the phase that took too long
+            let error = error.expect("set for failures");
+            if let Some(phase) = error.phase() {
+                tracing::warn!("{} {} timed out waiting for the upstream ({})", method, path, phase.name());
+            }
+            match fallback_dir {
+                Some(dir) => Ok(local_dir::serve(dir, &method, &path, depth, html, &error.to_string(), retry_after()).await),
+                None => Ok(fallback.failed(error, &path, &folder(error.reason()))),
+            }
+        }
+    }
+}
+
+// Background work for `config`: scheduled snapshots, and probing the
+// upstreams for locking support
+fn spawn_background(config: &Arc<ProxyConfig>) {
+    if let Some(hook) = &config.snapshot {
+        hook.clone().spawn(config.clone());
+    }
+
+    let settings = config.settings.current();
+    let candidate = config.rollout.as_ref().and_then(|rollout| rollout.candidate.upstreams.as_ref());
+    probe(config, settings.upstreams.iter().chain(candidate.into_iter().flat_map(|u| u.iter())), &settings);
+}
+
+// Ask `upstreams` what they support, if that decides about lock emulation
+fn probe<'a>(config: &ProxyConfig, upstreams: impl Iterator<Item = (&'a str, &'a Upstream)>, settings: &Settings) {
+    if config.emulate_locks == Emulation::Auto {
+        for (_, upstream) in upstreams {
+            capabilities::spawn(
+                upstream.capabilities.clone(),
+                config.client.clone(),
+                settings.upstream_auth.clone(),
+            );
+        }
+    }
+}
+
+// Read the configuration again (SIGHUP, /admin/reload); requests in flight
+// keep what they started with
+fn reload(config: &ProxyConfig) -> Result<Arc<Settings>, String> {
+    match config.settings.reload() {
+        Ok(settings) => {
+            probe(config, settings.upstreams.iter(), &settings);
+            let remotes: Vec<String> = settings
+                .upstreams
+                .iter()
+                .map(|(_, upstream)| upstreams::root_uri(&upstream.uri, &upstream.base_path).to_string())
+                .collect();
+            tracing::info!("Reloaded the configuration, upstreams {}", remotes.join(", "));
+            Ok(settings)
+        }
+        Err(e) => {
+            errors::report(format!("Failed to reload the configuration, keeping the running one: {}", e));
+            Err(e)
+        }
+    }
+}
+
+// What the requests of one downstream connection share, whichever listener
+// accepted it
+#[derive(Clone)]
+struct Downstream {
+    config: Arc<ProxyConfig>,
+    // One dedicated upstream connection per downstream connection when pinning
+    pinned: Option<Arc<Vec<PinnedConnection>>>,
+    caps: Option<Limiters>,
+    remote: SocketAddr,
+}
+
+impl Downstream {
+    fn new(config: Arc<ProxyConfig>, remote: SocketAddr) -> Self {
+        let pinned = if config.pin_connections {
+            Some(Arc::new(config.settings.current().upstreams.pinned(&config.resolver)))
+        } else {
+            None
+        };
+        let caps = config.rate_caps.as_ref().map(RateCaps::connection);
+        Downstream { config, pinned, caps, remote }
+    }
+
+    fn serve(&self, mut req: Request<Body>) -> impl Future<Output = Result<Response<Body>, hyper::Error>> {
+        if let Some(caps) = &self.caps {
+            req.extensions_mut().insert(caps.clone());
+        }
+        serve(req, self.config.clone(), self.pinned.clone(), self.remote)
+    }
+}
+
+// The proxy on `listener`, each accepted socket passed through `wrap`. Once
+// `shutdown` completes it stops accepting and ends after the requests in
+// flight are answered.
+fn server<F, S>(
+    config: Arc<ProxyConfig>,
+    listener: Listener,
+    access: AccessList,
+    wrap: F,
+    shutdown: S,
+) -> impl Future<Output = hyper::Result<()>>
+where
+    F: Fn(Box<dyn listener::Connection>) -> Box<dyn listener::Connection> + Send + 'static,
+    S: Future<Output = ()>,
+{
+    // Define the proxy service
+    let shared = config.clone();
+    let make_svc = make_service_fn(move |conn: &listener::Conn| {
+        let downstream = Downstream::new(shared.clone(), conn.remote);
+        async move { Ok::<_, Infallible>(service_fn(move |req: Request<Body>| downstream.serve(req))) }
+    });
+
+    let mut builder = Server::builder(listener::incoming(listener, access, wrap));
+    if let Some(max) = config.header_limits.max_size {
+        // Let hyper refuse oversized heads before buffering them; it needs
+        // at least 8 KiB and room for the request line
+        builder = builder
+            .http1_max_buf_size((max + 4096).max(8192))
+            .http2_max_header_list_size(max.try_into().unwrap_or(u32::MAX));
+    }
+    builder.serve(make_svc).with_graceful_shutdown(shutdown)
+}
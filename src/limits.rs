@@ -0,0 +1,71 @@
+use hyper::{Body, HeaderMap, Response, StatusCode, Uri};
+
+// Longest request path, and Destination, that rewrite and routing rules
+// get to see; no WebDAV client comes close
+pub const MAX_PATH: usize = 8 * 1024;
+
+// Caps on request headers on top of hyper's own (which always rejects more
+// than 100 headers), so neither the proxy nor a fragile upstream has to
+// cope with header bombs
+#[derive(Default)]
+pub struct HeaderLimits {
+    // Total of names and values, in bytes
+    pub max_size: Option<usize>,
+    pub max_count: Option<usize>,
+}
+
+fn too_large(message: &str) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE)
+        .header("Content-Type", "text/plain")
+        .header("Connection", "close")
+        .body(Body::from(format!("{}\n", message)))
+        .expect("response builder")
+}
+
+impl HeaderLimits {
+    // The 431 to send, if the headers exceed a limit
+    pub fn check(&self, headers: &HeaderMap) -> Option<Response<Body>> {
+        if self.max_count.is_some_and(|max| headers.len() > max) {
+            return Some(too_large("Too many request headers"));
+        }
+        if let Some(max) = self.max_size {
+            let size: usize = headers.iter().map(|(name, value)| name.as_str().len() + value.len()).sum();
+            if size > max {
+                return Some(too_large("Request headers too large"));
+            }
+        }
+        None
+    }
+}
+
+// The 414 to send if the path or the Destination is longer than MAX_PATH
+pub fn check_path(uri: &Uri, headers: &HeaderMap) -> Option<Response<Body>> {
+    let destination = headers.get("Destination").map_or(0, |v| v.len());
+    if uri.path().len() <= MAX_PATH && destination <= MAX_PATH {
+        return None;
+    }
+    Some(
+        Response::builder()
+            .status(StatusCode::URI_TOO_LONG)
+            .header("Content-Type", "text/plain")
+            .body(Body::from("The request path is too long\n"))
+            .expect("response builder"),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refuses_long_paths() {
+        let uri: Uri = format!("/old/{}", "a".repeat(MAX_PATH)).parse().unwrap();
+        assert_eq!(check_path(&uri, &HeaderMap::new()).map(|r| r.status()), Some(StatusCode::URI_TOO_LONG));
+        let uri: Uri = "/old/a".parse().unwrap();
+        assert!(check_path(&uri, &HeaderMap::new()).is_none());
+        let mut headers = HeaderMap::new();
+        headers.insert("Destination", format!("http://dav/{}", "a".repeat(MAX_PATH)).parse().unwrap());
+        assert!(check_path(&uri, &headers).is_some());
+    }
+}
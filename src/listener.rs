@@ -0,0 +1,131 @@
+use std::io;
+use std::net::SocketAddr;
+#[cfg(unix)]
+use std::path::Path;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use hyper::server::accept::{self, Accept};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpListener;
+#[cfg(unix)]
+use tokio::net::UnixListener;
+
+use crate::errors;
+use crate::network::AccessList;
+
+// Accept loop for the main listener, so accepted sockets can be wrapped
+// (e.g. to rewrite legacy request lines) before hyper sees them
+
+pub trait Connection: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> Connection for T {}
+
+// An accepted connection along with the address of its client
+pub struct Conn {
+    pub stream: Box<dyn Connection>,
+    pub remote: SocketAddr,
+}
+
+impl AsyncRead for Conn {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Conn {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+// A TCP port, or (`--bind unix:/PATH`) a Unix socket for clients on the
+// same host
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+impl From<TcpListener> for Listener {
+    fn from(listener: TcpListener) -> Self {
+        Listener::Tcp(listener)
+    }
+}
+
+impl Listener {
+    async fn accept(&self) -> io::Result<(Box<dyn Connection>, SocketAddr)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (socket, remote) = listener.accept().await?;
+                // Small responses shouldn't wait for the client's delayed ACK
+                if let Err(e) = socket.set_nodelay(true) {
+                    tracing::warn!("Failed to set TCP_NODELAY: {}", e);
+                }
+                Ok((Box::new(socket), remote))
+            }
+            // Its clients are on this host; they get the loopback address
+            // for access lists and logs
+            #[cfg(unix)]
+            Listener::Unix(listener) => {
+                let (socket, _) = listener.accept().await?;
+                Ok((Box::new(socket), SocketAddr::from(([127, 0, 0, 1], 0))))
+            }
+        }
+    }
+}
+
+pub async fn bind(addr: SocketAddr) -> io::Result<TcpListener> {
+    TcpListener::bind(addr).await
+}
+
+// A socket left behind by an earlier run is replaced, anything else at
+// `path` kept
+#[cfg(unix)]
+pub fn bind_unix(path: &Path) -> io::Result<UnixListener> {
+    use std::os::unix::fs::FileTypeExt;
+    if std::fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket()) {
+        std::fs::remove_file(path)?;
+    }
+    UnixListener::bind(path)
+}
+
+// Accepted connections, each passed through `wrap`. Clients `access`
+// doesn't permit are disconnected right away. Accept errors (such as
+// running out of file descriptors) are logged and retried instead of
+// stopping the server.
+pub fn incoming<F>(listener: Listener, access: AccessList, wrap: F) -> impl Accept<Conn = Conn, Error = io::Error>
+where
+    F: Fn(Box<dyn Connection>) -> Box<dyn Connection> + Send + 'static,
+{
+    let stream = futures::stream::unfold((listener, access, wrap), |(listener, access, wrap)| async move {
+        loop {
+            match listener.accept().await {
+                Ok((_, remote)) if !access.permits(remote.ip()) => {
+                    tracing::info!("Refused a connection from {}", remote.ip());
+                }
+                Ok((socket, remote)) => {
+                    let conn = Conn {
+                        stream: wrap(socket),
+                        remote,
+                    };
+                    return Some((Ok::<_, io::Error>(conn), (listener, access, wrap)));
+                }
+                Err(e) => {
+                    errors::report(format!("Failed to accept a connection: {}", e));
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                }
+            }
+        }
+    });
+    accept::from_stream(stream)
+}
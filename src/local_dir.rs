@@ -0,0 +1,243 @@
+use std::io;
+use std::path::{Path, PathBuf};
+
+use hyper::header::{HeaderMap, ACCEPT};
+use hyper::{Body, Method, Response, StatusCode};
+use tokio::io::AsyncReadExt;
+
+use crate::dates::http_date;
+use crate::fallback;
+use crate::multistatus;
+use crate::virtual_tree::encode_segment;
+use crate::xml::{self, Writer};
+
+// A local directory served over WebDAV, read-only: PROPFIND listings and
+// GET/HEAD streamed from disk. The proxy answers from it with --fallback-dir
+// while the upstream is unreachable, so a mapped drive still shows a cached
+// or placeholder tree; the test upstream builds its read-write share on the
+// same pieces.
+
+const ALLOW: &str = "OPTIONS, PROPFIND, GET, HEAD";
+
+// Files are sent in pieces of this size
+const CHUNK: usize = 64 * 1024;
+
+pub fn status(status: StatusCode) -> Response<Body> {
+    Response::builder().status(status).body(Body::empty()).expect("response builder")
+}
+
+fn io_status(e: &io::Error) -> StatusCode {
+    match e.kind() {
+        io::ErrorKind::NotFound => StatusCode::NOT_FOUND,
+        io::ErrorKind::PermissionDenied => StatusCode::FORBIDDEN,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+// The answer for a failed file operation
+pub fn io_error(e: &io::Error, head: bool) -> Response<Body> {
+    let body = if head { Body::empty() } else { Body::from(format!("{}\n", e)) };
+    Response::builder().status(io_status(e)).body(body).expect("response builder")
+}
+
+fn hex_value(b: u8) -> Option<u8> {
+    (b as char).to_digit(16).map(|d| d as u8)
+}
+
+pub fn percent_decode(s: &str) -> Option<String> {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            if let (Some(h), Some(l)) = (bytes.get(i + 1).and_then(|b| hex_value(*b)), bytes.get(i + 2).and_then(|b| hex_value(*b))) {
+                out.push(h << 4 | l);
+                i += 3;
+                continue;
+            }
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8(out).ok()
+}
+
+// The file behind a request path; None for paths escaping the directory
+pub fn local_path(root: &Path, path: &str) -> Option<PathBuf> {
+    let mut local = root.to_path_buf();
+    for segment in percent_decode(path)?.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => return None,
+            s if s.contains('\\') => return None,
+            s => local.push(s),
+        }
+    }
+    Some(local)
+}
+
+fn write_response(w: &mut Writer, href: &str, name: &str, metadata: &std::fs::Metadata) {
+    w.open("response").element("href", href).open("propstat").open("prop");
+    w.element("displayname", name);
+    if metadata.is_dir() {
+        w.open("resourcetype").empty("collection").close();
+    } else {
+        w.empty("resourcetype");
+        w.element("getcontentlength", &metadata.len().to_string());
+        w.element("getcontenttype", "application/octet-stream");
+    }
+    if let Ok(modified) = metadata.modified() {
+        w.element("getlastmodified", &http_date(modified));
+    }
+    w.close().element("status", "HTTP/1.1 200 OK").close().close();
+}
+
+// Depth 0 or 1; infinity is answered as 1
+pub async fn propfind(local: &Path, href: &str, depth: u32) -> Result<Response<Body>, io::Error> {
+    let metadata = tokio::fs::metadata(local).await?;
+    let mut w = Writer::new("d");
+    let name = local.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+    let href = if metadata.is_dir() && !href.ends_with('/') { format!("{}/", href) } else { href.to_string() };
+    write_response(&mut w, &href, &name, &metadata);
+    if metadata.is_dir() && depth > 0 {
+        let mut entries = tokio::fs::read_dir(local).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let Ok(metadata) = entry.metadata().await else {
+                continue;
+            };
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let mut child = format!("{}{}", href, encode_segment(&name));
+            if metadata.is_dir() {
+                child.push('/');
+            }
+            write_response(&mut w, &child, &name, &metadata);
+        }
+    }
+    Ok(Response::builder()
+        .status(StatusCode::MULTI_STATUS)
+        .header("Content-Type", "application/xml; charset=utf-8")
+        .body(Body::from(multistatus::document(&w.finish())))
+        .expect("response builder"))
+}
+
+// How a directory is listed on GET: plain names, or a page for browsers
+// which, while the directory stands in for the upstream, says so on top
+#[derive(Default)]
+pub struct Index {
+    pub html: bool,
+    // Why the upstream isn't answering
+    pub outage: Option<String>,
+}
+
+// Whether the client prefers a page to plain text, as browsers do
+pub fn wants_html(headers: &HeaderMap) -> bool {
+    headers
+        .get(ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|accept| accept.contains("text/html"))
+}
+
+fn html_listing(title: &str, names: &[String], banner: Option<&str>) -> String {
+    let mut page = format!(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>{}</title></head><body>\n",
+        xml::escape(title)
+    );
+    if let Some(banner) = banner {
+        page.push_str(&format!(
+            "<p style=\"background:#fff3cd;border:1px solid #e0b000;padding:.5em\">{}</p>\n",
+            xml::escape(banner)
+        ));
+    }
+    page.push_str(&format!("<h1>{}</h1>\n<ul>\n", xml::escape(title)));
+    for name in names {
+        let href = name
+            .strip_suffix('/')
+            .map_or_else(|| encode_segment(name), |dir| format!("{}/", encode_segment(dir)));
+        page.push_str(&format!("<li><a href=\"{}\">{}</a></li>\n", xml::escape(&href), xml::escape(name)));
+    }
+    page.push_str("</ul>\n</body></html>\n");
+    page
+}
+
+// Read `file` a chunk at a time as it is sent
+fn stream(file: tokio::fs::File) -> Body {
+    Body::wrap_stream(futures::stream::try_unfold(file, |mut file| async move {
+        let mut chunk = vec![0; CHUNK];
+        let read = file.read(&mut chunk).await?;
+        chunk.truncate(read);
+        Ok::<_, io::Error>((read > 0).then_some((chunk, file)))
+    }))
+}
+
+pub async fn get(local: &Path, head: bool, index: &Index) -> Result<Response<Body>, io::Error> {
+    let metadata = tokio::fs::metadata(local).await?;
+    let mut builder = Response::builder();
+    if let Ok(modified) = metadata.modified() {
+        builder = builder.header("Last-Modified", http_date(modified));
+    }
+    if metadata.is_dir() {
+        // A plain listing for browsers
+        let mut names = Vec::new();
+        let mut entries = tokio::fs::read_dir(local).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let dir = entry.file_type().await.map(|t| t.is_dir()).unwrap_or(false);
+            names.push(format!("{}{}", entry.file_name().to_string_lossy(), if dir { "/" } else { "" }));
+        }
+        names.sort();
+        let (listing, content_type) = if index.html {
+            let title = local.file_name().map_or("/".into(), |n| n.to_string_lossy());
+            let banner = index.outage.as_ref().map(|reason| {
+                let changed = metadata.modified().map_or("an unknown time".to_string(), http_date);
+                format!("The upstream can't be reached ({}), so this is the local copy of the folder as of {}", reason, changed)
+            });
+            (html_listing(&title, &names, banner.as_deref()), "text/html; charset=utf-8")
+        } else {
+            (names.iter().map(|name| format!("{}\n", name)).collect(), "text/plain; charset=utf-8")
+        };
+        // The page or the plain list, depending on Accept
+        builder = builder
+            .header("Content-Type", content_type)
+            .header("Content-Length", listing.len())
+            .header("Vary", "Accept");
+        return Ok(builder.body(if head { Body::empty() } else { Body::from(listing) }).expect("response builder"));
+    }
+    builder = builder
+        .header("Content-Type", "application/octet-stream")
+        .header("Content-Length", metadata.len());
+    let body = if head { Body::empty() } else { stream(tokio::fs::File::open(local).await?) };
+    Ok(builder.body(body).expect("response builder"))
+}
+
+// The read-only answer for a request to `path` while the upstream can't be
+// reached for `reason`
+pub async fn serve(root: &Path, method: &Method, path: &str, depth: u32, html: bool, reason: &str, retry_after: u64) -> Response<Body> {
+    let Some(local) = local_path(root, path) else {
+        return status(StatusCode::FORBIDDEN);
+    };
+    let result = match method.as_str() {
+        "OPTIONS" => Ok(Response::builder()
+            .header("DAV", "1")
+            .header("Allow", ALLOW)
+            .header("MS-Author-Via", "DAV")
+            .body(Body::empty())
+            .expect("response builder")),
+        "PROPFIND" => propfind(&local, path, depth.min(1)).await,
+        "GET" | "HEAD" => {
+            let index = Index {
+                html,
+                outage: Some(reason.to_string()),
+            };
+            get(&local, *method == Method::HEAD, &index).await
+        }
+        _ if fallback::deferrable(method) => Ok(fallback::deferred(reason, retry_after)),
+        // Writes have to wait for the upstream
+        _ => Ok(Response::builder()
+            .status(StatusCode::SERVICE_UNAVAILABLE)
+            .header("Retry-After", retry_after)
+            .header("Allow", ALLOW)
+            .header("Content-Type", "text/plain")
+            .body(Body::from("The share is read-only while the upstream is unreachable\n"))
+            .expect("response builder")),
+    };
+    result.unwrap_or_else(|e| io_error(&e, *method == Method::HEAD))
+}
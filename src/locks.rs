@@ -0,0 +1,106 @@
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use hyper::header::{HeaderMap, HeaderValue, ALLOW};
+use hyper::{Body, Method, Response, StatusCode};
+
+use crate::xml::Writer;
+
+// Locks for upstreams without DAV class 2. Windows and macOS mount such a
+// share read-only, so the proxy grants every LOCK itself with a token of
+// its own, answers UNLOCK, and drops If headers naming its tokens before
+// they reach the upstream. Nothing is actually locked: two clients can
+// still overwrite each other, as they could on the upstream directly.
+
+const TOKEN_PREFIX: &str = "opaquelocktoken:proxy-";
+
+// Longest lock handed out; clients refresh before it runs out
+const MAX_TIMEOUT: u64 = 3600;
+
+static NEXT_TOKEN: AtomicU64 = AtomicU64::new(0);
+
+pub fn is_lock_method(method: &Method) -> bool {
+    matches!(method.as_str(), "LOCK" | "UNLOCK")
+}
+
+// The lock token of ours in an If header, for a refresh
+fn own_token(headers: &HeaderMap) -> Option<String> {
+    let value = headers.get("if")?.to_str().ok()?;
+    let start = value.find(TOKEN_PREFIX)?;
+    let token = &value[start..];
+    Some(token[..token.find('>').unwrap_or(token.len())].to_string())
+}
+
+// Second-N up to MAX_TIMEOUT; Infinite and anything else get the maximum
+fn timeout(headers: &HeaderMap) -> u64 {
+    headers
+        .get("timeout")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split(',').find_map(|t| t.trim().strip_prefix("Second-")?.parse::<u64>().ok()))
+        .map_or(MAX_TIMEOUT, |secs| secs.clamp(1, MAX_TIMEOUT))
+}
+
+fn lock(path: &str, headers: &HeaderMap, body: &[u8]) -> Response<Body> {
+    let refresh = own_token(headers);
+    let token = refresh.clone().unwrap_or_else(|| {
+        let id = RandomState::new().hash_one(NEXT_TOKEN.fetch_add(1, Ordering::Relaxed));
+        format!("{}{:016x}", TOKEN_PREFIX, id)
+    });
+    let shared = String::from_utf8_lossy(body).contains("shared");
+    let depth = match headers.get("depth").and_then(|v| v.to_str().ok()) {
+        Some("0") => "0",
+        _ => "infinity",
+    };
+    let mut w = Writer::document("d");
+    w.open_with("prop", &[("xmlns:d", "DAV:")]).open("lockdiscovery").open("activelock");
+    w.open("locktype").empty("write").close();
+    w.open("lockscope").empty(if shared { "shared" } else { "exclusive" }).close();
+    w.element("depth", depth);
+    w.element("timeout", &format!("Second-{}", timeout(headers)));
+    w.open("locktoken").element("href", &token).close();
+    w.open("lockroot").element("href", path).close();
+    let mut response = Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/xml; charset=utf-8");
+    // Only new locks announce their token
+    if refresh.is_none() {
+        response = response.header("Lock-Token", format!("<{}>", token));
+    }
+    response.body(Body::from(w.finish())).expect("response builder")
+}
+
+// The answer to a LOCK or UNLOCK of `path`
+pub fn respond(method: &Method, path: &str, headers: &HeaderMap, body: &[u8]) -> Response<Body> {
+    match method.as_str() {
+        "LOCK" => lock(path, headers, body),
+        _ => Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .body(Body::empty())
+            .expect("response builder"),
+    }
+}
+
+// Keep conditions on our tokens from the upstream, which would fail them
+pub fn strip_tokens(headers: &mut HeaderMap) {
+    if own_token(headers).is_some() {
+        headers.remove("if");
+    }
+}
+
+// Add class 2 and the lock methods to the upstream's answer to OPTIONS
+pub fn advertise(headers: &mut HeaderMap) {
+    let classes = headers.get("dav").and_then(|v| v.to_str().ok()).unwrap_or("1").to_string();
+    if !classes.split(',').any(|c| c.trim() == "2") {
+        if let Ok(value) = HeaderValue::from_str(&format!("{}, 2", classes)) {
+            headers.insert("dav", value);
+        }
+    }
+    if let Some(allow) = headers.get(ALLOW).and_then(|v| v.to_str().ok()) {
+        if !allow.to_ascii_uppercase().contains("LOCK") {
+            if let Ok(value) = HeaderValue::from_str(&format!("{}, LOCK, UNLOCK", allow)) {
+                headers.insert(ALLOW, value);
+            }
+        }
+    }
+}
@@ -0,0 +1,144 @@
+use tracing::{Level, Subscriber};
+use tracing_subscriber::fmt::writer::{MakeWriter, MakeWriterExt};
+use tracing_subscriber::EnvFilter;
+
+// The proxy's messages are `tracing` events with a level, the access log at
+// info, printed by tracing-subscriber's formatter: as plain lines, or with
+// --log-format json as one JSON object per line for log shippers, with the
+// time, level, target and each field. Warnings and errors go to stderr, the
+// rest to stdout. --log-level picks the events; without it RUST_LOG does,
+// in EnvFilter's syntax. The info and debug events of hyper and the other
+// libraries only show at trace. Embedders of the library can install a
+// subscriber of their own instead.
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Format {
+    Text,
+    Json,
+}
+
+impl Format {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match s.trim() {
+            "text" => Ok(Format::Text),
+            "json" => Ok(Format::Json),
+            _ => Err(format!("Unknown log format (expected text or json): {}", s)),
+        }
+    }
+}
+
+pub fn parse_level(s: &str) -> Result<Level, String> {
+    s.trim()
+        .parse()
+        .map_err(|_| format!("Unknown log level (expected error, warn, info, debug or trace): {}", s))
+}
+
+// The proxy's events at `max` or more severe, libraries' only at warn
+// unless `max` is trace
+fn filter(max: Level) -> EnvFilter {
+    match max {
+        Level::TRACE => EnvFilter::new("trace"),
+        _ => EnvFilter::new(format!("{},{}={},access={}", max.min(Level::WARN), env!("CARGO_CRATE_NAME"), max, max)),
+    }
+}
+
+fn subscriber<W>(filter: EnvFilter, format: Format, writer: W) -> Box<dyn Subscriber + Send + Sync>
+where
+    W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+{
+    let builder = tracing_subscriber::fmt().with_env_filter(filter).with_writer(writer).with_ansi(false);
+    match format {
+        // Just the message and its fields, as before; journald and the
+        // like add the time
+        Format::Text => Box::new(builder.without_time().with_level(false).with_target(false).finish()),
+        Format::Json => Box::new(builder.json().flatten_event(true).finish()),
+    }
+}
+
+// Print events from now on; a subscriber installed before stays
+pub fn init(max: Option<Level>, format: Format) {
+    let filter = match max {
+        Some(max) => filter(max),
+        None => EnvFilter::try_from_default_env().unwrap_or_else(|_| filter(Level::INFO)),
+    };
+    let writer = std::io::stderr.with_max_level(Level::WARN).or_else(std::io::stdout);
+    let _ = tracing::subscriber::set_global_default(subscriber(filter, format, writer));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    // What `log` prints in `format`
+    fn printed(format: Format, log: impl FnOnce()) -> String {
+        let out = Arc::new(Mutex::new(Vec::new()));
+        let sink = out.clone();
+        let writer = move || Sink(sink.clone());
+        tracing::subscriber::with_default(subscriber(filter(Level::INFO), format, writer), log);
+        let out = out.lock().unwrap();
+        String::from_utf8(out.clone()).unwrap()
+    }
+
+    struct Sink(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for Sink {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn parses_levels() {
+        assert_eq!(parse_level("warn"), Ok(Level::WARN));
+        assert_eq!(parse_level(" DEBUG "), Ok(Level::DEBUG));
+        assert!(parse_level("verbose").is_err());
+        assert_eq!(Format::parse("json"), Ok(Format::Json));
+        assert!(Format::parse("xml").is_err());
+    }
+
+    #[test]
+    fn filters_by_level() {
+        let levels = tracing::subscriber::with_default(subscriber(filter(Level::INFO), Format::Text, std::io::sink), || {
+            [tracing::enabled!(Level::WARN), tracing::enabled!(Level::INFO), tracing::enabled!(Level::DEBUG)]
+        });
+        assert_eq!(levels, [true, true, false]);
+    }
+
+    #[test]
+    fn shows_library_chatter_only_at_trace() {
+        let library = |max| {
+            tracing::subscriber::with_default(subscriber(filter(max), Format::Text, std::io::sink), || {
+                [tracing::enabled!(target: "hyper::proto", Level::WARN), tracing::enabled!(target: "hyper::proto", Level::DEBUG)]
+            })
+        };
+        assert_eq!(library(Level::DEBUG), [true, false]);
+        assert_eq!(library(Level::TRACE), [true, true]);
+        let access = tracing::subscriber::with_default(subscriber(filter(Level::INFO), Format::Text, std::io::sink), || {
+            tracing::enabled!(target: "access", Level::INFO)
+        });
+        assert!(access);
+    }
+
+    #[test]
+    fn prints_lines_or_json() {
+        let log = || tracing::warn!(upstream = "http://nas/", "Upstream down");
+        assert_eq!(printed(Format::Text, log), "Upstream down upstream=\"http://nas/\"\n");
+        let json = printed(Format::Json, log);
+        assert!(json.starts_with("{\"timestamp\":\""), "{}", json);
+        assert!(
+            json.ends_with(&format!(
+                "\"level\":\"WARN\",\"message\":\"Upstream down\",\"upstream\":\"http://nas/\",\"target\":\"{}::logging::tests\"}}\n",
+                env!("CARGO_CRATE_NAME")
+            )),
+            "{}",
+            json
+        );
+        assert_eq!(printed(Format::Json, || tracing::debug!("Hidden")), "");
+    }
+}
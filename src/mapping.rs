@@ -0,0 +1,62 @@
+use hyper::header::{HeaderValue, CONTENT_LENGTH, LOCATION};
+use hyper::{Body, Response, StatusCode};
+
+use crate::multistatus;
+
+// A request whose client path `client` was sent upstream as `upstream`:
+// the answer's Location and hrefs at or below `upstream` are mapped back
+// below `client`, so the client keeps seeing the paths it asked for
+#[derive(Clone)]
+pub struct PathMapping {
+    client: String,
+    upstream: String,
+}
+
+// The path part of an href, Location or Destination, with what comes
+// before it
+pub fn split_url(url: &str) -> (&str, &str) {
+    let start = match url.find("://") {
+        Some(scheme) => url[scheme + 3..].find('/').map_or(url.len(), |p| scheme + 3 + p),
+        None => 0,
+    };
+    url.split_at(start)
+}
+
+impl PathMapping {
+    pub fn new(client: &str, upstream: &str) -> Self {
+        PathMapping {
+            client: client.trim_end_matches('/').to_string(),
+            upstream: upstream.trim_end_matches('/').to_string(),
+        }
+    }
+
+    // Back to the client's side, for paths under the upstream one
+    fn client_url(&self, url: &str) -> Option<String> {
+        let (origin, path) = split_url(url);
+        let rest = path.strip_prefix(&self.upstream)?;
+        (rest.is_empty() || rest.starts_with('/')).then(|| format!("{}{}{}", origin, self.client, rest))
+    }
+
+    // Map the Location header and the hrefs of a 207 body back
+    pub async fn apply_response(&self, response: Response<Body>) -> Result<Response<Body>, hyper::Error> {
+        let (mut parts, body) = response.into_parts();
+        let location = parts
+            .headers
+            .get(LOCATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| self.client_url(v));
+        if let Some(value) = location.and_then(|l| HeaderValue::from_str(&l).ok()) {
+            parts.headers.insert(LOCATION, value);
+        }
+        if parts.status != StatusCode::MULTI_STATUS {
+            return Ok(Response::from_parts(parts, body));
+        }
+        parts.headers.remove(CONTENT_LENGTH);
+        let mapping = self.clone();
+        let body = multistatus::map_body(body, move |name, text| match name {
+            "href" => mapping.client_url(text),
+            _ => None,
+        });
+        Ok(Response::from_parts(parts, body))
+    }
+}
@@ -0,0 +1,163 @@
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use hyper::{Body, Response, StatusCode};
+
+use crate::json;
+
+// Approximate accounting of the memory the proxy holds on purpose: request
+// bodies buffered whole (deduplicated PUTs, retried requests), cache entries
+// and requests queued for an upstream slot. hyper's own buffers and
+// allocator overhead aren't counted, so a cap should leave headroom below
+// what the box has. Over the cap, caches are dropped first; if that isn't
+// enough, new requests get a 503 until usage goes down, and bodies are
+// streamed instead of buffered.
+
+// What requests turned away over the cap ask clients to wait, in seconds:
+// the memory is held by transfers under way, which there's no telling the
+// end of, so a pause about as long as a typical upload
+const SHED_RETRY_AFTER: u64 = 5;
+
+#[derive(Clone, Copy)]
+pub enum Use {
+    Bodies,
+    Queues,
+}
+
+// Something that can give memory back on demand
+pub trait Cache: Send + Sync {
+    // Approximate bytes held
+    fn size(&self) -> usize;
+    // Drop every entry
+    fn evict(&self);
+}
+
+pub struct MemoryBudget {
+    // 0 for no cap
+    cap: usize,
+    bodies: AtomicUsize,
+    queues: AtomicUsize,
+    caches: Mutex<Vec<Arc<dyn Cache>>>,
+    peak: AtomicUsize,
+    // Requests refused for lack of memory
+    shed: AtomicU64,
+    evictions: AtomicU64,
+}
+
+// Memory accounted for until dropped
+pub struct Reservation {
+    budget: Arc<MemoryBudget>,
+    kind: Use,
+    bytes: usize,
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.budget.counter(self.kind).fetch_sub(self.bytes, Ordering::Relaxed);
+    }
+}
+
+impl MemoryBudget {
+    pub fn new(cap: usize) -> Arc<Self> {
+        Arc::new(MemoryBudget {
+            cap,
+            bodies: AtomicUsize::new(0),
+            queues: AtomicUsize::new(0),
+            caches: Mutex::new(Vec::new()),
+            peak: AtomicUsize::new(0),
+            shed: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+        })
+    }
+
+    // 0 for no cap
+    pub fn cap(&self) -> usize {
+        self.cap
+    }
+
+    pub fn register(&self, cache: Arc<dyn Cache>) {
+        self.caches.lock().unwrap().push(cache);
+    }
+
+    fn counter(&self, kind: Use) -> &AtomicUsize {
+        match kind {
+            Use::Bodies => &self.bodies,
+            Use::Queues => &self.queues,
+        }
+    }
+
+    fn cached(&self) -> usize {
+        self.caches.lock().unwrap().iter().map(|c| c.size()).sum()
+    }
+
+    fn used(&self) -> usize {
+        self.bodies.load(Ordering::Relaxed) + self.queues.load(Ordering::Relaxed) + self.cached()
+    }
+
+    // Whether `extra` more bytes fit, after dropping the caches if needed
+    fn fits(&self, extra: usize) -> bool {
+        if self.cap == 0 || self.used() + extra <= self.cap {
+            return true;
+        }
+        if self.cached() > 0 {
+            for cache in self.caches.lock().unwrap().iter() {
+                cache.evict();
+            }
+            self.evictions.fetch_add(1, Ordering::Relaxed);
+        }
+        self.used() + extra <= self.cap
+    }
+
+    fn add(self: &Arc<Self>, kind: Use, bytes: usize) -> Reservation {
+        self.counter(kind).fetch_add(bytes, Ordering::Relaxed);
+        self.peak.fetch_max(self.used(), Ordering::Relaxed);
+        Reservation {
+            budget: self.clone(),
+            kind,
+            bytes,
+        }
+    }
+
+    // Account for `bytes` about to be buffered; None if they don't fit, in
+    // which case the caller should do without buffering
+    pub fn reserve(self: &Arc<Self>, kind: Use, bytes: usize) -> Option<Reservation> {
+        self.fits(bytes).then(|| self.add(kind, bytes))
+    }
+
+    // Account for memory that is held regardless of the cap
+    pub fn track(self: &Arc<Self>, kind: Use, bytes: usize) -> Reservation {
+        self.add(kind, bytes)
+    }
+
+    // The answer for a new request while over the cap
+    pub fn admit(&self) -> Option<Response<Body>> {
+        if self.fits(0) {
+            return None;
+        }
+        self.shed.fetch_add(1, Ordering::Relaxed);
+        Some(
+            Response::builder()
+                .status(StatusCode::SERVICE_UNAVAILABLE)
+                .header("Retry-After", SHED_RETRY_AFTER)
+                .header("Content-Type", "text/plain")
+                .body(Body::from("The proxy is low on memory, try again shortly\n"))
+                .expect("response builder"),
+        )
+    }
+
+    pub fn to_json(&self) -> String {
+        let cached = self.cached();
+        let bodies = self.bodies.load(Ordering::Relaxed);
+        let queues = self.queues.load(Ordering::Relaxed);
+        json::object(&[
+            ("cap", if self.cap == 0 { "null".to_string() } else { self.cap.to_string() }),
+            ("used", (bodies + queues + cached).to_string()),
+            ("peak", self.peak.load(Ordering::Relaxed).to_string()),
+            ("bodies", bodies.to_string()),
+            ("caches", cached.to_string()),
+            ("queues", queues.to_string()),
+            ("shed", self.shed.load(Ordering::Relaxed).to_string()),
+            ("evictions", self.evictions.load(Ordering::Relaxed).to_string()),
+        ])
+    }
+}
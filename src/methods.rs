@@ -0,0 +1,28 @@
+use hyper::{HeaderMap, Method, Uri};
+
+// Methods that modify the share (HTTP plus WebDAV extensions)
+pub fn is_write(method: &Method) -> bool {
+    matches!(
+        method.as_str(),
+        "PUT" | "POST" | "PATCH" | "DELETE" | "MKCOL" | "MOVE" | "COPY" | "PROPPATCH" | "LOCK" | "UNLOCK"
+    )
+}
+
+// The declared length of a request body
+pub fn content_length(headers: &HeaderMap) -> Option<usize> {
+    headers.get(hyper::header::CONTENT_LENGTH)?.to_str().ok()?.parse().ok()
+}
+
+// Whether a request comes without a body at all
+pub fn has_no_body(headers: &HeaderMap) -> bool {
+    match content_length(headers) {
+        Some(length) => length == 0,
+        None => !headers.contains_key(hyper::header::TRANSFER_ENCODING),
+    }
+}
+
+// Path of the Destination header of MOVE and COPY, which may be absolute
+pub fn destination_path(headers: &HeaderMap) -> Option<String> {
+    let destination: Uri = headers.get("Destination")?.to_str().ok()?.parse().ok()?;
+    Some(destination.path().to_string())
+}
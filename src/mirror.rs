@@ -0,0 +1,114 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use hyper::header::{HeaderMap, HeaderValue};
+use hyper::{Body, Client, Method, Request, Uri};
+
+use crate::base_path;
+use crate::resolve::Connector;
+use crate::warm;
+
+// A second server with the same share (--fallback-upstream). A request
+// that can't reach one of the two is sent to the other before falling back
+// to the fake folder, and whichever answered is asked first from then on.
+// While requests go to the mirror, a background probe sends OPTIONS to the
+// primary every warm::CHECK_INTERVAL and switches back once it answers.
+pub struct Mirror {
+    pub uri: Uri,
+    // The primary's root, which the probe asks
+    primary: Uri,
+    client: Client<Connector>,
+    timeout: Duration,
+    // Set while the primary is taken for down
+    active: AtomicBool,
+}
+
+// `HOST:PORT` or `http[s]://HOST[:PORT]`, the base path being the primary's
+pub fn parse(remote: &str) -> Result<Uri, String> {
+    let (scheme, authority, base_path) = base_path::split_remote(remote)?;
+    if !base_path.is_empty() {
+        return Err(format!(
+            "The fallback upstream {} serves the primary's base path, it can't have its own",
+            remote
+        ));
+    }
+    format!("{}://{}", scheme, authority)
+        .parse::<Uri>()
+        .map_err(|e| format!("Invalid fallback upstream {}: {}", remote, e))
+}
+
+// The same URI on another server
+fn moved(uri: &Uri, to: &Uri) -> Uri {
+    let mut parts = uri.clone().into_parts();
+    parts.scheme = to.scheme().cloned();
+    parts.authority = to.authority().cloned();
+    Uri::from_parts(parts).expect("valid URI")
+}
+
+impl Mirror {
+    pub fn new(uri: Uri, primary: Uri, client: Client<Connector>, timeout: Duration) -> Self {
+        Mirror {
+            uri,
+            primary,
+            client,
+            timeout,
+            active: AtomicBool::new(false),
+        }
+    }
+
+    // Whether requests go to the mirror first
+    pub fn active(&self) -> bool {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    // The request's URI on the mirror, and its Destination, which names the
+    // server it is sent to
+    pub fn redirect(&self, uri: &Uri, headers: &mut HeaderMap) -> Uri {
+        let destination = headers
+            .get("Destination")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<Uri>().ok());
+        if let Some(destination) = destination.filter(|d| d.authority().is_some()) {
+            if let Ok(value) = HeaderValue::from_str(&moved(&destination, &self.uri).to_string()) {
+                headers.insert("Destination", value);
+            }
+        }
+        moved(uri, &self.uri)
+    }
+
+    // A request got through to the mirror (`true`) or the primary
+    pub fn answered(self: &Arc<Self>, mirror: bool, reason: &str) {
+        if self.active.swap(mirror, Ordering::Relaxed) == mirror {
+            return;
+        }
+        if mirror {
+            tracing::warn!(
+                "Upstream {} can't be reached ({}), sending its requests to {} until it is back",
+                self.primary, reason, self.uri
+            );
+            tokio::spawn(self.clone().probe());
+        } else {
+            tracing::info!("Upstream {} answers again, {} is no longer used", self.primary, self.uri);
+        }
+    }
+
+    // Runs while the mirror is in use
+    async fn probe(self: Arc<Self>) {
+        loop {
+            tokio::time::sleep(warm::CHECK_INTERVAL).await;
+            if !self.active() {
+                return;
+            }
+            let request = Request::builder()
+                .method(Method::OPTIONS)
+                .uri(self.primary.clone())
+                .body(Body::empty())
+                .expect("request builder");
+            // Any answer will do, even a 401
+            if let Ok(Ok(_)) = tokio::time::timeout(self.timeout, self.client.request(request)).await {
+                return self.answered(false, "");
+            }
+        }
+    }
+}
@@ -0,0 +1,602 @@
+// Helpers for editing and synthesizing 207 Multi-Status bodies
+
+use hyper::body::{Bytes, HttpBody};
+use hyper::Body;
+
+use crate::xml::Writer;
+
+// Find the closing multistatus tag and the prefix the upstream bound to DAV:
+fn closing_tag(body: &str) -> Option<(usize, String)> {
+    let end = body.rfind("multistatus>")?;
+    let open = body[..end].rfind("</")?;
+    let prefix = body[open + 2..end].trim_end_matches(':').to_string();
+    Some((open, prefix))
+}
+
+// Insert extra <response> elements (rendered by `render` with the upstream's
+// prefix) just before the end of an upstream multistatus body
+pub fn inject(body: &str, render: impl Fn(&str) -> String) -> Option<String> {
+    let (pos, prefix) = closing_tag(body)?;
+    let mut out = String::with_capacity(body.len() + 512);
+    out.push_str(&body[..pos]);
+    out.push_str(&render(&prefix));
+    out.push_str(&body[pos..]);
+    Some(out)
+}
+
+// Replace the text of every <href> element for which `map` returns a new
+// value, whatever prefix the upstream bound to DAV:
+pub fn map_hrefs(body: &str, map: impl Fn(&str) -> Option<String>) -> String {
+    map_elements(body, |name, text| if name == "href" { map(text) } else { None })
+}
+
+// Replace the text of every element for which `map`, given the element's
+// local name and text, returns a new value
+pub fn map_elements(body: &str, map: impl FnMut(&str, &str) -> Option<String>) -> String {
+    let mut mapper = ElementMapper::new(map);
+    let mut out = mapper.feed(body.as_bytes());
+    out.extend(mapper.finish());
+    // Split only at ASCII characters
+    String::from_utf8(out).unwrap_or_else(|e| String::from_utf8_lossy(e.as_bytes()).into_owned())
+}
+
+// map_elements on a body as it streams in
+pub fn map_body(body: Body, map: impl FnMut(&str, &str) -> Option<String> + Send + 'static) -> Body {
+    let state = (body, Some(ElementMapper::new(map)));
+    Body::wrap_stream(futures::stream::try_unfold(state, |(mut body, mapper)| async move {
+        let Some(mut mapper) = mapper else {
+            return Ok(None);
+        };
+        while let Some(chunk) = body.data().await {
+            let out = mapper.feed(&chunk?);
+            if !out.is_empty() {
+                return Ok::<_, hyper::Error>(Some((Bytes::from(out), (body, Some(mapper)))));
+            }
+        }
+        let out = mapper.finish();
+        Ok((!out.is_empty()).then(|| (Bytes::from(out), (body, None))))
+    }))
+}
+
+// Longest tag, or text of an element being mapped, held back while looking
+// for its end; past that it is passed on as it is
+const MAX_HELD: usize = 64 * 1024;
+
+// Markup that runs up to a terminator of its own, by how it starts
+const SECTIONS: [(&[u8], &[u8]); 3] = [(b"<!--", b"-->"), (b"<![CDATA[", b"]]>"), (b"<?", b"?>")];
+
+// The parser behind map_elements, taking the body in pieces. Only an
+// unfinished tag, or the text of an element that `map` may replace, is held
+// between pieces, so listings of many megabytes go through in bounded
+// memory. A `>` in a quoted attribute value, a comment, a CDATA section or
+// a processing instruction doesn't end the markup. Markup that isn't XML is
+// passed on; bytes that aren't UTF-8 are, without `map` seeing them.
+pub struct ElementMapper<F> {
+    map: F,
+    held: Vec<u8>,
+    // What `held` starts within
+    within: Within,
+    // The local name of the element whose text `held` is
+    element: Option<String>,
+}
+
+#[derive(Clone, Copy)]
+enum Within {
+    Text,
+    // Just after a `<`, before it is known what follows
+    Markup,
+    // A start or end tag, in a quoted value if `quote` is set, looked
+    // through up to `scanned`; `flushed` once its start was passed on for
+    // being too long
+    Tag {
+        quote: Option<u8>,
+        scanned: usize,
+        flushed: bool,
+    },
+    // A comment, CDATA section or processing instruction, ending at `end`
+    Section { end: &'static [u8], scanned: usize },
+}
+
+impl<F: FnMut(&str, &str) -> Option<String>> ElementMapper<F> {
+    pub fn new(map: F) -> Self {
+        ElementMapper {
+            map,
+            held: Vec::new(),
+            within: Within::Text,
+            element: None,
+        }
+    }
+
+    // What can be passed on once `chunk` came in
+    pub fn feed(&mut self, chunk: &[u8]) -> Vec<u8> {
+        self.held.extend_from_slice(chunk);
+        let mut out = Vec::with_capacity(self.held.len());
+        let mut done = 0;
+        loop {
+            let rest = &self.held[done..];
+            match self.within {
+                Within::Text => {
+                    let Some(start) = rest.iter().position(|&b| b == b'<') else {
+                        break;
+                    };
+                    self.text(start, &mut out, done);
+                    done += start;
+                    self.within = Within::Markup;
+                }
+                Within::Markup => {
+                    let section = SECTIONS.iter().find(|(start, _)| rest.starts_with(start));
+                    if section.is_none() && SECTIONS.iter().any(|(start, _)| rest.len() < start.len() && start.starts_with(rest)) {
+                        // Not enough to tell yet
+                        break;
+                    }
+                    self.within = match section {
+                        Some((start, end)) => Within::Section { end, scanned: start.len() },
+                        None => Within::Tag {
+                            quote: None,
+                            scanned: 1,
+                            flushed: false,
+                        },
+                    };
+                }
+                Within::Tag { mut quote, scanned, flushed } => {
+                    let end = rest[scanned..].iter().position(|&b| {
+                        match quote {
+                            Some(q) if b == q => quote = None,
+                            Some(_) => {}
+                            None if b == b'"' || b == b'\'' => quote = Some(b),
+                            None => return b == b'>',
+                        }
+                        false
+                    });
+                    let Some(end) = end.map(|end| scanned + end) else {
+                        self.within = Within::Tag {
+                            quote,
+                            scanned: rest.len(),
+                            flushed,
+                        };
+                        break;
+                    };
+                    self.element = match flushed {
+                        false => {
+                            let tag = String::from_utf8_lossy(&rest[1..end]);
+                            let name = tag.split_whitespace().next().unwrap_or("");
+                            (!name.starts_with('/') && !tag.ends_with('/')).then(|| local_name(name).1.to_string())
+                        }
+                        true => None,
+                    };
+                    out.extend_from_slice(&rest[..=end]);
+                    done += end + 1;
+                    self.within = Within::Text;
+                }
+                Within::Section { end, scanned } => {
+                    let Some(at) = rest[scanned..].windows(end.len()).position(|w| w == end) else {
+                        // The terminator may be cut in two
+                        let scanned = scanned.max(rest.len().saturating_sub(end.len() - 1));
+                        self.within = Within::Section { end, scanned };
+                        break;
+                    };
+                    let length = scanned + at + end.len();
+                    out.extend_from_slice(&rest[..length]);
+                    done += length;
+                    self.element = None;
+                    self.within = Within::Text;
+                }
+            }
+        }
+        let rest = self.held.len() - done;
+        match self.within {
+            Within::Text if self.element.is_none() || rest > MAX_HELD => {
+                out.extend_from_slice(&self.held[done..]);
+                done = self.held.len();
+                self.element = None;
+            }
+            Within::Tag { quote, flushed: _, .. } if rest > MAX_HELD => {
+                out.extend_from_slice(&self.held[done..]);
+                done = self.held.len();
+                self.within = Within::Tag {
+                    quote,
+                    scanned: 0,
+                    flushed: true,
+                };
+            }
+            Within::Section { end, .. } if rest > MAX_HELD => {
+                // All but what may be the start of the terminator
+                let kept = end.len() - 1;
+                out.extend_from_slice(&self.held[done..self.held.len() - kept]);
+                done = self.held.len() - kept;
+                self.within = Within::Section { end, scanned: 0 };
+            }
+            _ => {}
+        }
+        self.held.drain(..done);
+        out
+    }
+
+    // What is left at the end of the body
+    pub fn finish(mut self) -> Vec<u8> {
+        let mut out = Vec::new();
+        match self.within {
+            Within::Text => self.text(self.held.len(), &mut out, 0),
+            _ => out.append(&mut self.held),
+        }
+        out
+    }
+
+    // Pass on the `len` bytes of text at `at` in `held`, mapped if they are
+    // an element's and no longer than could have been held, however the
+    // body was cut into pieces
+    fn text(&mut self, len: usize, out: &mut Vec<u8>, at: usize) {
+        let text = &self.held[at..at + len];
+        let mapped = match (self.element.take(), std::str::from_utf8(text)) {
+            (Some(name), Ok(text)) if len <= MAX_HELD => (self.map)(&name, text),
+            _ => None,
+        };
+        match mapped {
+            Some(mapped) => out.extend_from_slice(mapped.as_bytes()),
+            None => out.extend_from_slice(text),
+        }
+    }
+}
+
+// The text of the first element named `local` (any prefix) in `xml`
+fn first_text<'a>(xml: &'a str, local: &str) -> Option<&'a str> {
+    let mut rest = xml;
+    while let Some(start) = rest.find('<') {
+        let end = start + rest[start..].find('>')?;
+        let tag = &rest[start + 1..end];
+        rest = &rest[end + 1..];
+        let name = tag.split_whitespace().next().unwrap_or("");
+        if !tag.starts_with('/') && !tag.ends_with('/') && local_name(name).1 == local {
+            return Some(&rest[..rest.find('<').unwrap_or(rest.len())]);
+        }
+    }
+    None
+}
+
+// Add to each <response> what `extra`, given its href and the prefix bound
+// to DAV:, returns, e.g. another <propstat>
+pub fn extend_responses(body: &str, extra: impl Fn(&str, &str) -> Option<String>) -> String {
+    let mut out = String::with_capacity(body.len());
+    let mut rest = body;
+    // Where the <response> being copied starts in `out`
+    let mut response = 0;
+    while let Some(start) = rest.find('<') {
+        let Some(end) = rest[start..].find('>').map(|e| start + e) else {
+            break;
+        };
+        let tag = &rest[start + 1..end];
+        let (prefix, name) = local_name(tag.trim_start_matches('/').split_whitespace().next().unwrap_or(""));
+        out.push_str(&rest[..start]);
+        if name == "response" && !tag.starts_with('/') {
+            response = out.len();
+        } else if name == "response" {
+            if let Some(markup) = first_text(&out[response..], "href").and_then(|href| extra(href.trim(), prefix)) {
+                out.push_str(&markup);
+            }
+        }
+        out.push_str(&rest[start..=end]);
+        rest = &rest[end + 1..];
+    }
+    out.push_str(rest);
+    out
+}
+
+// A standalone multistatus holding the given <response> elements
+pub fn document(responses: &str) -> String {
+    let mut w = Writer::document("d");
+    w.open_with("multistatus", &[("xmlns:d", "DAV:")]).raw(responses);
+    w.finish()
+}
+
+// A property named in a PROPFIND request
+pub struct PropName {
+    pub namespace: String,
+    pub name: String,
+}
+
+fn local_name(qname: &str) -> (&str, &str) {
+    match qname.split_once(':') {
+        Some((prefix, name)) => (prefix, name),
+        None => ("", qname),
+    }
+}
+
+// The properties a PROPFIND body asks for, or None for allprop (including
+// an empty body). Namespace prefixes are resolved against every xmlns
+// declaration in the body, which is good enough for the flat documents
+// clients send.
+pub fn requested_props(body: &[u8]) -> Option<Vec<PropName>> {
+    let body = std::str::from_utf8(body).ok()?;
+    let mut namespaces = std::collections::HashMap::new();
+    let mut tags = Vec::new();
+    let mut rest = body;
+    while let Some(start) = rest.find('<') {
+        let end = start + rest[start..].find('>')?;
+        let tag = &rest[start + 1..end];
+        rest = &rest[end + 1..];
+        if tag.starts_with('?') || tag.starts_with('!') {
+            continue;
+        }
+        let closing = tag.starts_with('/');
+        let empty = tag.ends_with('/');
+        let tag = tag.trim_start_matches('/').trim_end_matches('/');
+        let mut parts = tag.split_whitespace();
+        let qname = parts.next().unwrap_or("").to_string();
+        for attr in parts {
+            if let Some((key, value)) = attr.split_once('=') {
+                let value = value.trim_matches(|c| c == '"' || c == '\'');
+                if key == "xmlns" {
+                    namespaces.insert(String::new(), value.to_string());
+                } else if let Some(prefix) = key.strip_prefix("xmlns:") {
+                    namespaces.insert(prefix.to_string(), value.to_string());
+                }
+            }
+        }
+        tags.push((qname, closing, empty));
+    }
+
+    let mut props = Vec::new();
+    let mut depth = 0;
+    let mut prop_depth = None;
+    for (qname, closing, empty) in &tags {
+        let (prefix, name) = local_name(qname);
+        if *closing {
+            depth -= 1;
+            if prop_depth == Some(depth) {
+                prop_depth = None;
+            }
+            continue;
+        }
+        if prop_depth.is_some_and(|d| depth == d + 1) {
+            props.push(PropName {
+                namespace: namespaces.get(prefix).cloned().unwrap_or_default(),
+                name: name.to_string(),
+            });
+        } else if name == "prop" && prop_depth.is_none() && depth == 1 {
+            prop_depth = Some(depth);
+        }
+        if !*empty {
+            depth += 1;
+        }
+    }
+    // A <propfind> without <prop> is allprop or propname
+    if tags.iter().any(|(q, closing, _)| !*closing && local_name(q).1 == "prop") {
+        Some(props)
+    } else {
+        None
+    }
+}
+
+// How the client asked for a minimal multistatus, if it did
+#[derive(Clone, Copy, PartialEq)]
+pub enum Minimal {
+    No,
+    // Brief: t (draft-ietf-webdav-brief)
+    Brief,
+    // Prefer: return=minimal (RFC 8144), which wants Preference-Applied back
+    Prefer,
+}
+
+pub fn minimal(headers: &hyper::HeaderMap) -> Minimal {
+    let prefer = headers
+        .get_all("Prefer")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|p| p.split(';').next().is_some_and(|p| p.trim().eq_ignore_ascii_case("return=minimal")));
+    if prefer {
+        Minimal::Prefer
+    } else if headers.get("Brief").is_some_and(|b| b.as_bytes().eq_ignore_ascii_case(b"t")) {
+        Minimal::Brief
+    } else {
+        Minimal::No
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LISTING: &str = concat!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n",
+        "<d:multistatus xmlns:d=\"DAV:\" xmlns:x=\"urn:x\">",
+        "<!-- a <d:href>/not/this</d:href> > -->",
+        "<d:response><d:href>/dav/a</d:href>",
+        "<d:propstat><d:prop><x:note title=\"1 > 0\" other='<d:href>'>/dav/keep</x:note>",
+        "<x:data><![CDATA[<d:href>/dav/cdata</d:href> ]] > ]]></x:data>",
+        "<d:displayname>é ü</d:displayname><d:resourcetype/></d:prop></d:propstat></d:response>",
+        "<d:response><d:href>/dav/b%20c</d:href></d:response>",
+        "</d:multistatus>",
+    );
+
+    fn rewrite(name: &str, text: &str) -> Option<String> {
+        match name {
+            "href" => Some(text.replacen("/dav", "/share", 1)),
+            _ => None,
+        }
+    }
+
+    // The mapper's output for `body` fed `size` bytes at a time
+    fn chunked(body: &[u8], size: usize, map: impl FnMut(&str, &str) -> Option<String>) -> Vec<u8> {
+        let mut mapper = ElementMapper::new(map);
+        let mut out = Vec::new();
+        for chunk in body.chunks(size) {
+            out.extend(mapper.feed(chunk));
+            assert!(mapper.held.len() <= MAX_HELD + size, "holds {} bytes", mapper.held.len());
+        }
+        out.extend(mapper.finish());
+        out
+    }
+
+    #[test]
+    fn maps_only_element_text() {
+        let out = map_elements(LISTING, rewrite);
+        assert!(out.contains("<d:href>/share/a</d:href>"), "{}", out);
+        assert!(out.contains("<d:href>/share/b%20c</d:href>"), "{}", out);
+        // Not in the comment, the CDATA section or an attribute value
+        assert!(out.contains("<!-- a <d:href>/not/this</d:href> > -->"), "{}", out);
+        assert!(out.contains("<![CDATA[<d:href>/dav/cdata</d:href> ]] > ]]>"), "{}", out);
+        assert!(out.contains("other='<d:href>'>/dav/keep</x:note>"), "{}", out);
+        assert_eq!(out.matches("/share").count(), 2, "{}", out);
+    }
+
+    #[test]
+    fn chunk_boundaries_do_not_matter() {
+        let whole = map_elements(LISTING, rewrite).into_bytes();
+        for size in 1..=LISTING.len() {
+            assert_eq!(chunked(LISTING.as_bytes(), size, rewrite), whole, "in chunks of {}", size);
+        }
+    }
+
+    #[test]
+    fn sees_the_whole_text_of_a_split_element() {
+        let mut seen = Vec::new();
+        let body = b"<d:href>/dav/a long name</d:href><d:href>/b</d:href>";
+        chunked(body, 3, |name, text| {
+            seen.push((name.to_string(), text.to_string()));
+            None
+        });
+        assert_eq!(seen, [("href".to_string(), "/dav/a long name".to_string()), ("href".to_string(), "/b".to_string())]);
+    }
+
+    #[test]
+    fn quotes_hold_across_chunks() {
+        for quote in ['"', '\''] {
+            let body = format!("<x:a title={q}>{q}><d:href>/dav/x</d:href>", q = quote);
+            let out = chunked(body.as_bytes(), 1, rewrite);
+            assert_eq!(String::from_utf8(out).unwrap(), body.replace("/dav", "/share"));
+        }
+    }
+
+    #[test]
+    fn overlong_text_is_passed_on_unmapped() {
+        let long = "a".repeat(MAX_HELD * 2);
+        let body = format!("<d:href>/dav/{}</d:href><d:href>/dav/b</d:href>", long);
+        let out = chunked(body.as_bytes(), 4096, rewrite);
+        assert_eq!(String::from_utf8(out).unwrap(), format!("<d:href>/dav/{}</d:href><d:href>/share/b</d:href>", long));
+        // Also when it came in one piece
+        assert_eq!(map_elements(&body, rewrite), format!("<d:href>/dav/{}</d:href><d:href>/share/b</d:href>", long));
+    }
+
+    #[test]
+    fn overlong_tags_keep_their_quotes() {
+        let value = "v".repeat(MAX_HELD * 2);
+        let body = format!("<x:a title=\"{}>\" other='>'>/dav/a</x:a><d:href>/dav/b</d:href>", value);
+        let out = chunked(body.as_bytes(), 4096, rewrite);
+        assert_eq!(String::from_utf8(out).unwrap(), body.replace("/dav/b", "/share/b"));
+    }
+
+    #[test]
+    fn overlong_comments_end_at_their_terminator() {
+        let comment = "c>".repeat(MAX_HELD);
+        let body = format!("<!--{}--><d:href>/dav/b</d:href>", comment);
+        // Chunks that cut the terminator in two
+        for size in [4095, 4096, 4097] {
+            let out = chunked(body.as_bytes(), size, rewrite);
+            assert_eq!(String::from_utf8(out).unwrap(), body.replace("/dav/b", "/share/b"), "in chunks of {}", size);
+        }
+    }
+
+    #[test]
+    fn non_utf8_text_is_passed_on_unmapped() {
+        let body = b"<d:href>/dav/\xff\xfe</d:href><d:href>/dav/b</d:href><d:href>/dav/\xc3</d:href>";
+        let out = chunked(body, 5, rewrite);
+        assert_eq!(out, b"<d:href>/dav/\xff\xfe</d:href><d:href>/share/b</d:href><d:href>/dav/\xc3</d:href>");
+    }
+
+    #[test]
+    fn unfinished_markup_is_passed_on() {
+        for body in ["<d:href>/dav/a", "<d:href>/dav/a</d:hr", "<!-- open", "<x:a b=\"c>", "<"] {
+            assert_eq!(map_elements(body, |_, _| Some("x".to_string())), body.replace("/dav/a", "x"));
+        }
+    }
+
+    #[tokio::test]
+    async fn maps_a_streamed_body() {
+        let chunks: Vec<Result<_, hyper::Error>> = LISTING.as_bytes().chunks(7).map(|c| Ok(Bytes::copy_from_slice(c))).collect();
+        let body = map_body(Body::wrap_stream(futures::stream::iter(chunks)), rewrite);
+        let out = hyper::body::to_bytes(body).await.unwrap();
+        assert_eq!(out, map_elements(LISTING, rewrite).as_bytes());
+    }
+
+    // Without a property-testing crate: bodies made up of random pieces of
+    // markup, fed in random chunks, must come out as they do whole and as
+    // they went in when nothing is mapped
+    #[test]
+    fn random_bodies() {
+        const PIECES: [&[u8]; 17] = [
+            b"<d:href>", b"</d:href>", b"<x:a k='", b"<x:b k=\"", b"'", b"\"", b"<", b">", b"/>", b"<!--", b"-->", b"<![CDATA[",
+            b"]]>", b"<?pi ", b"?>", "é/dav/".as_bytes(), b"\xff",
+        ];
+        // xorshift, seeded so failures reproduce
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        let mut next = |n: usize| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            (state % n as u64) as usize
+        };
+        for _ in 0..2000 {
+            let mut body = Vec::new();
+            for _ in 0..next(40) {
+                body.extend_from_slice(PIECES[next(PIECES.len())]);
+            }
+            let size = 1 + next(body.len().max(1));
+            let whole = chunked(&body, body.len().max(1), rewrite);
+            assert_eq!(chunked(&body, size, rewrite), whole, "{:?} in chunks of {}", String::from_utf8_lossy(&body), size);
+            assert_eq!(chunked(&body, size, |_, _| None), body);
+        }
+    }
+
+    // Malformed bodies: random markup, unbalanced and cut off anywhere, with
+    // texts, tags, comments and CDATA sections longer than what is held,
+    // fed in chunks of random sizes. None may panic, hold more than
+    // MAX_HELD, lose a byte or depend on where the chunks end.
+    #[test]
+    fn malformed_bodies_stay_bounded() {
+        let long = "x".repeat(MAX_HELD / 2 + 1);
+        let pieces: Vec<Vec<u8>> = [
+            "<d:href>", "</d:href>", "</d:response>", "<d:prop>", "<x:a k='", "'>", "\"", "<", ">", "<!--", "-->", "--",
+            "<![CDATA[", "]]>", "]]", "<?", "?>", "/dav/", "<!-- > -->", &long, &long, &long,
+        ]
+        .iter()
+        .map(|piece| piece.as_bytes().to_vec())
+        .chain([b"\xc3".to_vec(), b"\xff\xfe".to_vec()])
+        .collect();
+        let mut state = 0x9e37_79b9_7f4a_7c15_u64;
+        let mut next = |n: usize| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            (state % n.max(1) as u64) as usize
+        };
+        // The output and the most held, `body` fed `sizes` bytes at a time
+        let feed = |body: &[u8], sizes: &[usize], map: &mut dyn FnMut(&str, &str) -> Option<String>| {
+            let mut mapper = ElementMapper::new(map);
+            let (mut out, mut most, mut at, mut turn) = (Vec::new(), 0, 0, 0);
+            while at < body.len() {
+                let end = body.len().min(at + sizes[turn % sizes.len()]);
+                out.extend(mapper.feed(&body[at..end]));
+                most = most.max(mapper.held.len());
+                (at, turn) = (end, turn + 1);
+            }
+            out.extend(mapper.finish());
+            (out, most)
+        };
+        for _ in 0..1000 {
+            let mut body = Vec::new();
+            for _ in 0..next(24) {
+                body.extend_from_slice(&pieces[next(pieces.len())]);
+            }
+            body.truncate(next(body.len() + 1));
+            let sizes: Vec<usize> = (0..8).map(|_| 1 + next(8192)).collect();
+            // Every element's text changes, so any difference in what is mapped shows
+            let mut mark = |_: &str, text: &str| Some(format!("[{}]", text));
+            let (whole, _) = feed(&body, &[body.len().max(1)], &mut mark);
+            let (out, most) = feed(&body, &sizes, &mut mark);
+            assert!(most <= MAX_HELD, "held {} bytes", most);
+            assert_eq!(out, whole, "{:?} in chunks of {:?}", String::from_utf8_lossy(&body), sizes);
+            assert_eq!(feed(&body, &sizes, &mut |_, _| None).0, body);
+        }
+    }
+}
@@ -0,0 +1,54 @@
+use std::net::IpAddr;
+
+// An address range, `ADDR/BITS` or a single address
+#[derive(Clone)]
+pub struct Network {
+    addr: IpAddr,
+    bits: u32,
+}
+
+impl Network {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let invalid = || format!("Invalid network (expected ADDR or ADDR/BITS): {}", s);
+        let (addr, bits) = match s.trim().split_once('/') {
+            Some((addr, bits)) => (addr, Some(bits)),
+            None => (s.trim(), None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| invalid())?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let bits = match bits {
+            Some(bits) => bits.parse().ok().filter(|b| *b <= max).ok_or_else(invalid)?,
+            None => max,
+        };
+        Ok(Network { addr, bits })
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.bits).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.bits).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+// Which clients may connect (--allow, --deny). A denied network wins over
+// an allowed one; without any allowed ones, everyone not denied may.
+#[derive(Clone, Default)]
+pub struct AccessList {
+    pub allow: Vec<Network>,
+    pub deny: Vec<Network>,
+}
+
+impl AccessList {
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        !self.deny.iter().any(|network| network.contains(ip))
+            && (self.allow.is_empty() || self.allow.iter().any(|network| network.contains(ip)))
+    }
+}
@@ -0,0 +1,182 @@
+use std::io;
+use std::net::IpAddr;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use crate::base64;
+use crate::local_dir::percent_decode;
+
+// Upstreams only reachable through a corporate proxy or an SSH dynamic
+// forward (--outbound-proxy): every upstream connection is opened to the
+// proxy, which is asked to connect on, with SOCKS5 (RFC 1928, user and
+// password per RFC 1929) or an HTTP CONNECT with Basic credentials. The
+// proxy resolves the upstream's name, so it needn't resolve here; TLS to
+// https:// upstreams runs through the tunnel as usual.
+
+// Longest CONNECT response head read
+const MAX_HEAD: usize = 8 * 1024;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Kind {
+    Socks5,
+    Http,
+}
+
+#[derive(Clone)]
+pub struct OutboundProxy {
+    kind: Kind,
+    host: String,
+    port: u16,
+    credentials: Option<(String, String)>,
+}
+
+fn failed(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::ConnectionRefused, message)
+}
+
+impl OutboundProxy {
+    // `socks5://[USER:PASSWORD@]HOST:PORT` or `http://[USER:PASSWORD@]HOST:PORT`
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let invalid = || format!("Invalid --outbound-proxy (expected socks5:// or http://[USER:PASSWORD@]HOST:PORT): {}", spec);
+        let (scheme, rest) = spec.trim().split_once("://").ok_or_else(invalid)?;
+        let kind = match scheme.to_ascii_lowercase().as_str() {
+            "socks5" => Kind::Socks5,
+            "http" => Kind::Http,
+            _ => return Err(invalid()),
+        };
+        let rest = rest.trim_end_matches('/');
+        let (credentials, authority) = match rest.rsplit_once('@') {
+            Some((userinfo, authority)) => {
+                let (user, password) = userinfo.split_once(':').unwrap_or((userinfo, ""));
+                let user = percent_decode(user).ok_or_else(invalid)?;
+                let password = percent_decode(password).ok_or_else(invalid)?;
+                (Some((user, password)), authority)
+            }
+            None => (None, rest),
+        };
+        let (host, port) = authority.rsplit_once(':').ok_or_else(invalid)?;
+        let port = port.parse().map_err(|_| invalid())?;
+        if host.is_empty() || authority.contains('/') {
+            return Err(invalid());
+        }
+        if kind == Kind::Socks5 && credentials.as_ref().is_some_and(|(u, p)| u.len() > 255 || p.len() > 255) {
+            return Err("SOCKS5 user names and passwords are at most 255 bytes".to_string());
+        }
+        Ok(OutboundProxy {
+            kind,
+            host: host.trim_start_matches('[').trim_end_matches(']').to_string(),
+            port,
+            credentials,
+        })
+    }
+
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    // Have the proxy connected on `stream` connect on to `host:port`
+    pub async fn handshake(&self, stream: &mut TcpStream, host: &str, port: u16) -> io::Result<()> {
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        match self.kind {
+            Kind::Socks5 => self.socks5(stream, host, port).await,
+            Kind::Http => self.connect(stream, host, port).await,
+        }
+    }
+
+    async fn socks5(&self, stream: &mut TcpStream, host: &str, port: u16) -> io::Result<()> {
+        // Offer no authentication, and user and password if there are some
+        let greeting: &[u8] = if self.credentials.is_some() { &[5, 2, 0, 2] } else { &[5, 1, 0] };
+        stream.write_all(greeting).await?;
+        let mut choice = [0u8; 2];
+        stream.read_exact(&mut choice).await?;
+        match (choice, &self.credentials) {
+            ([5, 0], _) => {}
+            ([5, 2], Some((user, password))) => {
+                let mut request = vec![1, user.len() as u8];
+                request.extend_from_slice(user.as_bytes());
+                request.push(password.len() as u8);
+                request.extend_from_slice(password.as_bytes());
+                stream.write_all(&request).await?;
+                let mut status = [0u8; 2];
+                stream.read_exact(&mut status).await?;
+                if status[1] != 0 {
+                    return Err(failed("the SOCKS5 proxy refused the user and password".to_string()));
+                }
+            }
+            ([5, _], _) => return Err(failed("the SOCKS5 proxy accepts none of the authentication methods offered".to_string())),
+            (_, _) => return Err(failed("the outbound proxy doesn't speak SOCKS5".to_string())),
+        }
+        let mut request = vec![5, 1, 0];
+        match host.parse::<IpAddr>() {
+            Ok(IpAddr::V4(ip)) => {
+                request.push(1);
+                request.extend_from_slice(&ip.octets());
+            }
+            Ok(IpAddr::V6(ip)) => {
+                request.push(4);
+                request.extend_from_slice(&ip.octets());
+            }
+            Err(_) if host.len() <= 255 => {
+                request.extend_from_slice(&[3, host.len() as u8]);
+                request.extend_from_slice(host.as_bytes());
+            }
+            Err(_) => return Err(failed(format!("{} is too long a name for SOCKS5", host))),
+        }
+        request.extend_from_slice(&port.to_be_bytes());
+        stream.write_all(&request).await?;
+        let mut reply = [0u8; 4];
+        stream.read_exact(&mut reply).await?;
+        if reply[1] != 0 {
+            let reason = match reply[1] {
+                2 => "not allowed by its rules",
+                3 => "network unreachable",
+                4 => "host unreachable",
+                5 => "connection refused",
+                6 => "TTL expired",
+                _ => "general failure",
+            };
+            return Err(failed(format!("the SOCKS5 proxy couldn't connect to {}:{}: {}", host, port, reason)));
+        }
+        // The address it connected from, which isn't needed
+        let length = match reply[3] {
+            1 => 4,
+            4 => 16,
+            3 => stream.read_u8().await? as usize,
+            _ => return Err(failed("malformed SOCKS5 reply".to_string())),
+        };
+        let mut bound = vec![0u8; length + 2];
+        stream.read_exact(&mut bound).await?;
+        Ok(())
+    }
+
+    async fn connect(&self, stream: &mut TcpStream, host: &str, port: u16) -> io::Result<()> {
+        let authority = if host.contains(':') { format!("[{}]:{}", host, port) } else { format!("{}:{}", host, port) };
+        let mut request = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", authority);
+        if let Some((user, password)) = &self.credentials {
+            let token = base64::encode(format!("{}:{}", user, password).as_bytes());
+            request.push_str(&format!("Proxy-Authorization: Basic {}\r\n", token));
+        }
+        request.push_str("\r\n");
+        stream.write_all(request.as_bytes()).await?;
+        // Byte by byte, so nothing the upstream sends after the head is lost
+        let mut head = Vec::new();
+        while !head.ends_with(b"\r\n\r\n") {
+            if head.len() >= MAX_HEAD {
+                return Err(failed("the outbound proxy's CONNECT response is too long".to_string()));
+            }
+            head.push(stream.read_u8().await?);
+        }
+        let head = String::from_utf8_lossy(&head);
+        let status_line = head.lines().next().unwrap_or_default();
+        match status_line.split_whitespace().nth(1) {
+            Some(status) if status.starts_with('2') => Ok(()),
+            Some("407") => Err(failed("the outbound proxy wants (other) credentials".to_string())),
+            _ => Err(failed(format!("the outbound proxy refused CONNECT {}: {}", authority, status_line))),
+        }
+    }
+}
@@ -0,0 +1,441 @@
+// A small regular expression engine for path rules, to spare the proxy a
+// regex dependency. It knows what paths need: literals, `.`, classes like
+// `[^/]` and `[a-z0-9]`, `\d` `\w` `\s` and escaped punctuation, groups
+// (capturing, or `(?:...)`) with `|`, and the greedy quantifiers `*`, `+`,
+// `?` and `{m,n}`. A pattern always matches the whole path, so `^` and `$`
+// at its ends are optional. Patterns are compiled to a small program run
+// over all alternatives at once (a Pike VM), so matching takes time linear
+// in the path and no recursion: paths come from clients, before any auth.
+
+// Instructions a compiled pattern may run to
+const MAX_PROGRAM: usize = 10_000;
+
+#[derive(Clone)]
+enum Node {
+    Char(char),
+    Any,
+    Class { ranges: Vec<(char, char)>, negated: bool },
+    Group { alternatives: Vec<Vec<Node>>, capture: Option<usize> },
+    Repeat { node: Box<Node>, min: usize, max: Option<usize> },
+}
+
+enum Inst {
+    // Char, Any or Class, taking one char
+    Take(Node),
+    // Go on at both, the first preferred
+    Split(usize, usize),
+    Jump(usize),
+    // Record the position in a capture slot, two per group
+    Save(usize),
+    Match,
+}
+
+pub struct Pattern {
+    program: Vec<Inst>,
+    groups: usize,
+}
+
+// Where each group started and ended, as char offsets
+type Slots = Vec<Option<usize>>;
+
+struct Parser<'a> {
+    chars: Vec<char>,
+    pos: usize,
+    groups: usize,
+    source: &'a str,
+}
+
+fn class_escape(c: char) -> Option<(Vec<(char, char)>, bool)> {
+    let word = vec![('a', 'z'), ('A', 'Z'), ('0', '9'), ('_', '_')];
+    let space = vec![(' ', ' '), ('\t', '\t'), ('\n', '\n'), ('\r', '\r')];
+    match c {
+        'd' => Some((vec![('0', '9')], false)),
+        'D' => Some((vec![('0', '9')], true)),
+        'w' => Some((word, false)),
+        'W' => Some((word, true)),
+        's' => Some((space, false)),
+        'S' => Some((space, true)),
+        _ => None,
+    }
+}
+
+impl Parser<'_> {
+    fn error(&self, what: &str) -> String {
+        format!("Invalid pattern {} ({} at {})", self.source, what, self.pos)
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek();
+        self.pos += 1;
+        c
+    }
+
+    // Alternatives up to the end or a closing parenthesis
+    fn alternatives(&mut self) -> Result<Vec<Vec<Node>>, String> {
+        let mut alternatives = vec![self.sequence()?];
+        while self.peek() == Some('|') {
+            self.pos += 1;
+            alternatives.push(self.sequence()?);
+        }
+        Ok(alternatives)
+    }
+
+    fn sequence(&mut self) -> Result<Vec<Node>, String> {
+        let mut nodes = Vec::new();
+        while let Some(c) = self.peek() {
+            if c == '|' || c == ')' {
+                break;
+            }
+            let atom = self.atom()?;
+            nodes.push(self.quantified(atom)?);
+        }
+        Ok(nodes)
+    }
+
+    fn atom(&mut self) -> Result<Node, String> {
+        match self.next().expect("peeked") {
+            '.' => Ok(Node::Any),
+            '(' => {
+                let capture = if self.chars[self.pos..].starts_with(&['?', ':']) {
+                    self.pos += 2;
+                    None
+                } else {
+                    self.groups += 1;
+                    Some(self.groups)
+                };
+                let alternatives = self.alternatives()?;
+                if self.next() != Some(')') {
+                    return Err(self.error("unclosed group"));
+                }
+                Ok(Node::Group { alternatives, capture })
+            }
+            '[' => self.class(),
+            '\\' => {
+                let c = self.next().ok_or_else(|| self.error("trailing backslash"))?;
+                Ok(match class_escape(c) {
+                    Some((ranges, negated)) => Node::Class { ranges, negated },
+                    None if c.is_ascii_alphanumeric() => return Err(self.error("unknown escape")),
+                    None => Node::Char(c),
+                })
+            }
+            '*' | '+' | '?' | '{' => Err(self.error("nothing to repeat")),
+            c => Ok(Node::Char(c)),
+        }
+    }
+
+    fn class(&mut self) -> Result<Node, String> {
+        let negated = self.peek() == Some('^');
+        if negated {
+            self.pos += 1;
+        }
+        let mut ranges = Vec::new();
+        let mut first = true;
+        loop {
+            let c = self.next().ok_or_else(|| self.error("unclosed class"))?;
+            if c == ']' && !first {
+                break;
+            }
+            first = false;
+            let c = match c {
+                '\\' => {
+                    let c = self.next().ok_or_else(|| self.error("unclosed class"))?;
+                    if let Some((escaped, false)) = class_escape(c) {
+                        ranges.extend(escaped);
+                        continue;
+                    }
+                    c
+                }
+                c => c,
+            };
+            if self.peek() == Some('-') && self.chars.get(self.pos + 1).is_some_and(|c| *c != ']') {
+                self.pos += 1;
+                let end = self.next().expect("checked above");
+                if end < c {
+                    return Err(self.error("reversed range"));
+                }
+                ranges.push((c, end));
+            } else {
+                ranges.push((c, c));
+            }
+        }
+        Ok(Node::Class { ranges, negated })
+    }
+
+    fn number(&mut self) -> Option<usize> {
+        let start = self.pos;
+        while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        self.chars[start..self.pos].iter().collect::<String>().parse().ok()
+    }
+
+    fn quantified(&mut self, node: Node) -> Result<Node, String> {
+        let (min, max) = match self.peek() {
+            Some('*') => (0, None),
+            Some('+') => (1, None),
+            Some('?') => (0, Some(1)),
+            Some('{') => {
+                self.pos += 1;
+                let min = self.number().ok_or_else(|| self.error("bad repetition"))?;
+                let max = match self.next() {
+                    Some('}') => Some(min),
+                    Some(',') if self.peek() == Some('}') => {
+                        self.pos += 1;
+                        None
+                    }
+                    Some(',') => {
+                        let max = self.number().filter(|max| *max >= min);
+                        if self.next() != Some('}') || max.is_none() {
+                            return Err(self.error("bad repetition"));
+                        }
+                        max
+                    }
+                    _ => return Err(self.error("bad repetition")),
+                };
+                return Ok(Node::Repeat { node: Box::new(node), min, max });
+            }
+            _ => return Ok(node),
+        };
+        self.pos += 1;
+        Ok(Node::Repeat { node: Box::new(node), min, max })
+    }
+}
+
+impl Node {
+    fn accepts(&self, c: char) -> bool {
+        match self {
+            Node::Char(expected) => c == *expected,
+            Node::Any => true,
+            Node::Class { ranges, negated } => ranges.iter().any(|(lo, hi)| (*lo..=*hi).contains(&c)) != *negated,
+            _ => false,
+        }
+    }
+}
+
+impl Pattern {
+    pub fn parse(source: &str) -> Result<Self, String> {
+        let trimmed = source.strip_prefix('^').unwrap_or(source);
+        let trimmed = match trimmed.strip_suffix('$') {
+            Some(rest) if !rest.ends_with('\\') => rest,
+            _ => trimmed,
+        };
+        let mut parser = Parser {
+            chars: trimmed.chars().collect(),
+            pos: 0,
+            groups: 0,
+            source,
+        };
+        let alternatives = parser.alternatives()?;
+        if parser.pos < parser.chars.len() {
+            return Err(parser.error("unmatched )"));
+        }
+        let whole = Node::Group {
+            alternatives,
+            capture: Some(0),
+        };
+        let mut program = Vec::new();
+        if !compile(&whole, &mut program) {
+            return Err(format!("Invalid pattern {} (repeats too much)", source));
+        }
+        program.push(Inst::Match);
+        Ok(Pattern {
+            program,
+            groups: parser.groups,
+        })
+    }
+
+    // The text of each group (0 being the whole match) if `text` matches
+    pub fn captures(&self, text: &str) -> Option<Vec<Option<String>>> {
+        let input: Vec<char> = text.chars().collect();
+        let slots = self.run(&input)?;
+        Some(
+            slots
+                .chunks(2)
+                .map(|span| match span {
+                    [Some(start), Some(end)] => Some(input[*start..*end].iter().collect()),
+                    _ => None,
+                })
+                .collect(),
+        )
+    }
+
+    // The slots of the preferred way through the program that takes all of
+    // `input`. Threads are kept in order of preference, one per
+    // instruction, and all take each char together.
+    fn run(&self, input: &[char]) -> Option<Slots> {
+        let mut threads = Vec::new();
+        let mut seen = vec![usize::MAX; self.program.len()];
+        self.follow(0, vec![None; (self.groups + 1) * 2], 0, &mut threads, &mut seen);
+        for (pos, c) in input.iter().enumerate() {
+            let mut next = Vec::new();
+            for (pc, slots) in threads {
+                if let Inst::Take(node) = &self.program[pc] {
+                    if node.accepts(*c) {
+                        self.follow(pc + 1, slots, pos + 1, &mut next, &mut seen);
+                    }
+                }
+            }
+            if next.is_empty() {
+                return None;
+            }
+            threads = next;
+        }
+        threads
+            .into_iter()
+            .find_map(|(pc, slots)| matches!(self.program[pc], Inst::Match).then_some(slots))
+    }
+
+    // Add the threads `pc` leads to at `pos` without taking a char, in
+    // order of preference; `seen` drops the ones already there
+    fn follow(&self, pc: usize, slots: Slots, pos: usize, threads: &mut Vec<(usize, Slots)>, seen: &mut [usize]) {
+        let mut pending = vec![(pc, slots)];
+        while let Some((pc, mut slots)) = pending.pop() {
+            if seen[pc] == pos {
+                continue;
+            }
+            seen[pc] = pos;
+            match self.program[pc] {
+                Inst::Split(first, second) => {
+                    pending.push((second, slots.clone()));
+                    pending.push((first, slots));
+                }
+                Inst::Jump(to) => pending.push((to, slots)),
+                Inst::Save(slot) => {
+                    slots[slot] = Some(pos);
+                    pending.push((pc + 1, slots));
+                }
+                Inst::Take(_) | Inst::Match => threads.push((pc, slots)),
+            }
+        }
+    }
+}
+
+// Append the instructions for `node`; false once the program grows past
+// MAX_PROGRAM
+fn compile(node: &Node, program: &mut Vec<Inst>) -> bool {
+    match node {
+        Node::Group { alternatives, capture } => {
+            if let Some(group) = capture {
+                program.push(Inst::Save(group * 2));
+            }
+            // Each alternative but the last is tried first, then jumps past the others
+            let mut jumps = Vec::new();
+            for (i, alternative) in alternatives.iter().enumerate() {
+                let split = program.len();
+                if i + 1 < alternatives.len() {
+                    program.push(Inst::Split(split + 1, 0));
+                }
+                if !alternative.iter().all(|node| compile(node, program)) {
+                    return false;
+                }
+                if i + 1 < alternatives.len() {
+                    jumps.push(program.len());
+                    program.push(Inst::Jump(0));
+                    program[split] = Inst::Split(split + 1, program.len());
+                }
+            }
+            for jump in jumps {
+                program[jump] = Inst::Jump(program.len());
+            }
+            if let Some(group) = capture {
+                program.push(Inst::Save(group * 2 + 1));
+            }
+        }
+        Node::Repeat { node, min, max } => {
+            if max.unwrap_or(*min) > MAX_PROGRAM {
+                return false;
+            }
+            for _ in 0..*min {
+                if !compile(node, program) {
+                    return false;
+                }
+            }
+            match max {
+                // Greedy: another one is preferred to going on
+                None => {
+                    let split = program.len();
+                    program.push(Inst::Split(split + 1, 0));
+                    if !compile(node, program) {
+                        return false;
+                    }
+                    program.push(Inst::Jump(split));
+                    program[split] = Inst::Split(split + 1, program.len());
+                }
+                Some(max) => {
+                    let mut splits = Vec::new();
+                    for _ in *min..*max {
+                        splits.push(program.len());
+                        program.push(Inst::Split(program.len() + 1, 0));
+                        if !compile(node, program) {
+                            return false;
+                        }
+                    }
+                    for split in splits {
+                        program[split] = Inst::Split(split + 1, program.len());
+                    }
+                }
+            }
+        }
+        node => program.push(Inst::Take(node.clone())),
+    }
+    program.len() <= MAX_PROGRAM
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn groups(pattern: &str, text: &str) -> Option<Vec<Option<String>>> {
+        Pattern::parse(pattern).unwrap().captures(text)
+    }
+
+    fn some(groups: &[&str]) -> Option<Vec<Option<String>>> {
+        Some(groups.iter().map(|g| Some(g.to_string())).collect())
+    }
+
+    #[test]
+    fn captures_groups() {
+        assert_eq!(groups("/old/(.*)", "/old/a/b.txt"), some(&["/old/a/b.txt", "a/b.txt"]));
+        assert_eq!(groups(r"/projects/(\d{4})/([^/]+)/(.*)", "/projects/2019/acme/x"), some(&["/projects/2019/acme/x", "2019", "acme", "x"]));
+        assert_eq!(groups(r"/projects/(\d{4})/(.*)", "/projects/19/x"), None);
+        // Greedy, backing off as far as the rest needs
+        assert_eq!(groups("/(.*)/(.*)", "/a/b/c"), some(&["/a/b/c", "a/b", "c"]));
+        // A group that took no part stays empty
+        assert_eq!(groups("/(a)|/(b)", "/b"), Some(vec![Some("/b".to_string()), None, Some("b".to_string())]));
+        assert_eq!(groups("/(?:docs|files)/(.+)", "/files/x"), some(&["/files/x", "x"]));
+        assert_eq!(groups("/(a+)+b", "/aaab"), some(&["/aaab", "aaa"]));
+        assert_eq!(groups("/(a*)*", "/aa"), some(&["/aa", "aa"]));
+    }
+
+    #[test]
+    fn matches_the_whole_path() {
+        assert!(groups("/old", "/old/x").is_none());
+        assert!(groups("old", "/old").is_none());
+        assert!(groups("^/old$", "/old").is_some());
+        assert!(groups("^/old/.*$", "/old/x").is_some());
+        assert!(groups(r"/price\$", "/price$").is_some());
+        assert!(groups("/[a-c]{2,3}", "/abca").is_none());
+        assert!(groups("/[a-c]{2,3}", "/abc").is_some());
+        assert!(groups("/[^/]+", "/a/b").is_none());
+    }
+
+    #[test]
+    fn rejects_invalid_patterns() {
+        for pattern in ["/(a", "/a)", "/[a", "*a", r"/\q", "/a{3,1}", "/[z-a]", "/a{100000}", "/(?:a{1000}){1000}"] {
+            assert!(Pattern::parse(pattern).is_err(), "{}", pattern);
+        }
+    }
+
+    #[test]
+    fn long_paths_take_no_stack() {
+        let pattern = Pattern::parse("/old/(.*)").unwrap();
+        let path = format!("/old/{}", "a".repeat(1_000_000));
+        assert_eq!(pattern.captures(&path).unwrap()[1].as_deref().map(str::len), Some(1_000_000));
+        let nested = Pattern::parse("/(?:(a|b)*c)*(.*)").unwrap();
+        assert!(nested.captures(&format!("/{}", "ab".repeat(100_000))).is_some());
+    }
+}
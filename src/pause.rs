@@ -0,0 +1,135 @@
+use hyper::{Body, Method, Response, StatusCode};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{watch, Notify};
+
+use crate::methods;
+
+// Temporarily hold back traffic, e.g. while the upstream is being snapshotted
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Scope {
+    Writes,
+    All,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+    // Hold requests until forwarding resumes (up to max_wait)
+    Queue,
+    // Answer 503 with Retry-After right away
+    Reject,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Paused {
+    pub scope: Scope,
+    pub mode: Mode,
+    pub retry_after: u64,
+    pub max_wait: Duration,
+}
+
+impl Paused {
+    fn applies(&self, method: &Method) -> bool {
+        self.scope == Scope::All || methods::is_write(method)
+    }
+}
+
+pub struct PauseControl {
+    state: watch::Sender<Option<Paused>>,
+    writes: Arc<InFlight>,
+}
+
+// Write requests currently being forwarded
+#[derive(Default)]
+struct InFlight {
+    count: AtomicUsize,
+    idle: Notify,
+}
+
+// Counts a write as in flight until dropped
+pub struct WriteGuard(Arc<InFlight>);
+
+impl Drop for WriteGuard {
+    fn drop(&mut self) {
+        if self.0.count.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.0.idle.notify_waiters();
+        }
+    }
+}
+
+fn unavailable(retry_after: u64) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::SERVICE_UNAVAILABLE)
+        .header("Retry-After", retry_after)
+        .header("Content-Type", "text/plain")
+        .body(Body::from("Forwarding is paused\n"))
+        .expect("response builder")
+}
+
+impl Default for PauseControl {
+    fn default() -> Self {
+        PauseControl {
+            state: watch::channel(None).0,
+            writes: Arc::default(),
+        }
+    }
+}
+
+impl PauseControl {
+    pub fn pause(&self, paused: Paused) {
+        self.state.send_replace(Some(paused));
+    }
+
+    pub fn resume(&self) {
+        self.state.send_replace(None);
+    }
+
+    pub fn track(&self, method: &Method) -> Option<WriteGuard> {
+        if !methods::is_write(method) {
+            return None;
+        }
+        self.writes.count.fetch_add(1, Ordering::SeqCst);
+        Some(WriteGuard(self.writes.clone()))
+    }
+
+    // Wait until no write is in flight; false if `limit` passed first
+    pub async fn drain(&self, limit: Duration) -> bool {
+        tokio::time::timeout(limit, async {
+            loop {
+                let idle = self.writes.idle.notified();
+                if self.writes.count.load(Ordering::SeqCst) == 0 {
+                    return;
+                }
+                idle.await;
+            }
+        })
+        .await
+        .is_ok()
+    }
+
+    pub fn current(&self) -> Option<Paused> {
+        *self.state.borrow()
+    }
+
+    // Wait out a pause that applies to this method, or return the 503 to send
+    pub async fn check(&self, method: &Method) -> Result<(), Response<Body>> {
+        let paused = match self.current() {
+            Some(paused) if paused.applies(method) => paused,
+            _ => return Ok(()),
+        };
+        if paused.mode == Mode::Reject {
+            return Err(unavailable(paused.retry_after));
+        }
+        let mut rx = self.state.subscribe();
+        let resumed = tokio::time::timeout(paused.max_wait, async {
+            rx.wait_for(|state| !state.is_some_and(|p| p.applies(method))).await.is_ok()
+        })
+        .await;
+        match resumed {
+            Ok(true) => Ok(()),
+            _ => Err(unavailable(paused.retry_after)),
+        }
+    }
+}
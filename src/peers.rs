@@ -0,0 +1,112 @@
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use hyper::header::{AUTHORIZATION, CONTENT_TYPE};
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Method, Request, Uri};
+
+use crate::ProxyConfig;
+
+// Instances behind one load balancer sharing their per-client request rate
+// (--peer): every second each one tells the others on their admin API how
+// many requests it admitted from each client address, and charges what
+// they report against its own buckets, so a client gets its --client-rate
+// across the pair rather than from each. Emulated locks need no sharing,
+// as any instance accepts the tokens another handed out. Reports go
+// straight to the peers, not through an --outbound-proxy.
+
+const INTERVAL: Duration = Duration::from_secs(1);
+
+const TIMEOUT: Duration = Duration::from_secs(2);
+
+// The admin API path the reports are sent to
+pub const PATH: &str = "/admin/peer/usage";
+
+pub struct Peers {
+    uris: Vec<Uri>,
+    token: Option<String>,
+    client: Client<HttpConnector>,
+}
+
+impl Peers {
+    // Peers by the base URL of their admin API, e.g. http://10.0.0.2:9090
+    pub fn new(specs: &[String], token: Option<String>) -> Result<Self, String> {
+        let uris = specs
+            .iter()
+            .map(|spec| {
+                let base = spec.trim().trim_end_matches('/');
+                format!("{}{}", base, PATH)
+                    .parse::<Uri>()
+                    .ok()
+                    .filter(|uri| uri.scheme_str() == Some("http") && uri.authority().is_some())
+                    .ok_or(format!("Invalid --peer (expected http://HOST:ADMIN_PORT): {}", spec))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Peers {
+            uris,
+            token,
+            client: Client::new(),
+        })
+    }
+
+    async fn send(&self, uri: &Uri, report: &str) -> Result<(), String> {
+        let mut request = Request::builder()
+            .method(Method::POST)
+            .uri(uri.clone())
+            .header(CONTENT_TYPE, "text/plain");
+        if let Some(token) = &self.token {
+            request = request.header(AUTHORIZATION, format!("Bearer {}", token));
+        }
+        let request = request.body(Body::from(report.to_string())).expect("request builder");
+        match tokio::time::timeout(TIMEOUT, self.client.request(request)).await {
+            Ok(Ok(response)) if response.status().is_success() => Ok(()),
+            Ok(Ok(response)) => Err(format!("answered {}", response.status())),
+            Ok(Err(e)) => Err(e.to_string()),
+            Err(_) => Err(format!("no answer within {} seconds", TIMEOUT.as_secs())),
+        }
+    }
+
+    pub async fn run(self, config: Arc<ProxyConfig>) {
+        let Some(limits) = config.client_limits.clone() else {
+            return;
+        };
+        // Failures are logged when they start and end, not every second
+        let mut failing = vec![false; self.uris.len()];
+        let mut interval = tokio::time::interval(INTERVAL);
+        loop {
+            interval.tick().await;
+            let admitted = limits.take_admitted();
+            if admitted.is_empty() {
+                continue;
+            }
+            let report: String = admitted.iter().map(|(ip, requests)| format!("{}\t{}\n", ip, requests)).collect();
+            for (uri, failing) in self.uris.iter().zip(failing.iter_mut()) {
+                match self.send(uri, &report).await {
+                    Ok(()) if *failing => {
+                        *failing = false;
+                        tracing::info!("Peer {} takes client usage reports again", uri);
+                    }
+                    Ok(()) => {}
+                    Err(e) if !*failing => {
+                        *failing = true;
+                        tracing::warn!("Failed to report client usage to peer {}: {}", uri, e);
+                    }
+                    Err(_) => {}
+                }
+            }
+        }
+    }
+}
+
+// A peer's report, `IP<TAB>REQUESTS` lines; None if it isn't one
+pub fn parse(report: &str) -> Option<Vec<(IpAddr, u32)>> {
+    report
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            let (ip, requests) = line.split_once('\t')?;
+            Some((ip.trim().parse().ok()?, requests.trim().parse().ok()?))
+        })
+        .collect()
+}
@@ -0,0 +1,132 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use hyper::service::Service;
+use hyper::Uri;
+
+// How far a request to the upstream got, so a timeout can tell which phase
+// took too long: resolving the host, opening the TCP connection, the TLS
+// handshake, or waiting for the response once the request was sent. The
+// connector and the resolver mark the phases they enter while the request
+// is tracked; a request on a pooled connection starts at the last one.
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Phase {
+    Dns,
+    Connect,
+    Tls,
+    FirstByte,
+}
+
+const PHASES: [Phase; 4] = [Phase::Dns, Phase::Connect, Phase::Tls, Phase::FirstByte];
+
+impl Phase {
+    pub fn name(self) -> &'static str {
+        match self {
+            Phase::Dns => "dns",
+            Phase::Connect => "connect",
+            Phase::Tls => "tls",
+            Phase::FirstByte => "first-byte",
+        }
+    }
+
+    // What comes after connecting to `uri`
+    fn after_connect(uri: &Uri) -> Phase {
+        match uri.scheme_str() {
+            Some("https") => Phase::Tls,
+            _ => Phase::FirstByte,
+        }
+    }
+}
+
+tokio::task_local! {
+    static CURRENT: Arc<AtomicU8>;
+}
+
+// The phase of one request to the upstream
+#[derive(Clone)]
+pub struct Tracker(Arc<AtomicU8>);
+
+impl Tracker {
+    pub fn new() -> Self {
+        Tracker(Arc::new(AtomicU8::new(Phase::FirstByte as u8)))
+    }
+
+    // Run `future`, recording the phases it enters
+    pub fn track<F: Future>(&self, future: F) -> impl Future<Output = F::Output> {
+        CURRENT.scope(self.0.clone(), future)
+    }
+
+    pub fn phase(&self) -> Phase {
+        PHASES[self.0.load(Ordering::Relaxed) as usize]
+    }
+}
+
+// Record that the tracked request entered `phase`; connections hyper opens
+// in the background for its pool aren't tracked
+pub fn enter(phase: Phase) {
+    let _ = CURRENT.try_with(|current| current.store(phase as u8, Ordering::Relaxed));
+}
+
+// Enter the resolving or connecting phase for `uri`
+pub fn start_connect(uri: &Uri) {
+    let literal = uri.host().is_some_and(|host| {
+        host.trim_start_matches('[')
+            .trim_end_matches(']')
+            .parse::<std::net::IpAddr>()
+            .is_ok()
+    });
+    enter(if literal { Phase::Connect } else { Phase::Dns });
+}
+
+// Marks the phases around a connector: `Phased::tcp` wraps the plain
+// connector, `Phased::tls` the TLS layer on top of it
+#[derive(Clone)]
+pub struct Phased<S> {
+    inner: S,
+    tls: bool,
+}
+
+impl<S> Phased<S> {
+    pub fn tcp(inner: S) -> Self {
+        Phased { inner, tls: false }
+    }
+
+    #[cfg(feature = "tls")]
+    pub fn tls(inner: S) -> Self {
+        Phased { inner, tls: true }
+    }
+}
+
+impl<S> Service<Uri> for Phased<S>
+where
+    S: Service<Uri>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<S::Response, S::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), S::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let next = match self.tls {
+            true => Phase::FirstByte,
+            false => {
+                start_connect(&uri);
+                Phase::after_connect(&uri)
+            }
+        };
+        let connecting = self.inner.call(uri);
+        Box::pin(async move {
+            let connection = connecting.await?;
+            enter(next);
+            Ok(connection)
+        })
+    }
+}
@@ -0,0 +1,213 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use futures::future::poll_fn;
+use hyper::client::conn::{self, SendRequest};
+use hyper::header::{HeaderMap, HeaderName, CONNECTION, WWW_AUTHENTICATE};
+use hyper::{Body, Request, Response, StatusCode, Uri};
+use tokio::sync::Mutex;
+
+use crate::resolve::Resolver;
+
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+// A single upstream connection dedicated to one downstream connection, so that
+// connection-bound auth schemes (NTLM/Negotiate) see the same socket on every leg
+pub struct PinnedConnection {
+    uri: Uri,
+    resolver: Resolver,
+    sender: Mutex<Option<SendRequest<Body>>>,
+    // The last answer was a 401 carrying an NTLM or Negotiate token, so the
+    // client's next request continues a handshake bound to this connection
+    handshake: AtomicBool,
+}
+
+// A challenge that is a leg of a handshake rather than its start
+fn continues_handshake(response: &Response<Body>) -> bool {
+    response.status() == StatusCode::UNAUTHORIZED
+        && response
+            .headers()
+            .get_all(WWW_AUTHENTICATE)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .filter_map(|v| v.trim().split_once(' '))
+            .any(|(scheme, token)| {
+                (scheme.eq_ignore_ascii_case("NTLM") || scheme.eq_ignore_ascii_case("Negotiate")) && !token.trim().is_empty()
+            })
+}
+
+// An intermediate 401 must not close the downstream connection the
+// handshake continues on
+pub fn keep_open(status: StatusCode, headers: &mut HeaderMap) {
+    if status == StatusCode::UNAUTHORIZED {
+        headers.remove(CONNECTION);
+        headers.remove(HeaderName::from_static("keep-alive"));
+    }
+}
+
+impl PinnedConnection {
+    pub fn new(upstream_uri: &Uri, resolver: Resolver) -> Self {
+        PinnedConnection {
+            uri: upstream_uri.clone(),
+            resolver,
+            sender: Mutex::new(None),
+            handshake: AtomicBool::new(false),
+        }
+    }
+
+    // Whether the next request continues a handshake; it can't be retried
+    // or answered from the fallback, which would leave the client stuck
+    // between legs
+    pub fn in_handshake(&self) -> bool {
+        self.handshake.load(Ordering::Relaxed)
+    }
+
+    async fn connect(&self) -> Result<SendRequest<Body>, BoxError> {
+        let stream = self.resolver.open(&self.uri).await?;
+        let (sender, connection) = conn::handshake(stream).await?;
+        tokio::spawn(async move {
+            // The connection ends when either side closes it; nothing to report
+            let _ = connection.await;
+        });
+        Ok(sender)
+    }
+
+    pub async fn request(&self, mut req: Request<Body>) -> Result<Response<Body>, BoxError> {
+        // A raw connection sends the URI as-is, so reduce it to origin-form
+        let path = req
+            .uri()
+            .path_and_query()
+            .map(|p| p.as_str().to_string())
+            .unwrap_or("/".to_string());
+        *req.uri_mut() = path.parse()?;
+
+        // Holding the lock serializes requests, just like a single HTTP/1.1 connection would
+        let mut guard = self.sender.lock().await;
+        if let Some(sender) = guard.as_mut() {
+            // A closed upstream connection fails here; reconnect below
+            if poll_fn(|cx| sender.poll_ready(cx)).await.is_err() {
+                *guard = None;
+            }
+        }
+        if guard.is_none() {
+            // The upstream forgot the handshake along with the connection and
+            // answers the next leg with a fresh challenge
+            if self.handshake.swap(false, Ordering::Relaxed) {
+                tracing::warn!("Upstream {} closed a connection in the middle of an auth handshake", self.uri);
+            }
+            *guard = Some(self.connect().await?);
+        }
+        let sender = guard.as_mut().expect("connected above");
+        poll_fn(|cx| sender.poll_ready(cx)).await?;
+        let response = sender.send_request(req).await;
+        self.handshake.store(
+            response.as_ref().is_ok_and(continues_handshake),
+            Ordering::Relaxed,
+        );
+        Ok(response?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+
+    const START: &str = "HTTP/1.1 401 Unauthorized\r\nWWW-Authenticate: Negotiate\r\nWWW-Authenticate: NTLM\r\nContent-Length: 0\r\n\r\n";
+    const CHALLENGE: &str = "HTTP/1.1 401 Unauthorized\r\nWWW-Authenticate: NTLM TlRMTVNTUAACAAAA\r\nContent-Length: 0\r\n\r\n";
+    const OK: &str = "HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok";
+
+    // An upstream answering each request with the next response of
+    // `script`, closing the connection after those marked so; it records
+    // which connection each request came on, with its Authorization
+    async fn upstream(script: Vec<(&'static str, bool)>) -> (Uri, Arc<std::sync::Mutex<Vec<(usize, String)>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let uri = format!("http://{}", listener.local_addr().unwrap()).parse().unwrap();
+        let seen = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let log = seen.clone();
+        tokio::spawn(async move {
+            let mut script = script.into_iter();
+            for connection in 0.. {
+                let (stream, _) = listener.accept().await.unwrap();
+                let mut stream = BufReader::new(stream);
+                loop {
+                    let mut authorization = String::new();
+                    let mut line = String::new();
+                    while stream.read_line(&mut line).await.unwrap() > 2 {
+                        if let Some(value) = line.to_ascii_lowercase().strip_prefix("authorization:") {
+                            authorization = value.trim().to_string();
+                        }
+                        line.clear();
+                    }
+                    if line.is_empty() {
+                        break;
+                    }
+                    log.lock().unwrap().push((connection, authorization));
+                    let (response, close) = script.next().expect("scripted");
+                    stream.write_all(response.as_bytes()).await.unwrap();
+                    if close {
+                        break;
+                    }
+                }
+            }
+        });
+        (uri, seen)
+    }
+
+    async fn send(pinned: &PinnedConnection, authorization: Option<&str>) -> StatusCode {
+        let mut req = Request::get("http://proxy/dav/x").body(Body::empty()).unwrap();
+        if let Some(authorization) = authorization {
+            req.headers_mut().insert("authorization", authorization.parse().unwrap());
+        }
+        let response = pinned.request(req).await.unwrap();
+        let status = response.status();
+        hyper::body::to_bytes(response.into_body()).await.unwrap();
+        status
+    }
+
+    #[tokio::test]
+    async fn follows_a_handshake_on_one_connection() {
+        let (uri, seen) = upstream(vec![(START, false), (CHALLENGE, false), (OK, false)]).await;
+        let pinned = PinnedConnection::new(&uri, Resolver::default());
+        assert!(!pinned.in_handshake());
+        // A bare challenge starts a handshake; the client's next request may
+        // go anywhere
+        assert_eq!(send(&pinned, None).await, StatusCode::UNAUTHORIZED);
+        assert!(!pinned.in_handshake());
+        // One carrying a token is a leg of it
+        assert_eq!(send(&pinned, Some("NTLM TlRMTVNTUAABAAAA")).await, StatusCode::UNAUTHORIZED);
+        assert!(pinned.in_handshake());
+        assert_eq!(send(&pinned, Some("NTLM TlRMTVNTUAADAAAA")).await, StatusCode::OK);
+        assert!(!pinned.in_handshake());
+        let seen = seen.lock().unwrap();
+        assert_eq!(seen.iter().map(|(connection, _)| *connection).collect::<Vec<_>>(), [0, 0, 0]);
+        assert_eq!(seen[2].1, "ntlm tlrmtvntuaadaaaa");
+    }
+
+    #[tokio::test]
+    async fn a_closed_connection_ends_the_handshake() {
+        let (uri, seen) = upstream(vec![(CHALLENGE, true), (START, false)]).await;
+        let pinned = PinnedConnection::new(&uri, Resolver::default());
+        assert_eq!(send(&pinned, Some("NTLM TlRMTVNTUAABAAAA")).await, StatusCode::UNAUTHORIZED);
+        assert!(pinned.in_handshake());
+        // Let the client notice the close
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        // The next leg goes to a new connection, which challenges afresh
+        assert_eq!(send(&pinned, Some("NTLM TlRMTVNTUAADAAAA")).await, StatusCode::UNAUTHORIZED);
+        assert!(!pinned.in_handshake());
+        let connections: Vec<_> = seen.lock().unwrap().iter().map(|(connection, _)| *connection).collect();
+        assert_eq!(connections, [0, 1]);
+    }
+
+    #[test]
+    fn keeps_the_connection_open_only_for_401() {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONNECTION, "close".parse().unwrap());
+        headers.insert("keep-alive", "timeout=5".parse().unwrap());
+        keep_open(StatusCode::OK, &mut headers);
+        assert_eq!(headers.len(), 2);
+        keep_open(StatusCode::UNAUTHORIZED, &mut headers);
+        assert!(headers.is_empty());
+    }
+}
@@ -0,0 +1,221 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use hyper::{Body, Method, Response, StatusCode};
+use tokio::sync::oneshot;
+
+use crate::json;
+
+// Concurrency limiter for upstream requests with two priority classes.
+// Metadata requests (PROPFIND/OPTIONS/HEAD) are woken before queued bulk
+// transfers and may use a few reserved slots, so browsing stays responsive
+// while long GET/PUT transfers hold every regular slot. With a `max_wait`,
+// a request that finds no slot in that time gets a 503 saying how busy the
+// gate is, instead of piling up behind the others.
+pub struct PriorityGate {
+    max: usize,
+    reserve: usize,
+    max_wait: Option<Duration>,
+    // Requests turned away after max_wait
+    refused: AtomicU64,
+    state: Mutex<GateState>,
+}
+
+#[derive(Default)]
+struct GateState {
+    active: usize,
+    metadata: VecDeque<oneshot::Sender<()>>,
+    bulk: VecDeque<oneshot::Sender<()>>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Class {
+    Metadata,
+    Bulk,
+}
+
+impl Class {
+    pub fn of(method: &Method) -> Class {
+        match method.as_str() {
+            "PROPFIND" | "OPTIONS" | "HEAD" => Class::Metadata,
+            _ => Class::Bulk,
+        }
+    }
+}
+
+// Held for the lifetime of an upstream exchange, including its body
+pub struct Permit {
+    gate: Arc<PriorityGate>,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.gate.release();
+    }
+}
+
+// A queued acquisition; if it is abandoned after being granted a slot, the
+// slot is handed back
+struct Pending {
+    rx: Option<oneshot::Receiver<()>>,
+    gate: Arc<PriorityGate>,
+}
+
+impl Drop for Pending {
+    fn drop(&mut self) {
+        if let Some(mut rx) = self.rx.take() {
+            rx.close();
+            if rx.try_recv().is_ok() {
+                self.gate.release();
+            }
+        }
+    }
+}
+
+// Waiters whose client is still there
+fn queued(waiters: &VecDeque<oneshot::Sender<()>>) -> usize {
+    waiters.iter().filter(|tx| !tx.is_closed()).count()
+}
+
+impl PriorityGate {
+    pub fn new(max: usize, reserve: usize, max_wait: Option<Duration>) -> Arc<Self> {
+        Arc::new(PriorityGate {
+            max,
+            reserve,
+            max_wait,
+            refused: AtomicU64::new(0),
+            state: Mutex::new(GateState::default()),
+        })
+    }
+
+    // Requests let through at once, not counting the metadata reserve
+    pub fn max(&self) -> usize {
+        self.max
+    }
+
+    fn limit(&self, class: Class) -> usize {
+        match class {
+            Class::Metadata => self.max + self.reserve,
+            Class::Bulk => self.max,
+        }
+    }
+
+    // A slot, or the 503 for a request that waited max_wait without one
+    pub async fn acquire(self: &Arc<Self>, class: Class) -> Result<Permit, Response<Body>> {
+        let rx = {
+            let mut state = self.state.lock().unwrap();
+            let queued = !state.metadata.is_empty() || (class == Class::Bulk && !state.bulk.is_empty());
+            if !queued && state.active < self.limit(class) {
+                state.active += 1;
+                return Ok(Permit { gate: self.clone() });
+            }
+            let (tx, rx) = oneshot::channel();
+            match class {
+                Class::Metadata => state.metadata.push_back(tx),
+                Class::Bulk => state.bulk.push_back(tx),
+            }
+            rx
+        };
+        let mut pending = Pending {
+            rx: Some(rx),
+            gate: self.clone(),
+        };
+        let rx = pending.rx.as_mut().expect("set above");
+        let granted = match self.max_wait {
+            Some(wait) => tokio::time::timeout(wait, rx).await.ok(),
+            None => Some(rx.await),
+        };
+        let Some(granted) = granted else {
+            // Hands back a slot granted just now
+            drop(pending);
+            return Err(self.refuse());
+        };
+        pending.rx = None;
+        debug_assert!(granted.is_ok(), "the gate never drops a queued sender");
+        Ok(Permit { gate: self.clone() })
+    }
+
+    fn refuse(&self) -> Response<Body> {
+        self.refused.fetch_add(1, Ordering::Relaxed);
+        let (active, queued) = {
+            let state = self.state.lock().unwrap();
+            (state.active, queued(&state.metadata) + queued(&state.bulk))
+        };
+        let message = format!(
+            "No upstream slot within {}s: {} in use (limit {}, plus {} for metadata), {} other requests waiting",
+            self.max_wait.unwrap_or_default().as_secs_f64(),
+            active,
+            self.max,
+            self.reserve,
+            queued
+        );
+        tracing::warn!("{}", message);
+        // About when the requests waiting now have had their turn: max_wait
+        // for every gateful of them
+        let rounds = 1 + queued / (self.max + self.reserve).max(1);
+        let retry_after = (self.max_wait.unwrap_or_default().as_secs_f64() * rounds as f64).ceil().max(1.0);
+        Response::builder()
+            .status(StatusCode::SERVICE_UNAVAILABLE)
+            .header("Retry-After", retry_after as u64)
+            .header("Content-Type", "text/plain")
+            .body(Body::from(format!("{}\n", message)))
+            .expect("response builder")
+    }
+
+    pub fn to_json(&self) -> String {
+        let state = self.state.lock().unwrap();
+        json::object(&[
+            ("max", self.max.to_string()),
+            ("metadata_reserve", self.reserve.to_string()),
+            ("active", state.active.to_string()),
+            ("queued_metadata", queued(&state.metadata).to_string()),
+            ("queued_bulk", queued(&state.bulk).to_string()),
+            ("refused", self.refused.load(Ordering::Relaxed).to_string()),
+        ])
+    }
+
+    fn release(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.active -= 1;
+        loop {
+            let next = if state.active < self.limit(Class::Metadata) && !state.metadata.is_empty() {
+                state.metadata.pop_front()
+            } else if state.active < self.limit(Class::Bulk) {
+                state.bulk.pop_front()
+            } else {
+                None
+            };
+            let tx = match next {
+                Some(tx) => tx,
+                None => break,
+            };
+            // Skip waiters whose client has already gone away
+            if tx.send(()).is_ok() {
+                state.active += 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn refusals_scale_with_the_queue() {
+        let gate = PriorityGate::new(1, 0, Some(Duration::from_millis(1500)));
+        let _held = gate.acquire(Class::Bulk).await.unwrap();
+        assert_eq!(gate.acquire(Class::Bulk).await.err().unwrap().headers()["Retry-After"], "2");
+        // Three ahead of the next one through one slot
+        let _waiting: Vec<_> = (0..3)
+            .map(|_| {
+                let (tx, rx) = oneshot::channel();
+                gate.state.lock().unwrap().bulk.push_back(tx);
+                rx
+            })
+            .collect();
+        assert_eq!(gate.refuse().headers()["Retry-After"], "6");
+    }
+}
@@ -0,0 +1,98 @@
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use hyper::header::{HeaderMap, HeaderValue, CONTENT_LENGTH, CONTENT_TYPE, RETRY_AFTER};
+use hyper::{Body, Response, StatusCode};
+
+use crate::json;
+
+// Errors the proxy generates itself, answered on API routes as RFC 7807
+// problem details instead of plain text or a multistatus fallback, so
+// scripts get a status, a reason and a request ID to quote
+
+// Marks responses relayed from the upstream, which are passed on as they are
+#[derive(Clone, Copy)]
+pub struct Forwarded;
+
+// Marks fallback answers given while the upstream is unreachable, with the
+// reason (closed, timeout or disabled) and the seconds to retry after
+#[derive(Clone)]
+pub struct Unreachable(pub String, pub u64);
+
+pub struct ApiRoutes {
+    prefixes: Vec<String>,
+}
+
+impl ApiRoutes {
+    pub fn new(prefixes: Vec<String>) -> Self {
+        ApiRoutes { prefixes }
+    }
+
+    pub fn matches(&self, path: &str) -> bool {
+        self.prefixes.iter().any(|prefix| path.starts_with(prefix.as_str()))
+    }
+}
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+// The client's X-Request-ID if it sent a sensible one, a new one otherwise
+pub fn request_id(headers: &HeaderMap) -> String {
+    let given = headers
+        .get("x-request-id")
+        .and_then(|v| v.to_str().ok())
+        .filter(|id| !id.is_empty() && id.len() <= 128 && id.bytes().all(|b| b.is_ascii_graphic()));
+    match given {
+        Some(id) => id.to_string(),
+        None => format!("{:016x}", RandomState::new().hash_one(NEXT_ID.fetch_add(1, Ordering::Relaxed))),
+    }
+}
+
+// Turn a proxy-generated error for `instance` (the request path) into
+// problem details; anything else is returned unchanged
+pub async fn convert(response: Response<Body>, request_id: &str, instance: &str) -> Response<Body> {
+    if response.extensions().get::<Forwarded>().is_some() {
+        return response;
+    }
+    let unreachable = response.extensions().get::<Unreachable>().cloned();
+    if unreachable.is_none() && response.status().as_u16() < 400 {
+        return response;
+    }
+    let (mut parts, body) = response.into_parts();
+    let text = parts
+        .headers
+        .get(CONTENT_TYPE)
+        .is_some_and(|v| v.as_bytes().starts_with(b"text/plain"));
+    let detail = match unreachable {
+        Some(Unreachable(reason, retry_after)) => {
+            parts.status = StatusCode::SERVICE_UNAVAILABLE;
+            if !parts.headers.contains_key(RETRY_AFTER) {
+                parts.headers.insert(RETRY_AFTER, retry_after.into());
+            }
+            Some(format!("Upstream {}", reason))
+        }
+        None if text => hyper::body::to_bytes(body)
+            .await
+            .ok()
+            .map(|bytes| String::from_utf8_lossy(&bytes).trim().to_string()),
+        None => None,
+    };
+    let mut fields = vec![
+        ("type", json::string("about:blank")),
+        ("title", json::string(parts.status.canonical_reason().unwrap_or("Error"))),
+        ("status", parts.status.as_u16().to_string()),
+    ];
+    if let Some(detail) = detail.filter(|d| !d.is_empty()) {
+        fields.push(("detail", json::string(&detail)));
+    }
+    fields.push(("instance", json::string(instance)));
+    fields.push(("request_id", json::string(request_id)));
+    parts.headers.remove(CONTENT_LENGTH);
+    parts
+        .headers
+        .insert(CONTENT_TYPE, HeaderValue::from_static("application/problem+json"));
+    if let Ok(id) = HeaderValue::from_str(request_id) {
+        parts.headers.insert("x-request-id", id);
+    }
+    Response::from_parts(parts, Body::from(json::object(&fields)))
+}
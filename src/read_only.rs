@@ -0,0 +1,53 @@
+use hyper::header::{HeaderMap, HeaderName, HeaderValue, ALLOW};
+use hyper::{Body, Method, Response, StatusCode};
+
+// --read-only: everything but the methods known to only read is refused
+// in the proxy, so extension methods (ACL, BIND, MKCALENDAR, CHECKIN, ...)
+// don't slip through, and OPTIONS answers say so, so clients mount the
+// share read-only instead of failing on the first save
+
+const READ_METHODS: [&str; 5] = ["OPTIONS", "GET", "HEAD", "PROPFIND", "REPORT"];
+
+const ALLOW_READ: &str = "OPTIONS, GET, HEAD, PROPFIND, REPORT";
+
+fn reads(method: &str) -> bool {
+    READ_METHODS.contains(&method)
+}
+
+pub fn check(method: &Method) -> Option<Response<Body>> {
+    if reads(method.as_str()) {
+        return None;
+    }
+    Some(
+        Response::builder()
+            .status(StatusCode::FORBIDDEN)
+            .header(ALLOW, ALLOW_READ)
+            .header("Content-Type", "text/plain")
+            .body(Body::from("The share is read-only\n"))
+            .expect("response builder"),
+    )
+}
+
+// Keep the items of a comma-separated header that pass `keep`
+fn filter_list(headers: &mut HeaderMap, name: HeaderName, keep: impl Fn(&str) -> bool) {
+    if !headers.contains_key(&name) {
+        return;
+    }
+    let items: Vec<&str> = headers
+        .get_all(&name)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(str::trim)
+        .filter(|item| !item.is_empty() && keep(item))
+        .collect();
+    if let Ok(value) = HeaderValue::from_str(&items.join(", ")) {
+        headers.insert(name, value);
+    }
+}
+
+// Keep only the read methods in Allow, and drop locking (class 2) from DAV
+pub fn filter_options(headers: &mut HeaderMap) {
+    filter_list(headers, ALLOW, |m| reads(&m.to_ascii_uppercase()));
+    filter_list(headers, HeaderName::from_static("dav"), |class| class != "2");
+}
@@ -0,0 +1,96 @@
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+use crate::cache_headers::CacheHeaders;
+use crate::header_rules::HeaderRules;
+use crate::rewrite::Rewrites;
+use crate::upstream_auth::UpstreamAuth;
+use crate::upstreams::Upstreams;
+
+// Reloading of the configuration while the proxy runs, on SIGHUP/SIGUSR1
+// or POST /admin/reload: the command line and --config file are read again
+// and the settings below replaced as a whole. Each request takes the
+// settings current when it comes in and keeps them to the end, so
+// transfers in flight finish against the old upstream with the old
+// credentials; connections stay open. A configuration that doesn't parse
+// leaves the running one alone. Everything else (listeners, TLS, client
+// authentication, limits) still needs a restart.
+//
+// Folders switched to another server on /admin/upstreams/switch stay
+// switched across reloads, until the process ends.
+
+// What a reload replaces
+pub struct Settings {
+    pub upstreams: Upstreams,
+    // Credentials sent to the upstream in place of the client's
+    pub upstream_auth: Option<Arc<UpstreamAuth>>,
+    pub rewrites: Rewrites,
+    // Cache-Control for GET answers by path
+    pub cache_headers: CacheHeaders,
+    // Edits of the headers sent to the upstream and of its answers
+    pub header_rules: HeaderRules,
+    // Longest the upstream may pause while sending a response body
+    pub idle_timeout: Option<Duration>,
+    // Longest a whole exchange may take, uploads and downloads included
+    pub transfer_timeout: Option<Duration>,
+}
+
+// Builds the settings again with the folders that were switched, as
+// (folder, remote), or says why it can't
+pub type Source = Box<dyn Fn(&[(String, String)]) -> Result<Settings, String> + Send + Sync>;
+
+pub struct Reloadable {
+    current: RwLock<Arc<Settings>>,
+    source: Option<Source>,
+    switched: Mutex<Vec<(String, String)>>,
+    // One reload at a time, so the last one read is the one kept
+    reloading: Mutex<()>,
+}
+
+impl Reloadable {
+    pub fn new(settings: Settings, source: Option<Source>) -> Self {
+        Reloadable {
+            current: RwLock::new(Arc::new(settings)),
+            source,
+            switched: Mutex::new(Vec::new()),
+            reloading: Mutex::new(()),
+        }
+    }
+
+    pub fn current(&self) -> Arc<Settings> {
+        self.current.read().unwrap().clone()
+    }
+
+    fn source(&self) -> Result<&Source, String> {
+        self.source
+            .as_ref()
+            .ok_or("This proxy wasn't started from a command line to read again".to_string())
+    }
+
+    // Read the configuration again and swap it in, with the new settings
+    pub fn reload(&self) -> Result<Arc<Settings>, String> {
+        let _reloading = self.reloading.lock().unwrap();
+        let settings = Arc::new(self.source()?(&self.switched.lock().unwrap())?);
+        *self.current.write().unwrap() = settings.clone();
+        Ok(settings)
+    }
+
+    // The settings with folder `name` served by `remote`, not yet in use
+    pub fn with_switch(&self, name: &str, remote: &str) -> Result<Settings, String> {
+        let mut switched = self.switched.lock().unwrap().clone();
+        switched.retain(|(folder, _)| folder != name);
+        switched.push((name.to_string(), remote.to_string()));
+        self.source()?(&switched)
+    }
+
+    // Put settings of `with_switch` in use
+    pub fn switch(&self, name: &str, remote: &str, settings: Settings) -> Arc<Settings> {
+        let _reloading = self.reloading.lock().unwrap();
+        let mut switched = self.switched.lock().unwrap();
+        switched.retain(|(folder, _)| folder != name);
+        switched.push((name.to_string(), remote.to_string()));
+        let settings = Arc::new(settings);
+        *self.current.write().unwrap() = settings.clone();
+        settings
+    }
+}
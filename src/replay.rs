@@ -0,0 +1,16 @@
+use hyper::Error;
+
+use crate::pinned::BoxError;
+
+// Requests that modify the share are only resent (with --replay-buffer)
+// when the upstream can't have seen any of them: the connection or TLS
+// handshake failed, or hyper gave up on the request before writing it.
+// A timeout or a reset once the request is on its way may mean it was
+// carried out.
+pub fn unsent(error: &BoxError) -> bool {
+    if let Some(error) = error.downcast_ref::<Error>() {
+        return error.is_connect() || error.is_canceled();
+    }
+    // Pinned and warm connections report failed connects as is
+    error.is::<std::io::Error>()
+}
@@ -0,0 +1,272 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use hyper::client::connect::dns::Name;
+use hyper::client::HttpConnector;
+use hyper::service::Service;
+use hyper::Uri;
+#[cfg(feature = "tls")]
+use hyper_tls::HttpsConnector;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+#[cfg(unix)]
+use tokio::net::UnixStream;
+#[cfg(feature = "tls")]
+use tokio_native_tls::{native_tls, TlsConnector, TlsStream};
+
+use crate::outbound::OutboundProxy;
+use crate::phase::{self, Phase, Phased};
+use crate::unix::{self, Sockets};
+
+// Name resolution for upstream connections, with static `host=ip` entries
+// taking precedence over the system resolver, like a hosts file that only
+// applies to the proxy; also holds the certificate checks for https://
+// upstreams, and the proxy upstream connections go through, if any
+#[derive(Clone, Default)]
+pub struct Resolver {
+    // Lowercase host names, each with the addresses to try in order
+    overrides: Arc<HashMap<String, Vec<IpAddr>>>,
+    outbound: Option<Arc<OutboundProxy>>,
+    // None checks against the system's trusted roots
+    #[cfg(feature = "tls")]
+    tls: Option<TlsConnector>,
+}
+
+#[cfg(feature = "tls")]
+pub type Connector = Phased<HttpsConnector<Phased<Sockets<HttpConnector<Resolver>>>>>;
+#[cfg(not(feature = "tls"))]
+pub type Connector = Phased<Sockets<HttpConnector<Resolver>>>;
+
+// A connection to an upstream, encrypted for https:// ones
+pub enum UpstreamStream {
+    Plain(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+    #[cfg(feature = "tls")]
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl Resolver {
+    // Parse entries like `dav.example.com=10.0.0.5`; a host may be given
+    // more than once to list several addresses
+    pub fn new(entries: &[String]) -> Result<Self, String> {
+        let mut overrides: HashMap<String, Vec<IpAddr>> = HashMap::new();
+        for entry in entries {
+            let (host, ip) = entry
+                .split_once('=')
+                .ok_or(format!("Invalid host entry {}, expected HOST=IP", entry))?;
+            let ip = ip
+                .trim()
+                .trim_start_matches('[')
+                .trim_end_matches(']')
+                .parse::<IpAddr>()
+                .map_err(|_| format!("Invalid address in host entry {}", entry))?;
+            overrides.entry(host.trim().to_ascii_lowercase()).or_default().push(ip);
+        }
+        Ok(Resolver {
+            overrides: Arc::new(overrides),
+            outbound: None,
+            #[cfg(feature = "tls")]
+            tls: None,
+        })
+    }
+
+    // Trust the certificates in the PEM files `ca_files` besides the
+    // system's, or, with `insecure`, whatever self-signed or mismatched
+    // certificate the upstream presents
+    #[cfg(feature = "tls")]
+    pub fn with_tls(mut self, ca_files: &[String], insecure: bool) -> Result<Self, String> {
+        if ca_files.is_empty() && !insecure {
+            return Ok(self);
+        }
+        let mut builder = native_tls::TlsConnector::builder();
+        for file in ca_files {
+            let pem = std::fs::read(file).map_err(|e| format!("Failed to read {}: {}", file, e))?;
+            let certificates = openssl::x509::X509::stack_from_pem(&pem)
+                .ok()
+                .filter(|certificates| !certificates.is_empty())
+                .ok_or(format!("No PEM certificates in {}", file))?;
+            for certificate in certificates {
+                let der = certificate.to_der().map_err(|e| format!("Invalid certificate in {}: {}", file, e))?;
+                let certificate = native_tls::Certificate::from_der(&der)
+                    .map_err(|e| format!("Invalid certificate in {}: {}", file, e))?;
+                builder.add_root_certificate(certificate);
+            }
+        }
+        builder.danger_accept_invalid_certs(insecure).danger_accept_invalid_hostnames(insecure);
+        let connector = builder.build().map_err(|e| format!("Failed to set up upstream TLS: {}", e))?;
+        self.tls = Some(connector.into());
+        Ok(self)
+    }
+
+    // Open upstream connections through the proxy at `spec`
+    pub fn with_outbound_proxy(mut self, spec: &str) -> Result<Self, String> {
+        self.outbound = Some(Arc::new(OutboundProxy::parse(spec)?));
+        Ok(self)
+    }
+
+    pub fn proxied(&self) -> bool {
+        self.outbound.is_some()
+    }
+
+    pub async fn lookup(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if let Some(ips) = self.overrides.get(&host.to_ascii_lowercase()) {
+            phase::enter(Phase::Connect);
+            return Ok(ips.iter().map(|ip| SocketAddr::new(*ip, port)).collect());
+        }
+        let addrs = tokio::net::lookup_host((host, port)).await?.collect();
+        phase::enter(Phase::Connect);
+        Ok(addrs)
+    }
+
+    // Connect to `host:port`, trying each address in turn
+    pub async fn connect(&self, authority: &str) -> io::Result<TcpStream> {
+        let (host, port) = authority
+            .rsplit_once(':')
+            .and_then(|(host, port)| Some((host, port.parse::<u16>().ok()?)))
+            .unwrap_or((authority, 80));
+        self.connect_to(host, port).await
+    }
+
+    // Connect to `host:port`, through the outbound proxy if there is one
+    pub async fn connect_to(&self, host: &str, port: u16) -> io::Result<TcpStream> {
+        let Some(outbound) = &self.outbound else {
+            return self.connect_direct(host, port).await;
+        };
+        let mut stream = self.connect_direct(outbound.host(), outbound.port()).await?;
+        outbound.handshake(&mut stream, host, port).await?;
+        Ok(stream)
+    }
+
+    async fn connect_direct(&self, host: &str, port: u16) -> io::Result<TcpStream> {
+        let mut last_error = io::Error::new(io::ErrorKind::NotFound, format!("{} has no addresses", host));
+        for addr in self.lookup(host, port).await? {
+            match TcpStream::connect(addr).await {
+                Ok(stream) => {
+                    stream.set_nodelay(true)?;
+                    return Ok(stream);
+                }
+                Err(e) => last_error = e,
+            }
+        }
+        Err(last_error)
+    }
+
+    // Connect to the upstream at `uri`, with a TLS handshake for https://
+    pub async fn open(&self, uri: &Uri) -> io::Result<UpstreamStream> {
+        #[cfg(unix)]
+        if let Some(path) = unix::socket_of(uri) {
+            phase::enter(Phase::Connect);
+            let stream = UnixStream::connect(path).await?;
+            phase::enter(Phase::FirstByte);
+            return Ok(UpstreamStream::Unix(stream));
+        }
+        let host = uri.host().unwrap_or_default();
+        let https = uri.scheme_str() == Some("https");
+        phase::start_connect(uri);
+        let stream = self.connect_to(host, uri.port_u16().unwrap_or(if https { 443 } else { 80 })).await?;
+        if !https {
+            phase::enter(Phase::FirstByte);
+            return Ok(UpstreamStream::Plain(stream));
+        }
+        phase::enter(Phase::Tls);
+        #[cfg(feature = "tls")]
+        {
+            let connector = match &self.tls {
+                Some(connector) => connector.clone(),
+                None => native_tls::TlsConnector::new().map_err(io::Error::other)?.into(),
+            };
+            let domain = host.trim_start_matches('[').trim_end_matches(']');
+            let stream = connector.connect(domain, stream).await.map_err(io::Error::other)?;
+            phase::enter(Phase::FirstByte);
+            Ok(UpstreamStream::Tls(Box::new(stream)))
+        }
+        #[cfg(not(feature = "tls"))]
+        Err(io::Error::other("https upstreams need the tls feature"))
+    }
+
+    // Without TCP_NODELAY, a request or response written in two segments
+    // waits out the peer's delayed ACK (40 ms on Linux)
+    pub fn connector(&self) -> Connector {
+        let mut connector = HttpConnector::new_with_resolver(self.clone());
+        connector.set_nodelay(true);
+        #[cfg(feature = "tls")]
+        {
+            connector.enforce_http(false);
+            let connector = Phased::tcp(Sockets::new(connector, self));
+            Phased::tls(match &self.tls {
+                Some(tls) => HttpsConnector::from((connector, tls.clone())),
+                None => HttpsConnector::new_with_connector(connector),
+            })
+        }
+        #[cfg(not(feature = "tls"))]
+        Phased::tcp(Sockets::new(connector, self))
+    }
+}
+
+impl AsyncRead for UpstreamStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            UpstreamStream::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(unix)]
+            UpstreamStream::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(feature = "tls")]
+            UpstreamStream::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for UpstreamStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            UpstreamStream::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(unix)]
+            UpstreamStream::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(feature = "tls")]
+            UpstreamStream::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            UpstreamStream::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(unix)]
+            UpstreamStream::Unix(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(feature = "tls")]
+            UpstreamStream::Tls(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            UpstreamStream::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(unix)]
+            UpstreamStream::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(feature = "tls")]
+            UpstreamStream::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+// Lets hyper's client resolve through the overrides
+impl Service<Name> for Resolver {
+    type Response = std::vec::IntoIter<SocketAddr>;
+    type Error = io::Error;
+    type Future = Pin<Box<dyn Future<Output = io::Result<Self::Response>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, name: Name) -> Self::Future {
+        let resolver = self.clone();
+        // The connector fills in the port
+        Box::pin(async move { Ok(resolver.lookup(name.as_str(), 0).await?.into_iter()) })
+    }
+}
@@ -0,0 +1,230 @@
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use hyper::body::Bytes;
+use hyper::header::{HeaderMap, HeaderName, HeaderValue, AUTHORIZATION, CACHE_CONTROL, COOKIE, SET_COOKIE, VARY};
+use hyper::{Body, Method, Response, StatusCode};
+
+use crate::buffering;
+use crate::memory::Cache;
+use crate::tenants::{Charge, Tenant};
+
+// Explorer sends the same PROPFIND Depth: 1 every time a folder is opened,
+// and again for every folder on the way there. Successful GETs and
+// PROPFINDs are kept for a short TTL, per method, path, Depth, request body
+// and credentials, and answered again without asking the upstream. A write
+// to a path drops what was kept for it, its members and its parent folder
+// (whose listing shows it), before it is forwarded and again once it is
+// done. The upstream's answers are kept as they came, so a hit goes
+// through the same rewriting as a fresh one. An answer with a Vary header
+// is only reused for requests with the same values of the headers it
+// names, so a gzipped or HTML variant never reaches a client that asked
+// for another one; `Vary: *` isn't kept at all. With --tenant an answer is
+// only kept while its site has room left in its share of the cache.
+
+// Largest response kept
+const MAX_BODY: usize = 1024 * 1024;
+// Largest PROPFIND body looked at for the key
+pub const MAX_REQUEST: usize = 64 * 1024;
+
+struct Stored {
+    // The client path, for invalidation
+    path: String,
+    // The request headers named by Vary, with the values this answer is for
+    vary: Vec<(HeaderName, Option<HeaderValue>)>,
+    at: Instant,
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+    // Held against the site's share of the cache
+    _charge: Option<Charge>,
+}
+
+pub struct ResponseCache {
+    ttl: Duration,
+    // The variants kept for each key
+    entries: Mutex<HashMap<u64, Vec<Stored>>>,
+    hasher: RandomState,
+}
+
+// What a request is looked up by: the hash of what every answer depends
+// on, and the request's headers for the variants that depend on more
+pub struct Key {
+    hash: u64,
+    headers: HeaderMap,
+}
+
+// The headers an answer's Vary names, or None for `Vary: *`
+fn varying(response: &HeaderMap) -> Option<Vec<HeaderName>> {
+    let mut names = Vec::new();
+    for value in response.get_all(VARY).iter().filter_map(|v| v.to_str().ok()) {
+        for name in value.split(',').map(str::trim).filter(|name| !name.is_empty()) {
+            if name == "*" {
+                return None;
+            }
+            if let Ok(name) = HeaderName::from_bytes(name.as_bytes()) {
+                names.push(name);
+            }
+        }
+    }
+    Some(names)
+}
+
+// Whether a request may be answered from the cache: GETs and PROPFINDs
+// that aren't conditional or partial, as their answers depend on more
+// than the key
+pub fn cacheable(method: &Method, headers: &HeaderMap) -> bool {
+    (method == Method::GET || method.as_str() == "PROPFIND")
+        && !["range", "if", "if-match", "if-none-match", "if-modified-since", "if-unmodified-since"]
+            .iter()
+            .any(|name| headers.contains_key(*name))
+}
+
+// The folder holding `path`, with a trailing slash
+fn parent(path: &str) -> &str {
+    let trimmed = path.trim_end_matches('/');
+    &trimmed[..trimmed.rfind('/').map_or(0, |i| i + 1)]
+}
+
+impl ResponseCache {
+    pub fn new(ttl: Duration) -> Self {
+        ResponseCache {
+            ttl,
+            entries: Mutex::new(HashMap::new()),
+            hasher: RandomState::new(),
+        }
+    }
+
+    // The key of a request to `target` (path and query); the proxy's own
+    // authentication leaves only `user` to tell clients apart
+    pub fn key(&self, method: &Method, target: &str, headers: &HeaderMap, user: Option<&str>, body: &[u8]) -> Key {
+        let mut h = self.hasher.build_hasher();
+        method.as_str().hash(&mut h);
+        target.hash(&mut h);
+        for name in ["depth", AUTHORIZATION.as_str(), COOKIE.as_str()] {
+            headers.get(name).map(|v| v.as_bytes()).hash(&mut h);
+        }
+        user.hash(&mut h);
+        body.hash(&mut h);
+        Key {
+            hash: h.finish(),
+            headers: headers.clone(),
+        }
+    }
+
+    // The kept answer for `key`, if any; with `max_age` only one younger
+    // than that, as after the upstream was down
+    pub fn get(&self, key: &Key, max_age: Option<Duration>) -> Option<Response<Body>> {
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, variants| {
+            variants.retain(|e| e.at.elapsed() < self.ttl);
+            !variants.is_empty()
+        });
+        let stored = entries.get(&key.hash)?.iter().find(|e| {
+            max_age.is_none_or(|age| e.at.elapsed() < age)
+                && e.vary
+                    .iter()
+                    .all(|(name, value)| key.headers.get(name) == value.as_ref())
+        })?;
+        let mut response = Response::new(Body::from(stored.body.clone()));
+        *response.status_mut() = stored.status;
+        *response.headers_mut() = stored.headers.clone();
+        Some(response)
+    }
+
+    // Keep a successful answer small enough, and hand it on
+    pub async fn store(
+        &self,
+        key: Key,
+        path: &str,
+        tenant: Option<&Arc<Tenant>>,
+        response: Response<Body>,
+    ) -> Result<Response<Body>, hyper::Error> {
+        let status = response.status();
+        let no_store = response
+            .headers()
+            .get_all(CACHE_CONTROL)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .any(|v| v.to_ascii_lowercase().contains("no-store"));
+        let Some(vary) = varying(response.headers()) else {
+            return Ok(response);
+        };
+        if !matches!(status.as_u16(), 200 | 207) || no_store || response.headers().contains_key(SET_COOKIE) {
+            return Ok(response);
+        }
+        let vary: Vec<_> = vary
+            .into_iter()
+            .map(|name| {
+                let value = key.headers.get(&name).cloned();
+                (name, value)
+            })
+            .collect();
+        let (parts, body) = response.into_parts();
+        let body = match buffering::read_up_to(body, MAX_BODY).await? {
+            Ok(bytes) => bytes,
+            Err(streamed) => return Ok(Response::from_parts(parts, streamed)),
+        };
+        let charge = match tenant {
+            Some(tenant) => match tenant.charge(body.len()) {
+                Some(charge) => Some(charge),
+                None => return Ok(Response::from_parts(parts, Body::from(body))),
+            },
+            None => None,
+        };
+        let mut entries = self.entries.lock().unwrap();
+        let variants = entries.entry(key.hash).or_default();
+        // A fresh answer replaces the variant it stands for
+        variants.retain(|e| e.vary != vary);
+        variants.push(Stored {
+            path: path.to_string(),
+            vary,
+            at: Instant::now(),
+            status,
+            headers: parts.headers.clone(),
+            body: body.clone(),
+            _charge: charge,
+        });
+        drop(entries);
+        Ok(Response::from_parts(parts, Body::from(body)))
+    }
+
+    // A write to `path` changed it, its members and its parent's listing
+    pub fn forget(&self, path: &str) {
+        let own = path.trim_end_matches('/');
+        let members = format!("{}/", own);
+        let parent = parent(path).trim_end_matches('/');
+        self.entries.lock().unwrap().retain(|_, variants| {
+            variants.retain(|e| {
+                let stored = e.path.trim_end_matches('/');
+                stored != own && stored != parent && !e.path.starts_with(&members)
+            });
+            !variants.is_empty()
+        });
+    }
+}
+
+impl Cache for ResponseCache {
+    fn size(&self) -> usize {
+        self.entries
+            .lock()
+            .unwrap()
+            .values()
+            .flatten()
+            .map(|e| {
+                std::mem::size_of::<(u64, Stored)>()
+                    + e.path.len()
+                    + e.body.len()
+                    + e.headers.iter().map(|(n, v)| n.as_str().len() + v.len()).sum::<usize>()
+                    + e.vary.iter().map(|(n, v)| n.as_str().len() + v.as_ref().map_or(0, |v| v.len())).sum::<usize>()
+            })
+            .sum()
+    }
+
+    fn evict(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+}
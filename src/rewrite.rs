@@ -0,0 +1,158 @@
+use hyper::header::{HeaderMap, HeaderValue};
+use hyper::Uri;
+
+use crate::mapping::{self, PathMapping};
+use crate::pattern::Pattern;
+
+// Path rewrite rules (--rewrite), applied before routing so the URLs old
+// clients have saved keep working after the upstream tree was reorganized:
+//
+//     --rewrite '/projects/(\d{4})/(.*) /archive/$1/projects/$2'
+//
+// The first rule whose pattern matches the whole (percent-encoded) path
+// wins; `$1`..`$9` in its replacement stand for the pattern's groups, `$0`
+// for the whole path and `$$` for a dollar sign. The query is kept. The
+// answer's Location and hrefs under the rewritten path are mapped back
+// under the path the client asked for.
+
+struct Rule {
+    pattern: Pattern,
+    replacement: String,
+}
+
+#[derive(Default)]
+pub struct Rewrites {
+    rules: Vec<Rule>,
+}
+
+// Fill in the groups of `replacement`
+fn expand(replacement: &str, groups: &[Option<String>]) -> String {
+    let mut out = String::with_capacity(replacement.len());
+    let mut chars = replacement.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, chars.peek().copied()) {
+            ('$', Some('$')) => {
+                chars.next();
+                out.push('$');
+            }
+            ('$', Some(d)) if d.is_ascii_digit() => {
+                chars.next();
+                let group = d.to_digit(10).expect("a digit") as usize;
+                out.push_str(groups.get(group).and_then(|g| g.as_deref()).unwrap_or(""));
+            }
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+impl Rewrites {
+    // `PATTERN REPLACEMENT`
+    pub fn add(&mut self, spec: &str) -> Result<(), String> {
+        let (pattern, replacement) = spec
+            .trim()
+            .split_once(char::is_whitespace)
+            .ok_or(format!("Invalid --rewrite (expected PATTERN REPLACEMENT): {}", spec))?;
+        let replacement = replacement.trim();
+        if !replacement.starts_with('/') {
+            return Err(format!("The --rewrite replacement must be a path: {}", spec));
+        }
+        self.rules.push(Rule {
+            pattern: Pattern::parse(pattern)?,
+            replacement: replacement.to_string(),
+        });
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    // The path to use instead of `path`, if a rule matches
+    pub fn apply(&self, path: &str) -> Option<String> {
+        self.rules.iter().find_map(|rule| {
+            let groups = rule.pattern.captures(path)?;
+            Some(expand(&rule.replacement, &groups))
+        })
+    }
+
+    // Rewrite the request's path and Destination; the mapping undoes the
+    // path's rewrite in the answer
+    pub fn apply_request(&self, uri: &mut Uri, headers: &mut HeaderMap) -> Option<PathMapping> {
+        let destination = headers.get("Destination").and_then(|v| v.to_str().ok()).map(str::to_string);
+        if let Some(destination) = destination {
+            let (origin, path) = mapping::split_url(&destination);
+            let rewritten = self.apply(path).map(|path| format!("{}{}", origin, path));
+            if let Some(value) = rewritten.and_then(|d| HeaderValue::from_str(&d).ok()) {
+                headers.insert("Destination", value);
+            }
+        }
+        let target = self.apply(uri.path())?;
+        let mapping = PathMapping::new(uri.path(), &target);
+        let target = match uri.query() {
+            Some(query) => format!("{}?{}", target, query),
+            None => target,
+        };
+        let mut parts = uri.clone().into_parts();
+        match target.parse() {
+            Ok(target) => parts.path_and_query = Some(target),
+            Err(_) => {
+                tracing::warn!("Ignoring the rewrite of {} to the invalid {}", uri.path(), target);
+                return None;
+            }
+        }
+        *uri = Uri::from_parts(parts).expect("valid URI");
+        Some(mapping)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rewrites(specs: &[&str]) -> Rewrites {
+        let mut rewrites = Rewrites::default();
+        for spec in specs {
+            rewrites.add(spec).unwrap();
+        }
+        rewrites
+    }
+
+    #[test]
+    fn fills_in_groups() {
+        let groups = [Some("/a/b".to_string()), Some("a".to_string()), None];
+        assert_eq!(expand("/x/$1/$2/$0", &groups), "/x/a///a/b");
+        assert_eq!(expand("/cost$$/$9/$", &groups), "/cost$//$");
+    }
+
+    #[test]
+    fn first_matching_rule_wins() {
+        let rewrites = rewrites(&[r"/projects/(\d{4})/(.*) /archive/$1/projects/$2", "/projects/(.*) /current/$1"]);
+        assert_eq!(rewrites.apply("/projects/2019/acme/x.txt").as_deref(), Some("/archive/2019/projects/acme/x.txt"));
+        assert_eq!(rewrites.apply("/projects/acme").as_deref(), Some("/current/acme"));
+        assert_eq!(rewrites.apply("/other/projects/acme"), None);
+        assert!(Rewrites::default().add("/old/(.*) new/$1").is_err());
+        assert!(Rewrites::default().add("/old/(.*)").is_err());
+    }
+
+    #[test]
+    fn rewrites_requests() {
+        let rewrites = rewrites(&["/old/(.*) /new/$1"]);
+        let mut uri: Uri = "/old/a%20b.txt?x=1".parse().unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert("Destination", HeaderValue::from_static("http://dav.example.com/old/c.txt"));
+        assert!(rewrites.apply_request(&mut uri, &mut headers).is_some());
+        assert_eq!(uri.to_string(), "/new/a%20b.txt?x=1");
+        assert_eq!(headers["Destination"], "http://dav.example.com/new/c.txt");
+        let mut uri: Uri = "/elsewhere".parse().unwrap();
+        assert!(rewrites.apply_request(&mut uri, &mut HeaderMap::new()).is_none());
+        assert_eq!(uri.to_string(), "/elsewhere");
+    }
+
+    #[test]
+    fn long_paths_are_fine() {
+        let rewrites = rewrites(&["/old/(.*) /new/$1"]);
+        let tail = "a".repeat(100_000);
+        assert_eq!(rewrites.apply(&format!("/old/{}", tail)), Some(format!("/new/{}", tail)));
+    }
+}
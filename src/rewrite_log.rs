@@ -0,0 +1,76 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// --log-rewrites: the URLs the base path rewriting touched in a request, as
+// `original -> rewritten` lines, to find out why a client still ends up
+// with broken links. Capped per request, since a listing can hold
+// thousands of hrefs.
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Level {
+    Off,
+    // URLs that were rewritten
+    Changed,
+    // Those left alone too, with the reason
+    All,
+}
+
+impl Level {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match s.trim() {
+            "off" => Ok(Level::Off),
+            "changed" => Ok(Level::Changed),
+            "all" => Ok(Level::All),
+            _ => Err(format!("Unknown rewrite log level (expected off, changed or all): {}", s)),
+        }
+    }
+}
+
+// The log of one request
+pub struct RewriteLog {
+    level: Level,
+    max: usize,
+    // Method and client path
+    request: String,
+    seen: AtomicUsize,
+}
+
+impl RewriteLog {
+    // None when nothing is to be logged
+    pub fn new(level: Level, max: usize, request: String) -> Option<Self> {
+        (level != Level::Off).then_some(RewriteLog {
+            level,
+            max,
+            request,
+            seen: AtomicUsize::new(0),
+        })
+    }
+
+    fn line(&self, text: String) {
+        if self.seen.fetch_add(1, Ordering::Relaxed) < self.max {
+            tracing::info!("rewrite: {}: {}", self.request, text);
+        }
+    }
+
+    // `what` is where the URL was: Destination, Location or href
+    pub fn changed(&self, what: &str, from: &str, to: &str) {
+        if from != to {
+            self.line(format!("{} {} -> {}", what, from, to));
+        } else if self.level == Level::All {
+            self.line(format!("{} {} unchanged", what, from));
+        }
+    }
+
+    pub fn kept(&self, what: &str, url: &str, why: &str) {
+        if self.level == Level::All {
+            self.line(format!("{} {} kept, {}", what, url, why));
+        }
+    }
+
+    // Say how many lines the cap held back
+    pub fn finish(&self) {
+        let seen = self.seen.load(Ordering::Relaxed);
+        if seen > self.max {
+            tracing::info!("rewrite: {}: {} more not logged", self.request, seen - self.max);
+        }
+    }
+}
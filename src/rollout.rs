@@ -0,0 +1,147 @@
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+use hyper::header::HeaderName;
+use hyper::{Body, Request, StatusCode};
+
+use crate::cache_headers::CacheHeaders;
+use crate::header_rules::HeaderRules;
+use crate::json;
+use crate::rewrite::Rewrites;
+use crate::upstreams::Upstreams;
+
+// A candidate configuration (--candidate-config) tried on some requests
+// before it replaces the running one: a share of them (--candidate-percent,
+// adjustable on /admin/rollout) and those carrying --candidate-header. The
+// candidate file may set the upstreams, rewrite rules, header rules and
+// cache headers; what it leaves out stays as configured. Requests and
+// server errors are counted per generation, so the two can be compared.
+
+// The settings a candidate replaces
+#[derive(Default)]
+pub struct Candidate {
+    pub upstreams: Option<Upstreams>,
+    pub rewrites: Option<Rewrites>,
+    pub header_rules: Option<HeaderRules>,
+    pub cache_headers: Option<CacheHeaders>,
+}
+
+// The keys a candidate file may set
+pub const KEYS: &[&str] = &["upstream-names", "rewrite", "request-header", "response-header", "cache-header"];
+
+// Which configuration a request got, kept in its extensions
+#[derive(Clone, Copy, PartialEq)]
+pub enum Generation {
+    Stable,
+    Candidate,
+}
+
+impl Generation {
+    fn name(self) -> &'static str {
+        match self {
+            Generation::Stable => "stable",
+            Generation::Candidate => "candidate",
+        }
+    }
+}
+
+#[derive(Default)]
+struct Counts {
+    requests: AtomicU64,
+    // Answers with a 5xx status, the fallback's 503 included
+    server_errors: AtomicU64,
+}
+
+impl Counts {
+    fn to_json(&self) -> String {
+        let requests = self.requests.load(Ordering::Relaxed);
+        let errors = self.server_errors.load(Ordering::Relaxed);
+        let rate = if requests == 0 { 0.0 } else { errors as f64 / requests as f64 };
+        json::object(&[
+            ("requests", requests.to_string()),
+            ("server_errors", errors.to_string()),
+            ("error_rate", format!("{:.4}", rate)),
+        ])
+    }
+}
+
+pub struct Rollout {
+    pub candidate: Candidate,
+    percent: AtomicU32,
+    header: Option<HeaderName>,
+    // Requests seen, for spreading the candidate's share evenly
+    seen: AtomicU64,
+    stable: Counts,
+    tried: Counts,
+}
+
+impl Rollout {
+    pub fn new(candidate: Candidate, percent: u32, header: Option<&str>) -> Result<Self, String> {
+        if percent > 100 {
+            return Err(format!("--candidate-percent must be between 0 and 100: {}", percent));
+        }
+        let header = header
+            .map(|name| HeaderName::from_bytes(name.as_bytes()).map_err(|_| format!("Invalid --candidate-header: {}", name)))
+            .transpose()?;
+        Ok(Rollout {
+            candidate,
+            percent: AtomicU32::new(percent),
+            header,
+            seen: AtomicU64::new(0),
+            stable: Counts::default(),
+            tried: Counts::default(),
+        })
+    }
+
+    pub fn set_percent(&self, percent: u32) -> Result<(), String> {
+        if percent > 100 {
+            return Err("percent must be between 0 and 100".to_string());
+        }
+        self.percent.store(percent, Ordering::Relaxed);
+        Ok(())
+    }
+
+    // Pick the generation for a request, dropping the test header, which
+    // isn't meant for the upstream
+    pub fn pick(&self, req: &mut Request<Body>) -> Generation {
+        let asked = self.header.as_ref().is_some_and(|header| req.headers_mut().remove(header).is_some());
+        // Request n is the candidate's when the share of the first n + 1
+        // reaches another whole request
+        let percent = self.percent.load(Ordering::Relaxed) as u64;
+        let n = self.seen.fetch_add(1, Ordering::Relaxed);
+        let share = (n + 1) * percent / 100 > n * percent / 100;
+        let generation = if asked || share { Generation::Candidate } else { Generation::Stable };
+        req.extensions_mut().insert(generation);
+        generation
+    }
+
+    pub fn record(&self, generation: Generation, status: StatusCode) {
+        let counts = match generation {
+            Generation::Stable => &self.stable,
+            Generation::Candidate => &self.tried,
+        };
+        counts.requests.fetch_add(1, Ordering::Relaxed);
+        if status.is_server_error() {
+            counts.server_errors.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        let replaced: Vec<String> = [
+            ("upstreams", self.candidate.upstreams.is_some()),
+            ("rewrites", self.candidate.rewrites.is_some()),
+            ("header_rules", self.candidate.header_rules.is_some()),
+            ("cache_headers", self.candidate.cache_headers.is_some()),
+        ]
+        .iter()
+        .filter(|(_, set)| *set)
+        .map(|(name, _)| json::string(name))
+        .collect();
+        json::object(&[
+            ("percent", self.percent.load(Ordering::Relaxed).to_string()),
+            ("header", self.header.as_ref().map_or("null".to_string(), |h| json::string(h.as_str()))),
+            ("replaces", json::array(&replaced)),
+            (Generation::Stable.name(), self.stable.to_json()),
+            (Generation::Candidate.name(), self.tried.to_json()),
+        ])
+    }
+}
@@ -0,0 +1,112 @@
+use std::sync::Mutex;
+
+use crate::atomic_file;
+use crate::json;
+
+// Path prefixes switched off at runtime through the admin API, e.g. while
+// one backend is being migrated. Kept in a state file, if there is one, so
+// they stay off across restarts.
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Disabled {
+    // Answer as if the upstream were unreachable
+    Fallback,
+    // Answer 404
+    NotFound,
+}
+
+impl Disabled {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match s.trim() {
+            "fallback" => Ok(Disabled::Fallback),
+            "404" => Ok(Disabled::NotFound),
+            _ => Err(format!("Unknown answer for a disabled route (expected fallback or 404): {}", s)),
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Disabled::Fallback => "fallback",
+            Disabled::NotFound => "404",
+        }
+    }
+}
+
+pub struct RouteSwitch {
+    disabled: Mutex<Vec<(String, Disabled)>>,
+    state_file: Option<String>,
+}
+
+impl RouteSwitch {
+    // Start with the routes disabled in `state_file`, if it exists yet
+    pub fn load(state_file: Option<String>) -> Self {
+        let mut disabled = Vec::new();
+        if let Some(contents) = state_file.as_ref().and_then(|path| std::fs::read_to_string(path).ok()) {
+            for line in contents.lines() {
+                let Some((prefix, answer)) = line.split_once('\t') else {
+                    continue;
+                };
+                if let Ok(answer) = Disabled::parse(answer) {
+                    disabled.push((prefix.to_string(), answer));
+                }
+            }
+        }
+        RouteSwitch {
+            disabled: Mutex::new(disabled),
+            state_file,
+        }
+    }
+
+    fn save(&self, disabled: &[(String, Disabled)]) -> std::io::Result<()> {
+        let Some(path) = &self.state_file else {
+            return Ok(());
+        };
+        let contents: String = disabled
+            .iter()
+            .map(|(prefix, answer)| format!("{}\t{}\n", prefix, answer.as_str()))
+            .collect();
+        atomic_file::write(path, contents)
+    }
+
+    pub fn disable(&self, prefix: &str, answer: Disabled) -> std::io::Result<()> {
+        let mut disabled = self.disabled.lock().unwrap();
+        disabled.retain(|(p, _)| p != prefix);
+        disabled.push((prefix.to_string(), answer));
+        self.save(&disabled)
+    }
+
+    // False if the route wasn't disabled
+    pub fn enable(&self, prefix: &str) -> std::io::Result<bool> {
+        let mut disabled = self.disabled.lock().unwrap();
+        let before = disabled.len();
+        disabled.retain(|(p, _)| p != prefix);
+        if disabled.len() == before {
+            return Ok(false);
+        }
+        self.save(&disabled).map(|_| true)
+    }
+
+    pub fn list(&self) -> Vec<(String, Disabled)> {
+        self.disabled.lock().unwrap().clone()
+    }
+
+    // How to answer a path under a disabled route; the longest prefix decides
+    pub fn check(&self, path: &str) -> Option<Disabled> {
+        self.disabled
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(prefix, _)| path.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, answer)| *answer)
+    }
+
+    pub fn to_json(&self) -> String {
+        let disabled = self.disabled.lock().unwrap();
+        let fields: Vec<(&str, String)> = disabled
+            .iter()
+            .map(|(prefix, answer)| (prefix.as_str(), json::string(answer.as_str())))
+            .collect();
+        json::object(&[("disabled", json::object(&fields))])
+    }
+}
@@ -0,0 +1,59 @@
+use std::process::Command;
+
+// Where a secret comes from, so it never has to appear in the process args
+pub enum SecretSource {
+    Env(String),
+    File(String),
+    Command(String),
+}
+
+impl SecretSource {
+    pub fn resolve(&self) -> Result<String, String> {
+        match self {
+            SecretSource::Env(var) => std::env::var(var).map_err(|_| format!("Environment variable {} is not set", var)),
+            SecretSource::File(path) => std::fs::read_to_string(path)
+                .map(|s| trim_newline(&s))
+                .map_err(|e| format!("{}: {}", path, e)),
+            SecretSource::Command(cmd) => {
+                let output = shell(cmd)
+                    .output()
+                    .map_err(|e| format!("Failed to run `{}`: {}", cmd, e))?;
+                if !output.status.success() {
+                    return Err(format!("`{}` exited with {}", cmd, output.status));
+                }
+                // Like `pass show`, only the first line holds the secret
+                let stdout = String::from_utf8_lossy(&output.stdout);
+                Ok(trim_newline(stdout.lines().next().unwrap_or("")))
+            }
+        }
+    }
+}
+
+fn trim_newline(s: &str) -> String {
+    s.trim_end_matches(['\r', '\n']).to_string()
+}
+
+#[cfg(unix)]
+pub fn shell(cmd: &str) -> Command {
+    let mut command = Command::new("sh");
+    command.arg("-c").arg(cmd);
+    command
+}
+
+#[cfg(windows)]
+pub fn shell(cmd: &str) -> Command {
+    let mut command = Command::new("cmd");
+    command.arg("/C").arg(cmd);
+    command
+}
+
+// Pick the single source given among the FILE/ENV/CMD variants of an option
+pub fn from_options(env: Option<String>, file: Option<String>, cmd: Option<String>) -> Result<Option<SecretSource>, String> {
+    match (env, file, cmd) {
+        (None, None, None) => Ok(None),
+        (Some(var), None, None) => Ok(Some(SecretSource::Env(var))),
+        (None, Some(path), None) => Ok(Some(SecretSource::File(path))),
+        (None, None, Some(cmd)) => Ok(Some(SecretSource::Command(cmd))),
+        _ => Err("Only one secret source (env, file or command) may be given".to_string()),
+    }
+}
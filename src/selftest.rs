@@ -0,0 +1,162 @@
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::Duration;
+
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Method, Request, StatusCode};
+#[cfg(feature = "tls")]
+use hyper_tls::HttpsConnector;
+
+use crate::fallback;
+use crate::upstream_error::UpstreamError;
+use crate::ProxyConfig;
+
+// `--self-test`: after startup, send requests through the proxy's own
+// listener to every upstream, check what clients would see if an upstream
+// were down, print a report and exit
+
+const PROPFIND_BODY: &str = r#"<?xml version="1.0" encoding="utf-8"?><propfind xmlns="DAV:"><prop><resourcetype/></prop></propfind>"#;
+
+struct Report {
+    failures: usize,
+}
+
+impl Report {
+    fn check(&mut self, ok: bool, what: &str, detail: &str) {
+        if !ok {
+            self.failures += 1;
+        }
+        println!("{} {}: {}", if ok { "ok  " } else { "FAIL" }, what, detail);
+    }
+}
+
+#[cfg(feature = "tls")]
+type TestClient = Client<HttpsConnector<HttpConnector>>;
+#[cfg(not(feature = "tls"))]
+type TestClient = Client<HttpConnector>;
+
+// The listener's certificate is whatever the configuration says, possibly
+// self-signed; the test is about the proxy, not the certificate
+#[cfg(feature = "tls")]
+fn client() -> TestClient {
+    let mut http = HttpConnector::new();
+    http.enforce_http(false);
+    let tls = native_tls::TlsConnector::builder()
+        .danger_accept_invalid_certs(true)
+        .danger_accept_invalid_hostnames(true)
+        .build()
+        .expect("TLS connector");
+    Client::builder().build(HttpsConnector::from((http, tls.into())))
+}
+
+#[cfg(not(feature = "tls"))]
+fn client() -> TestClient {
+    Client::new()
+}
+
+// A local port nothing listens on
+async fn closed_port() -> Option<u16> {
+    let listener = tokio::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.ok()?;
+    listener.local_addr().ok().map(|addr| addr.port())
+}
+
+async fn send(
+    client: &TestClient,
+    request: Request<Body>,
+) -> Result<(StatusCode, hyper::HeaderMap), String> {
+    match tokio::time::timeout(Duration::from_secs(30), client.request(request)).await {
+        Ok(Ok(response)) => Ok((response.status(), response.headers().clone())),
+        Ok(Err(e)) => Err(e.to_string()),
+        Err(_) => Err("no response within 30 seconds".to_string()),
+    }
+}
+
+// Returns whether every check passed
+pub async fn run(config: &ProxyConfig, scheme: &str, listening: SocketAddr) -> bool {
+    let mut report = Report { failures: 0 };
+    // A wildcard bind is reached over loopback
+    let host = match listening.ip() {
+        ip if ip.is_unspecified() && ip.is_ipv4() => Ipv4Addr::LOCALHOST.into(),
+        ip if ip.is_unspecified() => std::net::Ipv6Addr::LOCALHOST.into(),
+        ip => ip,
+    };
+    let base = format!("{}://{}", scheme, SocketAddr::new(host, listening.port()));
+    let client = client();
+
+    let settings = config.settings.current();
+    for (name, upstream) in settings.upstreams.iter() {
+        let path = if name.is_empty() { "/".to_string() } else { format!("/{}/", name) };
+        let authority = upstream.uri.authority().map(|a| a.to_string()).unwrap_or_default();
+        let reachable = match config.resolver.open(&upstream.uri).await {
+            Ok(_) => {
+                report.check(true, &format!("connect {}", authority), "reachable");
+                true
+            }
+            Err(e) => {
+                report.check(false, &format!("connect {}", authority), &e.to_string());
+                false
+            }
+        };
+        // Otherwise the fallback answers, which may look like success
+        let via = if reachable { "" } else { ", from the fallback" };
+
+        let options = Request::builder()
+            .method(Method::OPTIONS)
+            .uri(format!("{}{}", base, path))
+            .body(Body::empty())
+            .expect("request builder");
+        match send(&client, options).await {
+            Ok((status, headers)) => {
+                let dav = headers.get("DAV").and_then(|v| v.to_str().ok()).map(str::to_string);
+                let detail = format!("{} (DAV: {}){}", status, dav.as_deref().unwrap_or("none"), via);
+                let ok = reachable && ((status.is_success() && dav.is_some()) || status == StatusCode::UNAUTHORIZED);
+                report.check(ok, &format!("OPTIONS {}", path), &detail);
+            }
+            Err(e) => report.check(false, &format!("OPTIONS {}", path), &e),
+        }
+
+        let propfind = Request::builder()
+            .method("PROPFIND")
+            .uri(format!("{}{}", base, path))
+            .header("Depth", "0")
+            .header("Content-Type", "application/xml; charset=utf-8")
+            .body(Body::from(PROPFIND_BODY))
+            .expect("request builder");
+        match send(&client, propfind).await {
+            Ok((status, _)) => {
+                let ok = reachable && (status == StatusCode::MULTI_STATUS || status == StatusCode::UNAUTHORIZED);
+                report.check(ok, &format!("PROPFIND {}", path), &format!("{}{}", status, via));
+            }
+            Err(e) => report.check(false, &format!("PROPFIND {}", path), &e),
+        }
+
+        // What clients get once the upstream goes away
+        let fallback = config.fallback.for_path(&path, upstream.fallback);
+        match closed_port().await {
+            Some(port) => {
+                let wrong = format!("127.0.0.1:{}", port);
+                let refused = config.resolver.connect(&wrong).await.is_err();
+                let folder = fallback::Folder {
+                    name: config.fallback_names.get("closed", None),
+                    files: &config.fallback_files,
+                    method: &Method::from_bytes(b"PROPFIND").expect("method"),
+                    depth: 1,
+                    retry_after: fallback::retry_after(None),
+                };
+                let response = fallback.failed(UpstreamError::ConnectRefused, &path, &folder);
+                let status = response.status();
+                let ok = refused && (status == StatusCode::MULTI_STATUS || status == StatusCode::SERVICE_UNAVAILABLE);
+                let detail = format!("{} refused, clients get {} ({:?})", wrong, status, fallback);
+                report.check(ok, &format!("fallback {}", path), &detail);
+            }
+            None => report.check(false, &format!("fallback {}", path), "no free local port to test with"),
+        }
+    }
+
+    println!(
+        "Self-test {}: {} failure{}",
+        if report.failures == 0 { "passed" } else { "failed" },
+        report.failures,
+        if report.failures == 1 { "" } else { "s" }
+    );
+    report.failures == 0
+}
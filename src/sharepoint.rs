@@ -0,0 +1,18 @@
+use hyper::header::{HeaderMap, HeaderName, HeaderValue, HOST};
+use hyper::Uri;
+
+// Header fixups for fronting SharePoint's WebDAV endpoint
+
+pub fn apply_request_fixups(headers: &mut HeaderMap, upstream_uri: &Uri) {
+    // SharePoint validates the Host against its alternate access mappings
+    if let Some(authority) = upstream_uri.authority() {
+        if let Ok(host) = HeaderValue::from_str(authority.as_str()) {
+            headers.insert(HOST, host);
+        }
+    }
+    // Ask for a 401 challenge instead of a 302 to the forms login page
+    headers.insert(
+        HeaderName::from_static("x-forms_based_auth_accepted"),
+        HeaderValue::from_static("f"),
+    );
+}
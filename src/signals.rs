@@ -0,0 +1,99 @@
+use tokio::sync::broadcast::{self, error::RecvError};
+
+// Requests from outside the process, whatever the platform calls them:
+// SIGTERM/SIGINT and SIGHUP/SIGUSR1 on Unix; Ctrl-C, Ctrl-Break and the
+// console close, logoff and shutdown events on Windows, which is also how
+// service wrappers (NSSM, WinSW) stop a console program
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Signal {
+    // Stop accepting, let requests in flight finish, exit
+    Shutdown,
+    // Re-read the files that may change at runtime
+    Reload,
+}
+
+#[derive(Clone)]
+pub struct Signals {
+    sender: broadcast::Sender<Signal>,
+}
+
+impl Signals {
+    // Take over the platform's handlers
+    pub fn install() -> std::io::Result<Self> {
+        let (sender, _) = broadcast::channel(16);
+        listen(&sender)?;
+        Ok(Signals { sender })
+    }
+
+    async fn next(receiver: &mut broadcast::Receiver<Signal>, wanted: Signal) {
+        loop {
+            match receiver.recv().await {
+                Ok(signal) if signal == wanted => return,
+                Ok(_) | Err(RecvError::Lagged(_)) => {}
+                Err(RecvError::Closed) => std::future::pending().await,
+            }
+        }
+    }
+
+    // Resolves with the next shutdown request
+    pub async fn shutdown(&self) {
+        Self::next(&mut self.sender.subscribe(), Signal::Shutdown).await
+    }
+
+    // Run `reload` on every reload request
+    pub fn on_reload(&self, reload: impl Fn() + Send + 'static) {
+        let mut receiver = self.sender.subscribe();
+        tokio::spawn(async move {
+            loop {
+                Self::next(&mut receiver, Signal::Reload).await;
+                reload();
+            }
+        });
+    }
+}
+
+#[cfg(unix)]
+fn listen(sender: &broadcast::Sender<Signal>) -> std::io::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+    let handlers = [
+        (SignalKind::terminate(), "SIGTERM", Signal::Shutdown),
+        (SignalKind::interrupt(), "SIGINT", Signal::Shutdown),
+        (SignalKind::hangup(), "SIGHUP", Signal::Reload),
+        (SignalKind::user_defined1(), "SIGUSR1", Signal::Reload),
+    ];
+    for (kind, name, event) in handlers {
+        let mut stream = signal(kind)?;
+        let sender = sender.clone();
+        tokio::spawn(async move {
+            while stream.recv().await.is_some() {
+                tracing::info!("Received {}", name);
+                let _ = sender.send(event);
+            }
+        });
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+fn listen(sender: &broadcast::Sender<Signal>) -> std::io::Result<()> {
+    use tokio::signal::windows;
+    // Each event has its own stream type
+    macro_rules! forward {
+        ($stream:expr, $name:expr, $event:expr) => {{
+            let mut stream = $stream?;
+            let sender = sender.clone();
+            tokio::spawn(async move {
+                while stream.recv().await.is_some() {
+                    tracing::info!("Received {}", $name);
+                    let _ = sender.send($event);
+                }
+            });
+        }};
+    }
+    forward!(windows::ctrl_c(), "Ctrl-C", Signal::Shutdown);
+    forward!(windows::ctrl_close(), "console close", Signal::Shutdown);
+    forward!(windows::ctrl_logoff(), "logoff", Signal::Shutdown);
+    forward!(windows::ctrl_shutdown(), "system shutdown", Signal::Shutdown);
+    forward!(windows::ctrl_break(), "Ctrl-Break", Signal::Reload);
+    Ok(())
+}
@@ -0,0 +1,97 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use hyper::{Body, Client, Method, Request};
+
+use crate::clock::local_minute;
+use crate::errors;
+use crate::pause::{Mode, Paused, Scope};
+use crate::secrets;
+use crate::ProxyConfig;
+
+// Orchestrates upstream snapshots: since the proxy sees every mutation, it
+// can hold writes back, let in-flight ones finish, run the snapshot hook and
+// then resume
+pub struct SnapshotHook {
+    // Daily times, in minutes since local midnight
+    pub times: Vec<u32>,
+    pub command: Option<String>,
+    pub webhook: Option<String>,
+    // How long writes may be held back before clients get a 503
+    pub max_wait: Duration,
+}
+
+async fn run_command(cmd: &str) -> Result<(), String> {
+    let status = tokio::process::Command::from(secrets::shell(cmd))
+        .status()
+        .await
+        .map_err(|e| format!("Failed to run `{}`: {}", cmd, e))?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(format!("`{}` exited with {}", cmd, status))
+    }
+}
+
+async fn call_webhook(url: &str) -> Result<(), String> {
+    let req = Request::builder()
+        .method(Method::POST)
+        .uri(url)
+        .header("Content-Type", "application/json")
+        .body(Body::from(r#"{"event": "snapshot"}"#))
+        .map_err(|e| e.to_string())?;
+    let response = Client::new().request(req).await.map_err(|e| format!("{}: {}", url, e))?;
+    if response.status().is_success() {
+        Ok(())
+    } else {
+        Err(format!("{} answered {}", url, response.status()))
+    }
+}
+
+impl SnapshotHook {
+    pub async fn run(&self, config: &ProxyConfig) -> Result<(), String> {
+        tracing::info!("Snapshot: holding back writes");
+        config.pause.pause(Paused {
+            scope: Scope::Writes,
+            mode: Mode::Queue,
+            retry_after: 30,
+            max_wait: self.max_wait,
+        });
+        let result = async {
+            if !config.pause.drain(self.max_wait).await {
+                return Err("in-flight writes did not finish in time".to_string());
+            }
+            if let Some(cmd) = &self.command {
+                run_command(cmd).await?;
+            }
+            if let Some(url) = &self.webhook {
+                call_webhook(url).await?;
+            }
+            Ok(())
+        }
+        .await;
+        config.pause.resume();
+        match &result {
+            Ok(()) => tracing::info!("Snapshot: done, writes resumed"),
+            Err(e) => errors::report(format!("Snapshot failed, writes resumed: {}", e)),
+        }
+        result
+    }
+
+    pub fn spawn(self: Arc<Self>, config: Arc<ProxyConfig>) {
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(Duration::from_secs(20));
+            let mut last_run: Option<Instant> = None;
+            loop {
+                ticker.tick().await;
+                let due = self.times.contains(&local_minute());
+                // The same minute is seen by several ticks
+                let recent = last_run.is_some_and(|t| t.elapsed() < Duration::from_secs(90));
+                if due && !recent {
+                    last_run = Some(Instant::now());
+                    let _ = self.run(&config).await;
+                }
+            }
+        });
+    }
+}
@@ -0,0 +1,56 @@
+use crate::mapping::PathMapping;
+
+// A read-only view of the snapshots the upstream keeps of a share, e.g.
+// ZFS's hidden .zfs/snapshot/NAME/ folders, at /@snapshots/NAME/ in the
+// share, so users can restore files themselves. /@snapshots/ lists the
+// snapshots. The view is not part of any listing; clients go there by name.
+
+pub const FOLDER: &str = "@snapshots";
+
+// Where the upstream keeps a snapshot of the share, relative to its root:
+// the part before the snapshot's name and the part after it
+pub struct SnapshotView {
+    before: String,
+    after: String,
+}
+
+impl SnapshotView {
+    // `.zfs/snapshot` or, for layouts with more after the name,
+    // `.snapshots/{name}/snapshot`
+    pub fn parse(pattern: &str) -> Result<Self, String> {
+        let pattern = pattern.trim().trim_matches('/');
+        let pattern = if pattern.contains("{name}") { pattern.to_string() } else { format!("{}/{{name}}", pattern) };
+        let (before, after) = pattern.split_once("{name}").expect("added above");
+        if !before.is_empty() && !before.ends_with('/') || !after.is_empty() && !after.starts_with('/') {
+            return Err(format!("{{name}} must be a whole path segment in --snapshot-view: {}", pattern));
+        }
+        if after.contains("{name}") {
+            return Err(format!("--snapshot-view names the snapshot twice: {}", pattern));
+        }
+        Ok(SnapshotView {
+            before: format!("/{}", before),
+            after: after.trim_end_matches('/').to_string(),
+        })
+    }
+
+    // For a client path under `/MOUNT/@snapshots` (`mount` being empty at
+    // the root), the mapping and the upstream path to ask instead
+    pub fn map(&self, mount: &str, path: &str) -> Option<(PathMapping, String)> {
+        let mount = if mount.is_empty() { String::new() } else { format!("/{}", mount) };
+        let rest = path.strip_prefix(&mount)?.strip_prefix('/')?.strip_prefix(FOLDER)?;
+        let view = format!("{}/{}", mount, FOLDER);
+        if rest.is_empty() || rest == "/" {
+            let upstream = format!("{}{}", mount, self.before.trim_end_matches('/'));
+            let target = format!("{}/", upstream);
+            return Some((PathMapping::new(&view, &upstream), target));
+        }
+        let rest = rest.strip_prefix('/')?;
+        let (name, inner) = match rest.find('/') {
+            Some(slash) => rest.split_at(slash),
+            None => (rest, ""),
+        };
+        let upstream = format!("{}{}{}{}", mount, self.before, name, self.after);
+        let target = format!("{}{}", upstream, inner);
+        Some((PathMapping::new(&format!("{}/{}", view, name), &upstream), target))
+    }
+}
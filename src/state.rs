@@ -0,0 +1,149 @@
+use std::time::Duration;
+
+use crate::pause::{Mode, Paused, Scope};
+use crate::routes::Disabled;
+use crate::upstream_error::UpstreamError;
+use crate::ProxyConfig;
+
+// The runtime state worth carrying over to a new instance when the proxy
+// moves to another host: a pause, the routes disabled through the admin
+// API, which upstreams are taken for down or served by their mirror, the
+// guest accounts, the monthly usage of --usage-path folders and the
+// transfer statistics. Exported and imported on /admin/state, and with
+// --state-file read at startup and written on shutdown. Tab-separated
+// lines, upstreams named by their folder (`/` for a single one):
+//
+//     pause   writes  reject  30  60
+//     route   /archive/   404
+//     down    /nas/   timeout
+//     mirror  /nas/
+//     guest   guest-1a2b  SALT    HASH    1767225600  /projects/acme/
+//     usage   /public/    2026-10 120 4096    8192
+//     stats   total   -   120 4096    8192
+//
+// The response cache and the dedup records are left behind, as they only
+// hold for seconds; emulated locks need nothing, as their tokens are
+// accepted by any instance.
+
+fn folder(name: &str) -> String {
+    if name.is_empty() { "/".to_string() } else { format!("/{}/", name) }
+}
+
+pub fn export(config: &ProxyConfig) -> String {
+    let mut out = String::new();
+    if let Some(paused) = config.pause.current() {
+        out.push_str(&format!(
+            "pause\t{}\t{}\t{}\t{}\n",
+            if paused.scope == Scope::All { "all" } else { "writes" },
+            if paused.mode == Mode::Queue { "queue" } else { "reject" },
+            paused.retry_after,
+            paused.max_wait.as_secs()
+        ));
+    }
+    for (prefix, answer) in config.routes.list() {
+        out.push_str(&format!("route\t{}\t{}\n", prefix, answer.as_str()));
+    }
+    let settings = config.settings.current();
+    for (name, upstream) in settings.upstreams.iter() {
+        if let Some(error) = upstream.breaker.as_ref().and_then(|b| b.open_error()) {
+            out.push_str(&format!("down\t{}\t{}\n", folder(name), error.label()));
+        }
+        if upstream.mirror.as_ref().is_some_and(|m| m.active()) {
+            out.push_str(&format!("mirror\t{}\n", folder(name)));
+        }
+    }
+    for guest in config.guests.export() {
+        out.push_str(&format!("guest\t{}\n", guest));
+    }
+    for line in config.usage.iter().flat_map(|usage| usage.export()) {
+        out.push_str(&format!("usage\t{}\n", line));
+    }
+    for line in config.stats.serialize().lines() {
+        out.push_str(&format!("stats\t{}\n", line));
+    }
+    out
+}
+
+fn pause(fields: &[&str]) -> Option<Paused> {
+    let [scope, mode, retry_after, max_wait] = fields else {
+        return None;
+    };
+    Some(Paused {
+        scope: match *scope {
+            "writes" => Scope::Writes,
+            "all" => Scope::All,
+            _ => return None,
+        },
+        mode: match *mode {
+            "queue" => Mode::Queue,
+            "reject" => Mode::Reject,
+            _ => return None,
+        },
+        retry_after: retry_after.parse().ok()?,
+        max_wait: Duration::from_secs(max_wait.parse().ok()?),
+    })
+}
+
+// Apply exported state; the number of lines applied, and a problem for
+// each line that couldn't be
+pub fn import(config: &ProxyConfig, text: &str) -> (usize, Vec<String>) {
+    let settings = config.settings.current();
+    let mut applied = 0;
+    let mut problems = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let (kind, rest) = line.split_once('\t').unwrap_or((line, ""));
+        let fields: Vec<&str> = rest.split('\t').collect();
+        let upstream = || settings.upstreams.iter().find(|(name, _)| folder(name) == fields[0]).map(|(_, u)| u);
+        let done = match kind {
+            "pause" => match pause(&fields) {
+                Some(paused) => {
+                    config.pause.pause(paused);
+                    true
+                }
+                None => false,
+            },
+            "route" => match fields.as_slice() {
+                [prefix, answer] if prefix.starts_with('/') => match Disabled::parse(answer) {
+                    Ok(answer) => {
+                        if let Err(e) = config.routes.disable(prefix, answer) {
+                            problems.push(format!("line {}: failed to save route state: {}", number + 1, e));
+                        }
+                        true
+                    }
+                    Err(_) => false,
+                },
+                _ => false,
+            },
+            "down" => {
+                let error = fields.get(1).and_then(|label| UpstreamError::from_label(label));
+                match (upstream().and_then(|u| u.breaker.as_ref()), error) {
+                    (Some(breaker), Some(error)) => {
+                        breaker.trip(error);
+                        true
+                    }
+                    _ => false,
+                }
+            }
+            "mirror" => match upstream().and_then(|u| u.mirror.as_ref()) {
+                Some(mirror) => {
+                    mirror.answered(true, "in the imported state");
+                    true
+                }
+                None => false,
+            },
+            "guest" => config.guests.restore(&fields, |name| config.auth.as_ref().and_then(|auth| auth.has_user(name))),
+            "usage" => config.usage.as_ref().is_some_and(|usage| usage.restore(&fields)),
+            "stats" => config.stats.restore(rest),
+            _ => false,
+        };
+        if done {
+            applied += 1;
+        } else {
+            problems.push(format!("line {}: can't apply {}", number + 1, line.replace('\t', " ")));
+        }
+    }
+    (applied, problems)
+}
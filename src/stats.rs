@@ -0,0 +1,497 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures::TryStreamExt;
+use hyper::Body;
+
+use crate::atomic_file;
+use crate::clock::{self, LocalTime};
+use crate::errors;
+use crate::json;
+use crate::upstream_error::UpstreamError;
+
+// Running totals for one user, route, or the whole proxy
+#[derive(Default)]
+pub struct Counters {
+    pub requests: AtomicU64,
+    pub bytes_up: AtomicU64,
+    pub bytes_down: AtomicU64,
+}
+
+impl Counters {
+    // Requests, bytes uploaded and bytes downloaded
+    pub fn snapshot(&self) -> (u64, u64, u64) {
+        (
+            self.requests.load(Ordering::Relaxed),
+            self.bytes_up.load(Ordering::Relaxed),
+            self.bytes_down.load(Ordering::Relaxed),
+        )
+    }
+
+    pub fn set(&self, requests: u64, up: u64, down: u64) {
+        self.requests.store(requests, Ordering::Relaxed);
+        self.bytes_up.store(up, Ordering::Relaxed);
+        self.bytes_down.store(down, Ordering::Relaxed);
+    }
+
+    fn to_json(&self) -> String {
+        let (requests, up, down) = self.snapshot();
+        json::object(&[
+            ("requests", requests.to_string()),
+            ("bytes_uploaded", up.to_string()),
+            ("bytes_downloaded", down.to_string()),
+        ])
+    }
+}
+
+// Transfers the client gave up on before they finished
+#[derive(Default)]
+pub struct Aborts {
+    pub uploads: AtomicU64,
+    pub downloads: AtomicU64,
+}
+
+// Counts a download as aborted if its body is dropped before the end: the
+// last chunk, or as many bytes as its Content-Length said, as hyper drops
+// such a body once it has sent that much without polling it again. Bodies
+// that are never polled (HEAD, 304) don't count.
+//
+// Dropping it is also what stops the transfer: the upstream's body inside
+// goes with it, and hyper's client closes a connection whose body was
+// dropped before the end rather than reading the rest.
+struct WatchedBody {
+    body: Body,
+    started: bool,
+    finished: bool,
+    // The declared length, if any, and what went out of it
+    length: Option<u64>,
+    sent: u64,
+    aborts: Arc<Aborts>,
+}
+
+impl futures::Stream for WatchedBody {
+    type Item = Result<hyper::body::Bytes, hyper::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.started = true;
+        let next = Pin::new(&mut self.body).poll_next(cx);
+        match &next {
+            Poll::Ready(None) => self.finished = true,
+            Poll::Ready(Some(Ok(chunk))) => {
+                self.sent += chunk.len() as u64;
+                if self.length.is_some_and(|length| self.sent >= length) {
+                    self.finished = true;
+                }
+            }
+            _ => {}
+        }
+        next
+    }
+}
+
+impl Drop for WatchedBody {
+    fn drop(&mut self) {
+        if self.started && !self.finished {
+            self.aborts.downloads.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+type CounterMap = Mutex<BTreeMap<String, Arc<Counters>>>;
+
+// Upstream errors listed in the virtual stats file
+const RECENT_ERRORS: usize = 5;
+
+// Requests by method and failed ones since local midnight, for the virtual
+// stats file, where users can read them off to an admin
+#[derive(Default)]
+struct Today {
+    // (year, month, day) being counted
+    date: (i32, u32, u32),
+    methods: BTreeMap<String, u64>,
+    failures: u64,
+}
+
+impl Today {
+    // Start afresh on a new day
+    fn roll(&mut self, now: &LocalTime) -> &mut Self {
+        let date = (now.year, now.month, now.day);
+        if self.date != date {
+            *self = Today {
+                date,
+                ..Today::default()
+            };
+        }
+        self
+    }
+}
+
+#[derive(Default)]
+pub struct Stats {
+    global: Arc<Counters>,
+    aborts: Arc<Aborts>,
+    users: CounterMap,
+    routes: CounterMap,
+    countries: CounterMap,
+    tenants: CounterMap,
+    // Failed upstream requests by the error's label
+    upstream_errors: Mutex<BTreeMap<&'static str, u64>>,
+    // Days until the listener's TLS certificate expires, if serving TLS
+    certificate_days_left: Mutex<Option<i32>>,
+    today: Mutex<Today>,
+    // The last RECENT_ERRORS, oldest first
+    recent_errors: Mutex<VecDeque<String>>,
+}
+
+// The set of counters a single request contributes to
+#[derive(Clone)]
+pub struct Tally(Vec<Arc<Counters>>, Arc<Aborts>);
+
+impl Tally {
+    fn add(&self, field: fn(&Counters) -> &AtomicU64, n: u64) {
+        for counters in &self.0 {
+            field(counters).fetch_add(n, Ordering::Relaxed);
+        }
+    }
+
+    // Count the request against `more` counters as well
+    pub fn include(mut self, more: Vec<Arc<Counters>>) -> Self {
+        for counters in &more {
+            counters.requests.fetch_add(1, Ordering::Relaxed);
+        }
+        self.0.extend(more);
+        self
+    }
+
+    // Count request body bytes as they are streamed to the upstream; the
+    // body fails if the client goes away mid-upload
+    pub fn count_upload(&self, body: Body) -> Body {
+        let tally = self.clone();
+        let aborts = self.1.clone();
+        Body::wrap_stream(
+            body.inspect_ok(move |chunk| tally.add(|c| &c.bytes_up, chunk.len() as u64))
+                .inspect_err(move |_| {
+                    aborts.uploads.fetch_add(1, Ordering::Relaxed);
+                }),
+        )
+    }
+
+    // Count response body bytes as they are streamed to the client, and the
+    // download as aborted if the client disconnects before all of them, of
+    // `length` if known, went out
+    pub fn count_download(&self, body: Body, length: Option<u64>) -> Body {
+        let tally = self.clone();
+        let watched = WatchedBody {
+            body,
+            started: false,
+            finished: false,
+            length,
+            sent: 0,
+            aborts: self.1.clone(),
+        };
+        Body::wrap_stream(watched.inspect_ok(move |chunk| tally.add(|c| &c.bytes_down, chunk.len() as u64)))
+    }
+}
+
+fn entry(map: &CounterMap, key: &str) -> Arc<Counters> {
+    map.lock().unwrap().entry(key.to_string()).or_default().clone()
+}
+
+fn map_json(map: &CounterMap) -> String {
+    let rendered: Vec<(String, String)> = map.lock().unwrap().iter().map(|(k, c)| (k.clone(), c.to_json())).collect();
+    let fields: Vec<(&str, String)> = rendered.iter().map(|(k, v)| (k.as_str(), v.clone())).collect();
+    json::object(&fields)
+}
+
+impl Stats {
+    // Start accounting for a request against the global, route, user,
+    // country and tenant counters
+    pub fn start(
+        &self,
+        method: &str,
+        route: &str,
+        user: Option<&str>,
+        country: Option<&str>,
+        tenant: Option<&str>,
+    ) -> Tally {
+        *self.today.lock().unwrap().roll(&clock::local_time()).methods.entry(method.to_string()).or_default() += 1;
+        let mut counters = vec![self.global.clone(), entry(&self.routes, route)];
+        if let Some(user) = user {
+            counters.push(entry(&self.users, user));
+        }
+        if let Some(country) = country {
+            counters.push(entry(&self.countries, country));
+        }
+        if let Some(tenant) = tenant {
+            counters.push(entry(&self.tenants, tenant));
+        }
+        let tally = Tally(counters, self.aborts.clone());
+        tally.add(|c| &c.requests, 1);
+        tally
+    }
+
+    // A request for `path` that failed with `error`
+    pub fn count_error(&self, error: &UpstreamError, method: &str, path: &str) {
+        *self.upstream_errors.lock().unwrap().entry(error.label()).or_default() += 1;
+        let now = clock::local_time();
+        self.today.lock().unwrap().roll(&now).failures += 1;
+        let mut recent = self.recent_errors.lock().unwrap();
+        if recent.len() == RECENT_ERRORS {
+            recent.pop_front();
+        }
+        recent.push_back(format!(
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02} {} {}: {}",
+            now.year, now.month, now.day, now.hour, now.minute, now.second, method, path, error
+        ));
+    }
+
+    #[cfg(feature = "tls")]
+    pub fn set_certificate_days_left(&self, days: i32) {
+        *self.certificate_days_left.lock().unwrap() = Some(days);
+    }
+
+    // Requests, bytes uploaded and bytes downloaded since startup
+    pub fn totals(&self) -> (u64, u64, u64) {
+        self.global.snapshot()
+    }
+
+    pub fn to_json(&self) -> String {
+        let mut fields = vec![
+            ("global", self.global.to_json()),
+            (
+                "aborted",
+                json::object(&[
+                    ("uploads", self.aborts.uploads.load(Ordering::Relaxed).to_string()),
+                    ("downloads", self.aborts.downloads.load(Ordering::Relaxed).to_string()),
+                ]),
+            ),
+            ("routes", map_json(&self.routes)),
+            ("users", map_json(&self.users)),
+            ("countries", map_json(&self.countries)),
+            ("tenants", map_json(&self.tenants)),
+            ("upstream_errors", self.errors_json()),
+        ];
+        if let Some(days) = *self.certificate_days_left.lock().unwrap() {
+            fields.push(("certificate_days_left", days.to_string()));
+        }
+        json::object(&fields)
+    }
+
+    fn errors_json(&self) -> String {
+        let errors = self.upstream_errors.lock().unwrap();
+        let fields: Vec<(&str, String)> = errors.iter().map(|(label, n)| (*label, n.to_string())).collect();
+        json::object(&fields)
+    }
+
+    // Every counter as (kind, name, requests, uploaded, downloaded)
+    fn rows(&self) -> Vec<(&'static str, String, (u64, u64, u64))> {
+        let mut rows = vec![("total", "-".to_string(), self.global.snapshot())];
+        for (name, c) in self.routes.lock().unwrap().iter() {
+            rows.push(("route", name.clone(), c.snapshot()));
+        }
+        for (name, c) in self.users.lock().unwrap().iter() {
+            rows.push(("user", name.clone(), c.snapshot()));
+        }
+        for (name, c) in self.countries.lock().unwrap().iter() {
+            rows.push(("country", name.clone(), c.snapshot()));
+        }
+        for (name, c) in self.tenants.lock().unwrap().iter() {
+            rows.push(("tenant", name.clone(), c.snapshot()));
+        }
+        rows
+    }
+
+    // Human-readable form served as the virtual stats file
+    pub fn to_text(&self) -> String {
+        let mut out = format!(
+            "{:<8} {:<24} {:>10} {:>16} {:>16}\n",
+            "KIND", "NAME", "REQUESTS", "UPLOADED", "DOWNLOADED"
+        );
+        for (kind, name, (requests, up, down)) in self.rows() {
+            out.push_str(&format!("{:<8} {:<24} {:>10} {:>16} {:>16}\n", kind, name, requests, up, down));
+        }
+        out.push_str(&format!(
+            "\nAborted by the client: {} uploads, {} downloads\n",
+            self.aborts.uploads.load(Ordering::Relaxed),
+            self.aborts.downloads.load(Ordering::Relaxed)
+        ));
+        let errors = self.upstream_errors.lock().unwrap();
+        if !errors.is_empty() {
+            let counts: Vec<String> = errors.iter().map(|(label, n)| format!("{} {}", n, label)).collect();
+            out.push_str(&format!("Upstream errors: {}\n", counts.join(", ")));
+        }
+        if let Some(days) = *self.certificate_days_left.lock().unwrap() {
+            out.push_str(&format!("TLS certificate expires in {} days\n", days));
+        }
+        let mut today = self.today.lock().unwrap();
+        let today = today.roll(&clock::local_time());
+        let requests: u64 = today.methods.values().sum();
+        out.push_str(&format!("\nToday: {} requests, {} failed\n", requests, today.failures));
+        if !today.methods.is_empty() {
+            let methods: Vec<String> = today.methods.iter().map(|(method, n)| format!("{} {}", n, method)).collect();
+            out.push_str(&format!("By method: {}\n", methods.join(", ")));
+        }
+        let recent = self.recent_errors.lock().unwrap();
+        if !recent.is_empty() {
+            out.push_str("\nLast errors:\n");
+            for error in recent.iter().rev() {
+                out.push_str(&format!("  {}\n", error));
+            }
+        }
+        out
+    }
+
+    // Tab-separated `kind name requests up down` lines
+    pub fn serialize(&self) -> String {
+        self.rows()
+            .into_iter()
+            .map(|(kind, name, (requests, up, down))| format!("{}\t{}\t{}\t{}\t{}\n", kind, name, requests, up, down))
+            .collect()
+    }
+
+    pub fn load(&self, path: &str) {
+        // Nothing persisted yet otherwise
+        if let Ok(contents) = std::fs::read_to_string(path) {
+            for line in contents.lines() {
+                self.restore(line);
+            }
+        }
+    }
+
+    // Set the counters of one `serialize` line; false if it isn't one
+    pub fn restore(&self, line: &str) -> bool {
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() != 5 {
+            return false;
+        }
+        let numbers: Vec<u64> = fields[2..].iter().filter_map(|n| n.parse().ok()).collect();
+        if numbers.len() != 3 {
+            return false;
+        }
+        let counters = match fields[0] {
+            "total" => self.global.clone(),
+            "route" => entry(&self.routes, fields[1]),
+            "user" => entry(&self.users, fields[1]),
+            "country" => entry(&self.countries, fields[1]),
+            "tenant" => entry(&self.tenants, fields[1]),
+            _ => return false,
+        };
+        counters.set(numbers[0], numbers[1], numbers[2]);
+        true
+    }
+
+    pub fn save(&self, path: &str) -> std::io::Result<()> {
+        atomic_file::write(path, self.serialize())
+    }
+
+    pub fn spawn_persister(self: Arc<Self>, path: String, interval: Duration) {
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                if let Err(e) = self.save(&path) {
+                    errors::report(format!("Failed to save statistics to {}: {}", path, e));
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+    use hyper::body::Bytes;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    fn aborted(stats: &Stats) -> u64 {
+        stats.aborts.downloads.load(Ordering::Relaxed)
+    }
+
+    fn chunks(n: usize) -> Body {
+        Body::wrap_stream(futures::stream::iter((0..n).map(|_| Ok::<_, hyper::Error>(Bytes::from_static(b"0123456789")))))
+    }
+
+    #[tokio::test]
+    async fn complete_by_length_is_not_aborted() {
+        let stats = Stats::default();
+        let tally = stats.start("GET", "/", None, None, None);
+        let mut body = tally.count_download(chunks(3), Some(30));
+        for _ in 0..3 {
+            body.next().await.unwrap().unwrap();
+        }
+        // As hyper does once Content-Length bytes are out
+        drop(body);
+        assert_eq!(aborted(&stats), 0);
+        assert_eq!(stats.totals().2, 30);
+    }
+
+    #[tokio::test]
+    async fn complete_by_end_is_not_aborted() {
+        let stats = Stats::default();
+        let tally = stats.start("GET", "/", None, None, None);
+        let mut body = tally.count_download(chunks(2), None);
+        while body.next().await.is_some() {}
+        drop(body);
+        assert_eq!(aborted(&stats), 0);
+    }
+
+    #[tokio::test]
+    async fn dropped_midway_is_aborted() {
+        let stats = Stats::default();
+        let tally = stats.start("GET", "/", None, None, None);
+        let mut body = tally.count_download(chunks(3), Some(30));
+        body.next().await.unwrap().unwrap();
+        drop(body);
+        assert_eq!(aborted(&stats), 1);
+    }
+
+    #[tokio::test]
+    async fn never_polled_is_not_aborted() {
+        let stats = Stats::default();
+        let tally = stats.start("HEAD", "/", None, None, None);
+        drop(tally.count_download(Body::empty(), Some(30)));
+        assert_eq!(aborted(&stats), 0);
+    }
+
+    // The upstream stops sending once the client's end of the download is
+    // dropped
+    #[tokio::test]
+    async fn dropping_the_download_closes_the_upstream_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let upstream = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut head = [0; 1024];
+            let _ = socket.read(&mut head).await.unwrap();
+            socket
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 1073741824\r\n\r\n")
+                .await
+                .unwrap();
+            let chunk = vec![0; 64 * 1024];
+            let mut sent = 0;
+            while socket.write_all(&chunk).await.is_ok() {
+                sent += chunk.len();
+            }
+            sent
+        });
+        let stats = Stats::default();
+        let tally = stats.start("GET", "/", None, None, None);
+        let response = hyper::Client::new()
+            .get(format!("http://{}/", address).parse().unwrap())
+            .await
+            .unwrap();
+        let mut body = tally.count_download(response.into_body(), Some(1 << 30));
+        body.next().await.unwrap().unwrap();
+        drop(body);
+        let sent = tokio::time::timeout(Duration::from_secs(10), upstream).await.unwrap().unwrap();
+        assert!(sent < 1 << 30);
+        assert_eq!(aborted(&stats), 1);
+    }
+}
@@ -0,0 +1,193 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Instant, SystemTime};
+
+use futures::future::join_all;
+use hyper::header::{ACCEPT, CACHE_CONTROL, CONTENT_TYPE};
+use hyper::{Body, Method, Request, Response, StatusCode};
+
+use crate::dates;
+use crate::json;
+use crate::upstreams::Upstream;
+use crate::ProxyConfig;
+
+// Reserved paths the proxy answers itself (--status-path), never passing
+// them on, for load balancers and uptime monitors:
+//
+//     PREFIX/health   `ok` or `unavailable`
+//     PREFIX/status   uptime, request counters and each upstream's state,
+//                     as JSON for clients that accept it
+//
+// Both answer 200 while every upstream (or its mirror) accepts connections
+// and 503 otherwise. They come before authentication and client limits.
+
+#[derive(Default)]
+struct Outcome {
+    last_success: Option<SystemTime>,
+    last_error: Option<(SystemTime, String)>,
+}
+
+pub struct StatusPage {
+    prefix: String,
+    started: Instant,
+    // By upstream index, once requests went there
+    outcomes: Mutex<HashMap<usize, Outcome>>,
+}
+
+// What a connection attempt just now found
+struct Check {
+    name: String,
+    uri: String,
+    reachable: bool,
+    on_mirror: bool,
+    error: Option<String>,
+}
+
+async fn check(config: &ProxyConfig, name: &str, upstream: &Upstream) -> Check {
+    let connect = |uri| async move {
+        match tokio::time::timeout(upstream.timeout, config.resolver.open(uri)).await {
+            Ok(Ok(_)) => Ok(()),
+            Ok(Err(e)) => Err(e.to_string()),
+            Err(_) => Err(format!("no connection within {} seconds", upstream.timeout.as_secs_f64())),
+        }
+    };
+    let mut result = connect(&upstream.uri).await;
+    let mut on_mirror = false;
+    if let (Err(_), Some(mirror)) = (&result, &upstream.mirror) {
+        if connect(&mirror.uri).await.is_ok() {
+            on_mirror = true;
+            result = Ok(());
+        }
+    }
+    Check {
+        name: name.to_string(),
+        uri: upstream.uri.to_string(),
+        reachable: result.is_ok(),
+        on_mirror,
+        error: result.err(),
+    }
+}
+
+fn uptime(secs: u64) -> String {
+    format!("{}d {:02}:{:02}:{:02}", secs / 86400, secs / 3600 % 24, secs / 60 % 60, secs % 60)
+}
+
+fn respond(status: StatusCode, content_type: &str, body: String) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, content_type)
+        .header(CACHE_CONTROL, "no-store")
+        .body(Body::from(body))
+        .expect("response builder")
+}
+
+impl StatusPage {
+    pub fn new(prefix: &str) -> Result<Self, String> {
+        let prefix = prefix.trim().trim_end_matches('/');
+        if !prefix.starts_with('/') {
+            return Err(format!("--status-path must be a path like /_proxy: {}", prefix));
+        }
+        Ok(StatusPage {
+            prefix: prefix.to_string(),
+            started: Instant::now(),
+            outcomes: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn succeeded(&self, index: usize) {
+        self.outcomes.lock().unwrap().entry(index).or_default().last_success = Some(SystemTime::now());
+    }
+
+    pub fn failed(&self, index: usize, error: String) {
+        self.outcomes.lock().unwrap().entry(index).or_default().last_error = Some((SystemTime::now(), error));
+    }
+
+    // The answer to a request for one of the reserved paths
+    pub async fn answer(&self, req: &Request<Body>, config: &ProxyConfig) -> Option<Response<Body>> {
+        let page = req.uri().path().strip_prefix(&self.prefix)?;
+        if !page.is_empty() && !page.starts_with('/') {
+            return None;
+        }
+        if req.method() != Method::GET && req.method() != Method::HEAD {
+            return Some(respond(StatusCode::METHOD_NOT_ALLOWED, "text/plain", "Method not allowed\n".to_string()));
+        }
+        let settings = config.settings.current();
+        let checks = join_all(settings.upstreams.iter().map(|(name, upstream)| check(config, name, upstream))).await;
+        let healthy = checks.iter().all(|check| check.reachable);
+        let status = if healthy { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+        let json = req
+            .headers()
+            .get(ACCEPT)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|v| v.contains("application/json"));
+        Some(match page {
+            "/health" => respond(status, "text/plain", if healthy { "ok\n" } else { "unavailable\n" }.to_string()),
+            "/status" if json => respond(status, "application/json", self.to_json(config, &checks, healthy)),
+            "/status" => respond(status, "text/plain; charset=utf-8", self.to_text(config, &checks, healthy)),
+            _ => respond(StatusCode::NOT_FOUND, "text/plain", "Not found\n".to_string()),
+        })
+    }
+
+    fn to_text(&self, config: &ProxyConfig, checks: &[Check], healthy: bool) -> String {
+        let (requests, up, down) = config.stats.totals();
+        let mut out = format!(
+            "Status: {}\nUptime: {}\nRequests: {}\nUploaded: {} bytes\nDownloaded: {} bytes\n",
+            if healthy { "ok" } else { "unavailable" },
+            uptime(self.started.elapsed().as_secs()),
+            requests,
+            up,
+            down
+        );
+        let outcomes = self.outcomes.lock().unwrap();
+        for (index, check) in checks.iter().enumerate() {
+            let name = if check.name.is_empty() { "/".to_string() } else { format!("/{}/", check.name) };
+            let state = match (&check.error, check.on_mirror) {
+                (_, true) => "reachable through its mirror".to_string(),
+                (None, _) => "reachable".to_string(),
+                (Some(e), _) => format!("unreachable ({})", e),
+            };
+            out.push_str(&format!("\nUpstream {} at {}: {}\n", name, check.uri, state));
+            if let Some(outcome) = outcomes.get(&index) {
+                if let Some(time) = outcome.last_success {
+                    out.push_str(&format!("  Last answer: {}\n", dates::http_date(time)));
+                }
+                if let Some((time, error)) = &outcome.last_error {
+                    out.push_str(&format!("  Last error: {}: {}\n", dates::http_date(*time), error));
+                }
+            }
+        }
+        out
+    }
+
+    fn to_json(&self, config: &ProxyConfig, checks: &[Check], healthy: bool) -> String {
+        let (requests, up, down) = config.stats.totals();
+        let outcomes = self.outcomes.lock().unwrap();
+        let date = |time: Option<SystemTime>| time.map_or("null".to_string(), |t| json::string(&dates::http_date(t)));
+        let upstreams: Vec<String> = checks
+            .iter()
+            .enumerate()
+            .map(|(index, check)| {
+                let outcome = outcomes.get(&index);
+                let last_error = outcome.and_then(|o| o.last_error.as_ref());
+                json::object(&[
+                    ("name", json::string(&check.name)),
+                    ("uri", json::string(&check.uri)),
+                    ("reachable", check.reachable.to_string()),
+                    ("on_mirror", check.on_mirror.to_string()),
+                    ("error", check.error.as_deref().map_or("null".to_string(), json::string)),
+                    ("last_success", date(outcome.and_then(|o| o.last_success))),
+                    ("last_error", last_error.map_or("null".to_string(), |(_, e)| json::string(e))),
+                    ("last_error_at", date(last_error.map(|(t, _)| *t))),
+                ])
+            })
+            .collect();
+        json::object(&[
+            ("healthy", healthy.to_string()),
+            ("uptime_seconds", self.started.elapsed().as_secs().to_string()),
+            ("requests", requests.to_string()),
+            ("bytes_uploaded", up.to_string()),
+            ("bytes_downloaded", down.to_string()),
+            ("upstreams", json::array(&upstreams)),
+        ])
+    }
+}
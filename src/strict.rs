@@ -0,0 +1,181 @@
+use hyper::header::{HeaderMap, CONTENT_TYPE};
+use hyper::{Body, Method, Response, StatusCode, Uri};
+
+use crate::xml;
+
+// Validation of WebDAV requests against RFC 4918 before they are sent on
+// (--strict), for upstreams that misbehave on malformed ones instead of
+// refusing them. Each rule can be turned on by itself:
+//
+// - depth: a Depth header is 0, 1 or infinity, and one the method allows
+// - headers: COPY and MOVE have a Destination URI, Overwrite is T or F,
+//   UNLOCK has a Lock-Token, Timeout and If are in their syntax and a LOCK
+//   refresh has an If
+// - xml: PROPFIND, PROPPATCH and LOCK bodies are well-formed UTF-8 XML
+//   with the root element of their method
+//
+// A request that breaks one is answered 400 with what is wrong.
+
+// Bodies larger than this are sent on unchecked
+pub const BODY_LIMIT: usize = 1024 * 1024;
+
+#[derive(Clone, Copy, Default)]
+pub struct Strict {
+    depth: bool,
+    headers: bool,
+    xml: bool,
+}
+
+impl Strict {
+    // Comma-separated rules, `all` for every one and `-RULE` to leave one out
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let mut strict = Strict::default();
+        for rule in spec.split(',').map(str::trim).filter(|rule| !rule.is_empty()) {
+            let (rule, on) = match rule.strip_prefix('-') {
+                Some(rule) => (rule, false),
+                None => (rule, true),
+            };
+            match rule {
+                "depth" => strict.depth = on,
+                "headers" => strict.headers = on,
+                "xml" => strict.xml = on,
+                "all" => strict = Strict { depth: on, headers: on, xml: on },
+                _ => {
+                    return Err(format!(
+                        "Invalid --strict rule (expected depth, headers, xml or all): {}",
+                        rule
+                    ))
+                }
+            }
+        }
+        Ok(strict)
+    }
+
+    // What is wrong with the headers of a request, if anything
+    pub fn check(&self, method: &Method, headers: &HeaderMap) -> Option<String> {
+        let method = method.as_str();
+        if self.depth {
+            if let Some(problem) = depth(method, headers) {
+                return Some(problem);
+            }
+        }
+        if self.headers {
+            return check_headers(method, headers).err();
+        }
+        None
+    }
+
+    // Whether the body of a request with `method` is to be checked
+    pub fn wants_body(&self, method: &Method) -> bool {
+        self.xml && matches!(method.as_str(), "PROPFIND" | "PROPPATCH" | "LOCK")
+    }
+
+    // What is wrong with the body of a request `wants_body` took, if anything
+    pub fn check_body(&self, method: &Method, headers: &HeaderMap, body: &[u8]) -> Option<String> {
+        let root = match method.as_str() {
+            "PROPFIND" => "propfind",
+            "PROPPATCH" => "propertyupdate",
+            "LOCK" => "lockinfo",
+            _ => return None,
+        };
+        if body.iter().all(u8::is_ascii_whitespace) {
+            return match method.as_str() {
+                "PROPPATCH" => Some("PROPPATCH needs a propertyupdate body".to_string()),
+                // Without a body a LOCK refreshes the lock named in If
+                "LOCK" if !headers.contains_key("if") => Some("LOCK without a body needs an If header".to_string()),
+                _ => None,
+            };
+        }
+        let text = match std::str::from_utf8(body) {
+            Ok(text) => text,
+            Err(e) => return Some(format!("The body isn't UTF-8 (byte {})", e.valid_up_to())),
+        };
+        match xml::root_element(text) {
+            Ok((namespace, name)) if namespace == "DAV:" && name == root => None,
+            Ok((namespace, name)) => Some(format!(
+                "The root element of a {} body is {{DAV:}}{}, not {{{}}}{}",
+                method, root, namespace, name
+            )),
+            Err(e) => Some(format!("The body isn't well-formed XML: {}", e)),
+        }
+    }
+}
+
+fn header<'a>(headers: &'a HeaderMap, name: &str) -> Result<Option<&'a str>, String> {
+    match headers.get(name) {
+        Some(value) => value
+            .to_str()
+            .map(|value| Some(value.trim()))
+            .map_err(|_| format!("{} isn't ASCII", name)),
+        None => Ok(None),
+    }
+}
+
+fn depth(method: &str, headers: &HeaderMap) -> Option<String> {
+    let value = match header(headers, "depth") {
+        Ok(Some(value)) => value,
+        Ok(None) => return None,
+        Err(e) => return Some(e),
+    };
+    let depth = value.to_ascii_lowercase();
+    if !matches!(depth.as_str(), "0" | "1" | "infinity") {
+        return Some(format!("Depth is 0, 1 or infinity, not {}", value));
+    }
+    let allowed: &[&str] = match method {
+        "PROPFIND" => &["0", "1", "infinity"],
+        "COPY" | "LOCK" => &["0", "infinity"],
+        "MOVE" | "DELETE" => &["infinity"],
+        _ => return None,
+    };
+    (!allowed.contains(&depth.as_str())).then(|| format!("{} takes Depth {}, not {}", method, allowed.join(" or "), value))
+}
+
+fn check_headers(method: &str, headers: &HeaderMap) -> Result<(), String> {
+    if matches!(method, "COPY" | "MOVE") {
+        let destination = header(headers, "destination")?.ok_or(format!("{} needs a Destination", method))?;
+        let uri = destination
+            .parse::<Uri>()
+            .map_err(|_| format!("Destination isn't a URI: {}", destination))?;
+        if uri.scheme().is_none() && !destination.starts_with('/') {
+            return Err(format!("Destination is an absolute URI or path, not {}", destination));
+        }
+    }
+    if let Some(overwrite) = header(headers, "overwrite")? {
+        if overwrite != "T" && overwrite != "F" {
+            return Err(format!("Overwrite is T or F, not {}", overwrite));
+        }
+    }
+    if method == "UNLOCK" {
+        let token = header(headers, "lock-token")?.ok_or("UNLOCK needs a Lock-Token")?;
+        if !(token.len() > 2 && token.starts_with('<') && token.ends_with('>')) {
+            return Err(format!("Lock-Token is a <coded-URL>, not {}", token));
+        }
+    }
+    if let Some(timeout) = header(headers, "timeout")? {
+        for choice in timeout.split(',').map(str::trim) {
+            let seconds = choice.strip_prefix("Second-").map(|n| n.parse::<u64>().is_ok());
+            if choice != "Infinite" && seconds != Some(true) {
+                return Err(format!("Timeout is Infinite or Second-N, not {}", choice));
+            }
+        }
+    }
+    if let Some(condition) = header(headers, "if")? {
+        if !condition.starts_with('(') && !condition.starts_with('<') {
+            return Err(format!("If is a list of (conditions), maybe after a <resource>, not {}", condition));
+        }
+        if condition.matches('(').count() != condition.matches(')').count()
+            || condition.matches('<').count() != condition.matches('>').count()
+        {
+            return Err(format!("If has unbalanced brackets: {}", condition));
+        }
+    }
+    Ok(())
+}
+
+pub fn rejection(problem: &str) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::BAD_REQUEST)
+        .header(CONTENT_TYPE, "text/plain; charset=utf-8")
+        .body(Body::from(format!("{}\n", problem)))
+        .expect("response builder")
+}
@@ -0,0 +1,72 @@
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use crate::json;
+use crate::upstreams::{self, Upstream};
+use crate::ProxyConfig;
+
+// Blue/green migration of one upstream folder (POST /admin/upstreams/switch):
+// from one request to the next the folder is served by another server,
+// with the folder's timeouts, retries, fallback and credentials. The new
+// server may first have to answer OPTIONS with a success. Requests in
+// flight finish on the old server; with a drain period the answer waits
+// until the writes among them are done, so a last sync from the old server
+// can start right after. The switch is kept across reloads but not
+// restarts, so the configuration file should follow. With pinned
+// connections, which stay with the server they were opened to, there is no
+// switching.
+
+// How often the old server's writes are counted while draining
+const DRAIN_POLL: Duration = Duration::from_millis(100);
+
+fn address(upstream: &Upstream) -> String {
+    upstreams::root_uri(&upstream.uri, &upstream.base_path).to_string()
+}
+
+// Serve folder `name` (empty for a single upstream) from `remote`; what was
+// done, as JSON
+pub async fn run(
+    config: &ProxyConfig,
+    name: &str,
+    remote: &str,
+    check: bool,
+    drain: Option<Duration>,
+) -> Result<String, String> {
+    // Pinned connections keep going to the server they were opened to
+    if config.pin_connections {
+        return Err("Upstreams can't be switched with pinned connections (--pin-connections, --sharepoint, --negotiate passthrough)".to_string());
+    }
+    let old = config.settings.current();
+    let (_, from) = old
+        .upstreams
+        .iter()
+        .find(|(folder, _)| *folder == name)
+        .ok_or(format!("No upstream is served at /{}", name))?;
+    let settings = config.settings.with_switch(name, remote)?;
+    let (_, to) = settings.upstreams.iter().find(|(folder, _)| *folder == name).expect("switched folder");
+    if check && !to.capabilities.refresh(&config.client, settings.upstream_auth.as_deref()).await {
+        return Err(format!("{} didn't answer OPTIONS with a success, so /{} stays on {}", address(to), name, address(from)));
+    }
+    let to = address(to);
+    let settings = config.settings.switch(name, remote, settings);
+    let switched = settings.upstreams.iter().filter(|(folder, _)| *folder == name);
+    crate::probe(config, switched, &settings);
+    tracing::info!("Switched /{} from {} to {}", name, address(from), to);
+
+    let deadline = Instant::now() + drain.unwrap_or_default();
+    while from.writes.load(Ordering::Relaxed) > 0 && Instant::now() < deadline {
+        tokio::time::sleep(DRAIN_POLL).await;
+    }
+    let writes = from.writes.load(Ordering::Relaxed);
+    if writes > 0 && drain.is_some() {
+        tracing::warn!("{} write(s) to {} still under way after the switch", writes, address(from));
+    }
+    Ok(json::object(&[
+        ("folder", json::string(name)),
+        ("from", json::string(&address(from))),
+        ("to", json::string(&to)),
+        ("writes_left", writes.to_string()),
+    ]))
+}
@@ -0,0 +1,185 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use hyper::header::{HeaderMap, HOST};
+use hyper::{Body, Response, StatusCode};
+
+use crate::throttle::{self, Limiter, Limiters};
+
+// Sites served to different customers by one proxy, told apart by the Host
+// header (--tenant), each with its own share of the proxy: at most so many
+// requests at a time, a bandwidth per direction and room in the response
+// cache, so one customer's sync can't starve the others. `*` stands for
+// every host not named. Each tenant's traffic is counted apart in the
+// statistics.
+pub struct Tenant {
+    name: String,
+    max_concurrent: Option<usize>,
+    active: AtomicUsize,
+    limits: Limiters,
+    // Bytes of response cache it may hold, and holds
+    cache: Option<usize>,
+    cached: AtomicUsize,
+}
+
+// A request counted against its tenant's concurrency until dropped
+pub struct Slot(Arc<Tenant>);
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// Response cache held by a tenant, given back when the entry goes
+pub struct Charge {
+    tenant: Arc<Tenant>,
+    bytes: usize,
+}
+
+impl Drop for Charge {
+    fn drop(&mut self) {
+        self.tenant.cached.fetch_sub(self.bytes, Ordering::Relaxed);
+    }
+}
+
+impl Tenant {
+    // `max-concurrent:N,bandwidth:RATE,cache:SIZE`, any of them
+    fn parse(name: &str, spec: &str) -> Result<Self, String> {
+        let mut tenant = Tenant {
+            name: name.to_string(),
+            max_concurrent: None,
+            active: AtomicUsize::new(0),
+            limits: Limiters::default(),
+            cache: None,
+            cached: AtomicUsize::new(0),
+        };
+        for part in spec.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            let invalid = || format!("Invalid --tenant limit for {}: {}", name, part);
+            let (key, value) = part.split_once(':').ok_or_else(invalid)?;
+            let value = value.trim();
+            match key.trim() {
+                "max-concurrent" => {
+                    tenant.max_concurrent = Some(value.parse().ok().filter(|n| *n > 0).ok_or_else(invalid)?)
+                }
+                "bandwidth" => {
+                    let rate = throttle::parse_rate(value).map_err(|_| invalid())?;
+                    tenant.limits = Limiters {
+                        upload: Some(Arc::new(Limiter::new(rate))),
+                        download: Some(Arc::new(Limiter::new(rate))),
+                    };
+                }
+                "cache" => tenant.cache = Some(throttle::parse_rate(value).map_err(|_| invalid())? as usize),
+                _ => {
+                    return Err(format!(
+                        "Unknown --tenant limit (expected max-concurrent, bandwidth or cache): {}",
+                        key
+                    ))
+                }
+            }
+        }
+        Ok(tenant)
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn limits(&self) -> &Limiters {
+        &self.limits
+    }
+
+    // A slot for a request, unless the site has too many under way
+    pub fn admit(self: &Arc<Self>) -> Option<Slot> {
+        let active = self.active.fetch_add(1, Ordering::Relaxed);
+        let slot = Slot(self.clone());
+        if self.max_concurrent.is_some_and(|max| active >= max) {
+            return None;
+        }
+        Some(slot)
+    }
+
+    // Room for `bytes` more in the response cache, if its share has it
+    pub fn charge(self: &Arc<Self>, bytes: usize) -> Option<Charge> {
+        let cached = self.cached.fetch_add(bytes, Ordering::Relaxed);
+        let charge = Charge {
+            tenant: self.clone(),
+            bytes,
+        };
+        if self.cache.is_some_and(|cap| cached + bytes > cap) {
+            return None;
+        }
+        Some(charge)
+    }
+}
+
+impl Tenant {
+    // The answer to a request the site has no slot for. Retry-After is a
+    // second for every siteful of requests beyond the limit, so clients
+    // back off further the more of them are at it.
+    pub fn busy(&self) -> Response<Body> {
+        let max = self.max_concurrent.unwrap_or(1);
+        let over = self.active.load(Ordering::Relaxed).saturating_sub(max);
+        Response::builder()
+            .status(StatusCode::TOO_MANY_REQUESTS)
+            .header("Retry-After", 1 + over / max)
+            .header("Content-Type", "text/plain")
+            .body(Body::from("Too many concurrent requests for this site\n"))
+            .expect("response builder")
+    }
+}
+
+#[derive(Default)]
+pub struct Tenants {
+    // Lowercase host names without a port, `*` for the rest
+    tenants: Vec<(String, Arc<Tenant>)>,
+}
+
+impl Tenants {
+    // `HOST=LIMITS`, HOST being `*` for any other
+    pub fn add(&mut self, spec: &str) -> Result<(), String> {
+        let (host, limits) = spec
+            .split_once('=')
+            .ok_or(format!("Invalid --tenant (expected HOST=max-concurrent:N,bandwidth:RATE,cache:SIZE): {}", spec))?;
+        let host = host.trim().to_ascii_lowercase();
+        if self.tenants.iter().any(|(name, _)| *name == host) {
+            return Err(format!("--tenant {} is given twice", host));
+        }
+        let tenant = Tenant::parse(&host, limits)?;
+        self.tenants.push((host, Arc::new(tenant)));
+        Ok(())
+    }
+
+    // The tenant a request with these headers is for, if any
+    pub fn of(&self, headers: &HeaderMap) -> Option<Arc<Tenant>> {
+        if self.tenants.is_empty() {
+            return None;
+        }
+        let host = headers.get(HOST).and_then(|h| h.to_str().ok()).unwrap_or("");
+        // Without the port, minding IPv6 literals
+        let host = match host.rsplit_once(':') {
+            Some((name, port)) if !name.is_empty() && !port.contains(']') => name,
+            _ => host,
+        }
+        .to_ascii_lowercase();
+        let find = |host: &str| self.tenants.iter().find(|(name, _)| name == host).map(|(_, t)| t.clone());
+        find(&host).or_else(|| find("*"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn busier_sites_wait_longer() {
+        let tenant = Arc::new(Tenant::parse("site", "max-concurrent:2").unwrap());
+        let slots: Vec<Slot> = (0..2).filter_map(|_| tenant.admit()).collect();
+        assert!(tenant.admit().is_none());
+        assert_eq!(tenant.busy().headers()["Retry-After"], "1");
+        // Requests also waiting on a slot elsewhere, e.g. queued at the gate
+        tenant.active.fetch_add(4, Ordering::Relaxed);
+        assert_eq!(tenant.busy().headers()["Retry-After"], "3");
+        drop(slots);
+    }
+}
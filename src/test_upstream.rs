@@ -0,0 +1,238 @@
+use std::convert::Infallible;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use tokio::io::AsyncBufReadExt;
+use tokio::sync::oneshot;
+
+use crate::local_dir::{self, get, local_path, propfind, status, Index};
+use crate::virtual_tree;
+
+// `--builtin-test-upstream`: a small WebDAV server on a loopback port that
+// keeps its files in a temporary directory, so the proxy can be tried
+// without a real share. It can be stopped and started again from the
+// terminal to see the fallback at work. Class 1 only: clients that insist on
+// locking (e.g. Finder) mount it read-only.
+
+const ALLOW: &str = "OPTIONS, PROPFIND, GET, HEAD, PUT, DELETE, MKCOL, COPY, MOVE";
+
+pub struct TestUpstream {
+    root: PathBuf,
+    addr: SocketAddr,
+    // Ends the running server, if it runs
+    running: Mutex<Option<oneshot::Sender<()>>>,
+}
+
+impl TestUpstream {
+    // Create the directory and start serving it on a free loopback port
+    pub fn start_new() -> io::Result<Arc<Self>> {
+        let root = std::env::temp_dir().join(format!("proxy-optional-webdav-{}", std::process::id()));
+        std::fs::create_dir_all(&root)?;
+        std::fs::write(
+            root.join("README.txt"),
+            "This share is served by the proxy's built-in test upstream.\n\
+             Everything in it lives in a temporary directory.\n",
+        )?;
+        let listener = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+        let test = Arc::new(TestUpstream {
+            root,
+            addr: listener.local_addr()?,
+            running: Mutex::new(None),
+        });
+        test.serve(listener)?;
+        println!("Test upstream serving {} on {}", test.root.display(), test.addr);
+        Ok(test)
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    fn serve(&self, listener: std::net::TcpListener) -> io::Result<()> {
+        listener.set_nonblocking(true)?;
+        let root = Arc::new(self.root.clone());
+        let make_svc = make_service_fn(move |_| {
+            let root = root.clone();
+            async move { Ok::<_, Infallible>(service_fn(move |req| handle(root.clone(), req))) }
+        });
+        let (stop, stopped) = oneshot::channel::<()>();
+        let server = Server::from_tcp(listener)
+            .map_err(io::Error::other)?
+            .serve(make_svc)
+            .with_graceful_shutdown(async {
+                stopped.await.ok();
+            });
+        tokio::spawn(async move {
+            if let Err(e) = server.await {
+                eprintln!("Test upstream error: {}", e);
+            }
+        });
+        *self.running.lock().unwrap() = Some(stop);
+        Ok(())
+    }
+
+    // Serve again on the same port; false if it already runs
+    pub fn start(&self) -> io::Result<bool> {
+        if self.running.lock().unwrap().is_some() {
+            return Ok(false);
+        }
+        self.serve(std::net::TcpListener::bind(self.addr)?)?;
+        Ok(true)
+    }
+
+    // Close the listener and every connection; false if it wasn't running
+    pub fn stop(&self) -> bool {
+        match self.running.lock().unwrap().take() {
+            Some(stop) => stop.send(()).is_ok(),
+            None => false,
+        }
+    }
+
+    // Take `stop` and `start` commands from the terminal
+    pub async fn control(self: Arc<Self>) {
+        println!("Type `stop` or `start` and Enter to take the test upstream down or bring it back");
+        let mut lines = tokio::io::BufReader::new(tokio::io::stdin()).lines();
+        while let Ok(Some(line)) = lines.next_line().await {
+            match line.trim() {
+                "stop" if self.stop() => println!("Test upstream stopped"),
+                "stop" => println!("Test upstream isn't running"),
+                "start" => match self.start() {
+                    Ok(true) => println!("Test upstream started on {}", self.addr),
+                    Ok(false) => println!("Test upstream is already running"),
+                    Err(e) => println!("Failed to start the test upstream on {}: {}", self.addr, e),
+                },
+                "" => {}
+                other => println!("Unknown command (expected stop or start): {}", other),
+            }
+        }
+    }
+}
+
+async fn exists(path: &Path) -> bool {
+    tokio::fs::metadata(path).await.is_ok()
+}
+
+async fn parent_is_dir(path: &Path) -> bool {
+    match path.parent() {
+        Some(parent) => tokio::fs::metadata(parent).await.map(|m| m.is_dir()).unwrap_or(false),
+        None => false,
+    }
+}
+
+async fn remove(path: &Path) -> io::Result<()> {
+    if tokio::fs::metadata(path).await?.is_dir() {
+        tokio::fs::remove_dir_all(path).await
+    } else {
+        tokio::fs::remove_file(path).await
+    }
+}
+
+fn copy_recursive(from: &Path, to: &Path) -> io::Result<()> {
+    if from.is_dir() {
+        std::fs::create_dir(to)?;
+        for entry in std::fs::read_dir(from)? {
+            let entry = entry?;
+            copy_recursive(&entry.path(), &to.join(entry.file_name()))?;
+        }
+        Ok(())
+    } else {
+        std::fs::copy(from, to).map(|_| ())
+    }
+}
+
+// COPY and MOVE, with the Destination and Overwrite headers
+async fn transfer(root: &Path, local: &Path, req: &Request<Body>, keep: bool) -> Result<Response<Body>, io::Error> {
+    let destination = req
+        .headers()
+        .get("Destination")
+        .and_then(|d| d.to_str().ok())
+        .and_then(|d| d.parse::<hyper::Uri>().ok())
+        .and_then(|d| local_path(root, d.path()));
+    let Some(destination) = destination else {
+        return Ok(status(StatusCode::BAD_REQUEST));
+    };
+    // Not into itself
+    if destination.starts_with(local) {
+        return Ok(status(StatusCode::FORBIDDEN));
+    }
+    tokio::fs::metadata(local).await?;
+    if !parent_is_dir(&destination).await {
+        return Ok(status(StatusCode::CONFLICT));
+    }
+    let overwrite = req.headers().get("Overwrite").map(|o| o.as_bytes()) != Some(b"F");
+    let existed = exists(&destination).await;
+    if existed {
+        if !overwrite {
+            return Ok(status(StatusCode::PRECONDITION_FAILED));
+        }
+        remove(&destination).await?;
+    }
+    if keep {
+        let from = local.to_path_buf();
+        tokio::task::spawn_blocking(move || copy_recursive(&from, &destination))
+            .await
+            .map_err(io::Error::other)??;
+    } else {
+        tokio::fs::rename(local, &destination).await?;
+    }
+    Ok(status(if existed { StatusCode::NO_CONTENT } else { StatusCode::CREATED }))
+}
+
+async fn respond(root: &Path, req: Request<Body>) -> Result<Response<Body>, io::Error> {
+    let Some(local) = local_path(root, req.uri().path()) else {
+        return Ok(status(StatusCode::FORBIDDEN));
+    };
+    match req.method().as_str() {
+        "OPTIONS" => Ok(Response::builder()
+            .header("DAV", "1")
+            .header("Allow", ALLOW)
+            .header("MS-Author-Via", "DAV")
+            .body(Body::empty())
+            .expect("response builder")),
+        "PROPFIND" => propfind(&local, req.uri().path(), virtual_tree::depth(req.headers()).min(1)).await,
+        "GET" => get(&local, false, &Index::default()).await,
+        "HEAD" => get(&local, true, &Index::default()).await,
+        "PUT" => {
+            if !parent_is_dir(&local).await {
+                return Ok(status(StatusCode::CONFLICT));
+            }
+            if local.is_dir() {
+                return Ok(status(StatusCode::METHOD_NOT_ALLOWED));
+            }
+            let existed = exists(&local).await;
+            let body = hyper::body::to_bytes(req.into_body())
+                .await
+                .map_err(io::Error::other)?;
+            tokio::fs::write(&local, body).await?;
+            Ok(status(if existed { StatusCode::NO_CONTENT } else { StatusCode::CREATED }))
+        }
+        "DELETE" if local == root => Ok(status(StatusCode::FORBIDDEN)),
+        "DELETE" => remove(&local).await.map(|_| status(StatusCode::NO_CONTENT)),
+        "MKCOL" => {
+            if exists(&local).await {
+                return Ok(status(StatusCode::METHOD_NOT_ALLOWED));
+            }
+            if !parent_is_dir(&local).await {
+                return Ok(status(StatusCode::CONFLICT));
+            }
+            tokio::fs::create_dir(&local).await.map(|_| status(StatusCode::CREATED))
+        }
+        "COPY" => transfer(root, &local, &req, true).await,
+        "MOVE" if local == root => Ok(status(StatusCode::FORBIDDEN)),
+        "MOVE" => transfer(root, &local, &req, false).await,
+        _ => Ok(Response::builder()
+            .status(StatusCode::METHOD_NOT_ALLOWED)
+            .header("Allow", ALLOW)
+            .body(Body::empty())
+            .expect("response builder")),
+    }
+}
+
+async fn handle(root: Arc<PathBuf>, req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    let head = req.method() == Method::HEAD;
+    Ok(respond(&root, req).await.unwrap_or_else(|e| local_dir::io_error(&e, head)))
+}
@@ -0,0 +1,220 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::TryStreamExt;
+use hyper::Body;
+
+use crate::clock::{local_minute, parse_clock};
+
+// Token bucket shared by every transfer it is applied to. A rate of 0 means
+// unlimited. Tokens may go negative: a large chunk is let through at once
+// and the following ones wait until the debt is paid off.
+pub struct Limiter {
+    rate: AtomicU64,
+    bucket: Mutex<(f64, Instant)>,
+}
+
+impl Limiter {
+    pub fn new(rate: u64) -> Self {
+        Limiter {
+            rate: AtomicU64::new(rate),
+            bucket: Mutex::new((rate as f64, Instant::now())),
+        }
+    }
+
+    pub fn set_rate(&self, rate: u64) {
+        self.rate.store(rate, Ordering::Relaxed);
+    }
+
+    pub fn rate(&self) -> u64 {
+        self.rate.load(Ordering::Relaxed)
+    }
+
+    async fn acquire(&self, n: usize) {
+        let rate = self.rate();
+        if rate == 0 {
+            return;
+        }
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap();
+            let (tokens, last) = &mut *bucket;
+            let now = Instant::now();
+            // Allow at most one second worth of burst
+            *tokens = (*tokens + now.duration_since(*last).as_secs_f64() * rate as f64).min(rate as f64);
+            *last = now;
+            *tokens -= n as f64;
+            if *tokens < 0.0 {
+                Some(Duration::from_secs_f64(-*tokens / rate as f64))
+            } else {
+                None
+            }
+        };
+        if let Some(wait) = wait {
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    pub fn wrap(self: &Arc<Self>, body: Body) -> Body {
+        let limiter = self.clone();
+        Body::wrap_stream(body.and_then(move |chunk| {
+            let limiter = limiter.clone();
+            async move {
+                limiter.acquire(chunk.len()).await;
+                Ok(chunk)
+            }
+        }))
+    }
+}
+
+// A limiter per direction; either may be missing
+#[derive(Clone, Default)]
+pub struct Limiters {
+    pub upload: Option<Arc<Limiter>>,
+    pub download: Option<Arc<Limiter>>,
+}
+
+// Fixed limits per direction (--max-upload-rate, --max-download-rate), either
+// shared by all transfers or for each client connection on its own
+pub struct RateCaps {
+    upload: u64,
+    download: u64,
+    shared: Option<Limiters>,
+}
+
+impl RateCaps {
+    pub fn new(upload: u64, download: u64, per_connection: bool) -> Self {
+        let mut caps = RateCaps {
+            upload,
+            download,
+            shared: None,
+        };
+        if !per_connection {
+            caps.shared = Some(caps.limiters());
+        }
+        caps
+    }
+
+    // Bytes per second up and down, 0 for no cap
+    pub fn rates(&self) -> (u64, u64) {
+        (self.upload, self.download)
+    }
+
+    fn limiters(&self) -> Limiters {
+        let limiter = |rate| (rate > 0).then(|| Arc::new(Limiter::new(rate)));
+        Limiters {
+            upload: limiter(self.upload),
+            download: limiter(self.download),
+        }
+    }
+
+    // The limiters for the transfers of a new client connection
+    pub fn connection(&self) -> Limiters {
+        match &self.shared {
+            Some(shared) => shared.clone(),
+            None => self.limiters(),
+        }
+    }
+}
+
+// Parse a byte rate like 512K, 2M or 1G (per second); 0 or "unlimited" disable the limit
+pub fn parse_rate(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    if s.eq_ignore_ascii_case("unlimited") {
+        return Ok(0);
+    }
+    let (number, multiplier) = match s.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&s[..s.len() - 1], 1024),
+        Some('M') => (&s[..s.len() - 1], 1024 * 1024),
+        Some('G') => (&s[..s.len() - 1], 1024 * 1024 * 1024),
+        _ => (s, 1),
+    };
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .ok_or_else(|| format!("Invalid rate: {}", s))
+}
+
+// A time-of-day window in minutes since midnight; `start > end` wraps past midnight
+struct Rule {
+    start: u32,
+    end: u32,
+    rate: u64,
+}
+
+impl Rule {
+    fn matches(&self, minute: u32) -> bool {
+        if self.start <= self.end {
+            minute >= self.start && minute < self.end
+        } else {
+            minute >= self.start || minute < self.end
+        }
+    }
+}
+
+// Throttle limits by time of day, e.g. `08:00-18:00=512K,22:00-06:00=0`
+pub struct Schedule {
+    rules: Vec<Rule>,
+    default: u64,
+}
+
+impl Schedule {
+    // Rules are tried in order; `*=RATE` sets the rate outside every window
+    pub fn parse(spec: &str, default: u64) -> Result<Self, String> {
+        let mut schedule = Schedule { rules: Vec::new(), default };
+        for rule in spec.split(',').map(|r| r.trim()).filter(|r| !r.is_empty()) {
+            let (window, rate) = rule.split_once('=').ok_or(format!("Invalid schedule rule: {}", rule))?;
+            let rate = parse_rate(rate)?;
+            if window.trim() == "*" {
+                schedule.default = rate;
+                continue;
+            }
+            let (start, end) = window.split_once('-').ok_or(format!("Invalid schedule window: {}", window))?;
+            schedule.rules.push(Rule {
+                start: parse_clock(start)?,
+                end: parse_clock(end)?,
+                rate,
+            });
+        }
+        Ok(schedule)
+    }
+
+    pub fn rate_at(&self, minute: u32) -> u64 {
+        self.rules
+            .iter()
+            .find(|r| r.matches(minute))
+            .map_or(self.default, |r| r.rate)
+    }
+
+    // Re-evaluate the schedule periodically and push the rate into the limiters
+    pub fn spawn(self, limiters: Vec<Arc<Limiter>>) {
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(Duration::from_secs(15));
+            let mut current = None;
+            loop {
+                ticker.tick().await;
+                let rate = self.rate_at(local_minute());
+                if current != Some(rate) {
+                    for limiter in &limiters {
+                        limiter.set_rate(rate);
+                    }
+                    current = Some(rate);
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_rates() {
+        assert_eq!(parse_rate("512K"), Ok(512 * 1024));
+        assert_eq!(parse_rate("unlimited"), Ok(0));
+        assert!(parse_rate("fast").is_err());
+        assert!(parse_rate("99999999999999G").is_err());
+    }
+}
@@ -0,0 +1,400 @@
+use std::ffi::{c_int, c_long, c_void};
+use std::io::{self, Read, Write};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use openssl::rand::rand_bytes;
+use openssl::ssl::{
+    self, AlpnError, ErrorCode, Ssl, SslAcceptor, SslAcceptorBuilder, SslFiletype, SslMethod, SslOptions,
+    SslSessionCacheMode, SslStream, SslVersion,
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::certwatch::CertWatch;
+
+// TLS for the listener, with the protocol policy security teams ask for:
+// a cipher preset after Mozilla's server side TLS guidelines, optional
+// overrides of the versions and ciphers, and the ALPN protocols offered.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Preset {
+    // TLS 1.3 only
+    Modern,
+    // TLS 1.2 with forward-secret AEAD ciphers, and TLS 1.3
+    Intermediate,
+    // Down to TLS 1.0 and CBC ciphers, for ancient clients
+    Old,
+}
+
+impl Preset {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match s {
+            "modern" => Ok(Preset::Modern),
+            "intermediate" => Ok(Preset::Intermediate),
+            "old" => Ok(Preset::Old),
+            _ => Err(format!("Unknown TLS preset {}, expected modern, intermediate or old", s)),
+        }
+    }
+}
+
+// The TLS 1.2 and older ciphers of the old preset; @SECLEVEL=0 lets
+// OpenSSL 3 negotiate TLS 1.0 and 1.1 at all
+const OLD_CIPHERS: &str = "ECDHE-ECDSA-AES128-GCM-SHA256:ECDHE-RSA-AES128-GCM-SHA256:ECDHE-ECDSA-AES256-GCM-SHA384:\
+    ECDHE-RSA-AES256-GCM-SHA384:ECDHE-ECDSA-CHACHA20-POLY1305:ECDHE-RSA-CHACHA20-POLY1305:\
+    DHE-RSA-AES128-GCM-SHA256:DHE-RSA-AES256-GCM-SHA384:ECDHE-ECDSA-AES128-SHA256:ECDHE-RSA-AES128-SHA256:\
+    ECDHE-ECDSA-AES128-SHA:ECDHE-RSA-AES128-SHA:ECDHE-ECDSA-AES256-SHA384:ECDHE-RSA-AES256-SHA384:\
+    ECDHE-ECDSA-AES256-SHA:ECDHE-RSA-AES256-SHA:AES128-GCM-SHA256:AES256-GCM-SHA384:AES128-SHA256:\
+    AES256-SHA256:AES128-SHA:AES256-SHA:DES-CBC3-SHA:@SECLEVEL=0";
+
+pub struct TlsOptions {
+    pub cert: String,
+    pub key: String,
+    pub preset: Preset,
+    pub min_version: Option<SslVersion>,
+    pub max_version: Option<SslVersion>,
+    // OpenSSL cipher list for TLS 1.2 and older
+    pub ciphers: Option<String>,
+    // TLS 1.3 cipher suites
+    pub ciphersuites: Option<String>,
+    // In order of preference
+    pub alpn: Vec<String>,
+    // Source of OCSP responses to staple
+    pub watch: Option<Arc<CertWatch>>,
+    // How long a session ticket key is used to issue tickets; zero turns
+    // tickets off, leaving resumption by session ID
+    pub ticket_rotation: Duration,
+}
+
+pub fn parse_version(s: &str) -> Result<SslVersion, String> {
+    match s.trim_start_matches("TLSv").trim_start_matches("tls") {
+        "1.0" | "1" => Ok(SslVersion::TLS1),
+        "1.1" => Ok(SslVersion::TLS1_1),
+        "1.2" => Ok(SslVersion::TLS1_2),
+        "1.3" => Ok(SslVersion::TLS1_3),
+        _ => Err(format!("Unknown TLS version {}, expected 1.0, 1.1, 1.2 or 1.3", s)),
+    }
+}
+
+pub fn acceptor(options: &TlsOptions) -> Result<SslAcceptor, String> {
+    let policy = |e: openssl::error::ErrorStack| format!("Invalid TLS policy: {}", e);
+    let mut builder = match options.preset {
+        Preset::Modern => SslAcceptor::mozilla_modern_v5(SslMethod::tls_server()),
+        _ => SslAcceptor::mozilla_intermediate_v5(SslMethod::tls_server()),
+    }
+    .map_err(policy)?;
+    if options.preset == Preset::Old {
+        builder.clear_options(SslOptions::NO_TLSV1 | SslOptions::NO_TLSV1_1);
+        builder.set_min_proto_version(Some(SslVersion::TLS1)).map_err(policy)?;
+        builder.set_cipher_list(OLD_CIPHERS).map_err(policy)?;
+    }
+    if let Some(version) = options.min_version {
+        builder.clear_options(SslOptions::NO_TLSV1 | SslOptions::NO_TLSV1_1);
+        builder.set_min_proto_version(Some(version)).map_err(policy)?;
+    }
+    if let Some(version) = options.max_version {
+        builder.set_max_proto_version(Some(version)).map_err(policy)?;
+    }
+    if let Some(ciphers) = &options.ciphers {
+        builder.set_cipher_list(ciphers).map_err(policy)?;
+    }
+    if let Some(suites) = &options.ciphersuites {
+        builder.set_ciphersuites(suites).map_err(policy)?;
+    }
+    if !options.alpn.is_empty() {
+        // ALPN's wire format: each protocol prefixed by its length
+        let mut protocols = Vec::new();
+        for p in &options.alpn {
+            let len = u8::try_from(p.len()).ok().filter(|&n| n > 0).ok_or(format!("Invalid ALPN protocol {:?}", p))?;
+            protocols.push(len);
+            protocols.extend_from_slice(p.as_bytes());
+        }
+        builder.set_alpn_select_callback(move |_, client| {
+            ssl::select_next_proto(&protocols, client).ok_or(AlpnError::NOACK)
+        });
+    }
+    if let Some(watch) = options.watch.clone() {
+        builder
+            .set_status_callback(move |ssl| match watch.stapled() {
+                Some(der) => ssl.set_ocsp_status(&der).map(|()| true),
+                None => Ok(false),
+            })
+            .map_err(policy)?;
+    }
+    resumption(&mut builder, options.ticket_rotation).map_err(policy)?;
+    builder
+        .set_certificate_chain_file(&options.cert)
+        .map_err(|e| format!("Failed to load {}: {}", options.cert, e))?;
+    builder
+        .set_private_key_file(&options.key, SslFiletype::PEM)
+        .map_err(|e| format!("Failed to load {}: {}", options.key, e))?;
+    builder
+        .check_private_key()
+        .map_err(|e| format!("{} does not match {}: {}", options.key, options.cert, e))?;
+    Ok(builder.build())
+}
+
+// Session resumption, for clients that open many short connections, like
+// Windows' WebDAV redirector browsing folders: a session cache for
+// resumption by ID, and session tickets encrypted with a key that is
+// replaced every `rotation`. Tickets issued with the previous key are still
+// taken for one more period, and answered with a fresh ticket, so a ticket
+// works for at most two periods and a stolen key doesn't open old sessions.
+// The keys live in memory only and are shared by the listeners.
+
+struct TicketKey {
+    name: [u8; 16],
+    aes: [u8; 32],
+    hmac: [u8; 32],
+    created: Instant,
+}
+
+impl TicketKey {
+    fn new() -> Result<Self, openssl::error::ErrorStack> {
+        let mut key = TicketKey {
+            name: [0; 16],
+            aes: [0; 32],
+            hmac: [0; 32],
+            created: Instant::now(),
+        };
+        rand_bytes(&mut key.name)?;
+        rand_bytes(&mut key.aes)?;
+        rand_bytes(&mut key.hmac)?;
+        Ok(key)
+    }
+}
+
+struct TicketKeys {
+    rotation: Duration,
+    current: TicketKey,
+    previous: Option<TicketKey>,
+}
+
+impl TicketKeys {
+    // Rotated when a handshake comes along, not on a timer
+    fn rotate_if_due(&mut self) {
+        if self.current.created.elapsed() < self.rotation {
+            return;
+        }
+        if let Ok(key) = TicketKey::new() {
+            let previous = std::mem::replace(&mut self.current, key);
+            self.previous = Some(previous).filter(|p| p.created.elapsed() < 2 * self.rotation);
+        }
+    }
+}
+
+static TICKET_KEYS: Mutex<Option<TicketKeys>> = Mutex::new(None);
+
+const SSL_CTRL_SET_TLSEXT_TICKET_KEY_CB: c_int = 72;
+
+extern "C" {
+    fn SSL_CTX_callback_ctrl(ctx: *mut c_void, cmd: c_int, callback: Option<unsafe extern "C" fn()>) -> c_long;
+    fn EVP_aes_256_cbc() -> *const c_void;
+    fn EVP_sha256() -> *const c_void;
+    fn EVP_EncryptInit_ex(ctx: *mut c_void, cipher: *const c_void, engine: *mut c_void, key: *const u8, iv: *const u8) -> c_int;
+    fn EVP_DecryptInit_ex(ctx: *mut c_void, cipher: *const c_void, engine: *mut c_void, key: *const u8, iv: *const u8) -> c_int;
+    fn HMAC_Init_ex(ctx: *mut c_void, key: *const c_void, len: c_int, md: *const c_void, engine: *mut c_void) -> c_int;
+}
+
+// OpenSSL's ticket key callback: with `encrypt` set, pick the key for a new
+// ticket and fill in its name and IV; otherwise find the key `name` names.
+// 1 goes on, 2 also issues a new ticket, 0 falls back to a full handshake
+// and -1 fails it.
+unsafe extern "C" fn ticket_key(
+    _ssl: *mut c_void,
+    name: *mut u8,
+    iv: *mut u8,
+    cipher: *mut c_void,
+    hmac: *mut c_void,
+    encrypt: c_int,
+) -> c_int {
+    let mut keys = TICKET_KEYS.lock().unwrap_or_else(|e| e.into_inner());
+    let Some(keys) = keys.as_mut() else {
+        return -1;
+    };
+    keys.rotate_if_due();
+    let name = std::slice::from_raw_parts_mut(name, 16);
+    let iv = std::slice::from_raw_parts_mut(iv, 16);
+    let (key, renew) = if encrypt == 1 {
+        if rand_bytes(iv).is_err() {
+            return -1;
+        }
+        name.copy_from_slice(&keys.current.name);
+        (&keys.current, false)
+    } else if *name == keys.current.name {
+        (&keys.current, false)
+    } else {
+        match &keys.previous {
+            Some(previous) if *name == previous.name && previous.created.elapsed() < 2 * keys.rotation => (previous, true),
+            _ => return 0,
+        }
+    };
+    let init = if encrypt == 1 { EVP_EncryptInit_ex } else { EVP_DecryptInit_ex };
+    if init(cipher, EVP_aes_256_cbc(), std::ptr::null_mut(), key.aes.as_ptr(), iv.as_ptr()) != 1
+        || HMAC_Init_ex(hmac, key.hmac.as_ptr().cast(), 32, EVP_sha256(), std::ptr::null_mut()) != 1
+    {
+        return -1;
+    }
+    if renew {
+        2
+    } else {
+        1
+    }
+}
+
+type TicketKeyCallback = unsafe extern "C" fn(*mut c_void, *mut u8, *mut u8, *mut c_void, *mut c_void, c_int) -> c_int;
+
+fn resumption(builder: &mut SslAcceptorBuilder, rotation: Duration) -> Result<(), openssl::error::ErrorStack> {
+    builder.set_session_cache_mode(SslSessionCacheMode::SERVER);
+    builder.set_session_id_context(b"proxy-optional-webdav")?;
+    if rotation.is_zero() {
+        builder.set_options(SslOptions::NO_TICKET);
+        return Ok(());
+    }
+    let mut keys = TICKET_KEYS.lock().unwrap_or_else(|e| e.into_inner());
+    match keys.as_mut() {
+        Some(keys) => keys.rotation = rotation,
+        None => {
+            *keys = Some(TicketKeys {
+                rotation,
+                current: TicketKey::new()?,
+                previous: None,
+            })
+        }
+    }
+    let callback = ticket_key as TicketKeyCallback;
+    // OpenSSL takes any callback as a plain function pointer
+    let callback = unsafe { std::mem::transmute::<TicketKeyCallback, unsafe extern "C" fn()>(callback) };
+    unsafe { SSL_CTX_callback_ctrl(builder.as_ptr().cast(), SSL_CTRL_SET_TLSEXT_TICKET_KEY_CB, Some(callback)) };
+    Ok(())
+}
+
+// Lets OpenSSL's blocking-style I/O run on an async stream: reads and
+// writes poll the stream with the context of the current poll and turn
+// Pending into WouldBlock
+struct Bridge<S> {
+    stream: S,
+    // The `Context` of the poll in progress, only set during one
+    context: *mut (),
+}
+
+// The context pointer is only dereferenced while the poll that set it runs
+unsafe impl<S: Send> Send for Bridge<S> {}
+
+impl<S: Unpin> Bridge<S> {
+    fn poll<R>(&mut self, f: impl FnOnce(Pin<&mut S>, &mut Context<'_>) -> Poll<io::Result<R>>) -> io::Result<R> {
+        assert!(!self.context.is_null(), "TLS stream used outside of a poll");
+        let cx = unsafe { &mut *(self.context as *mut Context<'_>) };
+        match f(Pin::new(&mut self.stream), cx) {
+            Poll::Ready(result) => result,
+            Poll::Pending => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> Read for Bridge<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.poll(|stream, cx| {
+            let mut buf = ReadBuf::new(buf);
+            stream.poll_read(cx, &mut buf).map_ok(|()| buf.filled().len())
+        })
+    }
+}
+
+impl<S: AsyncWrite + Unpin> Write for Bridge<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.poll(|stream, cx| stream.poll_write(cx, buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.poll(|stream, cx| stream.poll_flush(cx))
+    }
+}
+
+// A server-side TLS connection. The handshake happens on first use, so the
+// stream can be handed to hyper straight from the accept loop.
+pub struct TlsStream<S> {
+    ssl: SslStream<Bridge<S>>,
+    handshaken: bool,
+}
+
+fn pending<T>(result: io::Result<T>) -> Poll<io::Result<T>> {
+    match result {
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock => Poll::Pending,
+        other => Poll::Ready(other),
+    }
+}
+
+fn ssl_result<T>(result: Result<T, ssl::Error>) -> io::Result<T> {
+    result.map_err(|e| match e.code() {
+        ErrorCode::WANT_READ | ErrorCode::WANT_WRITE => io::ErrorKind::WouldBlock.into(),
+        _ => e.into_io_error().unwrap_or_else(|e| io::Error::other(e.to_string())),
+    })
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> TlsStream<S> {
+    pub fn new(acceptor: &SslAcceptor, stream: S) -> Self {
+        let ssl = Ssl::new(acceptor.context()).expect("Failed to create a TLS session");
+        let bridge = Bridge {
+            stream,
+            context: std::ptr::null_mut(),
+        };
+        TlsStream {
+            ssl: SslStream::new(ssl, bridge).expect("Failed to create a TLS session"),
+            handshaken: false,
+        }
+    }
+
+    // Run `f` with the bridge able to reach the current poll's context
+    fn with_context<R>(&mut self, cx: &mut Context<'_>, f: impl FnOnce(&mut SslStream<Bridge<S>>) -> io::Result<R>) -> Poll<io::Result<R>> {
+        self.ssl.get_mut().context = cx as *mut Context<'_> as *mut ();
+        let result = f(&mut self.ssl);
+        self.ssl.get_mut().context = std::ptr::null_mut();
+        pending(result)
+    }
+
+    fn poll_handshake(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if self.handshaken {
+            return Poll::Ready(Ok(()));
+        }
+        let result = self.with_context(cx, |ssl| ssl_result(ssl.accept()));
+        if let Poll::Ready(Ok(())) = result {
+            self.handshaken = true;
+        }
+        result
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for TlsStream<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        std::task::ready!(self.poll_handshake(cx))?;
+        self.with_context(cx, |ssl| {
+            let n = ssl.read(buf.initialize_unfilled())?;
+            buf.advance(n);
+            Ok(())
+        })
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for TlsStream<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        std::task::ready!(self.poll_handshake(cx))?;
+        self.with_context(cx, |ssl| ssl.write(buf))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.with_context(cx, |ssl| ssl.flush())
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if self.handshaken {
+            // Send close_notify; not waiting for the peer's
+            let sent = self.with_context(cx, |ssl| match ssl.shutdown() {
+                Err(e) if e.code() == ErrorCode::WANT_WRITE => Err(io::ErrorKind::WouldBlock.into()),
+                _ => Ok(()),
+            });
+            std::task::ready!(sent)?;
+        }
+        Pin::new(&mut self.ssl.get_mut().stream).poll_shutdown(cx)
+    }
+}
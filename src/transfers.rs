@@ -0,0 +1,221 @@
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::StreamExt;
+use hyper::Body;
+use tokio::sync::Notify;
+
+use crate::json;
+use crate::pinned::BoxError;
+
+// Uploads and downloads past a size threshold: their progress is logged
+// every PROGRESS_INTERVAL and they are listed on /admin/transfers, where
+// one can be cancelled. A cancelled transfer fails its body, so the client
+// sees a broken transfer rather than a short file.
+
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Copy)]
+pub enum Direction {
+    Upload,
+    Download,
+}
+
+impl Direction {
+    fn name(self) -> &'static str {
+        match self {
+            Direction::Upload => "upload",
+            Direction::Download => "download",
+        }
+    }
+}
+
+// What a transfer is listed as
+pub struct Info {
+    pub method: String,
+    pub path: String,
+    pub user: Option<String>,
+    pub client: IpAddr,
+}
+
+struct Transfer {
+    id: u64,
+    direction: Direction,
+    info: Arc<Info>,
+    // From the Content-Length, if any
+    total: Option<u64>,
+    started: Instant,
+    bytes: AtomicU64,
+    cancelled: AtomicBool,
+    cancel: Notify,
+}
+
+fn megabytes(bytes: u64) -> f64 {
+    bytes as f64 / 1_000_000.0
+}
+
+impl Transfer {
+    // Bytes per second so far
+    fn rate(&self) -> f64 {
+        self.bytes.load(Ordering::Relaxed) as f64 / self.started.elapsed().as_secs_f64().max(0.001)
+    }
+
+    fn describe(&self) -> String {
+        let bytes = self.bytes.load(Ordering::Relaxed);
+        let total = self.total.map_or(String::new(), |t| format!(" of {:.1}", megabytes(t)));
+        format!(
+            "transfer {}: {} {} ({}) {:.1}{} MB, {:.2} MB/s",
+            self.id,
+            self.info.method,
+            self.info.path,
+            self.direction.name(),
+            megabytes(bytes),
+            total,
+            megabytes(self.rate() as u64)
+        )
+    }
+
+    fn to_json(&self) -> String {
+        json::object(&[
+            ("id", self.id.to_string()),
+            ("direction", json::string(self.direction.name())),
+            ("method", json::string(&self.info.method)),
+            ("path", json::string(&self.info.path)),
+            ("user", self.info.user.as_deref().map_or("null".to_string(), json::string)),
+            ("client", json::string(&self.info.client.to_string())),
+            ("bytes", self.bytes.load(Ordering::Relaxed).to_string()),
+            ("total", self.total.map_or("null".to_string(), |t| t.to_string())),
+            ("seconds", self.started.elapsed().as_secs().to_string()),
+            ("bytes_per_second", (self.rate() as u64).to_string()),
+        ])
+    }
+}
+
+pub struct Transfers {
+    threshold: u64,
+    next_id: AtomicU64,
+    // By id, the ones past the threshold
+    active: Mutex<BTreeMap<u64, Arc<Transfer>>>,
+}
+
+// The stream side of a transfer, listed once it passes the threshold
+struct Watch {
+    transfers: Arc<Transfers>,
+    transfer: Arc<Transfer>,
+    listed: bool,
+    logged: Instant,
+}
+
+impl Watch {
+    fn list(&mut self) {
+        self.listed = true;
+        self.transfers
+            .active
+            .lock()
+            .unwrap()
+            .insert(self.transfer.id, self.transfer.clone());
+    }
+
+    fn progress(&mut self, len: usize) {
+        let bytes = self.transfer.bytes.fetch_add(len as u64, Ordering::Relaxed) + len as u64;
+        if !self.listed && bytes >= self.transfers.threshold {
+            self.list();
+        }
+        if self.listed && self.logged.elapsed() >= PROGRESS_INTERVAL {
+            self.logged = Instant::now();
+            tracing::info!("{}", self.transfer.describe());
+        }
+    }
+}
+
+impl Drop for Watch {
+    fn drop(&mut self) {
+        if !self.listed {
+            return;
+        }
+        self.transfers.active.lock().unwrap().remove(&self.transfer.id);
+        let outcome = if self.transfer.cancelled.load(Ordering::Relaxed) {
+            "cancelled"
+        } else {
+            "ended"
+        };
+        tracing::info!(
+            "{} {} after {}s",
+            self.transfer.describe(),
+            outcome,
+            self.transfer.started.elapsed().as_secs()
+        );
+    }
+}
+
+impl Transfers {
+    pub fn new(threshold: u64) -> Arc<Self> {
+        Arc::new(Transfers {
+            threshold,
+            next_id: AtomicU64::new(1),
+            active: Mutex::new(BTreeMap::new()),
+        })
+    }
+
+    // Follow a body on its way through the proxy
+    pub fn watch(self: &Arc<Self>, direction: Direction, info: Arc<Info>, total: Option<u64>, body: Body) -> Body {
+        let transfer = Arc::new(Transfer {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            direction,
+            info,
+            total,
+            started: Instant::now(),
+            bytes: AtomicU64::new(0),
+            cancelled: AtomicBool::new(false),
+            cancel: Notify::new(),
+        });
+        let mut watch = Watch {
+            transfers: self.clone(),
+            transfer: transfer.clone(),
+            listed: false,
+            logged: Instant::now(),
+        };
+        // Known to be large from the start
+        if total.is_some_and(|t| t >= self.threshold) {
+            watch.list();
+        }
+        let cancel = transfer.clone();
+        let stream = body
+            .map(move |chunk| {
+                if let Ok(chunk) = &chunk {
+                    watch.progress(chunk.len());
+                }
+                chunk.map_err(BoxError::from)
+            })
+            .take_until(async move { cancel.cancel.notified().await })
+            .chain(
+                futures::stream::once(async move {
+                    transfer
+                        .cancelled
+                        .load(Ordering::Relaxed)
+                        .then(|| Err(BoxError::from("transfer cancelled")))
+                })
+                .filter_map(|cancelled| async move { cancelled }),
+            );
+        Body::wrap_stream(stream)
+    }
+
+    // False if there is no such transfer
+    pub fn cancel(&self, id: u64) -> bool {
+        let Some(transfer) = self.active.lock().unwrap().get(&id).cloned() else {
+            return false;
+        };
+        transfer.cancelled.store(true, Ordering::Relaxed);
+        transfer.cancel.notify_one();
+        tracing::info!("Cancelling {}", transfer.describe());
+        true
+    }
+
+    pub fn to_json(&self) -> String {
+        let active = self.active.lock().unwrap();
+        json::array(&active.values().map(|t| t.to_json()).collect::<Vec<_>>())
+    }
+}
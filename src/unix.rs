@@ -0,0 +1,153 @@
+use std::error::Error;
+use std::future::Future;
+use std::io;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use hyper::client::connect::{Connected, Connection};
+use hyper::service::Service;
+use hyper::Uri;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+#[cfg(unix)]
+use tokio::net::UnixStream;
+
+#[cfg(unix)]
+use crate::phase::{self, Phase};
+use crate::resolve::Resolver;
+
+// Upstreams on a Unix socket of this host (`unix:/PATH` as the remote), so
+// neither needs a TCP port. Such an upstream gets a made-up host naming the
+// socket, its path hex-encoded under `.unix`, by which the connector knows
+// where to connect and hyper pools its connections apart from the others';
+// requests still carry the client's Host header.
+
+const SUFFIX: &str = ".unix";
+
+// The made-up host for the socket at `path`
+pub fn authority(path: &str) -> String {
+    let hex: String = path.bytes().map(|b| format!("{:02x}", b)).collect();
+    format!("{}{}", hex, SUFFIX)
+}
+
+// The socket a made-up host stands for
+pub fn socket(host: &str) -> Option<PathBuf> {
+    let hex = host.strip_suffix(SUFFIX)?;
+    if hex.is_empty() || hex.len() % 2 != 0 {
+        return None;
+    }
+    let bytes: Option<Vec<u8>> = (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect();
+    String::from_utf8(bytes?).ok().map(PathBuf::from)
+}
+
+pub fn socket_of(uri: &Uri) -> Option<PathBuf> {
+    uri.host().and_then(socket)
+}
+
+// A connection to an upstream, over TCP or a Unix socket
+pub enum Socket {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+// Connects to Unix socket upstreams itself, and to the others when they
+// are reached through an outbound proxy, passing the rest on to `S`
+#[derive(Clone)]
+pub struct Sockets<S> {
+    inner: S,
+    proxied: Option<Resolver>,
+}
+
+impl<S> Sockets<S> {
+    pub fn new(inner: S, resolver: &Resolver) -> Self {
+        Sockets {
+            inner,
+            proxied: resolver.proxied().then(|| resolver.clone()),
+        }
+    }
+}
+
+impl<S> Service<Uri> for Sockets<S>
+where
+    S: Service<Uri, Response = TcpStream>,
+    S::Error: Into<Box<dyn Error + Send + Sync>>,
+    S::Future: Send + 'static,
+{
+    type Response = Socket;
+    type Error = Box<dyn Error + Send + Sync>;
+    type Future = Pin<Box<dyn Future<Output = Result<Socket, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        #[cfg(unix)]
+        if let Some(path) = socket_of(&uri) {
+            return Box::pin(async move {
+                phase::enter(Phase::Connect);
+                Ok(Socket::Unix(UnixStream::connect(path).await?))
+            });
+        }
+        if let Some(resolver) = self.proxied.clone() {
+            return Box::pin(async move {
+                let host = uri.host().unwrap_or_default();
+                let port = uri.port_u16().unwrap_or(if uri.scheme_str() == Some("https") { 443 } else { 80 });
+                Ok(Socket::Tcp(resolver.connect_to(host, port).await?))
+            });
+        }
+        let connecting = self.inner.call(uri);
+        Box::pin(async move { connecting.await.map(Socket::Tcp).map_err(Into::into) })
+    }
+}
+
+impl Connection for Socket {
+    fn connected(&self) -> Connected {
+        match self {
+            Socket::Tcp(stream) => stream.connected(),
+            #[cfg(unix)]
+            Socket::Unix(_) => Connected::new(),
+        }
+    }
+}
+
+impl AsyncRead for Socket {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Socket::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(unix)]
+            Socket::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Socket {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Socket::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(unix)]
+            Socket::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Socket::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(unix)]
+            Socket::Unix(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Socket::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(unix)]
+            Socket::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
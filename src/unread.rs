@@ -0,0 +1,88 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures::StreamExt;
+use hyper::body::HttpBody;
+use hyper::header::{HeaderValue, CONNECTION, EXPECT};
+use hyper::{Body, Request, Response, StatusCode};
+
+use crate::buffering;
+
+// Request bodies the proxy answered without reading, typically after
+// refusing the credentials. A small one is read and dropped, so the
+// connection stays open for the client's next attempt (which NTLM and
+// Negotiate need); anything bigger gets Connection: close, so the client
+// stops sending instead of pushing gigabytes nobody reads. hyper only
+// sends 100 Continue once a body is read, so a client waiting for it
+// hasn't sent the body and is told to close as well.
+
+// Largest body read just to keep the connection
+const DRAIN_LIMIT: usize = 64 * 1024;
+// Longest the client gets to send it
+const DRAIN_TIME: Duration = Duration::from_secs(5);
+
+// 417 for any expectation but 100-continue (RFC 7231 5.1.1), before the
+// body is sent
+pub fn unmet_expectation(req: &Request<Body>) -> Option<Response<Body>> {
+    let expect = req.headers().get(EXPECT)?;
+    if expect.as_bytes().eq_ignore_ascii_case(b"100-continue") {
+        return None;
+    }
+    Some(
+        Response::builder()
+            .status(StatusCode::EXPECTATION_FAILED)
+            .header(CONNECTION, "close")
+            .header("Content-Type", "text/plain")
+            .body(Body::from("Only Expect: 100-continue is supported\n"))
+            .expect("response builder"),
+    )
+}
+
+// The client's body until it is first read
+pub struct Unread {
+    body: Arc<Mutex<Option<Body>>>,
+    expects_continue: bool,
+}
+
+// Hand on a body that takes the client's out of the returned handle when
+// first read
+pub fn track(req: Request<Body>) -> (Request<Body>, Unread) {
+    let (parts, body) = req.into_parts();
+    let expects_continue = parts
+        .headers
+        .get(EXPECT)
+        .is_some_and(|v| v.as_bytes().eq_ignore_ascii_case(b"100-continue"));
+    // Kept as it is, so hyper's client sends no body either instead of an
+    // empty chunked one
+    if body.is_end_stream() {
+        let unread = Unread {
+            body: Arc::default(),
+            expects_continue,
+        };
+        return (Request::from_parts(parts, body), unread);
+    }
+    let slot = Arc::new(Mutex::new(Some(body)));
+    let taken = slot.clone();
+    let body = futures::stream::once(async move { taken.lock().unwrap().take().unwrap_or_default() }).flatten();
+    let unread = Unread {
+        body: slot,
+        expects_continue,
+    };
+    (Request::from_parts(parts, Body::wrap_stream(body)), unread)
+}
+
+impl Unread {
+    // Deal with the body if the proxy answered without reading it
+    pub async fn settle(self, response: &mut Response<Body>) {
+        let Some(body) = self.body.lock().unwrap().take() else {
+            return;
+        };
+        if !self.expects_continue {
+            let drained = tokio::time::timeout(DRAIN_TIME, buffering::read_up_to(body, DRAIN_LIMIT)).await;
+            if let Ok(Ok(Ok(_))) = drained {
+                return;
+            }
+        }
+        response.headers_mut().insert(CONNECTION, HeaderValue::from_static("close"));
+    }
+}
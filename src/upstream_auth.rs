@@ -0,0 +1,292 @@
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use hyper::header::{HeaderMap, HeaderValue, WWW_AUTHENTICATE};
+use hyper::Method;
+
+use crate::{base64, digest};
+
+// Credentials the proxy presents to the upstream on behalf of every client:
+// Basic up front, and Digest (RFC 7616) from the first challenge for it on.
+// Alternates are tried in turn when the upstream refuses the ones in use,
+// so a password rotation doesn't lock the proxy out while the upstream and
+// the proxy's configuration disagree.
+
+pub fn basic_authorization(user: &str, password: &str) -> Result<HeaderValue, String> {
+    let token = base64::encode(format!("{}:{}", user, password).as_bytes());
+    let mut value = HeaderValue::from_str(&format!("Basic {}", token)).map_err(|e| e.to_string())?;
+    value.set_sensitive(true);
+    Ok(value)
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Algorithm {
+    Md5,
+    Sha256,
+}
+
+impl Algorithm {
+    fn digest(self, input: &str) -> String {
+        match self {
+            Algorithm::Md5 => digest::hex(&digest::md5(input.as_bytes())),
+            Algorithm::Sha256 => digest::hex(&digest::sha256(input.as_bytes())),
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Algorithm::Md5 => "MD5",
+            Algorithm::Sha256 => "SHA-256",
+        }
+    }
+}
+
+// The upstream's last Digest challenge
+struct Challenge {
+    realm: String,
+    nonce: String,
+    opaque: Option<String>,
+    algorithm: Algorithm,
+    // The -sess variants hash the client nonce into the key
+    session: bool,
+    // qop=auth; without it the RFC 2069 form is used
+    qop: bool,
+    // Requests sent with this nonce so far
+    count: u32,
+}
+
+// `key=value` and `key="quoted, value"` pairs of a challenge
+fn params(s: &str) -> Vec<(String, String)> {
+    let mut out = Vec::new();
+    let mut rest = s.trim();
+    while let Some((key, after)) = rest.split_once('=') {
+        let key = key.trim().trim_start_matches(',').trim().to_ascii_lowercase();
+        let after = after.trim_start();
+        let (value, remaining) = match after.strip_prefix('"') {
+            Some(quoted) => {
+                let mut value = String::new();
+                let mut chars = quoted.char_indices();
+                let mut end = quoted.len();
+                while let Some((i, c)) = chars.next() {
+                    match c {
+                        '\\' => value.extend(chars.next().map(|(_, c)| c)),
+                        '"' => {
+                            end = i + 1;
+                            break;
+                        }
+                        c => value.push(c),
+                    }
+                }
+                (value, &quoted[end..])
+            }
+            None => {
+                let end = after.find(',').unwrap_or(after.len());
+                (after[..end].trim().to_string(), &after[end..])
+            }
+        };
+        out.push((key, value));
+        rest = remaining.trim_start().trim_start_matches(',');
+    }
+    out
+}
+
+impl Challenge {
+    fn parse(header: &str) -> Option<Self> {
+        let (scheme, rest) = header.trim().split_once(' ')?;
+        if !scheme.eq_ignore_ascii_case("digest") {
+            return None;
+        }
+        let params = params(rest);
+        let get = |name: &str| params.iter().find(|(k, _)| k == name).map(|(_, v)| v.clone());
+        let algorithm = get("algorithm").unwrap_or("MD5".to_string()).to_ascii_uppercase();
+        let (algorithm, session) = match algorithm.as_str() {
+            "MD5" => (Algorithm::Md5, false),
+            "MD5-SESS" => (Algorithm::Md5, true),
+            "SHA-256" => (Algorithm::Sha256, false),
+            "SHA-256-SESS" => (Algorithm::Sha256, true),
+            _ => return None,
+        };
+        // Only qop=auth; auth-int would need the body
+        let qop = match get("qop") {
+            Some(qop) if qop.split(',').any(|q| q.trim() == "auth") => true,
+            Some(_) => return None,
+            None => false,
+        };
+        Some(Challenge {
+            realm: get("realm").unwrap_or_default(),
+            nonce: get("nonce")?,
+            opaque: get("opaque"),
+            algorithm,
+            session,
+            qop,
+            count: 0,
+        })
+    }
+}
+
+struct Credentials {
+    user: String,
+    password: String,
+    basic: HeaderValue,
+}
+
+impl Credentials {
+    fn new(user: &str, password: &str) -> Result<Self, String> {
+        Ok(Credentials {
+            user: user.to_string(),
+            password: password.to_string(),
+            basic: basic_authorization(user, password)?,
+        })
+    }
+}
+
+pub struct UpstreamAuth {
+    // The configured ones first, then the alternates
+    credentials: Vec<Credentials>,
+    // Index of the ones in use
+    current: AtomicUsize,
+    // Whether the ones in use replaced refused ones and haven't been
+    // accepted yet
+    switched: AtomicBool,
+    digest: Mutex<Option<Challenge>>,
+}
+
+impl UpstreamAuth {
+    pub fn new(user: &str, password: &str) -> Result<Self, String> {
+        Ok(UpstreamAuth {
+            credentials: vec![Credentials::new(user, password)?],
+            current: AtomicUsize::new(0),
+            switched: AtomicBool::new(false),
+            digest: Mutex::new(None),
+        })
+    }
+
+    // Credentials to fall back on, from `USER:PASSWORD` lines
+    pub fn with_alternates(mut self, lines: &str) -> Result<Self, String> {
+        for line in lines.lines().map(|l| l.trim()).filter(|l| !l.is_empty() && !l.starts_with('#')) {
+            let (user, password) = line.split_once(':').ok_or("expected USER:PASSWORD lines")?;
+            self.credentials.push(Credentials::new(user, password)?);
+        }
+        Ok(self)
+    }
+
+    // How many credentials there are to try
+    pub fn count(&self) -> usize {
+        self.credentials.len()
+    }
+
+    // Index of the credentials authorization() currently uses
+    pub fn in_use(&self) -> usize {
+        self.current.load(Ordering::Relaxed)
+    }
+
+    // The Authorization for `method` on the upstream's request target
+    pub fn authorization(&self, method: &Method, target: &str) -> HeaderValue {
+        let credentials = &self.credentials[self.in_use()];
+        let mut digest = self.digest.lock().unwrap();
+        let Some(challenge) = digest.as_mut() else {
+            return credentials.basic.clone();
+        };
+        challenge.count += 1;
+        let count = format!("{:08x}", challenge.count);
+        let cnonce = format!("{:016x}", RandomState::new().hash_one(challenge.count));
+        let hash = |s: &str| challenge.algorithm.digest(s);
+        let mut key = hash(&format!("{}:{}:{}", credentials.user, challenge.realm, credentials.password));
+        if challenge.session {
+            key = hash(&format!("{}:{}:{}", key, challenge.nonce, cnonce));
+        }
+        let request = hash(&format!("{}:{}", method, target));
+        let response = if challenge.qop {
+            hash(&format!("{}:{}:{}:{}:auth:{}", key, challenge.nonce, count, cnonce, request))
+        } else {
+            hash(&format!("{}:{}:{}", key, challenge.nonce, request))
+        };
+        let quote = |s: &str| format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""));
+        let mut fields = vec![
+            format!("username={}", quote(&credentials.user)),
+            format!("realm={}", quote(&challenge.realm)),
+            format!("nonce={}", quote(&challenge.nonce)),
+            format!("uri={}", quote(target)),
+            format!("response=\"{}\"", response),
+            format!(
+                "algorithm={}{}",
+                challenge.algorithm.name(),
+                if challenge.session { "-sess" } else { "" }
+            ),
+        ];
+        if challenge.qop {
+            fields.push(format!("qop=auth, nc={}, cnonce=\"{}\"", count, cnonce));
+        }
+        if let Some(opaque) = &challenge.opaque {
+            fields.push(format!("opaque={}", quote(opaque)));
+        }
+        let mut value = HeaderValue::from_str(&format!("Digest {}", fields.join(", "))).unwrap_or(credentials.basic.clone());
+        value.set_sensitive(true);
+        value
+    }
+
+    // Take up a Digest challenge from a 401; true if the request is worth
+    // sending again, i.e. the challenge is new rather than a refusal of
+    // the credentials just computed for it
+    pub fn challenged(&self, headers: &HeaderMap) -> bool {
+        let Some(challenge) = headers
+            .get_all(WWW_AUTHENTICATE)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .filter_map(Challenge::parse)
+            // The strongest one offered
+            .max_by_key(|c| c.algorithm == Algorithm::Sha256)
+        else {
+            return false;
+        };
+        let mut digest = self.digest.lock().unwrap();
+        if digest.as_ref().is_some_and(|known| known.nonce == challenge.nonce) {
+            return false;
+        }
+        if digest.is_none() {
+            tracing::info!("The upstream asks for Digest auth ({}), switching from Basic", challenge.algorithm.name());
+        }
+        *digest = Some(challenge);
+        true
+    }
+
+    // Take up a 401 to a request sent with the credentials `used`; true if
+    // the request is worth sending again, for a new challenge or because
+    // other credentials are up next
+    pub fn refused(&self, used: usize, headers: &HeaderMap) -> bool {
+        if self.challenged(headers) {
+            return true;
+        }
+        if self.credentials.len() == 1 {
+            return false;
+        }
+        // Another request may have moved on already
+        let next = (used + 1) % self.credentials.len();
+        if self.current.compare_exchange(used, next, Ordering::Relaxed, Ordering::Relaxed).is_ok() {
+            tracing::warn!(
+                "The upstream refused credentials {} of {} ({}), trying {} ({})",
+                used + 1,
+                self.credentials.len(),
+                self.credentials[used].user,
+                next + 1,
+                self.credentials[next].user
+            );
+            self.switched.store(true, Ordering::Relaxed);
+        }
+        true
+    }
+
+    // A request sent with the credentials `used` got through
+    pub fn accepted(&self, used: usize) {
+        if used == self.in_use() && self.switched.swap(false, Ordering::Relaxed) {
+            tracing::info!(
+                "The upstream accepted credentials {} of {} ({})",
+                used + 1,
+                self.credentials.len(),
+                self.credentials[used].user
+            );
+        }
+    }
+}
@@ -0,0 +1,100 @@
+use std::fmt;
+
+use hyper::StatusCode;
+
+use crate::phase::Phase;
+
+/// Why a request got no proper answer from the upstream. The fallback, the
+/// circuit breaker, the statistics and the response hooks (as an extension
+/// of the answer) all go by these categories.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UpstreamError {
+    /// The upstream's host name didn't resolve
+    DnsFailure,
+    /// The connection was refused, or dropped before an answer came
+    ConnectRefused,
+    /// The TLS handshake with an https:// upstream failed
+    TlsError,
+    /// No answer within the upstream's timeout; `phase` is what took too long
+    Timeout { phase: Phase },
+    /// The upstream, or a gateway in front of it, answered 502, 503 or 504
+    BadGateway { status: StatusCode },
+}
+
+impl UpstreamError {
+    // A connection that failed in `phase`
+    pub(crate) fn failed(phase: Phase) -> Self {
+        match phase {
+            Phase::Dns => UpstreamError::DnsFailure,
+            Phase::Tls => UpstreamError::TlsError,
+            Phase::Connect | Phase::FirstByte => UpstreamError::ConnectRefused,
+        }
+    }
+
+    // An answer that only passes on a failure further up
+    pub(crate) fn answered(status: StatusCode) -> Option<Self> {
+        matches!(status.as_u16(), 502..=504).then_some(UpstreamError::BadGateway { status })
+    }
+
+    /// The category as a label for statistics and logs, e.g. `dns_failure`
+    pub fn label(&self) -> &'static str {
+        match self {
+            UpstreamError::DnsFailure => "dns_failure",
+            UpstreamError::ConnectRefused => "connect_refused",
+            UpstreamError::TlsError => "tls_error",
+            UpstreamError::Timeout { .. } => "timeout",
+            UpstreamError::BadGateway { .. } => "bad_gateway",
+        }
+    }
+
+    // A label as written by `label`, or one of the older reasons
+    pub(crate) fn from_label(label: &str) -> Option<Self> {
+        match label {
+            "dns_failure" | "dns" => Some(UpstreamError::DnsFailure),
+            "connect_refused" | "closed" => Some(UpstreamError::ConnectRefused),
+            "tls_error" => Some(UpstreamError::TlsError),
+            "timeout" => Some(UpstreamError::Timeout { phase: Phase::FirstByte }),
+            _ => None,
+        }
+    }
+
+    /// The fallback reason the error folder is named by: `dns`, `closed`
+    /// or `timeout`
+    pub fn reason(&self) -> &'static str {
+        match self {
+            UpstreamError::DnsFailure => "dns",
+            UpstreamError::Timeout { .. } => "timeout",
+            UpstreamError::ConnectRefused | UpstreamError::TlsError | UpstreamError::BadGateway { .. } => "closed",
+        }
+    }
+
+    /// The phase a timeout happened in
+    pub fn phase(&self) -> Option<Phase> {
+        match self {
+            UpstreamError::Timeout { phase } => Some(*phase),
+            _ => None,
+        }
+    }
+
+    /// The status of an error passed on as it is: 504 for a timeout, 502
+    /// for the rest, or what the upstream answered
+    pub fn status(&self) -> StatusCode {
+        match self {
+            UpstreamError::Timeout { .. } => StatusCode::GATEWAY_TIMEOUT,
+            UpstreamError::BadGateway { status } => *status,
+            _ => StatusCode::BAD_GATEWAY,
+        }
+    }
+}
+
+impl fmt::Display for UpstreamError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UpstreamError::DnsFailure => write!(f, "dns failure"),
+            UpstreamError::ConnectRefused => write!(f, "connection refused"),
+            UpstreamError::TlsError => write!(f, "tls error"),
+            UpstreamError::Timeout { phase } => write!(f, "timeout ({})", phase.name()),
+            UpstreamError::BadGateway { status } => write!(f, "bad gateway ({})", status.as_u16()),
+        }
+    }
+}
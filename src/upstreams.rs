@@ -0,0 +1,318 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use hyper::http::uri::PathAndQuery;
+use hyper::{Client, Uri};
+
+use crate::base_path::{self, BasePath};
+use crate::breaker::{Breaker, Recovery};
+use crate::capabilities::Probe;
+use crate::fallback::Fallback;
+use crate::mirror::{self, Mirror};
+use crate::pinned::PinnedConnection;
+use crate::resolve::{Connector, Resolver};
+use crate::unix;
+use crate::virtual_tree::VirtualTree;
+use crate::warm::{self, WarmPool};
+
+// Retries wait 250 ms, 500 ms, 1 s, ... but never longer than MAX_BACKOFF
+const DEFAULT_BACKOFF: Duration = Duration::from_millis(250);
+const MAX_BACKOFF: Duration = Duration::from_secs(10);
+
+// One upstream share
+pub struct Upstream {
+    pub uri: Uri,
+    pub base_path: BasePath,
+    // Connections kept open ahead of time
+    pub warm: Option<Arc<WarmPool>>,
+    // Longest wait for a connection and the response head, not counting
+    // the time the request body takes to send
+    pub timeout: Duration,
+    // Further attempts for requests that don't modify the share, after a
+    // timeout or failed connection
+    pub retries: u32,
+    // Wait before the first retry, doubled for each one after it
+    pub backoff: Duration,
+    // What to answer when it can't be reached, unless a route says otherwise
+    pub fallback: Fallback,
+    // What its OPTIONS last said it supports
+    pub capabilities: Arc<Probe>,
+    // Set while it is taken for down after failing too often
+    pub breaker: Option<Arc<Breaker>>,
+    // A second server to try when it can't be reached
+    pub mirror: Option<Arc<Mirror>>,
+    // Writes under way, for draining it before a switch is done
+    pub writes: AtomicUsize,
+}
+
+// A write to an upstream, counted until dropped
+pub struct Writing<'a>(&'a AtomicUsize);
+
+impl Drop for Writing<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Upstream {
+    pub fn writing(&self) -> Writing<'_> {
+        self.writes.fetch_add(1, Ordering::Relaxed);
+        Writing(&self.writes)
+    }
+
+    // How long to wait before retry number `attempt`, counting from 1
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 1u32.checked_shl(attempt.saturating_sub(1)).unwrap_or(u32::MAX);
+        self.backoff.saturating_mul(factor).min(MAX_BACKOFF)
+    }
+}
+
+// The share's root on the upstream
+pub fn root_uri(uri: &Uri, base_path: &BasePath) -> Uri {
+    let mut parts = uri.clone().into_parts();
+    parts.path_and_query = Some(base_path.add(&PathAndQuery::from_static("/")));
+    Uri::from_parts(parts).expect("valid URI")
+}
+
+// How the folders of several upstreams are named at the root
+#[derive(Clone, Copy, PartialEq)]
+pub enum Naming {
+    // `/1/`, `/2/`, ... in the order given
+    Numbered,
+    // `/host_port/`
+    Host,
+}
+
+impl Naming {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match s.trim() {
+            "numbered" => Ok(Naming::Numbered),
+            "host" => Ok(Naming::Host),
+            _ => Err(format!("Unknown upstream naming (expected numbered or host): {}", s)),
+        }
+    }
+}
+
+// `nas:8080` becomes `nas_8080`; anything else that would need encoding too
+fn host_name(authority: &str) -> String {
+    // A socket by its file name
+    let socket = unix::socket(authority);
+    let authority = match socket.as_ref().and_then(|path| path.file_stem()) {
+        Some(stem) => stem.to_string_lossy(),
+        None => authority.into(),
+    };
+    authority
+        .trim_start_matches('[')
+        .replace("]:", ":")
+        .trim_end_matches(']')
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || matches!(c, '-' | '.') { c } else { '_' })
+        .collect()
+}
+
+// A remote's server, base path and authority
+fn address(remote: &str) -> Result<(Uri, BasePath, String), String> {
+    let (scheme, authority, base_path) = base_path::split_remote(remote)?;
+    let uri = format!("{}://{}", scheme, authority)
+        .parse::<Uri>()
+        .map_err(|e| format!("Invalid upstream {}: {}", remote, e))?;
+    Ok((uri, base_path, authority))
+}
+
+// The upstreams given on the command line. A single one is served at the
+// proxy's root; several get a folder each there instead. Either way a
+// remote may name its own mount path, which can be several levels deep.
+pub struct Upstreams {
+    // Folder name (empty for a single upstream) and upstream
+    mounts: Vec<(String, Upstream)>,
+}
+
+impl Upstreams {
+    // Each remote is `HOST:PORT`, `http[s]://HOST[:PORT][/BASE/PATH]` or
+    // `unix:/PATH`, optionally preceded by `/MOUNT/PATH=` (or `MOUNT/PATH=`)
+    pub fn parse(remotes: &[String], naming: Naming) -> Result<Self, String> {
+        let single = remotes.len() == 1;
+        let mut mounts: Vec<(String, Upstream)> = Vec::new();
+        for (i, remote) in remotes.iter().enumerate() {
+            // A HOST:PORT or URL has a colon before any `=`, a mount path
+            // without the leading slash mustn't
+            let (mount, remote) = match remote.split_once('=') {
+                Some((mount, remote)) if mount.starts_with('/') || !mount.contains(':') => {
+                    (Some(mount.trim_matches('/')), remote)
+                }
+                _ => (None, remote.as_str()),
+            };
+            if mount == Some("") && !single {
+                return Err(format!("Only a single upstream can be served at the root: {}", remote));
+            }
+            let (uri, base_path, authority) = address(remote)?;
+            let mut name = match (mount, single, naming) {
+                (Some(mount), _, _) => mount.to_string(),
+                (None, true, _) => String::new(),
+                (None, false, Naming::Numbered) => (i + 1).to_string(),
+                (None, false, Naming::Host) => host_name(&authority),
+            };
+            // The same host may serve several shares
+            let taken = |name: &str| mounts.iter().any(|(n, _)| n == name);
+            if taken(&name) {
+                let base = name.clone();
+                name = (2..).map(|n| format!("{}_{}", base, n)).find(|n| !taken(n)).expect("a free name");
+            }
+            let base_path = if name.is_empty() { base_path } else { base_path.mounted_at(&name) };
+            let capabilities = Arc::new(Probe::new(root_uri(&uri, &base_path)));
+            mounts.push((
+                name,
+                Upstream {
+                    uri,
+                    base_path,
+                    warm: None,
+                    timeout: Duration::from_secs(5),
+                    retries: 0,
+                    backoff: DEFAULT_BACKOFF,
+                    fallback: Fallback::Folder,
+                    capabilities,
+                    breaker: None,
+                    mirror: None,
+                    writes: AtomicUsize::new(0),
+                },
+            ));
+        }
+        Ok(Upstreams { mounts })
+    }
+
+    // Send the requests of folder `name` to `remote` instead, keeping the
+    // folder's settings; called before breakers, mirrors and warm
+    // connections are added
+    pub fn switch(&mut self, name: &str, remote: &str) -> Result<(), String> {
+        let upstream = self
+            .mounts
+            .iter_mut()
+            .find(|(n, _)| n == name)
+            .map(|(_, upstream)| upstream)
+            .ok_or(format!("No upstream is served at /{}", name))?;
+        let (uri, base_path, _) = address(remote)?;
+        let base_path = if name.is_empty() { base_path } else { base_path.mounted_at(name) };
+        upstream.capabilities = Arc::new(Probe::new(root_uri(&uri, &base_path)));
+        upstream.uri = uri;
+        upstream.base_path = base_path;
+        Ok(())
+    }
+
+    // Apply `[UPSTREAM=]VALUE` settings of --`option`: bare values to every
+    // upstream, then named ones to just that one, in whatever order given
+    pub fn configure(
+        &mut self,
+        option: &str,
+        specs: &[String],
+        mut apply: impl FnMut(&mut Upstream, &str) -> Result<(), String>,
+    ) -> Result<(), String> {
+        let (named, bare): (Vec<&String>, Vec<&String>) = specs.iter().partition(|s| s.contains('='));
+        for value in bare {
+            for (_, upstream) in &mut self.mounts {
+                apply(upstream, value)?;
+            }
+        }
+        for spec in named {
+            let (name, value) = spec.split_once('=').expect("partitioned on =");
+            let name = name.trim().trim_matches('/');
+            let upstream = self
+                .mounts
+                .iter_mut()
+                .find(|(n, _)| n == name)
+                .map(|(_, upstream)| upstream)
+                .ok_or(format!("--{} names an unknown upstream folder: {}", option, name))?;
+            apply(upstream, value)?;
+        }
+        Ok(())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &Upstream)> {
+        self.mounts.iter().map(|(name, upstream)| (name.as_str(), upstream))
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut Upstream> {
+        self.mounts.iter_mut().map(|(_, upstream)| upstream)
+    }
+
+    pub fn get(&self, index: usize) -> &Upstream {
+        &self.mounts[index].1
+    }
+
+    // The folder it is served as, empty at the root
+    pub fn mount(&self, index: usize) -> &str {
+        &self.mounts[index].0
+    }
+
+    // Index of the upstream serving a client path, the one with the longest
+    // matching mount; None for the root and anything else outside the
+    // upstream folders
+    pub fn route(&self, path: &str) -> Option<usize> {
+        if self.mounts.len() == 1 && self.mounts[0].0.is_empty() {
+            return Some(0);
+        }
+        let path = path.trim_start_matches('/');
+        self.mounts
+            .iter()
+            .enumerate()
+            .filter(|(_, (name, _))| {
+                path.strip_prefix(name.as_str())
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+            })
+            .max_by_key(|(_, (name, _))| name.len())
+            .map(|(index, _)| index)
+    }
+
+    // The root listing the upstream folders, when there are several
+    pub fn root(&self) -> VirtualTree {
+        self.mounts
+            .iter()
+            .fold(VirtualTree::default(), |tree, (name, _)| tree.collection(name))
+    }
+
+    // Keep `size` connections open to each upstream
+    pub fn warm_up(&mut self, size: usize, resolver: &Resolver) {
+        for (_, upstream) in &mut self.mounts {
+            let pool = Arc::new(WarmPool::new(&upstream.uri, size, resolver.clone()));
+            warm::spawn(pool.clone());
+            upstream.warm = Some(pool);
+        }
+    }
+
+    // Stop trying an upstream after `threshold` failed requests in a row
+    // until it answers again; called once the timeouts are configured
+    pub fn add_breakers(&mut self, threshold: u32, recovery: &Recovery, client: &Client<Connector>) {
+        for (_, upstream) in &mut self.mounts {
+            let uri = root_uri(&upstream.uri, &upstream.base_path);
+            let breaker = Breaker::new(threshold, uri, client.clone(), upstream.timeout, recovery.clone());
+            upstream.breaker = Some(Arc::new(breaker));
+        }
+    }
+
+    // Send requests the upstream can't take to a mirror given as
+    // `[UPSTREAM=]HOST:PORT`; like the breakers, once the timeouts are known
+    pub fn add_mirrors(&mut self, specs: &[String], client: &Client<Connector>) -> Result<(), String> {
+        self.configure("fallback-upstream", specs, |upstream, remote| {
+            let primary = root_uri(&upstream.uri, &upstream.base_path);
+            let mirror = Mirror::new(mirror::parse(remote)?, primary, client.clone(), upstream.timeout);
+            upstream.mirror = Some(Arc::new(mirror));
+            Ok(())
+        })
+    }
+
+    // The folders and servers pinned connections are opened to, in order
+    pub fn pinned_to(&self) -> Vec<(String, Uri)> {
+        self.mounts
+            .iter()
+            .map(|(name, upstream)| (name.clone(), upstream.uri.clone()))
+            .collect()
+    }
+
+    // Dedicated connections for one downstream connection, in upstream order
+    pub fn pinned(&self, resolver: &Resolver) -> Vec<PinnedConnection> {
+        self.mounts
+            .iter()
+            .map(|(_, upstream)| PinnedConnection::new(&upstream.uri, resolver.clone()))
+            .collect()
+    }
+}
@@ -0,0 +1,197 @@
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use hyper::header::CONTENT_LENGTH;
+use hyper::{Body, Response, StatusCode};
+
+use crate::clock;
+use crate::local_dir::percent_decode;
+use crate::multistatus::{self, PropName};
+use crate::stats::Counters;
+use crate::xml::Writer;
+
+// Traffic under chosen folders (--usage-path), counted by calendar month in
+// UTC and shown on each folder as properties in PROPFIND answers, so users
+// see what they used from any DAV client:
+//
+//     <u:bytes-downloaded xmlns:u="urn:proxy-optional-webdav:usage">1048576</u:bytes-downloaded>
+//
+// besides `period` (the month, e.g. 2026-10), `requests` and
+// `bytes-uploaded`. They come with allprop and when asked for by name, in
+// a propstat of their own. A folder within another counts for both. The
+// counts are part of the runtime state, so a restart keeps the month's.
+
+pub const NAMESPACE: &str = "urn:proxy-optional-webdav:usage";
+
+// PROPFINDs with larger bodies are answered without the properties
+pub const MAX_REQUEST: usize = 64 * 1024;
+
+const PROPERTIES: [&str; 4] = ["period", "requests", "bytes-uploaded", "bytes-downloaded"];
+
+pub struct Usage {
+    // Without the trailing slash
+    folders: Vec<(String, Arc<Counters>)>,
+    // (year, month) being counted
+    period: Mutex<(i32, u32)>,
+}
+
+fn this_month() -> (i32, u32) {
+    let secs = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs() as i64);
+    let (year, month, _) = clock::civil_from_days(secs.div_euclid(86400));
+    (year, month)
+}
+
+fn period_name((year, month): (i32, u32)) -> String {
+    format!("{:04}-{:02}", year, month)
+}
+
+impl Usage {
+    pub fn new(folders: &[String]) -> Result<Self, String> {
+        let folders = folders
+            .iter()
+            .map(|folder| {
+                let folder = folder.trim();
+                if !folder.starts_with('/') || folder == "/" {
+                    return Err(format!("Invalid --usage-path (expected a folder like /public): {}", folder));
+                }
+                Ok((folder.trim_end_matches('/').to_string(), Arc::default()))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Usage {
+            folders,
+            period: Mutex::new(this_month()),
+        })
+    }
+
+    // Start counting afresh when the month has changed
+    fn roll(&self) -> (i32, u32) {
+        let now = this_month();
+        let mut period = self.period.lock().unwrap();
+        if *period != now {
+            for (_, counters) in &self.folders {
+                counters.set(0, 0, 0);
+            }
+            *period = now;
+        }
+        now
+    }
+
+    fn folder(&self, path: &str) -> Option<&Arc<Counters>> {
+        let path = percent_decode(path)?;
+        let path = path.trim_end_matches('/');
+        self.folders.iter().find(|(folder, _)| folder == path).map(|(_, counters)| counters)
+    }
+
+    // The counters a request for `path` goes to
+    pub fn counters(&self, path: &str) -> Vec<Arc<Counters>> {
+        self.roll();
+        let Some(path) = percent_decode(path) else {
+            return Vec::new();
+        };
+        self.folders
+            .iter()
+            .filter(|(folder, _)| path == *folder || path.strip_prefix(folder.as_str()).is_some_and(|rest| rest.starts_with('/')))
+            .map(|(_, counters)| counters.clone())
+            .collect()
+    }
+
+    // Whether a PROPFIND of `path` may list one of the folders
+    pub fn listed(&self, path: &str) -> bool {
+        let Some(path) = percent_decode(path) else {
+            return false;
+        };
+        let path = path.trim_end_matches('/');
+        self.folders
+            .iter()
+            .any(|(folder, _)| folder.strip_prefix(path).is_some_and(|rest| rest.is_empty() || rest.starts_with('/')))
+    }
+
+    // Add the properties to the folders' responses in a multistatus, all of
+    // them or those in `requested`
+    pub async fn apply_response(
+        &self,
+        response: Response<Body>,
+        requested: Option<&[PropName]>,
+    ) -> Result<Response<Body>, hyper::Error> {
+        if response.status() != StatusCode::MULTI_STATUS {
+            return Ok(response);
+        }
+        let (mut parts, body) = response.into_parts();
+        let bytes = hyper::body::to_bytes(body).await?;
+        let body = match std::str::from_utf8(&bytes) {
+            Ok(xml) => {
+                parts.headers.remove(CONTENT_LENGTH);
+                Body::from(self.annotate(xml, requested))
+            }
+            Err(_) => Body::from(bytes),
+        };
+        Ok(Response::from_parts(parts, body))
+    }
+
+    fn annotate(&self, body: &str, requested: Option<&[PropName]>) -> String {
+        let period = self.roll();
+        let wanted: Vec<&str> = PROPERTIES
+            .into_iter()
+            .filter(|property| {
+                requested.is_none_or(|props| props.iter().any(|p| p.namespace == NAMESPACE && p.name == *property))
+            })
+            .collect();
+        if wanted.is_empty() {
+            return body.to_string();
+        }
+        multistatus::extend_responses(body, |href, prefix| {
+            // Hrefs may be absolute URLs
+            let path = match href.find("://") {
+                Some(scheme) => href[scheme + 3..].find('/').map_or("/", |slash| &href[scheme + 3 + slash..]),
+                None => href,
+            };
+            let (requests, up, down) = self.folder(path)?.snapshot();
+            let mut w = Writer::new(prefix);
+            w.open("propstat").open("prop");
+            for property in &wanted {
+                let value = match *property {
+                    "period" => period_name(period),
+                    "requests" => requests.to_string(),
+                    "bytes-uploaded" => up.to_string(),
+                    _ => down.to_string(),
+                };
+                let mut u = Writer::new("u");
+                u.open_with(property, &[("xmlns:u", NAMESPACE)]).text(&value);
+                w.raw(&u.finish());
+            }
+            w.close().element("status", "HTTP/1.1 200 OK");
+            Some(w.finish())
+        })
+    }
+
+    // Tab-separated `folder period requests up down` lines for the runtime
+    // state
+    pub fn export(&self) -> Vec<String> {
+        let period = period_name(self.roll());
+        self.folders
+            .iter()
+            .map(|(folder, counters)| {
+                let (requests, up, down) = counters.snapshot();
+                format!("{}/\t{}\t{}\t{}\t{}", folder, period, requests, up, down)
+            })
+            .collect()
+    }
+
+    // A line of `export`; false if it isn't one. Counts of another month
+    // are dropped.
+    pub fn restore(&self, fields: &[&str]) -> bool {
+        let [folder, period, requests, up, down] = fields else {
+            return false;
+        };
+        let (Ok(requests), Ok(up), Ok(down)) = (requests.parse(), up.parse(), down.parse()) else {
+            return false;
+        };
+        let Some(counters) = self.folder(folder) else {
+            return false;
+        };
+        if *period == period_name(self.roll()) {
+            counters.set(requests, up, down);
+        }
+        true
+    }
+}
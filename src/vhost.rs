@@ -0,0 +1,64 @@
+use hyper::header::{HeaderMap, HeaderValue, HOST};
+use hyper::http::uri::{Authority, PathAndQuery};
+use hyper::{Body, Response, StatusCode, Uri};
+
+// Host names the proxy answers for when a client sends an absolute-form
+// request target (`PUT http://host/path HTTP/1.1`), as proxy-aware clients
+// and some intermediaries do
+#[derive(Default)]
+pub struct VirtualHosts {
+    // Lowercase, with a port only if the name was given with one
+    names: Vec<String>,
+}
+
+fn misdirected(authority: &Authority) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::MISDIRECTED_REQUEST)
+        .header("Content-Type", "text/plain")
+        .body(Body::from(format!("This proxy does not serve {}\n", authority)))
+        .expect("response builder")
+}
+
+impl VirtualHosts {
+    pub fn add(&mut self, name: &str) {
+        self.names.push(name.trim().to_ascii_lowercase());
+    }
+
+    fn matches(&self, authority: &Authority) -> bool {
+        let host = authority.host().to_ascii_lowercase();
+        let full = authority.as_str().to_ascii_lowercase();
+        self.names.iter().any(|name| *name == host || *name == full)
+    }
+
+    // For an absolute-form target, check its authority and make the Host
+    // header agree with it (RFC 9112 3.2.2). Without configured names the
+    // authority has to match the Host header the client sent. Returns the
+    // rejection to send, if any.
+    pub fn check(&self, uri: &Uri, headers: &mut HeaderMap) -> Option<Response<Body>> {
+        let authority = uri.authority()?;
+        let allowed = if self.names.is_empty() {
+            headers
+                .get(HOST)
+                .and_then(|h| h.to_str().ok())
+                .is_none_or(|h| h.eq_ignore_ascii_case(authority.as_str()))
+        } else {
+            self.matches(authority)
+        };
+        if !allowed {
+            return Some(misdirected(authority));
+        }
+        if let Ok(host) = HeaderValue::from_str(authority.as_str()) {
+            headers.insert(HOST, host);
+        }
+        None
+    }
+}
+
+// The origin-form target to send upstream: the path and query of an
+// absolute-form target, and `/` for the asterisk form of OPTIONS
+pub fn origin_form(uri: &Uri) -> PathAndQuery {
+    match uri.path_and_query() {
+        Some(pq) if pq.as_str() != "*" && pq.as_str().starts_with('/') => pq.clone(),
+        _ => PathAndQuery::from_static("/"),
+    }
+}
@@ -0,0 +1,55 @@
+use std::path::Path;
+
+// Static files the proxy lists at the share root and serves itself, next to
+// the upstream's own entries: instructions such as HOW_TO_CONNECT.txt or a
+// policy notice. Their content is read once at startup.
+pub struct VirtualFile {
+    pub name: String,
+    pub content: Vec<u8>,
+    pub content_type: &'static str,
+}
+
+pub fn content_type(name: &str) -> &'static str {
+    let extension = Path::new(name).extension().and_then(|e| e.to_str()).unwrap_or("");
+    match extension.to_ascii_lowercase().as_str() {
+        "txt" | "md" => "text/plain; charset=utf-8",
+        "html" | "htm" => "text/html; charset=utf-8",
+        "url" => "application/internet-shortcut",
+        "pdf" => "application/pdf",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        _ => "application/octet-stream",
+    }
+}
+
+fn split(spec: &str, option: &str) -> Result<(String, String), String> {
+    let (name, value) = spec
+        .split_once('=')
+        .ok_or(format!("Invalid --{} (expected NAME=...): {}", option, spec))?;
+    let name = name.trim();
+    if name.is_empty() || name.contains('/') || name == "." || name == ".." {
+        return Err(format!("--{} needs a plain file name: {}", option, spec));
+    }
+    Ok((name.to_string(), value.to_string()))
+}
+
+impl VirtualFile {
+    // `NAME=PATH`, a local file served under NAME
+    pub fn from_file(spec: &str) -> Result<Self, String> {
+        let (name, path) = split(spec, "virtual-file")?;
+        let content = std::fs::read(&path).map_err(|e| format!("Failed to read --virtual-file {}: {}", path, e))?;
+        let content_type = content_type(&name);
+        Ok(VirtualFile { name, content, content_type })
+    }
+
+    // `NAME=TEXT`
+    pub fn from_text(spec: &str) -> Result<Self, String> {
+        let (name, text) = split(spec, "virtual-text")?;
+        let content_type = content_type(&name);
+        Ok(VirtualFile {
+            name,
+            content: text.into_bytes(),
+            content_type,
+        })
+    }
+}
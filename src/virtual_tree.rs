@@ -0,0 +1,394 @@
+use hyper::header::HeaderMap;
+use hyper::{Body, Method, Response, StatusCode};
+
+use crate::multistatus::{self, Minimal, PropName};
+use crate::xml::{self, Writer};
+
+// In-memory WebDAV namespace for everything the proxy answers by itself,
+// e.g. the error fallback folders and the virtual statistics file.
+// Paths are given unencoded; the tree can be mounted below a base href.
+pub struct VirtualTree {
+    base: String,
+    root: Node,
+}
+
+enum Kind {
+    Collection,
+    File { content: Vec<u8>, content_type: String },
+}
+
+struct Node {
+    kind: Kind,
+    // Extra DAV: properties, written as given
+    props: Vec<(String, String)>,
+    children: Vec<(String, Node)>,
+}
+
+impl Node {
+    fn collection() -> Self {
+        Node {
+            kind: Kind::Collection,
+            props: Vec::new(),
+            children: Vec::new(),
+        }
+    }
+
+    fn child(&self, name: &str) -> Option<&Node> {
+        self.children.iter().find(|(n, _)| n == name).map(|(_, node)| node)
+    }
+
+    fn child_mut(&mut self, name: &str) -> &mut Node {
+        let pos = match self.children.iter().position(|(n, _)| n == name) {
+            Some(pos) => pos,
+            None => {
+                self.children.push((name.to_string(), Node::collection()));
+                self.children.len() - 1
+            }
+        };
+        &mut self.children[pos].1
+    }
+}
+
+enum Value {
+    Text(String),
+    Empty,
+    Collection,
+}
+
+// What a PROPFIND asked for
+pub struct PropRequest<'a> {
+    // None for allprop
+    pub props: Option<&'a [PropName]>,
+    pub minimal: Minimal,
+}
+
+impl PropRequest<'_> {
+    pub const ALL: PropRequest<'static> = PropRequest {
+        props: None,
+        minimal: Minimal::No,
+    };
+}
+
+fn segments(path: &str) -> impl Iterator<Item = &str> {
+    path.split('/').filter(|s| !s.is_empty())
+}
+
+// Percent-encode a path segment for use in an href
+pub fn encode_segment(name: &str) -> String {
+    let mut out = String::with_capacity(name.len());
+    for b in name.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => out.push(b as char),
+            _ => out.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    out
+}
+
+// Value of the Depth header; a missing header means infinity for PROPFIND
+pub fn depth(headers: &HeaderMap) -> u32 {
+    match headers.get("Depth").and_then(|d| d.to_str().ok()).map(str::trim) {
+        Some("0") => 0,
+        Some("1") => 1,
+        _ => u32::MAX,
+    }
+}
+
+impl Default for VirtualTree {
+    fn default() -> Self {
+        VirtualTree::at("/")
+    }
+}
+
+impl VirtualTree {
+    // A tree whose root is listed at the (already encoded) href `base`
+    pub fn at(base: &str) -> Self {
+        VirtualTree {
+            base: base.trim_end_matches('/').to_string(),
+            root: Node::collection(),
+        }
+    }
+
+    fn node_mut(&mut self, path: &str) -> &mut Node {
+        let mut node = &mut self.root;
+        for segment in segments(path) {
+            node = node.child_mut(segment);
+        }
+        node
+    }
+
+    fn node(&self, path: &str) -> Option<&Node> {
+        let mut node = &self.root;
+        for segment in segments(path) {
+            node = node.child(segment)?;
+        }
+        Some(node)
+    }
+
+    // Add a collection, along with any missing parents
+    pub fn collection(mut self, path: &str) -> Self {
+        self.node_mut(path);
+        self
+    }
+
+    pub fn file(mut self, path: &str, content: impl Into<Vec<u8>>, content_type: &str) -> Self {
+        self.node_mut(path).kind = Kind::File {
+            content: content.into(),
+            content_type: content_type.to_string(),
+        };
+        self
+    }
+
+    // Set a DAV: property, e.g. a displayname differing from the path segment
+    pub fn property(mut self, path: &str, name: &str, value: &str) -> Self {
+        let props = &mut self.node_mut(path).props;
+        props.retain(|(n, _)| n != name);
+        props.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    fn href(&self, path: &[&str], collection: bool) -> String {
+        let mut href = self.base.clone();
+        for segment in path {
+            href.push('/');
+            href.push_str(&encode_segment(segment));
+        }
+        if collection || href.is_empty() {
+            href.push('/');
+        }
+        href
+    }
+
+    fn render(&self, w: &mut Writer, path: &mut Vec<String>, node: &Node, depth: u32, request: &PropRequest) {
+        let segments: Vec<&str> = path.iter().map(String::as_str).collect();
+        let collection = matches!(node.kind, Kind::Collection);
+        let mut available: Vec<(&str, Value)> = Vec::new();
+        if let Some(name) = segments.last() {
+            available.push(("displayname", Value::Text(name.to_string())));
+        }
+        match &node.kind {
+            Kind::Collection => available.push(("resourcetype", Value::Collection)),
+            Kind::File { content, content_type } => {
+                available.push(("resourcetype", Value::Empty));
+                available.push(("getcontentlength", Value::Text(content.len().to_string())));
+                available.push(("getcontenttype", Value::Text(content_type.clone())));
+            }
+        }
+        for (name, value) in &node.props {
+            available.retain(|(n, _)| n != name);
+            available.push((name, Value::Text(value.clone())));
+        }
+
+        let (found, missing): (Vec<_>, Vec<_>) = match request.props {
+            None => (available.iter().collect(), Vec::new()),
+            Some(props) => {
+                let found = available
+                    .iter()
+                    .filter(|(n, _)| props.iter().any(|p| p.namespace == "DAV:" && p.name == *n))
+                    .collect();
+                let missing = props
+                    .iter()
+                    .filter(|p| p.namespace != "DAV:" || !available.iter().any(|(n, _)| *n == p.name))
+                    .collect();
+                (found, missing)
+            }
+        };
+
+        // Every response needs at least one propstat, if only an empty one
+        let minimal = request.minimal != Minimal::No;
+        w.open("response").element("href", &self.href(&segments, collection));
+        if !found.is_empty() || missing.is_empty() || minimal {
+            w.open("propstat").open("prop");
+            for (name, value) in found {
+                match value {
+                    Value::Text(text) => w.element(name, text),
+                    Value::Empty => w.empty(name),
+                    Value::Collection => w.open(name).empty("collection").close(),
+                };
+            }
+            w.close().element("status", "HTTP/1.1 200 OK");
+            w.close();
+        }
+        // Brief and return=minimal clients don't want to hear what is missing
+        if !missing.is_empty() && !minimal {
+            w.open("propstat").open("prop");
+            for prop in missing {
+                if prop.namespace == "DAV:" {
+                    w.empty(&prop.name);
+                } else {
+                    w.raw(&format!("<x:{} xmlns:x=\"{}\"/>", prop.name, xml::escape(&prop.namespace)));
+                }
+            }
+            w.close().element("status", "HTTP/1.1 404 Not Found");
+            w.close();
+        }
+        w.close();
+        if depth > 0 {
+            for (name, child) in &node.children {
+                path.push(name.clone());
+                self.render(w, path, child, depth - 1, request);
+                path.pop();
+            }
+        }
+    }
+
+    // The <response> elements for `path` and its members down to `depth`,
+    // written with the given DAV: prefix so they can be merged into an
+    // upstream listing
+    pub fn responses(&self, path: &str, depth: u32, prefix: &str, request: &PropRequest) -> Option<String> {
+        let node = self.node(path)?;
+        let mut w = Writer::new(prefix);
+        let mut segments = segments(path).map(str::to_string).collect();
+        self.render(&mut w, &mut segments, node, depth, request);
+        Some(w.finish())
+    }
+
+    // A complete 207 listing of `path`
+    pub fn multistatus(&self, path: &str, depth: u32, request: &PropRequest) -> Option<Response<Body>> {
+        let responses = self.responses(path, depth, "d", request)?;
+        let mut builder = Response::builder()
+            .status(207)
+            .header("Content-Type", "application/xml; charset=utf-8");
+        if request.minimal == Minimal::Prefer {
+            builder = builder.header("Preference-Applied", "return=minimal");
+        }
+        Some(builder.body(Body::from(multistatus::document(&responses))).expect("response builder"))
+    }
+
+    // Answer a request for `path` (relative to the base), or None if the
+    // tree has no such entry. The tree is read-only.
+    pub fn serve(&self, method: &Method, path: &str, headers: &HeaderMap, body: &[u8]) -> Option<Response<Body>> {
+        let node = self.node(path)?;
+        let builder = Response::builder();
+        let response = match (method, &node.kind) {
+            (&Method::GET, Kind::File { content, content_type }) | (&Method::HEAD, Kind::File { content, content_type }) => {
+                let body = if *method == Method::GET { Body::from(content.clone()) } else { Body::empty() };
+                builder
+                    .header("Content-Type", content_type.as_str())
+                    .header("Content-Length", content.len())
+                    .body(body)
+            }
+            (&Method::OPTIONS, _) => builder
+                .header("DAV", "1")
+                .header("Allow", "OPTIONS, GET, HEAD, PROPFIND")
+                .body(Body::empty()),
+            _ if method.as_str() == "PROPFIND" => {
+                let props = multistatus::requested_props(body);
+                let request = PropRequest {
+                    props: props.as_deref(),
+                    minimal: multistatus::minimal(headers),
+                };
+                return self.multistatus(path, depth(headers), &request);
+            }
+            _ => builder
+                .status(StatusCode::METHOD_NOT_ALLOWED)
+                .header("Allow", "OPTIONS, GET, HEAD, PROPFIND")
+                .body(Body::empty()),
+        };
+        Some(response.expect("response builder"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tree() -> VirtualTree {
+        VirtualTree::default()
+            .collection("TIMEOUT/deeper")
+            .collection("a b")
+            .file("x&y<z>.txt", "hello", "text/plain; charset=utf-8")
+            .property("a b", "displayname", "Tom & \"Jerry\" <1>")
+    }
+
+    async fn listing(tree: &VirtualTree, path: &str, depth: &str, body: &str) -> (StatusCode, String) {
+        let mut headers = HeaderMap::new();
+        headers.insert("depth", depth.parse().unwrap());
+        let method = Method::from_bytes(b"PROPFIND").unwrap();
+        let response = tree.serve(&method, path, &headers, body.as_bytes()).expect("in the tree");
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    fn hrefs(xml: &str) -> Vec<&str> {
+        xml.split("<d:href>").skip(1).map(|rest| &rest[..rest.find("</d:href>").unwrap()]).collect()
+    }
+
+    fn assert_multistatus(xml: &str) {
+        assert_eq!(xml::root_element(xml), Ok(("DAV:".to_string(), "multistatus".to_string())), "{}", xml);
+    }
+
+    #[tokio::test]
+    async fn depth_0_lists_only_the_resource() {
+        let (status, xml) = listing(&tree(), "/", "0", "").await;
+        assert_eq!(status, StatusCode::MULTI_STATUS);
+        assert_multistatus(&xml);
+        assert_eq!(hrefs(&xml), ["/"]);
+    }
+
+    #[tokio::test]
+    async fn depth_1_lists_the_members() {
+        let (_, xml) = listing(&tree(), "/", "1", "").await;
+        assert_multistatus(&xml);
+        assert_eq!(hrefs(&xml), ["/", "/TIMEOUT/", "/a%20b/", "/x%26y%3Cz%3E.txt"]);
+    }
+
+    #[tokio::test]
+    async fn depth_infinity_lists_everything() {
+        let (_, xml) = listing(&tree(), "/", "infinity", "").await;
+        assert_multistatus(&xml);
+        assert_eq!(hrefs(&xml), ["/", "/TIMEOUT/", "/TIMEOUT/deeper/", "/a%20b/", "/x%26y%3Cz%3E.txt"]);
+    }
+
+    #[tokio::test]
+    async fn names_and_properties_are_escaped() {
+        let (_, xml) = listing(&tree(), "/", "1", "").await;
+        assert!(xml.contains("<d:displayname>x&amp;y&lt;z&gt;.txt</d:displayname>"), "{}", xml);
+        assert!(xml.contains("<d:displayname>Tom &amp; &quot;Jerry&quot; &lt;1&gt;</d:displayname>"), "{}", xml);
+        assert!(xml.contains("<d:getcontentlength>5</d:getcontentlength>"), "{}", xml);
+        assert!(xml.contains("<d:resourcetype><d:collection/></d:resourcetype>"), "{}", xml);
+    }
+
+    #[tokio::test]
+    async fn members_are_listed_below_the_base() {
+        let tree = VirtualTree::at("/dav/my%20share").collection("TIMEOUT");
+        let (_, xml) = listing(&tree, "/", "1", "").await;
+        assert_multistatus(&xml);
+        assert_eq!(hrefs(&xml), ["/dav/my%20share/", "/dav/my%20share/TIMEOUT/"]);
+    }
+
+    #[tokio::test]
+    async fn unknown_properties_are_not_found() {
+        let body = r#"<?xml version="1.0"?><propfind xmlns="DAV:" xmlns:o="urn:other"><prop><getcontentlength/><quota-used-bytes/><o:color/></prop></propfind>"#;
+        let (_, xml) = listing(&tree(), "/x&y<z>.txt", "0", body).await;
+        assert_multistatus(&xml);
+        let (found, missing) = xml.split_once("HTTP/1.1 200 OK").unwrap();
+        assert!(found.contains("<d:getcontentlength>5</d:getcontentlength>"), "{}", xml);
+        assert!(!found.contains("getcontenttype"), "{}", xml);
+        assert!(missing.contains("<d:quota-used-bytes/>"), "{}", xml);
+        assert!(missing.contains(r#"<x:color xmlns:x="urn:other"/>"#), "{}", xml);
+        assert!(missing.contains("HTTP/1.1 404 Not Found"), "{}", xml);
+    }
+
+    #[tokio::test]
+    async fn files_are_served_and_writes_refused() {
+        let tree = tree();
+        let headers = HeaderMap::new();
+        let response = tree.serve(&Method::GET, "/x&y<z>.txt", &headers, b"").unwrap();
+        assert_eq!(hyper::body::to_bytes(response.into_body()).await.unwrap(), "hello");
+        let response = tree.serve(&Method::PUT, "/a b", &headers, b"").unwrap();
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert!(tree.serve(&Method::GET, "/missing", &headers, b"").is_none());
+    }
+
+    #[test]
+    fn depth_header() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(depth(&headers), u32::MAX);
+        for (value, expected) in [("0", 0), (" 1 ", 1), ("infinity", u32::MAX), ("2", u32::MAX)] {
+            headers.insert("depth", value.parse().unwrap());
+            assert_eq!(depth(&headers), expected, "{}", value);
+        }
+    }
+}
@@ -0,0 +1,129 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures::future::poll_fn;
+use hyper::client::conn::{self, SendRequest};
+use hyper::{Body, Request, Response, Uri};
+
+use crate::pinned::BoxError;
+use crate::resolve::Resolver;
+
+// Upstream connections opened ahead of time, so the first requests after
+// startup (or after the upstream went away) don't wait for a connect. Idle
+// connections are handed out to requests and come back once their response
+// has been read; closed ones are replaced in the background.
+pub struct WarmPool {
+    uri: Uri,
+    resolver: Resolver,
+    size: usize,
+    idle: Mutex<Vec<SendRequest<Body>>>,
+    // Connections currently serving a request
+    busy: AtomicUsize,
+}
+
+// How often closed connections are noticed and replaced
+pub const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+// A connection taken from the pool for one request
+pub struct WarmConnection {
+    sender: SendRequest<Body>,
+    pool: Arc<WarmPool>,
+}
+
+// Whether an idle connection can take a request right away
+fn usable(sender: &mut SendRequest<Body>) -> bool {
+    let mut cx = Context::from_waker(futures::task::noop_waker_ref());
+    matches!(sender.poll_ready(&mut cx), Poll::Ready(Ok(())))
+}
+
+impl WarmPool {
+    pub fn new(upstream_uri: &Uri, size: usize, resolver: Resolver) -> Self {
+        WarmPool {
+            uri: upstream_uri.clone(),
+            resolver,
+            size,
+            idle: Mutex::new(Vec::new()),
+            busy: AtomicUsize::new(0),
+        }
+    }
+
+    async fn connect(&self) -> Result<SendRequest<Body>, BoxError> {
+        let stream = self.resolver.open(&self.uri).await?;
+        let (sender, connection) = conn::handshake(stream).await?;
+        tokio::spawn(async move {
+            let _ = connection.await;
+        });
+        Ok(sender)
+    }
+
+    // Open connections until the pool is full again
+    async fn fill(&self) {
+        let mut idle = {
+            let mut idle = self.idle.lock().unwrap();
+            idle.retain_mut(usable);
+            idle.len()
+        };
+        while idle + self.busy.load(Ordering::Relaxed) < self.size {
+            match self.connect().await {
+                Ok(sender) => self.idle.lock().unwrap().push(sender),
+                Err(e) => {
+                    tracing::warn!("Failed to open a warm upstream connection: {}", e);
+                    return;
+                }
+            }
+            idle += 1;
+        }
+    }
+
+    pub fn take(self: &Arc<Self>) -> Option<WarmConnection> {
+        let mut idle = self.idle.lock().unwrap();
+        while let Some(mut sender) = idle.pop() {
+            if usable(&mut sender) {
+                self.busy.fetch_add(1, Ordering::Relaxed);
+                return Some(WarmConnection {
+                    sender,
+                    pool: self.clone(),
+                });
+            }
+        }
+        None
+    }
+}
+
+impl WarmConnection {
+    pub async fn request(mut self, mut req: Request<Body>) -> Result<Response<Body>, BoxError> {
+        // A raw connection sends the URI as-is, so reduce it to origin-form
+        let path = req
+            .uri()
+            .path_and_query()
+            .map(|p| p.as_str().to_string())
+            .unwrap_or("/".to_string());
+        *req.uri_mut() = path.parse()?;
+        let response = self.sender.send_request(req).await;
+        // The connection is ready again once the response body has been read
+        tokio::spawn(async move {
+            let ready = poll_fn(|cx| self.sender.poll_ready(cx)).await.is_ok();
+            self.pool.busy.fetch_sub(1, Ordering::Relaxed);
+            if ready {
+                self.pool.idle.lock().unwrap().push(self.sender);
+            }
+        });
+        Ok(response?)
+    }
+}
+
+// Keep `pool` filled until its upstream is gone, replaced by a reload
+pub fn spawn(pool: Arc<WarmPool>) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(CHECK_INTERVAL);
+        loop {
+            ticker.tick().await;
+            if Arc::strong_count(&pool) == 1 {
+                break;
+            }
+            pool.fill().await;
+        }
+    });
+}
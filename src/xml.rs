@@ -0,0 +1,308 @@
+// Minimal XML writer for the responses the proxy synthesizes itself, and a
+// well-formedness check for the bodies of requests (--strict)
+
+pub fn escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&apos;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+// Make text from an untrusted source safe to embed: drop characters XML 1.0
+// can't represent at all and cut it to at most `max` bytes
+pub fn sanitize(s: &str, max: usize) -> String {
+    let mut out = String::with_capacity(s.len().min(max));
+    for c in s.chars() {
+        let allowed = matches!(c, '\t' | '\n' | '\r') || (c >= ' ' && c != '\u{FFFE}' && c != '\u{FFFF}');
+        if !allowed {
+            continue;
+        }
+        if out.len() + c.len_utf8() > max {
+            break;
+        }
+        out.push(c);
+    }
+    out
+}
+
+// Writes elements in the namespace bound to `prefix`, closing them in order
+pub struct Writer {
+    out: String,
+    prefix: String,
+    open: Vec<String>,
+}
+
+impl Writer {
+    // An empty prefix writes unprefixed names
+    pub fn new(prefix: &str) -> Self {
+        Writer {
+            out: String::new(),
+            prefix: if prefix.is_empty() { String::new() } else { format!("{}:", prefix) },
+            open: Vec::new(),
+        }
+    }
+
+    // Start a document with the XML declaration
+    pub fn document(prefix: &str) -> Self {
+        let mut writer = Writer::new(prefix);
+        writer.out.push_str("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+        writer
+    }
+
+    fn tag(&mut self, name: &str, attrs: &[(&str, &str)]) {
+        self.out.push('<');
+        self.out.push_str(&self.prefix);
+        self.out.push_str(name);
+        for (key, value) in attrs {
+            self.out.push(' ');
+            self.out.push_str(key);
+            self.out.push_str("=\"");
+            self.out.push_str(&escape(value));
+            self.out.push('"');
+        }
+    }
+
+    pub fn open(&mut self, name: &str) -> &mut Self {
+        self.open_with(name, &[])
+    }
+
+    pub fn open_with(&mut self, name: &str, attrs: &[(&str, &str)]) -> &mut Self {
+        self.tag(name, attrs);
+        self.out.push('>');
+        self.open.push(name.to_string());
+        self
+    }
+
+    pub fn close(&mut self) -> &mut Self {
+        if let Some(name) = self.open.pop() {
+            self.out.push_str("</");
+            self.out.push_str(&self.prefix);
+            self.out.push_str(&name);
+            self.out.push('>');
+        }
+        self
+    }
+
+    pub fn empty(&mut self, name: &str) -> &mut Self {
+        self.tag(name, &[]);
+        self.out.push_str("/>");
+        self
+    }
+
+    pub fn text(&mut self, text: &str) -> &mut Self {
+        self.out.push_str(&escape(text));
+        self
+    }
+
+    // Markup that was built elsewhere
+    pub fn raw(&mut self, markup: &str) -> &mut Self {
+        self.out.push_str(markup);
+        self
+    }
+
+    // <name>text</name>
+    pub fn element(&mut self, name: &str, text: &str) -> &mut Self {
+        self.open(name).text(text).close()
+    }
+
+    // Close whatever is still open and return the markup
+    pub fn finish(mut self) -> String {
+        while !self.open.is_empty() {
+            self.close();
+        }
+        self.out
+    }
+}
+
+// A name as XML allows it, with at most one colon between prefix and local
+// part
+fn valid_name(name: &str) -> bool {
+    let mut parts = name.split(':');
+    let valid = |part: &str| {
+        let mut chars = part.chars();
+        chars.next().is_some_and(|c| c.is_alphabetic() || c == '_')
+            && chars.all(|c| c.is_alphanumeric() || matches!(c, '-' | '.' | '_'))
+    };
+    parts.next().is_some_and(valid) && parts.next().is_none_or(valid) && parts.next().is_none()
+}
+
+// Character data or an attribute value: references must be complete and,
+// without a DTD, the predefined ones
+fn check_references(text: &str) -> Result<(), String> {
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        let after = &rest[start + 1..];
+        let end = after.find(';').ok_or("an & that doesn't start a reference")?;
+        let name = &after[..end];
+        let valid = match name.strip_prefix('#') {
+            Some(hex) if hex.starts_with('x') => u32::from_str_radix(&hex[1..], 16).ok().and_then(char::from_u32).is_some(),
+            Some(decimal) => decimal.parse::<u32>().ok().and_then(char::from_u32).is_some(),
+            None => matches!(name, "amp" | "lt" | "gt" | "quot" | "apos"),
+        };
+        if !valid {
+            return Err(format!("unknown reference &{};", name));
+        }
+        rest = &after[end + 1..];
+    }
+    Ok(())
+}
+
+// A tag's content up to its `>`, which may appear in quoted values, and
+// what follows
+fn split_tag(s: &str) -> Option<(&str, &str)> {
+    let mut quote = None;
+    for (i, c) in s.char_indices() {
+        match (quote, c) {
+            (None, '"' | '\'') => quote = Some(c),
+            (Some(q), c) if c == q => quote = None,
+            (None, '>') => return Some((&s[..i], &s[i + 1..])),
+            _ => {}
+        }
+    }
+    None
+}
+
+// Attribute names and values
+type Attributes<'a> = Vec<(&'a str, &'a str)>;
+
+// A start tag's name and attributes
+fn parse_tag(tag: &str) -> Result<(&str, Attributes<'_>), String> {
+    let end = tag.find(char::is_whitespace).unwrap_or(tag.len());
+    let name = &tag[..end];
+    if !valid_name(name) {
+        return Err(format!("invalid element name <{}>", name));
+    }
+    let mut attributes: Attributes = Vec::new();
+    let mut rest = tag[end..].trim_start();
+    while !rest.is_empty() {
+        let (attribute, after) = rest.split_once('=').ok_or(format!("attribute without a value in <{}>", name))?;
+        let attribute = attribute.trim_end();
+        if !valid_name(attribute) {
+            return Err(format!("invalid attribute name {} in <{}>", attribute, name));
+        }
+        let after = after.trim_start();
+        let quote = after.chars().next().filter(|c| matches!(c, '"' | '\''));
+        let quote = quote.ok_or(format!("unquoted value of {} in <{}>", attribute, name))?;
+        let end = after[1..].find(quote).expect("split_tag saw the closing quote") + 1;
+        let value = &after[1..end];
+        if value.contains('<') {
+            return Err(format!("< in the value of {} in <{}>", attribute, name));
+        }
+        check_references(value)?;
+        if attributes.iter().any(|(a, _)| *a == attribute) {
+            return Err(format!("{} is given twice in <{}>", attribute, name));
+        }
+        attributes.push((attribute, value));
+        rest = after[end + 1..].trim_start();
+        if !rest.is_empty() && !after[end + 1..].starts_with(char::is_whitespace) {
+            return Err(format!("no space between the attributes of <{}>", name));
+        }
+    }
+    Ok((name, attributes))
+}
+
+// Whether `text` is a well-formed document, as far as WebDAV bodies go:
+// elements, attributes, namespaces, references, comments, CDATA sections
+// and processing instructions, but no DTD. Its root element as namespace
+// and local name, or what is wrong and on which line.
+pub fn root_element(text: &str) -> Result<(String, String), String> {
+    let line = |rest: &str| text[..text.len() - rest.len()].matches('\n').count() + 1;
+    let fail = |rest: &str, what: &str| format!("line {}: {}", line(rest), what);
+    // Bound prefixes, "" for the default namespace, innermost last
+    let mut scopes: Vec<(&str, &str)> = vec![("xml", "http://www.w3.org/XML/1998/namespace")];
+    // Open elements, with the scopes outside them
+    let mut open: Vec<(&str, usize)> = Vec::new();
+    let mut root = None;
+    let mut rest = text.strip_prefix('\u{feff}').unwrap_or(text);
+    while !rest.is_empty() {
+        let done = root.is_some() && open.is_empty();
+        if let Some(after) = rest.strip_prefix("<!--") {
+            let end = after.find("-->").ok_or_else(|| fail(rest, "unterminated comment"))?;
+            rest = &after[end + 3..];
+        } else if let Some(after) = rest.strip_prefix("<![CDATA[") {
+            if open.is_empty() {
+                return Err(fail(rest, "CDATA outside the root element"));
+            }
+            let end = after.find("]]>").ok_or_else(|| fail(rest, "unterminated CDATA section"))?;
+            rest = &after[end + 3..];
+        } else if rest.starts_with("<!") {
+            return Err(fail(rest, "DTDs aren't accepted"));
+        } else if let Some(after) = rest.strip_prefix("<?") {
+            let end = after.find("?>").ok_or_else(|| fail(rest, "unterminated processing instruction"))?;
+            rest = &after[end + 2..];
+        } else if let Some(after) = rest.strip_prefix("</") {
+            let end = after.find('>').ok_or_else(|| fail(rest, "unterminated end tag"))?;
+            let name = after[..end].trim_end();
+            match open.pop() {
+                Some((expected, outside)) if expected == name => scopes.truncate(outside),
+                Some((expected, _)) => return Err(fail(rest, &format!("</{}> closes <{}>", name, expected))),
+                None => return Err(fail(rest, &format!("</{}> without a start tag", name))),
+            }
+            rest = &after[end + 1..];
+        } else if let Some(after) = rest.strip_prefix('<') {
+            if done {
+                return Err(fail(rest, "more than one root element"));
+            }
+            let (tag, after) = split_tag(after).ok_or_else(|| fail(rest, "unterminated start tag"))?;
+            let (tag, empty) = match tag.strip_suffix('/') {
+                Some(tag) => (tag, true),
+                None => (tag, false),
+            };
+            let (name, attributes) = parse_tag(tag).map_err(|e| fail(rest, &e))?;
+            let outside = scopes.len();
+            for (attribute, value) in &attributes {
+                if *attribute == "xmlns" {
+                    scopes.push(("", value));
+                } else if let Some(prefix) = attribute.strip_prefix("xmlns:") {
+                    if value.is_empty() {
+                        return Err(fail(rest, &format!("prefix {} bound to no namespace", prefix)));
+                    }
+                    scopes.push((prefix, value));
+                }
+            }
+            let namespace = |prefix: &str| scopes.iter().rev().find(|(p, _)| *p == prefix).map(|(_, uri)| *uri);
+            for (attribute, _) in &attributes {
+                match attribute.split_once(':') {
+                    Some(("xmlns", _)) | None => {}
+                    Some((prefix, _)) if namespace(prefix).is_none() => {
+                        return Err(fail(rest, &format!("undeclared namespace prefix {}", prefix)));
+                    }
+                    _ => {}
+                }
+            }
+            let (prefix, local) = name.split_once(':').unwrap_or(("", name));
+            let uri = match namespace(prefix) {
+                Some(uri) => uri,
+                None if prefix.is_empty() => "",
+                None => return Err(fail(rest, &format!("undeclared namespace prefix {}", prefix))),
+            };
+            root.get_or_insert_with(|| (uri.to_string(), local.to_string()));
+            if empty {
+                scopes.truncate(outside);
+            } else {
+                open.push((name, outside));
+            }
+            rest = after;
+        } else {
+            let end = rest.find('<').unwrap_or(rest.len());
+            let chars = &rest[..end];
+            if open.is_empty() && !chars.trim().is_empty() {
+                return Err(fail(rest, "text outside the root element"));
+            }
+            check_references(chars).map_err(|e| fail(rest, &e))?;
+            rest = &rest[end..];
+        }
+    }
+    if let Some((name, _)) = open.last() {
+        return Err(fail(rest, &format!("<{}> isn't closed", name)));
+    }
+    root.ok_or_else(|| "no root element".to_string())
+}
@@ -0,0 +1,253 @@
+// The subset of YAML the --verify fixtures are written in: nested block
+// mappings and sequences, plain, 'single' and "double" quoted scalars, and
+// `|` literal blocks for bodies. No flow collections, anchors, tags or
+// scalars spanning lines outside `|` blocks; keys are plain.
+
+#[derive(Debug)]
+pub enum Yaml {
+    // Every scalar is kept as text; an empty value is ""
+    Scalar(String),
+    List(Vec<Yaml>),
+    Map(Vec<(String, Yaml)>),
+}
+
+impl Yaml {
+    pub fn get(&self, key: &str) -> Option<&Yaml> {
+        match self {
+            Yaml::Map(entries) => entries.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Yaml::Scalar(s) => Some(s),
+            _ => None,
+        }
+    }
+}
+
+fn indent_of(line: &str) -> usize {
+    line.len() - line.trim_start_matches(' ').len()
+}
+
+// Blank lines, comments and document markers carry nothing
+fn insignificant(line: &str) -> bool {
+    let text = line.trim();
+    text.is_empty() || text.starts_with('#') || text == "---"
+}
+
+// A list item's text after the dash, if the line is one
+fn item(text: &str) -> Option<&str> {
+    match text.strip_prefix('-') {
+        Some(rest) if rest.is_empty() || rest.starts_with(' ') => Some(rest.trim_start()),
+        _ => None,
+    }
+}
+
+// A `key: value` line's key and value
+fn entry(text: &str) -> Option<(&str, &str)> {
+    if text.starts_with(['"', '\'']) {
+        return None;
+    }
+    let (key, value) = match text.find(": ") {
+        Some(i) => (&text[..i], &text[i + 2..]),
+        None => (text.strip_suffix(':')?, ""),
+    };
+    (!key.is_empty() && !key.contains(" #")).then(|| (key.trim(), value.trim()))
+}
+
+fn scalar(text: &str, number: usize) -> Result<String, String> {
+    let bad = |what: &str| format!("line {}: {}", number, what);
+    if let Some(rest) = text.strip_prefix('"') {
+        let mut out = String::new();
+        let mut chars = rest.chars();
+        loop {
+            match chars.next() {
+                Some('"') => break,
+                Some('\\') => out.push(match chars.next() {
+                    Some('n') => '\n',
+                    Some('r') => '\r',
+                    Some('t') => '\t',
+                    Some('0') => '\0',
+                    Some(c @ ('"' | '\\' | '/')) => c,
+                    _ => return Err(bad("unknown escape in a double-quoted string")),
+                }),
+                Some(c) => out.push(c),
+                None => return Err(bad("unterminated double-quoted string")),
+            }
+        }
+        let rest = chars.as_str().trim();
+        if !rest.is_empty() && !rest.starts_with('#') {
+            return Err(bad("only a comment may follow a quoted string"));
+        }
+        return Ok(out);
+    }
+    if let Some(rest) = text.strip_prefix('\'') {
+        let mut out = String::new();
+        let mut rest = rest;
+        loop {
+            let end = rest.find('\'').ok_or_else(|| bad("unterminated single-quoted string"))?;
+            out.push_str(&rest[..end]);
+            rest = &rest[end + 1..];
+            match rest.strip_prefix('\'') {
+                Some(more) => {
+                    out.push('\'');
+                    rest = more;
+                }
+                None => break,
+            }
+        }
+        let rest = rest.trim();
+        if !rest.is_empty() && !rest.starts_with('#') {
+            return Err(bad("only a comment may follow a quoted string"));
+        }
+        return Ok(out);
+    }
+    if text.starts_with(['[', '{', '&', '*', '!', '>']) {
+        return Err(bad("flow collections, anchors, tags and folded blocks aren't supported"));
+    }
+    let text = match text.find(" #") {
+        Some(i) => &text[..i],
+        None => text,
+    };
+    Ok(text.trim().to_string())
+}
+
+struct Parser {
+    lines: Vec<String>,
+    pos: usize,
+}
+
+impl Parser {
+    // The next line that carries something, as its index and indentation
+    fn peek(&mut self) -> Option<(usize, usize)> {
+        while self.pos < self.lines.len() && insignificant(&self.lines[self.pos]) {
+            self.pos += 1;
+        }
+        let line = self.lines.get(self.pos)?;
+        Some((self.pos, indent_of(line)))
+    }
+
+    fn node(&mut self, indent: usize) -> Result<Yaml, String> {
+        let Some((index, _)) = self.peek() else {
+            return Ok(Yaml::Scalar(String::new()));
+        };
+        if self.lines[index].contains('\t') && self.lines[index].trim_start_matches(' ').starts_with('\t') {
+            return Err(format!("line {}: indent with spaces, not tabs", index + 1));
+        }
+        if item(self.lines[index].trim()).is_some() {
+            self.list(indent)
+        } else {
+            self.map(indent)
+        }
+    }
+
+    fn list(&mut self, indent: usize) -> Result<Yaml, String> {
+        let mut items = Vec::new();
+        while let Some((index, at)) = self.peek() {
+            if at != indent {
+                break;
+            }
+            let line = self.lines[index].clone();
+            let Some(text) = item(line.trim()) else {
+                break;
+            };
+            if text.is_empty() {
+                self.pos += 1;
+                match self.peek() {
+                    Some((_, deeper)) if deeper > indent => items.push(self.node(deeper)?),
+                    _ => items.push(Yaml::Scalar(String::new())),
+                }
+            } else if entry(text).is_some() {
+                // A mapping starting on the dash's line continues below it
+                let inner = indent + line.trim().len() - text.len();
+                self.lines[index] = format!("{}{}", " ".repeat(inner), text);
+                items.push(self.map(inner)?);
+            } else {
+                self.pos += 1;
+                items.push(Yaml::Scalar(scalar(text, index + 1)?));
+            }
+        }
+        Ok(Yaml::List(items))
+    }
+
+    fn map(&mut self, indent: usize) -> Result<Yaml, String> {
+        let mut entries: Vec<(String, Yaml)> = Vec::new();
+        while let Some((index, at)) = self.peek() {
+            if at < indent {
+                break;
+            }
+            let line = self.lines[index].clone();
+            if at > indent {
+                return Err(format!("line {}: unexpected indentation", index + 1));
+            }
+            if item(line.trim()).is_some() {
+                break;
+            }
+            let (key, value) = entry(line.trim()).ok_or(format!("line {}: expected `key: value`", index + 1))?;
+            if entries.iter().any(|(k, _)| k == key) {
+                return Err(format!("line {}: {} is given twice", index + 1, key));
+            }
+            self.pos += 1;
+            let value = match value {
+                "" => match self.peek() {
+                    Some((_, deeper)) if deeper > indent => self.node(deeper)?,
+                    // A list may sit at its key's indentation
+                    Some((next, same)) if same == indent && item(self.lines[next].trim()).is_some() => self.list(indent)?,
+                    _ => Yaml::Scalar(String::new()),
+                },
+                "|" | "|-" | "|+" => Yaml::Scalar(self.block(indent, value)),
+                value => Yaml::Scalar(scalar(value, index + 1)?),
+            };
+            entries.push((key.to_string(), value));
+        }
+        Ok(Yaml::Map(entries))
+    }
+
+    // A literal block below a key at `indent`, chomped as `style` says
+    fn block(&mut self, indent: usize, style: &str) -> String {
+        let mut lines = Vec::new();
+        let mut inner = None;
+        while let Some(line) = self.lines.get(self.pos) {
+            if line.trim().is_empty() {
+                lines.push("");
+                self.pos += 1;
+                continue;
+            }
+            let at = indent_of(line);
+            if at <= indent {
+                break;
+            }
+            let inner = *inner.get_or_insert(at);
+            if at < inner {
+                break;
+            }
+            lines.push(&line[inner..]);
+            self.pos += 1;
+        }
+        let mut text = lines.join("\n");
+        match style {
+            "|+" => text.push('\n'),
+            "|-" => text.truncate(text.trim_end_matches('\n').len()),
+            _ => {
+                text.truncate(text.trim_end_matches('\n').len());
+                text.push('\n');
+            }
+        }
+        text
+    }
+}
+
+pub fn parse(text: &str) -> Result<Yaml, String> {
+    let mut parser = Parser {
+        lines: text.lines().map(|l| l.trim_end_matches('\r').to_string()).collect(),
+        pos: 0,
+    };
+    let indent = parser.peek().map_or(0, |(_, indent)| indent);
+    let node = parser.node(indent)?;
+    match parser.peek() {
+        Some((index, _)) => Err(format!("line {}: unexpected indentation", index + 1)),
+        None => Ok(node),
+    }
+}
@@ -0,0 +1,40 @@
+// Runs each file in fixtures/ through `--verify`, with the command line
+// given on its `# args:` line (split at spaces, so arguments can't hold any)
+
+use std::path::Path;
+use std::process::Command;
+
+fn args(text: &str) -> Option<Vec<&str>> {
+    text.lines()
+        .find_map(|line| line.strip_prefix("# args:"))
+        .map(|args| args.split_whitespace().collect())
+}
+
+#[test]
+fn fixtures() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures");
+    let mut files: Vec<_> = std::fs::read_dir(&dir)
+        .expect("fixtures directory")
+        .map(|entry| entry.expect("fixtures directory").path())
+        .filter(|path| path.extension().is_some_and(|e| e == "yaml"))
+        .collect();
+    files.sort();
+    assert!(!files.is_empty(), "no fixtures in {}", dir.display());
+    let mut failed = Vec::new();
+    for file in files {
+        let text = std::fs::read_to_string(&file).expect("readable fixture");
+        let args = args(&text).unwrap_or_else(|| panic!("{} has no `# args:` line", file.display()));
+        let output = Command::new(env!("CARGO_BIN_EXE_proxy-optional-webdav"))
+            .arg("--verify")
+            .arg(&file)
+            .args(args)
+            .output()
+            .expect("proxy binary");
+        print!("{}", String::from_utf8_lossy(&output.stdout));
+        if !output.status.success() {
+            eprint!("{}", String::from_utf8_lossy(&output.stderr));
+            failed.push(file.display().to_string());
+        }
+    }
+    assert!(failed.is_empty(), "failing fixtures in {}", failed.join(", "));
+}